@@ -4,12 +4,14 @@
 
 from __future__ import absolute_import, division, print_function, unicode_literals
 
+import hashlib
+
 from future.utils import binary_type, text_type
 
-from pants.base.project_tree import Dir, File
+from pants.base.project_tree import Dir, File, Link
 from pants.engine.rules import RootRule
 from pants.option.global_options import GlobMatchErrorBehavior
-from pants.util.objects import Collection, datatype
+from pants.util.objects import Collection, Exactly, datatype
 
 
 class FileContent(datatype([('path', text_type), ('content', binary_type)])):
@@ -33,34 +35,42 @@ class PathGlobs(datatype([
     'include',
     'exclude',
     ('glob_match_error_behavior', GlobMatchErrorBehavior),
+    ('preserve_symlinks', bool),
 ])):
   """A wrapper around sets of filespecs to include and exclude.
 
   The syntax supported is roughly git's glob syntax.
 
+  By default, symlinks are resolved to the files and directories that they point to. If
+  `preserve_symlinks` is True, they are instead captured as symlinks (and not traversed), and
+  capturing fails for symlinks whose targets are absolute or which otherwise escape the root.
+
   NB: this object is interpreted from within Snapshot::lift_path_globs() -- that method will need to
   be aware of any changes to this object's definition.
   """
 
-  def __new__(cls, include, exclude=(), glob_match_error_behavior=None):
+  def __new__(cls, include, exclude=(), glob_match_error_behavior=None, preserve_symlinks=False):
     """Given various file patterns create a PathGlobs object (without using filesystem operations).
 
     :param include: A list of filespecs to include.
     :param exclude: A list of filespecs to exclude.
     :param glob_match_error_behavior: The value to pass to GlobMatchErrorBehavior.create()
+    :param preserve_symlinks: Whether to capture symlinks as symlinks, rather than resolving them.
     :rtype: :class:`PathGlobs`
     """
     return super(PathGlobs, cls).__new__(
       cls,
       tuple(include),
       tuple(exclude),
-      GlobMatchErrorBehavior.create(glob_match_error_behavior))
+      GlobMatchErrorBehavior.create(glob_match_error_behavior),
+      preserve_symlinks)
 
   def with_match_error_behavior(self, glob_match_error_behavior):
     return PathGlobs(
       include=self.include,
       exclude=self.exclude,
-      glob_match_error_behavior=glob_match_error_behavior)
+      glob_match_error_behavior=glob_match_error_behavior,
+      preserve_symlinks=self.preserve_symlinks)
 
 
 class PathGlobsAndRoot(datatype([('path_globs', PathGlobs), ('root', text_type)])):
@@ -91,6 +101,18 @@ class DirectoryDigest(datatype([('fingerprint', text_type), ('serialized_bytes_l
     return repr(self)
 
 
+class FileDigest(datatype([('fingerprint', text_type), ('serialized_bytes_length', int)])):
+  """A FileDigest identifies the content of a single file known about by the engine.
+
+  The content of any file which has been captured in a Snapshot is known about by the engine.
+  """
+
+  @classmethod
+  def for_content(cls, content):
+    """The FileDigest of the given bytes."""
+    return cls(text_type(hashlib.sha256(content).hexdigest()), len(content))
+
+
 class Snapshot(datatype([('directory_digest', DirectoryDigest), ('path_stats', tuple)])):
   """A Snapshot is a collection of Files and Dirs fingerprinted by their names/content.
 
@@ -115,10 +137,103 @@ class Snapshot(datatype([('directory_digest', DirectoryDigest), ('path_stats', t
   def file_stats(self):
     return [p.stat for p in self.files]
 
+  @property
+  def links(self):
+    """Symlinks, which are only present if they were captured with `preserve_symlinks`."""
+    return [p for p in self.path_stats if type(p.stat) == Link]
+
+
+class DirectoryToMaterialize(datatype([
+  ('path', text_type),
+  ('directory_digest', DirectoryDigest),
+  ('sync', bool),
+  ('managed_prefix', Exactly(text_type, type(None))),
+])):
+  """A request to materialize the contents of a directory digest at the provided path.
+
+  By default, the contents are written into the path, overwriting any existing files. If `sync` is
+  True, the path is instead made to match the digest: only files whose contents differ are written,
+  and anything not in the digest is removed. If a `managed_prefix` (relative to the path) is also
+  given, only things below it are removed.
+  """
+
+  def __new__(cls, path, directory_digest, sync=False, managed_prefix=None):
+    return super(DirectoryToMaterialize, cls).__new__(
+      cls, path, directory_digest, sync, managed_prefix)
+
+class MergePolicy(object):
+  """How to treat a file which is present in more than one of the directories being merged.
+
+  NB: these values are interpreted by MergePolicy::create() in the fs crate.
+  """
+
+  # Fail if a file is present more than once, even with identical contents.
+  ERROR = 'error'
+  # Fail if a file is present more than once with different contents.
+  IDENTICAL_ONLY = 'identical_only'
+  # Use the file from the last directory in which it is present.
+  LAST_WINS = 'last_wins'
+  # Use the file from the first directory in which it is present.
+  FIRST_WINS = 'first_wins'
+
+  allowed_values = [ERROR, IDENTICAL_ONLY, LAST_WINS, FIRST_WINS]
+
+
+class DirectoryWithPrefixToAdd(datatype([
+  ('directory_digest', DirectoryDigest),
+  ('prefix', text_type),
+])):
+  """A request to nest the contents of a directory digest under the given relative path."""
+
+
+class DirectoryWithPrefixToStrip(datatype([
+  ('directory_digest', DirectoryDigest),
+  ('prefix', text_type),
+])):
+  """A request to remove the given relative path from the front of a directory digest.
+
+  It is an error for the directory to contain anything other than the prefix.
+  """
+
+
+class DirectorySubset(datatype([
+  ('directory_digest', DirectoryDigest),
+  ('path_globs', PathGlobs),
+])):
+  """A request for the files in a directory digest which match the given PathGlobs."""
+
+
+class ArchiveFormat(object):
+  """The format of an archive of a directory.
+
+  NB: these values are interpreted by ArchiveFormat::create() in the fs crate.
+  """
+
+  TAR = 'tar'
+  # Zip archives cannot contain symlinks.
+  ZIP = 'zip'
+
+  allowed_values = [TAR, ZIP]
+
+
+class DirectoryToArchive(datatype([
+  ('directory_digest', DirectoryDigest),
+  ('path', text_type),
+  ('format', text_type),
+])):
+  """A request to write the contents of a directory digest to an archive at the given path.
+
+  Archives are deterministic: entries are sorted, and timestamps and ownership are zeroed. The
+  archive is only moved into place at the path once it is complete.
+  """
+
+
+class ArchiveToImport(datatype([
+  ('path', text_type),
+  ('format', text_type),
+])):
+  """A request to store the contents of the archive at the given path as a directory digest."""
 
-class DirectoryToMaterialize(datatype([('path', text_type), ('directory_digest', DirectoryDigest)])):
-  """A request to materialize the contents of a directory digest at the provided path."""
-  pass
 
 FilesContent = Collection.of(FileContent)
 
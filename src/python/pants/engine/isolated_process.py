@@ -8,7 +8,7 @@ import logging
 
 from future.utils import text_type
 
-from pants.engine.fs import DirectoryDigest
+from pants.engine.fs import DirectoryDigest, FileDigest
 from pants.engine.rules import RootRule, rule
 from pants.engine.selectors import Select
 from pants.util.objects import Exactly, TypeCheckError, datatype
@@ -19,6 +19,41 @@ logger = logging.getLogger(__name__)
 _default_timeout_seconds = 15 * 60
 
 
+class ResourceLimits(datatype([
+  ('cpu_seconds', Exactly(int, type(None))),
+  ('address_space_bytes', Exactly(int, type(None))),
+  ('open_files', Exactly(int, type(None))),
+  # NB: bounds both the size of any file which the process writes and its combined stdout/stderr.
+  ('max_output_bytes', Exactly(int, type(None))),
+])):
+  """Limits on the resources which a process may use, which are only enforced locally.
+
+  Limits which are None are not enforced.
+  """
+
+  def __new__(cls, cpu_seconds=None, address_space_bytes=None, open_files=None,
+              max_output_bytes=None):
+    return super(ResourceLimits, cls).__new__(
+      cls,
+      cpu_seconds=cpu_seconds,
+      address_space_bytes=address_space_bytes,
+      open_files=open_files,
+      max_output_bytes=max_output_bytes,
+    )
+
+
+class ResourceUsage(datatype([
+  ('user_time_micros', int),
+  ('system_time_micros', int),
+  ('max_rss_bytes', int),
+])):
+  """The resources used by a locally executed process.
+
+  NB: On Linux, max_rss_bytes includes the memory of pantsd before the process was exec'd, so is
+  only meaningful for processes which are larger than pantsd.
+  """
+
+
 class ExecuteProcessRequest(datatype([
   ('argv', tuple),
   ('input_files', DirectoryDigest),
@@ -29,6 +64,16 @@ class ExecuteProcessRequest(datatype([
   # NB: timeout_seconds covers the whole remote operation including queuing and setup.
   ('timeout_seconds', Exactly(float, int)),
   ('jdk_home', Exactly(text_type, type(None))),
+  # NB: relative to the root of input_files; output paths are relative to it.
+  ('working_directory', Exactly(text_type, type(None))),
+  # NB: the content of the file must already be known to the engine: see FileDigest. Remote
+  # execution provides it as `.pants-stdin` in the input root, redirected by a /bin/sh.
+  ('stdin', Exactly(FileDigest, type(None))),
+  # NB: flattened pairs of cache name and the relative path at which to make the cache available.
+  ('named_caches', tuple),
+  ('resource_limits', ResourceLimits),
+  # NB: usage is only reported for local execution, which then spawns the process via a reaper.
+  ('report_resource_usage', bool),
 ])):
   """Request for execution with args and snapshots to extract."""
 
@@ -42,6 +87,11 @@ class ExecuteProcessRequest(datatype([
     output_directories=(),
     timeout_seconds=_default_timeout_seconds,
     jdk_home=None,
+    working_directory=None,
+    stdin=None,
+    named_caches=None,
+    resource_limits=None,
+    report_resource_usage=False,
   ):
     if env is None:
       env = ()
@@ -56,6 +106,22 @@ class ExecuteProcessRequest(datatype([
         )
       env = tuple(item for pair in env.items() for item in pair)
 
+    if named_caches is None:
+      named_caches = ()
+    else:
+      if not isinstance(named_caches, dict):
+        raise TypeCheckError(
+          cls.__name__,
+          "arg 'named_caches' was invalid: value {} (with type {}) must be a dict".format(
+            named_caches,
+            type(named_caches)
+          )
+        )
+      named_caches = tuple(item for pair in sorted(named_caches.items()) for item in pair)
+
+    if resource_limits is None:
+      resource_limits = ResourceLimits()
+
     return super(ExecuteProcessRequest, cls).__new__(
       cls,
       argv=argv,
@@ -66,6 +132,11 @@ class ExecuteProcessRequest(datatype([
       output_directories=output_directories,
       timeout_seconds=timeout_seconds,
       jdk_home=jdk_home,
+      working_directory=working_directory,
+      stdin=stdin,
+      named_caches=named_caches,
+      resource_limits=resource_limits,
+      report_resource_usage=report_resource_usage,
     )
 
 
@@ -75,11 +146,32 @@ class ExecuteProcessResult(datatype(['stdout', 'stderr', 'output_directory_diges
   Requesting one of these will raise an exception if the exit code is non-zero."""
 
 
-class FallibleExecuteProcessResult(datatype(['stdout', 'stderr', 'exit_code', 'output_directory_digest'])):
+class FallibleExecuteProcessResult(datatype([
+  'stdout',
+  'stderr',
+  'exit_code',
+  'output_directory_digest',
+  # NB: Only available for local execution of requests with report_resource_usage: otherwise None.
+  'resource_usage',
+])):
   """Result of executing a process.
 
   Requesting one of these will not raise an exception if the exit code is non-zero."""
 
+  def __new__(cls, stdout, stderr, exit_code, output_directory_digest, resource_usage=None):
+    # NB: The engine provides the fields of the ResourceUsage as a tuple, which is empty if the
+    # usage is unavailable.
+    if isinstance(resource_usage, tuple) and not isinstance(resource_usage, ResourceUsage):
+      resource_usage = ResourceUsage(*resource_usage) if resource_usage else None
+    return super(FallibleExecuteProcessResult, cls).__new__(
+      cls,
+      stdout=stdout,
+      stderr=stderr,
+      exit_code=exit_code,
+      output_directory_digest=output_directory_digest,
+      resource_usage=resource_usage,
+    )
+
 
 class ProcessExecutionFailure(Exception):
   """Used to denote that a process exited, but was unsuccessful in some way.
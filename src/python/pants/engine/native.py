@@ -10,6 +10,7 @@ import os
 import sys
 import sysconfig
 import traceback
+import uuid
 from builtins import bytes, object, open, str
 from contextlib import closing
 
@@ -22,6 +23,7 @@ from pants.util.contextutil import temporary_dir
 from pants.util.dirutil import read_file, safe_mkdir, safe_mkdtemp
 from pants.util.memo import memoized_property
 from pants.util.objects import datatype
+from pants.version import VERSION
 
 
 logger = logging.getLogger(__name__)
@@ -176,7 +178,7 @@ void tasks_task_end(Tasks*);
 void tasks_singleton_add(Tasks*, Handle, TypeConstraint);
 void tasks_destroy(Tasks*);
 
-Scheduler* scheduler_create(Tasks*,
+PyResult scheduler_create(Tasks*,
                             Function,
                             Function,
                             Function,
@@ -205,14 +207,29 @@ Scheduler* scheduler_create(Tasks*,
                             Buffer,
                             BufferBuffer,
                             TypeIdBuffer,
+                            BufferBuffer,
+                            Buffer,
+                            Buffer,
                             Buffer,
                             Buffer,
+                            Buffer,
+                            uint64_t,
+                            uint64_t,
+                            uint64_t,
+                            uint64_t,
+                            _Bool,
+                            _Bool,
+                            BufferBuffer,
+                            _Bool,
+                            uint64_t,
+                            Buffer,
                             uint64_t,
                             uint64_t,
                             uint64_t,
                             uint64_t,
-                            _Bool);
+                            Scheduler**);
 void scheduler_pre_fork(Scheduler*);
+PyResult scheduler_check_capabilities(Scheduler*);
 Handle scheduler_metrics(Scheduler*, Session*);
 RawNodes* scheduler_execute(Scheduler*, Session*, ExecutionRequest*);
 void scheduler_destroy(Scheduler*);
@@ -234,6 +251,11 @@ PyResult  execution_add_root_select(Scheduler*, ExecutionRequest*, Key, TypeCons
 PyResult capture_snapshots(Scheduler*, Handle);
 
 PyResult merge_directories(Scheduler*, Handle);
+PyResult add_prefix(Scheduler*, Handle);
+PyResult strip_prefix(Scheduler*, Handle);
+PyResult subset_directory(Scheduler*, Handle);
+PyResult archive_directory(Scheduler*, Handle);
+PyResult import_archive(Scheduler*, Handle);
 
 PyResult materialize_directories(Scheduler*, Handle);
 
@@ -822,7 +844,8 @@ class Native(object):
     def tc(constraint):
       return TypeConstraint(self.context.to_key(constraint))
 
-    scheduler = self.lib.scheduler_create(
+    scheduler_ptr = self.ffi.new('Scheduler**')
+    res = self.lib.scheduler_create(
         tasks,
         # Constructors/functions.
         func(construct_directory_digest),
@@ -858,15 +881,31 @@ class Native(object):
         self.to_ids_buf(root_subject_types),
         # Remote execution config.
         # We can't currently pass Options to the rust side, so we pass empty strings for None.
-        self.context.utf8_buf(execution_options.remote_store_server or ""),
+        self.context.utf8_buf_buf(execution_options.remote_store_server),
+        self.context.utf8_buf(execution_options.remote_store_server_selection),
         self.context.utf8_buf(execution_options.remote_execution_server or ""),
+        # Identifies this invocation to remote servers, which see every Scheduler as a new one.
+        self.context.utf8_buf(VERSION),
+        self.context.utf8_buf(str(uuid.uuid4())),
+        self.context.utf8_buf(execution_options.remote_correlated_invocations_id or ""),
         execution_options.remote_store_thread_count,
         execution_options.remote_store_chunk_bytes,
         execution_options.remote_store_chunk_upload_timeout_seconds,
         execution_options.process_execution_parallelism,
-        execution_options.process_execution_cleanup_local_dirs
+        execution_options.process_execution_cleanup_local_dirs,
+        execution_options.process_execution_local_sandbox,
+        self.context.utf8_buf_buf(execution_options.process_execution_local_sandbox_read_only_paths),
+        execution_options.process_execution_local_link_inputs,
+        execution_options.local_store_compression_threshold_bytes,
+        self.context.utf8_buf(execution_options.local_store_read_only_dir or ""),
+        execution_options.local_store_shard_count,
+        execution_options.local_store_map_size_bytes,
+        execution_options.local_store_max_map_size_bytes or 0,
+        execution_options.local_store_large_file_threshold_bytes,
+        scheduler_ptr,
       )
-    return self.gc(scheduler, self.lib.scheduler_destroy)
+    self.context.raise_or_return(res)
+    return self.gc(scheduler_ptr[0], self.lib.scheduler_destroy)
 
   def set_panic_handler(self):
     if os.getenv("RUST_BACKTRACE", "0") == "0":
@@ -11,11 +11,13 @@ from builtins import object, open, str, zip
 from collections import defaultdict
 from types import GeneratorType
 
+from future.utils import text_type
+
 from pants.base.exceptions import TaskError
 from pants.base.project_tree import Dir, File, Link
 from pants.build_graph.address import Address
 from pants.engine.fs import (DirectoryDigest, DirectoryToMaterialize, FileContent, FilesContent,
-                             Path, PathGlobs, PathGlobsAndRoot, Snapshot)
+                             MergePolicy, Path, PathGlobs, PathGlobsAndRoot, Snapshot)
 from pants.engine.isolated_process import ExecuteProcessRequest, FallibleExecuteProcessResult
 from pants.engine.native import Function, TypeConstraint, TypeId
 from pants.engine.nodes import Return, State, Throw
@@ -155,6 +157,10 @@ class Scheduler(object):
     if validate:
       self._assert_ruleset_valid()
 
+    # Checking capabilities may have to wait on a remote execution server, so it is deferred until
+    # something is actually run.
+    self._capabilities_checked = False
+
   def _root_type_ids(self):
     return self._to_ids_buf(sorted(self._root_subject_types, key=repr))
 
@@ -316,7 +322,14 @@ class Scheduler(object):
   def pre_fork(self):
     self._native.lib.scheduler_pre_fork(self._scheduler)
 
+  def _check_capabilities(self):
+    """Fails if processes can't be run as configured (e.g. by a remote execution server)."""
+    if not self._capabilities_checked:
+      self._raise_or_return(self._native.lib.scheduler_check_capabilities(self._scheduler))
+      self._capabilities_checked = True
+
   def _run_and_return_roots(self, session, execution_request):
+    self._check_capabilities()
     raw_roots = self._native.lib.scheduler_execute(self._scheduler, session, execution_request)
     try:
       roots = []
@@ -348,15 +361,78 @@ class Scheduler(object):
     )
     return self._raise_or_return(result)
 
-  def merge_directories(self, directory_digests):
+  def merge_directories(self, directory_digests, merge_policy=MergePolicy.IDENTICAL_ONLY):
     """Merges any number of directories.
 
     :param directory_digests: Tuple of DirectoryDigests.
+    :param merge_policy: One of the MergePolicy values, which decides what happens when a file is
+           present in more than one of the directories.
     :return: A DirectoryDigest.
     """
     result = self._native.lib.merge_directories(
       self._scheduler,
-      self._to_value(_DirectoryDigests(directory_digests)),
+      self._to_value(
+        _DirectoriesToMerge(_DirectoryDigests(directory_digests), text_type(merge_policy))),
+    )
+    return self._raise_or_return(result)
+
+  def add_prefix(self, directory_with_prefix_to_add):
+    """Nests the contents of a directory under a prefix.
+
+    :param directory_with_prefix_to_add: A DirectoryWithPrefixToAdd.
+    :return: A DirectoryDigest.
+    """
+    result = self._native.lib.add_prefix(
+      self._scheduler,
+      self._to_value(directory_with_prefix_to_add),
+    )
+    return self._raise_or_return(result)
+
+  def strip_prefix(self, directory_with_prefix_to_strip):
+    """Removes a prefix from the contents of a directory.
+
+    :param directory_with_prefix_to_strip: A DirectoryWithPrefixToStrip.
+    :return: A DirectoryDigest.
+    """
+    result = self._native.lib.strip_prefix(
+      self._scheduler,
+      self._to_value(directory_with_prefix_to_strip),
+    )
+    return self._raise_or_return(result)
+
+  def subset_directory(self, directory_subset):
+    """Selects the files in a directory which match some PathGlobs.
+
+    :param directory_subset: A DirectorySubset.
+    :return: A DirectoryDigest.
+    """
+    result = self._native.lib.subset_directory(
+      self._scheduler,
+      self._to_value(directory_subset),
+    )
+    return self._raise_or_return(result)
+
+  def archive_directory(self, directory_to_archive):
+    """Writes the contents of a directory to a tar or zip archive.
+
+    :param directory_to_archive: A DirectoryToArchive.
+    :returns: Nothing or an error.
+    """
+    result = self._native.lib.archive_directory(
+      self._scheduler,
+      self._to_value(directory_to_archive),
+    )
+    return self._raise_or_return(result)
+
+  def import_archive(self, archive_to_import):
+    """Stores the contents of a tar or zip archive, without extracting it.
+
+    :param archive_to_import: An ArchiveToImport.
+    :return: A DirectoryDigest.
+    """
+    result = self._native.lib.import_archive(
+      self._scheduler,
+      self._to_value(archive_to_import),
     )
     return self._raise_or_return(result)
 
@@ -394,6 +470,10 @@ _PathGlobsAndRootCollection = Collection.of(PathGlobsAndRoot)
 _DirectoryDigests = Collection.of(DirectoryDigest)
 
 
+class _DirectoriesToMerge(datatype(['directories', ('merge_policy', text_type)])):
+  """The argument to the merge_directories intrinsic: a _DirectoryDigests and a MergePolicy."""
+
+
 _DirectoriesToMaterialize = Collection.of(DirectoryToMaterialize)
 
 
@@ -587,8 +667,23 @@ class SchedulerSession(object):
     """
     return self._scheduler.capture_snapshots(path_globs_and_roots)
 
-  def merge_directories(self, directory_digests):
-    return self._scheduler.merge_directories(directory_digests)
+  def merge_directories(self, directory_digests, merge_policy=MergePolicy.IDENTICAL_ONLY):
+    return self._scheduler.merge_directories(directory_digests, merge_policy)
+
+  def add_prefix(self, directory_with_prefix_to_add):
+    return self._scheduler.add_prefix(directory_with_prefix_to_add)
+
+  def strip_prefix(self, directory_with_prefix_to_strip):
+    return self._scheduler.strip_prefix(directory_with_prefix_to_strip)
+
+  def subset_directory(self, directory_subset):
+    return self._scheduler.subset_directory(directory_subset)
+
+  def archive_directory(self, directory_to_archive):
+    return self._scheduler.archive_directory(directory_to_archive)
+
+  def import_archive(self, archive_to_import):
+    return self._scheduler.import_archive(archive_to_import)
 
   def materialize_directories(self, directories_paths_and_digests):
     """Creates the specified directories on the file system.
@@ -61,12 +61,23 @@ class GlobMatchErrorBehavior(datatype(['failure_behavior'])):
 
 class ExecutionOptions(datatype([
   'remote_store_server',
+  'remote_store_server_selection',
   'remote_store_thread_count',
   'remote_execution_server',
+  'remote_correlated_invocations_id',
   'remote_store_chunk_bytes',
   'remote_store_chunk_upload_timeout_seconds',
   'process_execution_parallelism',
   'process_execution_cleanup_local_dirs',
+  'process_execution_local_sandbox',
+  'process_execution_local_sandbox_read_only_paths',
+  'process_execution_local_link_inputs',
+  'local_store_compression_threshold_bytes',
+  'local_store_read_only_dir',
+  'local_store_shard_count',
+  'local_store_map_size_bytes',
+  'local_store_max_map_size_bytes',
+  'local_store_large_file_threshold_bytes',
 ])):
   """A collection of all options related to (remote) execution of processes.
 
@@ -78,23 +89,46 @@ class ExecutionOptions(datatype([
   def from_bootstrap_options(cls, bootstrap_options):
     return cls(
       remote_store_server=bootstrap_options.remote_store_server,
+      remote_store_server_selection=bootstrap_options.remote_store_server_selection,
       remote_execution_server=bootstrap_options.remote_execution_server,
+      remote_correlated_invocations_id=bootstrap_options.remote_correlated_invocations_id,
       remote_store_thread_count=bootstrap_options.remote_store_thread_count,
       remote_store_chunk_bytes=bootstrap_options.remote_store_chunk_bytes,
       remote_store_chunk_upload_timeout_seconds=bootstrap_options.remote_store_chunk_upload_timeout_seconds,
       process_execution_parallelism=bootstrap_options.process_execution_parallelism,
       process_execution_cleanup_local_dirs=bootstrap_options.process_execution_cleanup_local_dirs,
+      process_execution_local_sandbox=bootstrap_options.process_execution_local_sandbox,
+      process_execution_local_sandbox_read_only_paths=bootstrap_options.process_execution_local_sandbox_read_only_paths,
+      process_execution_local_link_inputs=bootstrap_options.process_execution_local_link_inputs,
+      local_store_compression_threshold_bytes=bootstrap_options.local_store_compression_threshold_bytes,
+      local_store_read_only_dir=bootstrap_options.local_store_read_only_dir,
+      local_store_shard_count=bootstrap_options.local_store_shard_count,
+      local_store_map_size_bytes=bootstrap_options.local_store_map_size_bytes,
+      local_store_max_map_size_bytes=bootstrap_options.local_store_max_map_size_bytes,
+      local_store_large_file_threshold_bytes=bootstrap_options.local_store_large_file_threshold_bytes,
     )
 
 
 DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
-    remote_store_server=None,
+    remote_store_server=[],
+    remote_store_server_selection='primary_secondary',
     remote_store_thread_count=1,
     remote_execution_server=None,
+    remote_correlated_invocations_id=None,
     remote_store_chunk_bytes=1024*1024,
     remote_store_chunk_upload_timeout_seconds=60,
     process_execution_parallelism=multiprocessing.cpu_count()*2,
     process_execution_cleanup_local_dirs=True,
+    process_execution_local_sandbox=False,
+    # NB: Empty to use the engine's default read-only paths.
+    process_execution_local_sandbox_read_only_paths=[],
+    process_execution_local_link_inputs=False,
+    local_store_compression_threshold_bytes=0,
+    local_store_read_only_dir=None,
+    local_store_shard_count=16,
+    local_store_map_size_bytes=1024 * 1024 * 1024 * 1024 // 10,
+    local_store_max_map_size_bytes=None,
+    local_store_large_file_threshold_bytes=16 * 1024 * 1024,
   )
 
 
@@ -295,13 +329,27 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
              advanced=True,
              help='Whether to allow import statements in BUILD files')
 
-    register('--remote-store-server', advanced=True,
-             help='host:port of grpc server to use as remote execution file store.')
+    register('--remote-store-server', type=list, default=[], advanced=True,
+             help='host:port of grpc server to use as remote execution file store. May be given '
+                  'more than once, for replicas of the same store, which are failed over to when '
+                  'one of them is down or slow: see --remote-store-server-selection.')
+    register('--remote-store-server-selection', advanced=True,
+             choices=['round_robin', 'primary_secondary'],
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_server_selection,
+             help='How requests are spread across the --remote-store-server replicas. round_robin '
+                  'starts each request with the next server in turn; primary_secondary starts '
+                  'each request with the first server, and only uses the others while it is '
+                  'failing.')
     register('--remote-store-thread-count', type=int, advanced=True,
              default=DEFAULT_EXECUTION_OPTIONS.remote_store_thread_count,
              help='Thread count to use for the pool that interacts with the remote file store.')
     register('--remote-execution-server', advanced=True,
              help='host:port of grpc server to use as remote execution scheduler.')
+    register('--remote-correlated-invocations-id', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_correlated_invocations_id,
+             help='An ID which is sent with every request to the remote execution and store '
+                  'servers, to tie together several runs of pants, e.g. all of those in one CI '
+                  'build.')
     register('--remote-store-chunk-bytes', type=int, advanced=True,
              default=DEFAULT_EXECUTION_OPTIONS.remote_store_chunk_bytes,
              help='Size in bytes of chunks transferred to/from the remote file store.')
@@ -317,6 +365,53 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
     register('--process-execution-cleanup-local-dirs', type=bool, default=True,
              help='Whether or not to cleanup directories used for local process execution '
                   '(primarily useful for e.g. debugging).')
+    register('--process-execution-local-sandbox', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_sandbox,
+             help='Whether to run local processes in Linux user, mount, PID and network namespaces, '
+                  'so that they can only see their inputs and the read-only paths, and have no '
+                  'network access. Linux only.')
+    register('--process-execution-local-sandbox-read-only-paths', type=list, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_sandbox_read_only_paths,
+             help='Absolute paths of the host system which are visible (read-only) to processes '
+                  'run in the local sandbox. If unset, a default set of system paths (such as /bin '
+                  'and /usr) is used.')
+    register('--process-execution-local-link-inputs', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_link_inputs,
+             help='Whether to link the input files of local processes into place from a cache of '
+                  'files in the workdir, rather than writing them out for each process. Files are '
+                  'reflinked where the filesystem supports it, and otherwise hardlinked, in which '
+                  'case they are read-only.')
+    register('--local-store-compression-threshold-bytes', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_compression_threshold_bytes,
+             help='If positive, blobs of at least this many bytes are stored zstd-compressed in '
+                  'the local file store, where that makes them smaller.')
+    register('--local-store-read-only-dir', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_read_only_dir,
+             help='An existing local file store (e.g. a warm cache shared between machines on a '
+                  'read-only mount) to look in for anything missing from the local file store. '
+                  'It is never written to, and must not be written to by anything else while '
+                  'pants is running.')
+    register('--local-store-shard-count', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_shard_count,
+             help='The number of shards (a power of two, at most 16) the local file store is split '
+                  'into, each of which can be written to concurrently. An existing store must be '
+                  'used with the number of shards it was created with.')
+    register('--local-store-map-size-bytes', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_map_size_bytes,
+             help='The initial size of each shard of the local file store. This is reserved as '
+                  'address space, but disk space is only used as the store grows.')
+    register('--local-store-max-map-size-bytes', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_max_map_size_bytes,
+             help='The size to which each shard of the local file store may grow when it fills '
+                  'up. If a shard is full at this size, everything in it which is not in use is '
+                  'garbage collected. If unset, the shards of each of the stores of files and of '
+                  'directories share 4 TiB equally between them, but each may grow to at most '
+                  '1 TiB (and at least to its initial size).')
+    register('--local-store-large-file-threshold-bytes', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_large_file_threshold_bytes,
+             help='Files of at least this many bytes are kept in a directory of files next to the '
+                  'local file store, rather than in the store itself, so that they can be stored '
+                  'and materialized without being held in memory.')
 
   @classmethod
   def register_options(cls, register):
@@ -40,7 +40,7 @@ use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::HashMap;
 use std::ffi::{CString, OsStr, OsString};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 const TTL: time::Timespec = time::Timespec { sec: 0, nsec: 0 };
 
@@ -503,36 +503,20 @@ impl fuse::Filesystem for BuildResultFS {
         entry_type: EntryType::File,
         ..
       }) => {
-        let reply = Arc::new(Mutex::new(Some(reply)));
-        let reply2 = reply.clone();
         // TODO: Read from a cache of Futures driven from a CPU pool, so we can merge in-flight
         // requests, rather than reading from the store directly here.
-        let result: Result<(), ()> = self
+        match self
           .store
-          .load_file_bytes_with(digest, move |bytes| {
-            let begin = std::cmp::min(offset as usize, bytes.len());
-            let end = std::cmp::min(offset as usize + size as usize, bytes.len());
-            let mut reply = reply.lock().unwrap();
-            reply.take().unwrap().data(&bytes.slice(begin, end));
-          })
-          .map(|v| {
-            if v.is_none() {
-              let maybe_reply = reply2.lock().unwrap().take();
-              if let Some(reply) = maybe_reply {
-                reply.error(libc::ENOENT);
-              }
-            }
-          })
-          .or_else(|err| {
+          .load_file_bytes_range(digest, offset as usize, size as usize)
+          .wait()
+        {
+          Ok(Some(bytes)) => reply.data(&bytes),
+          Ok(None) => reply.error(libc::ENOENT),
+          Err(err) => {
             error!("Error loading bytes for {:?}: {}", digest, err);
-            let maybe_reply = reply2.lock().unwrap().take();
-            if let Some(reply) = maybe_reply {
-              reply.error(libc::EINVAL);
-            }
-            Ok(())
-          })
-          .wait();
-        result.expect("Error from read future which should have been handled in the future ");
+            reply.error(libc::EINVAL);
+          }
+        }
       }
       _ => reply.error(libc::ENOENT),
     }
@@ -647,7 +631,8 @@ fn main() {
     Some(address) => fs::Store::with_remote(
       &store_path,
       pool,
-      address.to_owned(),
+      vec![address.to_owned()],
+      fs::EndpointSelection::PrimarySecondary,
       1,
       4 * 1024 * 1024,
       std::time::Duration::from_secs(5 * 60),
@@ -29,11 +29,14 @@ extern crate protobuf;
 use boxfuture::{BoxFuture, Boxable};
 use bytes::Bytes;
 use clap::{App, Arg, SubCommand};
-use fs::{GlobMatching, ResettablePool, Snapshot, Store, StoreFileByDigest};
+use fs::{
+  ArchiveFormat, EndpointSelection, EntryStats, EntryTypeStats, GlobMatching, HotCacheStats,
+  ResettablePool, Snapshot, Store, StoreFileByDigest, MAX_LOCAL_STORE_SIZE_BYTES,
+};
 use futures::future::Future;
 use hashing::{Digest, Fingerprint};
 use protobuf::Message;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::{Arc, Mutex};
@@ -138,8 +141,112 @@ to this directory.",
               .arg(Arg::with_name("size_bytes").required(true).takes_value(
                 true,
               )),
+          )
+          .subcommand(
+            SubCommand::with_name("diff")
+              .about(
+                "Output the paths which differ between two directories addressed by fingerprint, \
+one per line, prefixed by A (added), D (deleted), M (modified) or X (executable bit changed).",
+              )
+              .arg(Arg::with_name("left_fingerprint").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("left_size_bytes").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("right_fingerprint").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("right_size_bytes").required(true).takes_value(
+                true,
+              )),
+          )
+          .subcommand(
+            SubCommand::with_name("export")
+              .about(
+                "Output a directory addressed by fingerprint as a deterministic tar or zip archive.",
+              )
+              .arg(
+                Arg::with_name("format")
+                  .long("format")
+                  .takes_value(true)
+                  .default_value("tar")
+                  .possible_values(&["tar", "zip"]),
+              )
+              .arg(Arg::with_name("fingerprint").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("size_bytes").required(true).takes_value(
+                true,
+              )),
+          )
+          .subcommand(
+            SubCommand::with_name("import")
+              .about(
+                "Ingest a tar or zip archive read from stdin, without extracting it. Outputs a \
+fingerprint of the canonical top-level Directory proto and the size of the serialized proto in \
+bytes, separated by a space.",
+              )
+              .arg(
+                Arg::with_name("format")
+                  .long("format")
+                  .takes_value(true)
+                  .default_value("tar")
+                  .possible_values(&["tar", "zip"]),
+              ),
           ),
       )
+      .subcommand(
+        SubCommand::with_name("store")
+          .subcommand(SubCommand::with_name("stats").about(
+            "Output statistics about the local store as JSON: the number and size of entries \
+of each type, in total and per shard, how many are leased, and when their leases expire.",
+          ))
+          .subcommand(
+            SubCommand::with_name("pin")
+              .about(
+                "Pin a file or Directory by fingerprint under a label, so that garbage collection \
+never removes it (or anything reachable from it) until it is unpinned. Replaces any existing pin \
+with the same label.",
+              )
+              .arg(Arg::with_name("label").required(true).takes_value(true))
+              .arg(Arg::with_name("fingerprint").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("size_bytes").required(true).takes_value(
+                true,
+              )),
+          )
+          .subcommand(
+            SubCommand::with_name("unpin")
+              .about("Remove the pin with the given label.")
+              .arg(Arg::with_name("label").required(true).takes_value(true)),
+          )
+          .subcommand(SubCommand::with_name("list-pins").about(
+            "List every pin, as the fingerprint and size in bytes of the pinned digest, followed \
+by its label, separated by spaces.",
+          ))
+          .subcommand(
+            SubCommand::with_name("export")
+              .about(
+                "Output a bundle of every file and Directory reachable from the given files and \
+Directories, which can be imported into another store.",
+              )
+              .arg(
+                Arg::with_name("root")
+                  .long("root")
+                  .required(true)
+                  .takes_value(true)
+                  .multiple(true)
+                  .number_of_values(2)
+                  .value_names(&["fingerprint", "size_bytes"]),
+              ),
+          )
+          .subcommand(SubCommand::with_name("import").about(
+            "Verify and import a bundle read from stdin. Outputs the fingerprint and size in \
+bytes of each of its roots, one per line.",
+          )),
+      )
       .subcommand(
         SubCommand::with_name("cat")
           .about(
@@ -157,12 +264,57 @@ to this directory.",
           .takes_value(true)
           .long("local-store-path")
           .required(true),
+      )
+      .arg(
+        Arg::with_name("local-store-shard-count")
+          .help("The number of shards the local store is split into: a power of two, at most 16.")
+          .takes_value(true)
+          .long("local-store-shard-count")
+          .required(false)
+          .default_value("16"),
+      )
+      .arg(
+        Arg::with_name("local-store-map-size-bytes")
+          .help("The initial size of each shard of the local store.")
+          .takes_value(true)
+          .long("local-store-map-size-bytes")
+          .required(false)
+          .default_value(&format!("{}", MAX_LOCAL_STORE_SIZE_BYTES)),
+      )
+      .arg(
+        Arg::with_name("local-store-max-map-size-bytes")
+          .help(
+            "The size to which each shard of the local store may grow when it fills up. Defaults \
+to an equal share of 4 TiB between the shards, but at most 1 TiB and at least their initial size.",
+          )
+          .takes_value(true)
+          .long("local-store-max-map-size-bytes")
+          .required(false),
+      )
+      .arg(
+        Arg::with_name("read-only-local-store-path")
+          .help("An existing store to read from (but never write to) when an entry isn't in the local store.")
+          .takes_value(true)
+          .long("read-only-local-store-path")
+          .required(false),
       )
         .arg(
           Arg::with_name("server-address")
+              .help("host:port of the remote CAS. May be repeated for replicas of the same CAS.")
               .takes_value(true)
               .long("server-address")
               .required(false)
+              .multiple(true)
+              .number_of_values(1)
+        )
+        .arg(
+          Arg::with_name("server-selection")
+              .help("How requests are spread across the --server-address replicas.")
+              .takes_value(true)
+              .long("server-selection")
+              .required(false)
+              .possible_values(&["round_robin", "primary_secondary"])
+              .default_value("primary_secondary")
         )
         .arg(
           Arg::with_name("chunk-bytes")
@@ -186,15 +338,20 @@ fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
   let store_dir = top_match.value_of("local-store-path").unwrap();
   let pool = Arc::new(ResettablePool::new("fsutil-pool-".to_string()));
   let (store, store_has_remote) = {
-    let (store_result, store_has_remote) = match top_match.value_of("server-address") {
-      Some(cas_address) => {
+    let (store_result, store_has_remote) = match top_match.values_of("server-address") {
+      Some(cas_addresses) => {
         let chunk_size =
           value_t!(top_match.value_of("chunk-bytes"), usize).expect("Bad chunk-bytes flag");
+        let endpoint_selection = match top_match.value_of("server-selection").unwrap() {
+          "round_robin" => EndpointSelection::RoundRobin,
+          _ => EndpointSelection::PrimarySecondary,
+        };
         (
           Store::with_remote(
             store_dir,
             pool.clone(),
-            cas_address.to_owned(),
+            cas_addresses.map(str::to_owned).collect(),
+            endpoint_selection,
             1,
             chunk_size,
             Duration::from_secs(30),
@@ -210,6 +367,31 @@ fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
         store_dir, e
       )
     })?;
+    let shard_count = value_t!(top_match.value_of("local-store-shard-count"), usize)
+      .expect("Bad local-store-shard-count flag");
+    let map_size_bytes = value_t!(top_match.value_of("local-store-map-size-bytes"), usize)
+      .expect("Bad local-store-map-size-bytes flag");
+    let max_map_size_bytes = if top_match.is_present("local-store-max-map-size-bytes") {
+      Some(
+        value_t!(top_match.value_of("local-store-max-map-size-bytes"), usize)
+          .expect("Bad local-store-max-map-size-bytes flag"),
+      )
+    } else {
+      None
+    };
+    let store = store.with_lmdb_config(shard_count, map_size_bytes, max_map_size_bytes)?;
+    let store = store.negotiate_remote_capabilities()?;
+    let store = match top_match.value_of("read-only-local-store-path") {
+      Some(read_only_store_dir) => store
+        .with_read_only_local_store(read_only_store_dir, pool.clone())
+        .map_err(|e| {
+          format!(
+            "Failed to open read-only store for directory {}: {}",
+            read_only_store_dir, e
+          )
+        })?,
+      None => store,
+    };
     (store, store_has_remote)
   };
 
@@ -366,6 +548,167 @@ fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
           )),
         }
       }
+      ("diff", Some(args)) => {
+        let digest = |fingerprint_arg: &str, size_bytes_arg: &str| -> Result<Digest, String> {
+          let fingerprint = Fingerprint::from_hex_string(args.value_of(fingerprint_arg).unwrap())?;
+          let size_bytes = args
+            .value_of(size_bytes_arg)
+            .unwrap()
+            .parse::<usize>()
+            .expect("size_bytes must be a non-negative number");
+          Ok(Digest(fingerprint, size_bytes))
+        };
+        let diff = store
+          .diff_directories(
+            digest("left_fingerprint", "left_size_bytes")?,
+            digest("right_fingerprint", "right_size_bytes")?,
+          )
+          .wait()
+          .map_err(|err| {
+            if err.contains("not found") {
+              ExitError(err, ExitCode::NotFound)
+            } else {
+              err.into()
+            }
+          })?;
+        let mut lines: Vec<(&Path, &str)> = Vec::new();
+        for &(paths, status) in &[
+          (&diff.added, "A"),
+          (&diff.removed, "D"),
+          (&diff.modified, "M"),
+          (&diff.executable_changed, "X"),
+        ] {
+          lines.extend(paths.iter().map(|path| (path.as_path(), status)));
+        }
+        lines.sort();
+        for (path, status) in lines {
+          println!("{} {}", status, path.display());
+        }
+        Ok(())
+      }
+      ("export", Some(args)) => {
+        let fingerprint = Fingerprint::from_hex_string(args.value_of("fingerprint").unwrap())?;
+        let size_bytes = args
+          .value_of("size_bytes")
+          .unwrap()
+          .parse::<usize>()
+          .expect("size_bytes must be a non-negative number");
+        let digest = Digest(fingerprint, size_bytes);
+        let format = ArchiveFormat::create(args.value_of("format").unwrap())?;
+        // Writing a zip archive requires seeking, which stdout may not support, so the archive is
+        // built in memory.
+        let archive = store
+          .export_archive(digest, format, io::Cursor::new(Vec::new()))
+          .wait()
+          .map_err(|err| {
+            if err.contains("not found") {
+              ExitError(err, ExitCode::NotFound)
+            } else {
+              err.into()
+            }
+          })?;
+        io::stdout()
+          .write_all(archive.get_ref())
+          .map_err(|e| ExitError(format!("Error writing archive: {}", e), ExitCode::UnknownError))
+      }
+      ("import", Some(args)) => {
+        let format = ArchiveFormat::create(args.value_of("format").unwrap())?;
+        // Reading a zip archive requires seeking, which stdin may not support, so the archive is
+        // read into memory.
+        let mut archive = Vec::new();
+        io::stdin()
+          .read_to_end(&mut archive)
+          .map_err(|e| format!("Error reading archive: {}", e))?;
+        let digest = store
+          .import_archive(format, io::Cursor::new(archive))
+          .wait()?;
+        if store_has_remote {
+          store.ensure_remote_has_recursive(vec![digest]).wait()?;
+        }
+        println!("{} {}", digest.0, digest.1);
+        Ok(())
+      }
+      (_, _) => unimplemented!(),
+    },
+    ("store", Some(sub_match)) => match sub_match.subcommand() {
+      ("stats", Some(_)) => {
+        let stats = store.stats()?;
+        println!(
+          "{{\"files\": {}, \"directories\": {}, \"hot_cache\": {}}}",
+          entry_type_stats_json(&stats.files),
+          entry_type_stats_json(&stats.directories),
+          hot_cache_stats_json(&stats.hot_cache)
+        );
+        Ok(())
+      }
+      ("pin", Some(args)) => {
+        let fingerprint = Fingerprint::from_hex_string(args.value_of("fingerprint").unwrap())?;
+        let size_bytes = args
+          .value_of("size_bytes")
+          .unwrap()
+          .parse::<usize>()
+          .expect("size_bytes must be a non-negative number");
+        let digest = Digest(fingerprint, size_bytes);
+        store
+          .pin(args.value_of("label").unwrap().to_owned(), digest)
+          .wait()
+          .map_err(|err| {
+            if err.contains("not found") {
+              ExitError(err, ExitCode::NotFound)
+            } else {
+              err.into()
+            }
+          })
+      }
+      ("unpin", Some(args)) => {
+        let label = args.value_of("label").unwrap();
+        if store.unpin(label)? {
+          Ok(())
+        } else {
+          Err(ExitError(
+            format!("No pin with label {}", label),
+            ExitCode::NotFound,
+          ))
+        }
+      }
+      ("list-pins", Some(_)) => {
+        for (label, digest) in store.pins()? {
+          println!("{} {} {}", digest.0, digest.1, label);
+        }
+        Ok(())
+      }
+      ("export", Some(args)) => {
+        let root_args = args.values_of("root").unwrap().collect::<Vec<_>>();
+        let mut roots = Vec::new();
+        for root in root_args.chunks(2) {
+          let fingerprint = Fingerprint::from_hex_string(root[0])?;
+          let size_bytes = root[1]
+            .parse::<usize>()
+            .expect("size_bytes must be a non-negative number");
+          roots.push(Digest(fingerprint, size_bytes));
+        }
+        store
+          .export_bundle(roots, io::stdout())
+          .wait()
+          .map(|_| ())
+          .map_err(|err| {
+            if err.contains("not found") {
+              ExitError(err, ExitCode::NotFound)
+            } else {
+              err.into()
+            }
+          })
+      }
+      ("import", Some(_)) => {
+        let roots = store.import_bundle(io::stdin()).wait()?;
+        if store_has_remote {
+          store.ensure_remote_has_recursive(roots.clone()).wait()?;
+        }
+        for root in roots {
+          println!("{} {}", root.0, root.1);
+        }
+        Ok(())
+      }
       (_, _) => unimplemented!(),
     },
     ("cat", Some(args)) => {
@@ -458,6 +801,50 @@ fn expand_files_helper(
     .to_boxed()
 }
 
+fn entry_type_stats_json(stats: &EntryTypeStats) -> String {
+  let shards = stats
+    .shards
+    .iter()
+    .map(|(shard, shard_stats)| format!("\"{:02x}\": {}", shard, entry_stats_json(shard_stats)))
+    .collect::<Vec<_>>()
+    .join(", ");
+  format!(
+    "{{\"total\": {}, \"shards\": {{{}}}}}",
+    entry_stats_json(&stats.total),
+    shards
+  )
+}
+
+fn entry_stats_json(stats: &EntryStats) -> String {
+  let optional = |value: Option<u64>| value.map_or_else(|| "null".to_owned(), |v| v.to_string());
+  format!(
+    "{{\"entries\": {}, \"size_bytes\": {}, \"leased_entries\": {}, \"expired_entries\": {}, \
+     \"oldest_lease_secs_since_epoch\": {}, \"newest_lease_secs_since_epoch\": {}}}",
+    stats.entries,
+    stats.size_bytes,
+    stats.leased_entries,
+    stats.expired_entries,
+    optional(stats.oldest_lease_secs_since_epoch),
+    optional(stats.newest_lease_secs_since_epoch)
+  )
+}
+
+fn hot_cache_stats_json(stats: &HotCacheStats) -> String {
+  format!(
+    "{{\"entries\": {}, \"size_bytes\": {}, \"capacity_bytes\": {}, \
+     \"directory_hits\": {}, \"directory_misses\": {}, \"file_hits\": {}, \
+     \"file_misses\": {}, \"hit_rate\": {}}}",
+    stats.entries,
+    stats.size_bytes,
+    stats.capacity_bytes,
+    stats.directory_hits,
+    stats.directory_misses,
+    stats.file_hits,
+    stats.file_misses,
+    stats.hit_rate().map_or_else(|| "null".to_owned(), |rate| rate.to_string())
+  )
+}
+
 fn make_posix_fs<P: AsRef<Path>>(root: P, pool: Arc<ResettablePool>) -> fs::PosixFS {
   fs::PosixFS::new(&root, pool, &[]).unwrap()
 }
@@ -0,0 +1,721 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, Write};
+use std::path::{Component, Path};
+
+use bazel_protos;
+use boxfuture::{BoxFuture, Boxable};
+use bytes::Bytes;
+use futures::{future, stream, Future, Stream};
+use hashing::Digest;
+use protobuf;
+use tar;
+use zip;
+
+use store::Store;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+  Tar,
+  Zip,
+}
+
+impl ArchiveFormat {
+  pub fn create(format: &str) -> Result<ArchiveFormat, String> {
+    match format {
+      "tar" => Ok(ArchiveFormat::Tar),
+      "zip" => Ok(ArchiveFormat::Zip),
+      other => Err(format!(
+        "Unknown archive format {:?}: must be one of \"tar\" or \"zip\"",
+        other
+      )),
+    }
+  }
+}
+
+// Mode bits for the file type of a symlink, as stored in the external attributes of zip entries.
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+///
+/// Writes the Directory with the given digest to the writer as an archive. See
+/// `Store::export_archive`.
+///
+pub fn export<W: Write + Seek + Send + 'static>(
+  store: Store,
+  digest: Digest,
+  format: ArchiveFormat,
+  writer: W,
+) -> BoxFuture<W, String> {
+  let archive_writer: Box<ArchiveWriter<W> + Send> = match format {
+    ArchiveFormat::Tar => Box::new(TarWriter(tar::Builder::new(writer))),
+    ArchiveFormat::Zip => Box::new(ZipWriter(zip::ZipWriter::new(writer))),
+  };
+  let store2 = store.clone();
+  list_entries(store, digest, String::new())
+    .and_then(move |mut entries| {
+      entries.sort_by(|l, r| l.path().cmp(r.path()));
+      stream::iter_ok::<_, String>(entries)
+        .fold(archive_writer, move |mut archive_writer, entry| match entry {
+          ExportEntry::Directory(path) => future::result(
+            archive_writer
+              .directory(&path)
+              .map(|()| archive_writer),
+          ).to_boxed(),
+          ExportEntry::Symlink(path, target) => future::result(
+            archive_writer
+              .symlink(&path, &target)
+              .map(|()| archive_writer),
+          ).to_boxed(),
+          ExportEntry::File(path, digest, is_executable) => store2
+            .load_file_bytes_with(digest, |bytes| bytes)
+            .and_then(move |maybe_bytes| {
+              let bytes = maybe_bytes
+                .ok_or_else(|| format!("File {} with digest {:?} not found", path, digest))?;
+              archive_writer
+                .file(&path, &bytes, is_executable)
+                .map(|()| archive_writer)
+            })
+            .to_boxed(),
+        })
+        .and_then(|archive_writer| archive_writer.finish())
+    })
+    .to_boxed()
+}
+
+///
+/// Stores the contents of the archive read from the reader, returning the digest of the Directory
+/// which contains them. See `Store::import_archive`.
+///
+pub fn import<R: Read + Seek>(
+  store: Store,
+  format: ArchiveFormat,
+  reader: R,
+) -> BoxFuture<Digest, String> {
+  let mut root = DirectoryBuilder::default();
+  let read_result = match format {
+    ArchiveFormat::Tar => import_tar(&store, &mut root, reader),
+    ArchiveFormat::Zip => import_zip(&store, &mut root, reader),
+  };
+  try_future!(read_result);
+  root.store(store)
+}
+
+enum ExportEntry {
+  Directory(String),
+  File(String, Digest, bool),
+  Symlink(String, String),
+}
+
+impl ExportEntry {
+  fn path(&self) -> &str {
+    match self {
+      &ExportEntry::Directory(ref path) => path,
+      &ExportEntry::File(ref path, _, _) => path,
+      &ExportEntry::Symlink(ref path, _) => path,
+    }
+  }
+}
+
+fn list_entries(
+  store: Store,
+  digest: Digest,
+  prefix: String,
+) -> BoxFuture<Vec<ExportEntry>, String> {
+  store
+    .load_directory(digest)
+    .and_then(move |maybe_directory| {
+      maybe_directory.ok_or_else(|| format!("Directory with digest {:?} not found", digest))
+    })
+    .and_then(move |directory| {
+      let mut entries = Vec::new();
+      for file_node in directory.get_files() {
+        let digest: Result<Digest, String> = file_node.get_digest().into();
+        entries.push(ExportEntry::File(
+          format!("{}{}", prefix, file_node.get_name()),
+          try_future!(digest),
+          file_node.get_is_executable(),
+        ));
+      }
+      for symlink_node in directory.get_symlinks() {
+        entries.push(ExportEntry::Symlink(
+          format!("{}{}", prefix, symlink_node.get_name()),
+          symlink_node.get_target().to_owned(),
+        ));
+      }
+      let mut subdirectory_futures = Vec::new();
+      for directory_node in directory.get_directories() {
+        let path = format!("{}{}", prefix, directory_node.get_name());
+        let digest: Result<Digest, String> = directory_node.get_digest().into();
+        subdirectory_futures.push(list_entries(
+          store.clone(),
+          try_future!(digest),
+          format!("{}/", path),
+        ));
+        entries.push(ExportEntry::Directory(path));
+      }
+      future::join_all(subdirectory_futures)
+        .map(move |subdirectory_entries| {
+          entries.extend(subdirectory_entries.into_iter().flat_map(|e| e));
+          entries
+        })
+        .to_boxed()
+    })
+    .to_boxed()
+}
+
+trait ArchiveWriter<W> {
+  fn directory(&mut self, path: &str) -> Result<(), String>;
+
+  fn file(&mut self, path: &str, bytes: &[u8], is_executable: bool) -> Result<(), String>;
+
+  fn symlink(&mut self, path: &str, target: &str) -> Result<(), String>;
+
+  fn finish(self: Box<Self>) -> Result<W, String>;
+}
+
+///
+/// Tar entries are given GNU headers (so that long paths are supported), with zeroed timestamps
+/// and ownership.
+///
+struct TarWriter<W: Write>(tar::Builder<W>);
+
+impl<W: Write> TarWriter<W> {
+  fn append(&mut self, mut header: tar::Header, path: &str, bytes: &[u8]) -> Result<(), String> {
+    header.set_size(bytes.len() as u64);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    self
+      .0
+      .append_data(&mut header, path, bytes)
+      .map_err(|e| format!("Error writing {} to tar archive: {}", path, e))
+  }
+}
+
+impl<W: Write> ArchiveWriter<W> for TarWriter<W> {
+  fn directory(&mut self, path: &str) -> Result<(), String> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_mode(0o755);
+    self.append(header, path, &[])
+  }
+
+  fn file(&mut self, path: &str, bytes: &[u8], is_executable: bool) -> Result<(), String> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(if is_executable { 0o755 } else { 0o644 });
+    self.append(header, path, bytes)
+  }
+
+  fn symlink(&mut self, path: &str, target: &str) -> Result<(), String> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_mode(0o777);
+    header
+      .set_link_name(target)
+      .map_err(|e| format!("Error writing {} to tar archive: {}", path, e))?;
+    self.append(header, path, &[])
+  }
+
+  fn finish(self: Box<Self>) -> Result<W, String> {
+    self
+      .0
+      .into_inner()
+      .map_err(|e| format!("Error finishing tar archive: {}", e))
+  }
+}
+
+///
+/// Writing a zip archive requires seeking back to the start of each entry, so it is written
+/// directly to a seekable writer (e.g. a File), rather than being built in memory.
+///
+/// Zip entries are given zeroed (i.e. 1980-01-01) timestamps. There is no portable way to
+/// represent a symlink in a zip archive, so symlinks cannot be exported (or imported).
+///
+struct ZipWriter<W: Write + Seek>(zip::ZipWriter<W>);
+
+fn zip_options(mode: u32) -> zip::write::FileOptions {
+  zip::write::FileOptions::default()
+    .compression_method(zip::CompressionMethod::Deflated)
+    .last_modified_time(zip::DateTime::default())
+    .unix_permissions(mode)
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> for ZipWriter<W> {
+  fn directory(&mut self, path: &str) -> Result<(), String> {
+    self
+      .0
+      .add_directory(format!("{}/", path), zip_options(0o755))
+      .map_err(|e| format!("Error writing {} to zip archive: {}", path, e))
+  }
+
+  fn file(&mut self, path: &str, bytes: &[u8], is_executable: bool) -> Result<(), String> {
+    let mode = if is_executable { 0o755 } else { 0o644 };
+    self
+      .0
+      .start_file(path, zip_options(mode))
+      .map_err(|e| format!("Error writing {} to zip archive: {}", path, e))?;
+    self
+      .0
+      .write_all(bytes)
+      .map_err(|e| format!("Error writing {} to zip archive: {}", path, e))
+  }
+
+  fn symlink(&mut self, path: &str, target: &str) -> Result<(), String> {
+    Err(format!(
+      "Cannot export symlink {} (to {}): zip archives cannot represent symlinks",
+      path, target
+    ))
+  }
+
+  fn finish(self: Box<Self>) -> Result<W, String> {
+    let ZipWriter(mut zip) = *self;
+    zip
+      .finish()
+      .map_err(|e| format!("Error finishing zip archive: {}", e))
+  }
+}
+
+fn import_tar<R: Read>(
+  store: &Store,
+  root: &mut DirectoryBuilder,
+  reader: R,
+) -> Result<(), String> {
+  let mut archive = tar::Archive::new(reader);
+  let entries = archive
+    .entries()
+    .map_err(|e| format!("Error reading tar archive: {}", e))?;
+  for entry in entries {
+    let mut entry = entry.map_err(|e| format!("Error reading tar archive: {}", e))?;
+    let path = entry
+      .path()
+      .map_err(|e| format!("Error reading tar archive: {}", e))?
+      .into_owned();
+    let components = relative_components(&path)?;
+    let entry_type = entry.header().entry_type();
+    if entry_type.is_dir() {
+      root.add_directory(&components)?;
+    } else if entry_type.is_symlink() {
+      let target = entry
+        .link_name()
+        .map_err(|e| format!("Error reading tar archive: {}", e))?
+        .ok_or_else(|| format!("Symlink {:?} in tar archive had no target", path))?;
+      let target = target
+        .to_str()
+        .ok_or_else(|| format!("Target of symlink {:?} was not valid UTF-8", path))?
+        .to_owned();
+      root.add_symlink(&components, target)?;
+    } else if entry_type.is_file() {
+      let mode = entry
+        .header()
+        .mode()
+        .map_err(|e| format!("Error reading mode of {:?} in tar archive: {}", path, e))?;
+      let mut bytes = Vec::new();
+      entry
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("Error reading {:?} from tar archive: {}", path, e))?;
+      let digest = store.store_file_bytes(Bytes::from(bytes), true);
+      root.add_file(&components, digest, mode & 0o111 != 0)?;
+    } else {
+      return Err(format!(
+        "Unsupported entry {:?} in tar archive: only files, directories and symlinks are \
+         supported, but it was a {:?}",
+        path, entry_type
+      ));
+    }
+  }
+  Ok(())
+}
+
+///
+/// Reading a zip archive requires seeking to its central directory at the end, so it is read
+/// directly from a seekable reader (e.g. a File), rather than being read into memory.
+///
+fn import_zip<R: Read + Seek>(
+  store: &Store,
+  root: &mut DirectoryBuilder,
+  reader: R,
+) -> Result<(), String> {
+  let mut archive =
+    zip::ZipArchive::new(reader).map_err(|e| format!("Error reading zip archive: {}", e))?;
+  for i in 0..archive.len() {
+    let mut entry = archive
+      .by_index(i)
+      .map_err(|e| format!("Error reading zip archive: {}", e))?;
+    let name = entry.name().to_owned();
+    let components = relative_components(Path::new(&name))?;
+    let mode = entry.unix_mode().unwrap_or(0o644);
+    if name.ends_with('/') {
+      root.add_directory(&components)?;
+    } else if mode & S_IFMT == S_IFLNK {
+      return Err(format!(
+        "Cannot import symlink {}: zip archives cannot represent symlinks",
+        name
+      ));
+    } else {
+      let mut bytes = Vec::new();
+      entry
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("Error reading {} from zip archive: {}", name, e))?;
+      let digest = store.store_file_bytes(Bytes::from(bytes), true);
+      root.add_file(&components, digest, mode & 0o111 != 0)?;
+    }
+  }
+  Ok(())
+}
+
+///
+/// Splits a path from an archive into its components, which must all lie within the archive.
+///
+fn relative_components(path: &Path) -> Result<Vec<String>, String> {
+  let mut components = Vec::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => {}
+      Component::Normal(name) => components.push(
+        name
+          .to_str()
+          .ok_or_else(|| format!("Archive entry {:?} was not valid UTF-8", path))?
+          .to_owned(),
+      ),
+      Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+        return Err(format!(
+          "Archive entries must be relative paths within the archive, but found {:?}",
+          path
+        ))
+      }
+    }
+  }
+  Ok(components)
+}
+
+///
+/// A Directory being assembled from the entries of an archive, which may appear in any order.
+///
+#[derive(Default)]
+struct DirectoryBuilder {
+  files: BTreeMap<String, (BoxFuture<Digest, String>, bool)>,
+  symlinks: BTreeMap<String, String>,
+  directories: BTreeMap<String, DirectoryBuilder>,
+}
+
+impl DirectoryBuilder {
+  fn add_directory(&mut self, components: &[String]) -> Result<(), String> {
+    let mut directory = self;
+    for (i, name) in components.iter().enumerate() {
+      if directory.files.contains_key(name) || directory.symlinks.contains_key(name) {
+        return Err(format!(
+          "Archive contained more than one entry for {}",
+          components[..i + 1].join("/")
+        ));
+      }
+      directory = directory
+        .directories
+        .entry(name.clone())
+        .or_insert_with(DirectoryBuilder::default);
+    }
+    Ok(())
+  }
+
+  fn add_file(
+    &mut self,
+    components: &[String],
+    digest: BoxFuture<Digest, String>,
+    is_executable: bool,
+  ) -> Result<(), String> {
+    let (name, parent) = self.parent_of(components)?;
+    parent.files.insert(name, (digest, is_executable));
+    Ok(())
+  }
+
+  fn add_symlink(&mut self, components: &[String], target: String) -> Result<(), String> {
+    let (name, parent) = self.parent_of(components)?;
+    parent.symlinks.insert(name, target);
+    Ok(())
+  }
+
+  ///
+  /// Returns the final component of the path, and the (created if necessary) DirectoryBuilder
+  /// which it should be added to, checking that nothing already exists there.
+  ///
+  fn parent_of(
+    &mut self,
+    components: &[String],
+  ) -> Result<(String, &mut DirectoryBuilder), String> {
+    let (name, parent_components) = components
+      .split_last()
+      .ok_or_else(|| "Archive contained a file or symlink with an empty path".to_owned())?;
+    self.add_directory(parent_components)?;
+    let mut parent = self;
+    for parent_name in parent_components {
+      parent = parent
+        .directories
+        .get_mut(parent_name)
+        .expect("Parent directory was just added");
+    }
+    if parent.files.contains_key(name)
+      || parent.symlinks.contains_key(name)
+      || parent.directories.contains_key(name)
+    {
+      return Err(format!(
+        "Archive contained more than one entry for {}",
+        components.join("/")
+      ));
+    }
+    Ok((name.clone(), parent))
+  }
+
+  fn store(self, store: Store) -> BoxFuture<Digest, String> {
+    let file_futures = future::join_all(
+      self
+        .files
+        .into_iter()
+        .map(|(name, (digest, is_executable))| {
+          digest.map(move |digest| {
+            let mut file_node = bazel_protos::remote_execution::FileNode::new();
+            file_node.set_name(name);
+            file_node.set_digest((&digest).into());
+            file_node.set_is_executable(is_executable);
+            file_node
+          })
+        })
+        .collect::<Vec<_>>(),
+    );
+    let directory_futures = future::join_all(
+      self
+        .directories
+        .into_iter()
+        .map(|(name, directory)| {
+          directory.store(store.clone()).map(move |digest| {
+            let mut directory_node = bazel_protos::remote_execution::DirectoryNode::new();
+            directory_node.set_name(name);
+            directory_node.set_digest((&digest).into());
+            directory_node
+          })
+        })
+        .collect::<Vec<_>>(),
+    );
+    let symlinks = self
+      .symlinks
+      .into_iter()
+      .map(|(name, target)| {
+        let mut symlink_node = bazel_protos::remote_execution::SymlinkNode::new();
+        symlink_node.set_name(name);
+        symlink_node.set_target(target);
+        symlink_node
+      })
+      .collect();
+    file_futures
+      .join(directory_futures)
+      .and_then(move |(files, directories)| {
+        // BTreeMaps iterate in sorted order, so the Directory is canonical.
+        let mut directory = bazel_protos::remote_execution::Directory::new();
+        directory.set_files(protobuf::RepeatedField::from_vec(files));
+        directory.set_directories(protobuf::RepeatedField::from_vec(directories));
+        directory.set_symlinks(protobuf::RepeatedField::from_vec(symlinks));
+        store.record_directory(&directory, true)
+      })
+      .to_boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{ResettablePool, Store};
+  use super::ArchiveFormat;
+
+  use futures::Future;
+  use hashing::Digest;
+  use std::io::{self, Cursor, Write};
+  use std::path::Path;
+  use std::sync::Arc;
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+
+  #[test]
+  fn round_trip() {
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let digest = store_recursive_and_executable(&store);
+
+    for &format in &[ArchiveFormat::Tar, ArchiveFormat::Zip] {
+      let archive = export(&store, digest, format).expect("Error exporting");
+
+      let import_dir = TempDir::new().unwrap();
+      let import_store = new_store(import_dir.path());
+      assert_eq!(
+        import_store
+          .import_archive(format, Cursor::new(archive))
+          .wait(),
+        Ok(digest)
+      );
+      assert_eq!(
+        import_store
+          .load_file_bytes_with(TestData::catnip().digest(), |bytes| bytes)
+          .wait(),
+        Ok(Some(TestData::catnip().bytes()))
+      );
+    }
+  }
+
+  #[test]
+  fn round_trip_symlinks() {
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let testdir = TestDirectory::containing_roland_and_symlinks();
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(TestData::roland().bytes(), false)
+      .wait()
+      .expect("Error saving file bytes");
+
+    let archive = export(&store, testdir.digest(), ArchiveFormat::Tar).expect("Error exporting");
+    assert_eq!(
+      store
+        .import_archive(ArchiveFormat::Tar, Cursor::new(archive))
+        .wait(),
+      Ok(testdir.digest())
+    );
+
+    let err = export(&store, testdir.digest(), ArchiveFormat::Zip).expect_err("Want err");
+    assert!(
+      err.contains("zip archives cannot represent symlinks"),
+      "Bad error message: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn export_is_deterministic() {
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let digest = store_recursive_and_executable(&store);
+
+    for &format in &[ArchiveFormat::Tar, ArchiveFormat::Zip] {
+      let first = export(&store, digest, format).expect("Error exporting");
+      let second = export(&store, digest, format).expect("Error exporting");
+      assert_eq!(first, second);
+    }
+  }
+
+  #[test]
+  fn export_missing_file() {
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let testdir = TestDirectory::containing_roland();
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+
+    let err = export(&store, testdir.digest(), ArchiveFormat::Tar).expect_err("Want err");
+    assert!(err.contains("not found"), "Bad error message: {}", err);
+  }
+
+  #[test]
+  fn import_rejects_paths_outside_archive() {
+    let mut builder = ::tar::Builder::new(Vec::new());
+    let mut header = ::tar::Header::new_gnu();
+    header.set_entry_type(::tar::EntryType::Regular);
+    header.set_size(0);
+    // `set_path` refuses to write `..` components, so write the name directly.
+    header.as_gnu_mut().unwrap().name[..9].copy_from_slice(b"../escape");
+    header.set_cksum();
+    builder.append(&header, io::empty()).unwrap();
+    let archive = builder.into_inner().unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let err = store
+      .import_archive(ArchiveFormat::Tar, Cursor::new(archive))
+      .wait()
+      .expect_err("Want err");
+    assert!(
+      err.contains("must be relative paths within the archive"),
+      "Bad error message: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn import_rejects_zip_symlinks() {
+    let mut zip = ::zip::ZipWriter::new(Cursor::new(Vec::new()));
+    zip
+      .start_file("felines", ::zip::write::FileOptions::default())
+      .unwrap();
+    zip.write_all(b"cats").unwrap();
+    let mut archive = zip.finish().unwrap().into_inner();
+    // The writer can only set permissions, so make the entry a symlink by setting the file type in
+    // the external attributes of its central directory header.
+    let header = archive
+      .windows(4)
+      .position(|window| window == b"PK\x01\x02")
+      .expect("Central directory header");
+    let external_attributes = 0o120_777_u32 << 16;
+    for i in 0..4 {
+      archive[header + 38 + i] = (external_attributes >> (8 * i)) as u8;
+    }
+
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let err = store
+      .import_archive(ArchiveFormat::Zip, Cursor::new(archive))
+      .wait()
+      .expect_err("Want err");
+    assert!(
+      err.contains("zip archives cannot represent symlinks"),
+      "Bad error message: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn archive_format_create() {
+    assert_eq!(ArchiveFormat::create("tar"), Ok(ArchiveFormat::Tar));
+    assert_eq!(ArchiveFormat::create("zip"), Ok(ArchiveFormat::Zip));
+    ArchiveFormat::create("rar").expect_err("Want err");
+  }
+
+  fn new_store(dir: &Path) -> Store {
+    Store::local_only(dir, Arc::new(ResettablePool::new("test-pool-".to_string()))).unwrap()
+  }
+
+  fn store_recursive_and_executable(store: &Store) -> Digest {
+    let recursive_testdir = TestDirectory::recursive();
+    let executable_testdir = TestDirectory::with_mixed_executable_files();
+    for testdir in &[
+      TestDirectory::containing_roland(),
+      recursive_testdir.clone(),
+      executable_testdir.clone(),
+    ] {
+      store
+        .record_directory(&testdir.directory(), false)
+        .wait()
+        .expect("Error saving Directory");
+    }
+    for data in &[TestData::roland(), TestData::catnip()] {
+      store
+        .store_file_bytes(data.bytes(), false)
+        .wait()
+        .expect("Error saving file bytes");
+    }
+    super::super::Snapshot::merge_directories(
+      store.clone(),
+      vec![recursive_testdir.digest(), executable_testdir.digest()],
+    ).wait()
+      .expect("Error merging")
+  }
+
+  fn export(store: &Store, digest: Digest, format: ArchiveFormat) -> Result<Vec<u8>, String> {
+    store
+      .export_archive(digest, format, Cursor::new(Vec::new()))
+      .wait()
+      .map(Cursor::into_inner)
+  }
+}
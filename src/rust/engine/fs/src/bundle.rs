@@ -0,0 +1,516 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::str;
+
+use bazel_protos;
+use boxfuture::{BoxFuture, Boxable};
+use bytes::Bytes;
+use digest::{Digest as DigestTrait, FixedOutput};
+use futures::{future, stream, Future, Stream};
+use hashing::{Digest, Fingerprint};
+use protobuf::Message;
+use sha2::Sha256;
+use tar;
+
+use store::{EntryType, Store};
+
+// A bundle is a tar archive. Its first entry is a manifest, which starts with this line, and then
+// has a line for each root of the form "root <fingerprint> <size_bytes>".
+const MANIFEST_PATH: &str = "MANIFEST";
+const MANIFEST_HEADER: &str = "pants-store-bundle 1";
+
+///
+/// Writes every entry reachable from the given roots (files or Directories) to the writer as a
+/// bundle. See `Store::export_bundle`.
+///
+pub fn export<W: Write + Send + 'static>(
+  store: Store,
+  roots: Vec<Digest>,
+  writer: W,
+) -> BoxFuture<W, String> {
+  let store2 = store.clone();
+  future::join_all(
+    roots
+      .iter()
+      .map(|root| expand_root(&store, *root))
+      .collect::<Vec<_>>(),
+  ).and_then(move |expansions| {
+    let mut entries: HashMap<Digest, EntryType> = HashMap::new();
+    for expansion in expansions {
+      entries.extend(expansion);
+    }
+    // Sort entries so that bundles are deterministic.
+    let mut entries: Vec<(EntryType, Digest)> = entries
+      .into_iter()
+      .map(|(digest, entry_type)| (entry_type, digest))
+      .collect();
+    entries.sort_by_key(|&(entry_type, digest)| (entry_type, digest.0, digest.1));
+
+    let mut builder = tar::Builder::new(writer);
+    try_future!(append(
+      &mut builder,
+      MANIFEST_PATH,
+      manifest(&roots).as_bytes()
+    ));
+    stream::iter_ok::<_, String>(entries)
+      .fold(builder, move |mut builder, (entry_type, digest)| {
+        load_bytes(&store2, entry_type, digest).and_then(move |bytes| {
+          append(&mut builder, &entry_path(entry_type, digest), &bytes).map(|()| builder)
+        })
+      })
+      .and_then(|builder| {
+        builder
+          .into_inner()
+          .map_err(|e| format!("Error finishing bundle: {}", e))
+      })
+      .to_boxed()
+  })
+    .to_boxed()
+}
+
+///
+/// Everything reachable from a root, which is either a Directory or a file.
+///
+fn expand_root(store: &Store, root: Digest) -> BoxFuture<HashMap<Digest, EntryType>, String> {
+  let store = store.clone();
+  store
+    .load_directory(root)
+    .and_then(move |maybe_directory| match maybe_directory {
+      Some(_) => store.expand_directory(root),
+      None => store
+        .load_file_bytes_with(root, |_| ())
+        .and_then(move |maybe_file| match maybe_file {
+          Some(()) => Ok(vec![(root, EntryType::File)].into_iter().collect()),
+          None => Err(format!(
+            "Root {:?} was not found as either a Directory or a file",
+            root
+          )),
+        })
+        .to_boxed(),
+    })
+    .to_boxed()
+}
+
+///
+/// Verifies and stores every entry of the bundle read from the reader, returning its roots. See
+/// `Store::import_bundle`.
+///
+/// The whole bundle is read and verified (including that it is complete) before anything is
+/// stored, so that importing a bad bundle leaves nothing behind in the store.
+///
+pub fn import<R: Read>(store: Store, reader: R) -> BoxFuture<Vec<Digest>, String> {
+  let (roots, files, directories) = try_future!(read(reader));
+  try_future!(check_complete(&roots, &files, &directories));
+  let file_futures = files.into_iter().map(|(_, bytes)| {
+    store
+      .store_file_bytes(Bytes::from(bytes), true)
+      .map(|_| ())
+      .to_boxed()
+  });
+  let directory_futures = directories.values().map(|directory| {
+    store
+      .record_directory(directory, true)
+      .map(|_| ())
+      .to_boxed()
+  });
+  let store_futures: Vec<_> = file_futures.chain(directory_futures).collect();
+  future::join_all(store_futures)
+    .map(move |_| roots)
+    .to_boxed()
+}
+
+///
+/// Reads the roots of the bundle, and its files and Directories, verifying each against its
+/// digest.
+///
+fn read<R: Read>(
+  reader: R,
+) -> Result<
+  (
+    Vec<Digest>,
+    HashMap<Digest, Vec<u8>>,
+    HashMap<Digest, bazel_protos::remote_execution::Directory>,
+  ),
+  String,
+> {
+  let mut archive = tar::Archive::new(reader);
+  let mut roots = None;
+  let mut files = HashMap::new();
+  let mut directories = HashMap::new();
+  let entries = archive
+    .entries()
+    .map_err(|e| format!("Error reading bundle: {}", e))?;
+  for entry in entries {
+    let mut entry = entry.map_err(|e| format!("Error reading bundle: {}", e))?;
+    let path = entry
+      .path()
+      .map_err(|e| format!("Error reading bundle: {}", e))?
+      .to_string_lossy()
+      .into_owned();
+    let mut bytes = Vec::new();
+    entry
+      .read_to_end(&mut bytes)
+      .map_err(|e| format!("Error reading {} from bundle: {}", path, e))?;
+
+    if roots.is_none() {
+      if path != MANIFEST_PATH {
+        return Err(format!(
+          "Bundle must start with a {}, but started with {}",
+          MANIFEST_PATH, path
+        ));
+      }
+      roots = Some(parse_manifest(&bytes)?);
+      continue;
+    }
+
+    let (entry_type, digest) = parse_entry_path(&path)?;
+    verify(&path, digest, &bytes)?;
+    match entry_type {
+      EntryType::File => {
+        files.insert(digest, bytes);
+      }
+      EntryType::Directory => {
+        let mut directory = bazel_protos::remote_execution::Directory::new();
+        directory
+          .merge_from_bytes(&bytes)
+          .map_err(|e| format!("Bundle entry {} was not a valid Directory: {}", path, e))?;
+        bazel_protos::verify_directory_canonical(&directory)?;
+        directories.insert(digest, directory);
+      }
+    }
+  }
+  let roots = roots.ok_or_else(|| "Bundle was empty".to_owned())?;
+  Ok((roots, files, directories))
+}
+
+///
+/// Checks that the bundle contains everything reachable from its roots, so that nothing is missing
+/// when the roots are used.
+///
+fn check_complete(
+  roots: &[Digest],
+  files: &HashMap<Digest, Vec<u8>>,
+  directories: &HashMap<Digest, bazel_protos::remote_execution::Directory>,
+) -> Result<(), String> {
+  let mut checked = HashSet::new();
+  for root in roots {
+    if files.contains_key(root) {
+      continue;
+    }
+    let missing = |digest: &Digest| {
+      format!(
+        "Bundle was missing {:?}, which is reachable from root {:?}",
+        digest, root
+      )
+    };
+    let mut to_check = vec![*root];
+    while let Some(digest) = to_check.pop() {
+      if !checked.insert(digest) {
+        continue;
+      }
+      let directory = directories.get(&digest).ok_or_else(|| missing(&digest))?;
+      for file in directory.get_files() {
+        let file_digest: Result<Digest, String> = file.get_digest().into();
+        let file_digest = file_digest?;
+        if !files.contains_key(&file_digest) {
+          return Err(missing(&file_digest));
+        }
+      }
+      for subdirectory in directory.get_directories() {
+        let subdirectory_digest: Result<Digest, String> = subdirectory.get_digest().into();
+        to_check.push(subdirectory_digest?);
+      }
+    }
+  }
+  Ok(())
+}
+
+fn manifest(roots: &[Digest]) -> String {
+  let mut manifest = format!("{}\n", MANIFEST_HEADER);
+  for root in roots {
+    manifest.push_str(&format!("root {} {}\n", root.0, root.1));
+  }
+  manifest
+}
+
+fn parse_manifest(bytes: &[u8]) -> Result<Vec<Digest>, String> {
+  let manifest =
+    str::from_utf8(bytes).map_err(|e| format!("Bundle manifest was not valid UTF-8: {}", e))?;
+  let mut lines = manifest.lines();
+  match lines.next() {
+    Some(MANIFEST_HEADER) => {}
+    other => {
+      return Err(format!(
+        "Bundle manifest should have started with {:?}, but started with {:?}",
+        MANIFEST_HEADER, other
+      ))
+    }
+  }
+  lines
+    .map(|line| match line.split(' ').collect::<Vec<_>>().as_slice() {
+      &["root", fingerprint, size_bytes] => parse_digest(fingerprint, size_bytes),
+      _ => Err(format!("Bad line in bundle manifest: {:?}", line)),
+    })
+    .collect()
+}
+
+fn entry_path(entry_type: EntryType, digest: Digest) -> String {
+  let prefix = match entry_type {
+    EntryType::File => "files",
+    EntryType::Directory => "directories",
+  };
+  format!("{}/{}-{}", prefix, digest.0, digest.1)
+}
+
+fn parse_entry_path(path: &str) -> Result<(EntryType, Digest), String> {
+  let bad_path = || format!("Unexpected entry {} in bundle", path);
+  let mut parts = path.splitn(2, '/');
+  let entry_type = match parts.next() {
+    Some("files") => EntryType::File,
+    Some("directories") => EntryType::Directory,
+    _ => return Err(bad_path()),
+  };
+  let name = parts.next().ok_or_else(bad_path)?;
+  let mut name_parts = name.splitn(2, '-');
+  match (name_parts.next(), name_parts.next()) {
+    (Some(fingerprint), Some(size_bytes)) => {
+      parse_digest(fingerprint, size_bytes).map(|digest| (entry_type, digest))
+    }
+    _ => Err(bad_path()),
+  }
+}
+
+fn parse_digest(fingerprint: &str, size_bytes: &str) -> Result<Digest, String> {
+  let fingerprint = Fingerprint::from_hex_string(fingerprint)?;
+  let size_bytes = size_bytes
+    .parse::<usize>()
+    .map_err(|e| format!("Bad size {:?} in bundle: {}", size_bytes, e))?;
+  Ok(Digest(fingerprint, size_bytes))
+}
+
+fn verify(path: &str, digest: Digest, bytes: &[u8]) -> Result<(), String> {
+  let mut hasher = Sha256::default();
+  hasher.input(bytes);
+  let fingerprint = Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice());
+  if digest == Digest(fingerprint, bytes.len()) {
+    Ok(())
+  } else {
+    Err(format!(
+      "Bundle entry {} had contents with digest {:?}",
+      path,
+      Digest(fingerprint, bytes.len())
+    ))
+  }
+}
+
+fn load_bytes(store: &Store, entry_type: EntryType, digest: Digest) -> BoxFuture<Bytes, String> {
+  let maybe_bytes = match entry_type {
+    EntryType::File => store.load_file_bytes_with(digest, |bytes| bytes),
+    EntryType::Directory => store
+      .load_directory(digest)
+      .and_then(move |maybe_directory| match maybe_directory {
+        Some(directory) => directory
+          .write_to_bytes()
+          .map(|bytes| Some(Bytes::from(bytes)))
+          .map_err(|e| format!("Error serializing Directory {:?}: {}", digest, e)),
+        None => Ok(None),
+      })
+      .to_boxed(),
+  };
+  maybe_bytes
+    .and_then(move |maybe_bytes| {
+      maybe_bytes.ok_or_else(|| format!("{:?} with digest {:?} not found", entry_type, digest))
+    })
+    .to_boxed()
+}
+
+///
+/// Entries are given zeroed timestamps and ownership, so that bundles are deterministic.
+///
+fn append<W: Write>(
+  builder: &mut tar::Builder<W>,
+  path: &str,
+  bytes: &[u8],
+) -> Result<(), String> {
+  let mut header = tar::Header::new_gnu();
+  header.set_entry_type(tar::EntryType::Regular);
+  header.set_mode(0o644);
+  header.set_size(bytes.len() as u64);
+  header.set_mtime(0);
+  header.set_uid(0);
+  header.set_gid(0);
+  builder
+    .append_data(&mut header, path, bytes)
+    .map_err(|e| format!("Error writing {} to bundle: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{ResettablePool, Store};
+
+  use futures::Future;
+  use std::io::Cursor;
+  use std::path::Path;
+  use std::sync::Arc;
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+
+  #[test]
+  fn round_trip() {
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let recursive = TestDirectory::recursive();
+    let containing_roland = TestDirectory::containing_roland();
+    store_all(&store);
+
+    let roots = vec![recursive.digest(), containing_roland.digest()];
+    let bundle = store
+      .export_bundle(roots.clone(), Vec::new())
+      .wait()
+      .expect("Error exporting");
+    assert_eq!(
+      store.export_bundle(roots.clone(), Vec::new()).wait(),
+      Ok(bundle.clone()),
+      "Bundles should be deterministic"
+    );
+
+    let import_dir = TempDir::new().unwrap();
+    let import_store = new_store(import_dir.path());
+    assert_eq!(
+      import_store.import_bundle(Cursor::new(bundle)).wait(),
+      Ok(roots)
+    );
+    assert_eq!(
+      import_store.load_directory(recursive.digest()).wait(),
+      Ok(Some(recursive.directory()))
+    );
+    assert_eq!(
+      import_store
+        .load_file_bytes_with(TestData::catnip().digest(), |bytes| bytes)
+        .wait(),
+      Ok(Some(TestData::catnip().bytes()))
+    );
+  }
+
+  #[test]
+  fn export_missing_entry() {
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let testdir = TestDirectory::containing_roland();
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+
+    let err = store
+      .export_bundle(vec![testdir.digest()], Vec::new())
+      .wait()
+      .expect_err("Want err");
+    assert!(err.contains("not found"), "Bad error message: {}", err);
+  }
+
+  #[test]
+  fn import_rejects_corrupt_entries() {
+    let roland = TestData::roland();
+    let mut builder = ::tar::Builder::new(Vec::new());
+    super::append(&mut builder, super::MANIFEST_PATH, b"pants-store-bundle 1\n").unwrap();
+    super::append(
+      &mut builder,
+      &format!("files/{}-{}", roland.fingerprint(), roland.len()),
+      b"European Burmese",
+    ).unwrap();
+    let bundle = builder.into_inner().unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let err = new_store(dir.path())
+      .import_bundle(Cursor::new(bundle))
+      .wait()
+      .expect_err("Want err");
+    assert!(err.contains("had contents with digest"), "Bad error message: {}", err);
+  }
+
+  #[test]
+  fn import_rejects_incomplete_bundles() {
+    let testdir = TestDirectory::containing_roland();
+    let mut builder = ::tar::Builder::new(Vec::new());
+    super::append(
+      &mut builder,
+      super::MANIFEST_PATH,
+      super::manifest(&[testdir.digest()]).as_bytes(),
+    ).unwrap();
+    super::append(
+      &mut builder,
+      &format!("directories/{}-{}", testdir.fingerprint(), testdir.digest().1),
+      &testdir.bytes(),
+    ).unwrap();
+    let bundle = builder.into_inner().unwrap();
+
+    // Nothing is stored from a bundle which is incomplete.
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let err = store
+      .import_bundle(Cursor::new(bundle))
+      .wait()
+      .expect_err("Want err");
+    assert!(err.contains("Bundle was missing"), "Bad error message: {}", err);
+    assert_eq!(store.load_directory(testdir.digest()).wait(), Ok(None));
+  }
+
+  #[test]
+  fn round_trip_file_root() {
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path());
+    let catnip = TestData::catnip();
+    store_all(&store);
+
+    let bundle = store
+      .export_bundle(vec![catnip.digest()], Vec::new())
+      .wait()
+      .expect("Error exporting");
+
+    let import_dir = TempDir::new().unwrap();
+    let import_store = new_store(import_dir.path());
+    assert_eq!(
+      import_store.import_bundle(Cursor::new(bundle)).wait(),
+      Ok(vec![catnip.digest()])
+    );
+    assert_eq!(
+      import_store
+        .load_file_bytes_with(catnip.digest(), |bytes| bytes)
+        .wait(),
+      Ok(Some(catnip.bytes()))
+    );
+  }
+
+  #[test]
+  fn export_missing_root() {
+    let dir = TempDir::new().unwrap();
+    let err = new_store(dir.path())
+      .export_bundle(vec![TestData::catnip().digest()], Vec::new())
+      .wait()
+      .expect_err("Want err");
+    assert!(err.contains("was not found"), "Bad error message: {}", err);
+  }
+
+  fn new_store(dir: &Path) -> Store {
+    Store::local_only(dir, Arc::new(ResettablePool::new("test-pool-".to_string()))).unwrap()
+  }
+
+  fn store_all(store: &Store) {
+    for testdir in &[TestDirectory::containing_roland(), TestDirectory::recursive()] {
+      store
+        .record_directory(&testdir.directory(), false)
+        .wait()
+        .expect("Error saving Directory");
+    }
+    for data in &[TestData::roland(), TestData::catnip()] {
+      store
+        .store_file_bytes(data.bytes(), false)
+        .wait()
+        .expect("Error saving file bytes");
+    }
+  }
+}
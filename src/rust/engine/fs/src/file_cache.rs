@@ -0,0 +1,625 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::Future;
+use hashing::Digest;
+use libc;
+
+use pool::ResettablePool;
+
+// Used to give temporary files unique names within this process.
+static TEMP_FILE_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// How long a cached file which isn't hardlinked anywhere is kept for after it was last used.
+const MAX_UNUSED_AGE_SECS: u64 = 24 * 60 * 60;
+
+///
+/// A content-addressed cache of read-only files on disk, from which files can be linked into
+/// place, rather than having their contents written out afresh each time they are materialized.
+///
+/// Files are reflinked where the filesystem supports it, which gives the destination its own
+/// (copy-on-write) contents, so that it can be written to as usual. Otherwise files are hardlinked,
+/// which means that they share their (read-only) contents with the cache and every other place they
+/// have been linked to. If neither is possible (e.g. because the destination is on a different
+/// filesystem to the cache), files are copied.
+///
+/// Because a process which ignores the read-only mode of a hardlinked file (e.g. because it runs as
+/// root) can modify it in place, cached files are only used while they are verifiably unmodified:
+/// their modification time is fixed at the epoch when they are stored, and any write changes it.
+/// Their last use is instead recorded in their access time.
+///
+/// Because executability is a property of the file's contents rather than of its path, executable
+/// and non-executable versions of a file are cached separately.
+///
+/// Every use of the cache holds a shared lock on it (using flock, so that concurrent pants
+/// processes also respect it), and garbage collection holds an exclusive one. Files are written
+/// out without the cache while it is being collected, rather than waiting for it.
+///
+#[derive(Clone)]
+pub struct FileCache {
+  root: PathBuf,
+  pool: Arc<ResettablePool>,
+}
+
+impl FileCache {
+  pub fn new(root: PathBuf, pool: Arc<ResettablePool>) -> Result<FileCache, String> {
+    super::safe_create_dir_all(&root)
+      .map_err(|e| format!("Error making file cache at {:?}: {}", root, e))?;
+    Ok(FileCache { root, pool })
+  }
+
+  ///
+  /// Links the cached file with the given digest to the destination, if it is present in the
+  /// cache. Returns false if it is not present (or the cache is being garbage collected).
+  ///
+  pub fn materialize(
+    &self,
+    digest: Digest,
+    is_executable: bool,
+    destination: PathBuf,
+  ) -> BoxFuture<bool, String> {
+    let cache = self.clone();
+    let path = self.path(digest, is_executable);
+    self
+      .pool
+      .spawn_fn(move || {
+        let _lock = match cache.try_lock(libc::LOCK_SH)? {
+          Some(lock) => lock,
+          None => return Ok(false),
+        };
+        if !is_intact(&path, digest, is_executable) {
+          return Ok(false);
+        }
+        link(&path, &destination, is_executable)
+          .map(|()| true)
+          .map_err(|e| {
+            format!(
+              "Error linking cached file {:?} to {:?}: {:?}",
+              path, destination, e
+            )
+          })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Adds the given contents to the cache, and then links them to the destination.
+  ///
+  /// Must be called on a thread which is allowed to block.
+  ///
+  pub fn store_and_materialize(
+    &self,
+    digest: Digest,
+    is_executable: bool,
+    bytes: &[u8],
+    destination: &Path,
+  ) -> Result<(), String> {
+    self.store_and_materialize_from(digest, is_executable, &mut &bytes[..], destination)
+  }
+
+  ///
+  /// As store_and_materialize, but copies the contents from a reader, so that they need not be
+  /// held in memory.
+  ///
+  /// Must be called on a thread which is allowed to block.
+  ///
+  pub fn store_and_materialize_from<R: Read>(
+    &self,
+    digest: Digest,
+    is_executable: bool,
+    reader: &mut R,
+    destination: &Path,
+  ) -> Result<(), String> {
+    let _lock = match self.try_lock(libc::LOCK_SH)? {
+      Some(lock) => lock,
+      None => {
+        return write(reader, destination, is_executable)
+          .map_err(|e| format!("Error writing file {:?}: {:?}", destination, e))
+      }
+    };
+    let path = self.path(digest, is_executable);
+    if !is_intact(&path, digest, is_executable) {
+      self
+        .store(&path, is_executable, reader)
+        .map_err(|e| format!("Error adding {:?} to file cache: {:?}", digest, e))?;
+    }
+    link(&path, destination, is_executable).map_err(|e| {
+      format!(
+        "Error linking cached file {:?} to {:?}: {:?}",
+        path, destination, e
+      )
+    })
+  }
+
+  ///
+  /// Removes every cached file which is not currently hardlinked anywhere else, and which has not
+  /// been used recently. Does nothing if the cache is in use.
+  ///
+  pub fn garbage_collect(&self) -> Result<(), String> {
+    self.garbage_collect_unused_for(Duration::from_secs(MAX_UNUSED_AGE_SECS))
+  }
+
+  fn garbage_collect_unused_for(&self, max_unused_age: Duration) -> Result<(), String> {
+    let _lock = match self.try_lock(libc::LOCK_EX)? {
+      Some(lock) => lock,
+      None => {
+        debug!("File cache {:?} is in use; not garbage collecting it", self.root);
+        return Ok(());
+      }
+    };
+    let now = SystemTime::now();
+    let shards = fs::read_dir(&self.root)
+      .map_err(|e| format!("Error listing file cache {:?}: {:?}", self.root, e))?;
+    for shard in shards {
+      let shard = shard.map_err(|e| format!("Error listing file cache {:?}: {:?}", self.root, e))?;
+      if !shard.path().is_dir() {
+        continue;
+      }
+      let entries = fs::read_dir(shard.path())
+        .map_err(|e| format!("Error listing file cache {:?}: {:?}", shard.path(), e))?;
+      for entry in entries {
+        let path = entry
+          .map_err(|e| format!("Error listing file cache {:?}: {:?}", shard.path(), e))?
+          .path();
+        // Hardlinked files are in use. Files which were reflinked or copied don't share their
+        // contents with the cache, so their last use is recorded in their access time.
+        let unused = fs::symlink_metadata(&path)
+          .and_then(|metadata| {
+            let last_used = metadata.accessed()?;
+            Ok(metadata.nlink() <= 1 && last_used + max_unused_age <= now)
+          })
+          .unwrap_or(false);
+        if unused {
+          fs::remove_file(&path)
+            .map_err(|e| format!("Error removing cached file {:?}: {:?}", path, e))?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  /// Takes the given kind of flock on the cache without blocking, returning None if it is held
+  /// incompatibly by someone else. The lock is released when the returned file is closed.
+  ///
+  fn try_lock(&self, operation: libc::c_int) -> Result<Option<File>, String> {
+    let lock_path = self.root.join(".lock");
+    let file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .open(&lock_path)
+      .map_err(|e| format!("Error opening file cache lock {:?}: {:?}", lock_path, e))?;
+    loop {
+      if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+        return Ok(Some(file));
+      }
+      let err = io::Error::last_os_error();
+      match err.kind() {
+        io::ErrorKind::Interrupted => continue,
+        io::ErrorKind::WouldBlock => return Ok(None),
+        _ => {
+          return Err(format!(
+            "Error locking file cache {:?}: {:?}",
+            lock_path, err
+          ))
+        }
+      }
+    }
+  }
+
+  fn path(&self, digest: Digest, is_executable: bool) -> PathBuf {
+    let hex = digest.0.to_hex();
+    let name = if is_executable {
+      format!("{}-{}.executable", hex, digest.1)
+    } else {
+      format!("{}-{}", hex, digest.1)
+    };
+    self.root.join(&hex[0..2]).join(name)
+  }
+
+  ///
+  /// Writes the file to a temporary path before moving it into place, so that concurrent readers
+  /// (possibly in other processes) never observe a partially written file.
+  ///
+  fn store<R: Read>(&self, path: &Path, is_executable: bool, reader: &mut R) -> io::Result<()> {
+    let parent = path.parent().expect("Cached files always have a parent");
+    super::safe_create_dir_all_ioerror(parent)?;
+    let temp_path = parent.join(format!(
+      ".tmp-{}-{}",
+      process::id(),
+      TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let result = OpenOptions::new()
+      .create_new(true)
+      .write(true)
+      .mode(read_only_mode(is_executable))
+      .open(&temp_path)
+      .and_then(|mut f| io::copy(reader, &mut f))
+      .and_then(|_| mark_used(&temp_path))
+      .and_then(|()| fs::rename(&temp_path, path));
+    if result.is_err() {
+      let _ = fs::remove_file(&temp_path);
+    }
+    result
+  }
+}
+
+fn read_only_mode(is_executable: bool) -> u32 {
+  if is_executable {
+    0o555
+  } else {
+    0o444
+  }
+}
+
+fn writable_mode(is_executable: bool) -> u32 {
+  if is_executable {
+    0o755
+  } else {
+    0o644
+  }
+}
+
+///
+/// Whether the cached file at the given path exists, and has not been tampered with in any
+/// cheaply detectable way: e.g. by a process which made a hardlinked copy of it writable, or which
+/// wrote to a hardlinked copy of it (which changes its modification time from the epoch).
+///
+fn is_intact(path: &Path, digest: Digest, is_executable: bool) -> bool {
+  match fs::symlink_metadata(path) {
+    Ok(metadata) => {
+      metadata.is_file()
+        && metadata.len() == digest.1 as u64
+        && metadata.permissions().mode() & 0o777 == read_only_mode(is_executable)
+        && metadata.mtime() == 0
+        && metadata.mtime_nsec() == 0
+    }
+    Err(_) => false,
+  }
+}
+
+///
+/// Links the cached file at source (which must be intact) to the destination. Files which are
+/// reflinked or copied have their use recorded for garbage collection: see
+/// FileCache::garbage_collect.
+///
+fn link(source: &Path, destination: &Path, is_executable: bool) -> io::Result<()> {
+  if reflink(source, destination, writable_mode(is_executable)).is_ok() {
+    return mark_used(source);
+  }
+  if fs::hard_link(source, destination).is_ok() {
+    return Ok(());
+  }
+  fs::copy(source, destination)?;
+  fs::set_permissions(
+    destination,
+    fs::Permissions::from_mode(writable_mode(is_executable)),
+  )?;
+  mark_used(source)
+}
+
+///
+/// Sets the access time of the given cached file to now, and (re)sets its modification time to the
+/// epoch, by which it is verified: see is_intact. Files which are hardlinked are left alone,
+/// because they share their times with everywhere they have been linked to.
+///
+fn mark_used(path: &Path) -> io::Result<()> {
+  if fs::symlink_metadata(path)?.nlink() > 1 {
+    return Ok(());
+  }
+  let c_path = CString::new(path.as_os_str().as_bytes())
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+  let times = [
+    libc::timeval {
+      tv_sec: now.as_secs() as libc::time_t,
+      tv_usec: libc::suseconds_t::from(now.subsec_micros() as i32),
+    },
+    libc::timeval {
+      tv_sec: 0,
+      tv_usec: 0,
+    },
+  ];
+  if unsafe { libc::utimes(c_path.as_ptr(), times.as_ptr()) } == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+///
+/// Writes the contents from a reader to the destination, as though there were no cache.
+///
+fn write<R: Read>(reader: &mut R, destination: &Path, is_executable: bool) -> io::Result<()> {
+  OpenOptions::new()
+    .create(true)
+    .write(true)
+    .mode(writable_mode(is_executable))
+    .open(destination)
+    .and_then(|mut f| io::copy(reader, &mut f))
+    .map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path, mode: u32) -> io::Result<()> {
+  // _IOW(0x94, 9, int), from linux/fs.h.
+  const FICLONE: libc::c_ulong = 0x4004_9409;
+
+  let source_file = File::open(source)?;
+  let destination_file = OpenOptions::new()
+    .create_new(true)
+    .write(true)
+    .mode(mode)
+    .open(destination)?;
+  let result = unsafe {
+    libc::ioctl(
+      destination_file.as_raw_fd(),
+      FICLONE as _,
+      source_file.as_raw_fd(),
+    )
+  };
+  if result == 0 {
+    Ok(())
+  } else {
+    let err = io::Error::last_os_error();
+    drop(destination_file);
+    let _ = fs::remove_file(destination);
+    Err(err)
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _destination: &Path, _mode: u32) -> io::Result<()> {
+  Err(io::Error::new(
+    io::ErrorKind::Other,
+    "Reflinking is only supported on Linux",
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::FileCache;
+
+  use futures::Future;
+  use libc;
+  use pool::ResettablePool;
+  use std::fs;
+  use std::os::unix::fs::{MetadataExt, PermissionsExt};
+  use std::path::Path;
+  use std::sync::Arc;
+  use std::time::Duration;
+  use tempfile::TempDir;
+  use testutil::data::TestData;
+
+  #[test]
+  fn materialize_missing() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path());
+    let linked = cache
+      .materialize(
+        TestData::roland().digest(),
+        false,
+        materialize_dir.path().join("roland"),
+      )
+      .wait()
+      .unwrap();
+    assert!(!linked);
+    assert!(!materialize_dir.path().join("roland").exists());
+  }
+
+  #[test]
+  fn store_and_materialize() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path());
+    let roland = TestData::roland();
+
+    cache
+      .store_and_materialize(
+        roland.digest(),
+        true,
+        &roland.bytes(),
+        &materialize_dir.path().join("first"),
+      )
+      .unwrap();
+    let linked = cache
+      .materialize(roland.digest(), true, materialize_dir.path().join("second"))
+      .wait()
+      .unwrap();
+    assert!(linked);
+
+    for name in &["first", "second"] {
+      let path = materialize_dir.path().join(name);
+      assert_eq!(fs::read(&path).unwrap(), roland.bytes().to_vec());
+      assert_eq!(mode(&path) & 0o111, 0o111);
+    }
+
+    // Executable and non-executable versions of a file are distinct.
+    let linked = cache
+      .materialize(roland.digest(), false, materialize_dir.path().join("third"))
+      .wait()
+      .unwrap();
+    assert!(!linked);
+  }
+
+  #[test]
+  fn tampered_files_are_not_used() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path());
+    let roland = TestData::roland();
+
+    cache
+      .store_and_materialize(
+        roland.digest(),
+        false,
+        &roland.bytes(),
+        &materialize_dir.path().join("first"),
+      )
+      .unwrap();
+    let cached = cache.path(roland.digest(), false);
+    fs::set_permissions(&cached, fs::Permissions::from_mode(0o644)).unwrap();
+
+    let linked = cache
+      .materialize(roland.digest(), false, materialize_dir.path().join("second"))
+      .wait()
+      .unwrap();
+    assert!(!linked);
+  }
+
+  #[test]
+  fn modified_files_are_not_used() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path());
+    let roland = TestData::roland();
+
+    cache
+      .store_and_materialize(
+        roland.digest(),
+        false,
+        &roland.bytes(),
+        &materialize_dir.path().join("first"),
+      )
+      .unwrap();
+    // Modify the cached file in place, without changing its size or mode, as a process which
+    // ignores the mode of a hardlinked file could.
+    let cached = cache.path(roland.digest(), false);
+    let mut modified = roland.bytes().to_vec();
+    modified.reverse();
+    fs::set_permissions(&cached, fs::Permissions::from_mode(0o644)).unwrap();
+    fs::write(&cached, &modified).unwrap();
+    fs::set_permissions(&cached, fs::Permissions::from_mode(0o444)).unwrap();
+
+    let linked = cache
+      .materialize(roland.digest(), false, materialize_dir.path().join("second"))
+      .wait()
+      .unwrap();
+    assert!(!linked);
+
+    cache
+      .store_and_materialize(
+        roland.digest(),
+        false,
+        &roland.bytes(),
+        &materialize_dir.path().join("second"),
+      )
+      .unwrap();
+    assert_eq!(
+      fs::read(materialize_dir.path().join("second")).unwrap(),
+      roland.bytes().to_vec()
+    );
+  }
+
+  #[test]
+  fn garbage_collect() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path());
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+
+    cache
+      .store_and_materialize(
+        roland.digest(),
+        false,
+        &roland.bytes(),
+        &materialize_dir.path().join("roland"),
+      )
+      .unwrap();
+    cache
+      .store_and_materialize(
+        catnip.digest(),
+        false,
+        &catnip.bytes(),
+        &materialize_dir.path().join("catnip"),
+      )
+      .unwrap();
+    fs::remove_file(materialize_dir.path().join("catnip")).unwrap();
+
+    // Recently used files are kept, whether or not they are linked anywhere.
+    cache.garbage_collect().unwrap();
+    assert!(cache.path(roland.digest(), false).exists());
+    assert!(cache.path(catnip.digest(), false).exists());
+
+    // Nothing is collected while the cache is in use.
+    {
+      let _lock = cache.try_lock(libc::LOCK_SH).unwrap().unwrap();
+      cache.garbage_collect_unused_for(Duration::from_secs(0)).unwrap();
+      assert!(cache.path(catnip.digest(), false).exists());
+    }
+
+    // Files which were reflinked or copied don't share their contents with the cache, so are
+    // collected once they haven't been used for long enough.
+    let roland_is_hardlinked = fs::metadata(materialize_dir.path().join("roland"))
+      .unwrap()
+      .nlink() > 1;
+
+    cache.garbage_collect_unused_for(Duration::from_secs(0)).unwrap();
+
+    assert_eq!(
+      cache.path(roland.digest(), false).exists(),
+      roland_is_hardlinked
+    );
+    assert!(!cache.path(catnip.digest(), false).exists());
+    assert_eq!(
+      fs::read(materialize_dir.path().join("roland")).unwrap(),
+      roland.bytes().to_vec()
+    );
+  }
+
+  #[test]
+  fn files_are_written_while_collecting() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path());
+    let roland = TestData::roland();
+
+    let _lock = cache.try_lock(libc::LOCK_EX).unwrap().unwrap();
+    cache
+      .store_and_materialize(
+        roland.digest(),
+        true,
+        &roland.bytes(),
+        &materialize_dir.path().join("roland"),
+      )
+      .unwrap();
+    let path = materialize_dir.path().join("roland");
+    assert_eq!(fs::read(&path).unwrap(), roland.bytes().to_vec());
+    assert_eq!(mode(&path) & 0o111, 0o111);
+    assert!(!cache.path(roland.digest(), true).exists());
+
+    let linked = cache
+      .materialize(roland.digest(), true, materialize_dir.path().join("second"))
+      .wait()
+      .unwrap();
+    assert!(!linked);
+  }
+
+  fn new_cache(dir: &Path) -> FileCache {
+    FileCache::new(
+      dir.to_owned(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+    ).unwrap()
+  }
+
+  fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode()
+  }
+}
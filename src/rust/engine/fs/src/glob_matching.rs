@@ -13,7 +13,7 @@ use indexmap::{map::Entry::Occupied, IndexMap, IndexSet};
 
 use {
   Dir, GitignoreStyleExcludes, GlobParsedSource, GlobSource, GlobWithSource, Link, PathGlob,
-  PathGlobs, PathStat, Stat, VFS,
+  PathGlobs, PathStat, Stat, SymlinkBehavior, VFS,
 };
 
 pub trait GlobMatching<E: Send + Sync + 'static>: VFS<E> {
@@ -66,6 +66,8 @@ struct PathGlobsExpansion<T: Sized> {
   todo: Vec<GlobWithSource>,
   // Paths to exclude.
   exclude: Arc<GitignoreStyleExcludes>,
+  // Whether to capture Links, rather than canonicalizing them.
+  symlink_behavior: SymlinkBehavior,
   // Globs that have already been expanded.
   completed: IndexMap<PathGlob, GlobExpansionCacheEntry>,
   // Unique Paths that have been matched, in order.
@@ -83,6 +85,7 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
     symbolic_path: PathBuf,
     wildcard: Pattern,
     exclude: &Arc<GitignoreStyleExcludes>,
+    symlink_behavior: SymlinkBehavior,
   ) -> BoxFuture<Vec<PathStat>, E> {
     // List the directory.
     let context = self.clone();
@@ -121,7 +124,18 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
                 future::ok(None).to_boxed()
               } else {
                 match stat {
-                  Stat::Link(l) => context.canonicalize(stat_symbolic_path, l),
+                  Stat::Link(l) => match symlink_behavior {
+                    SymlinkBehavior::Aware => {
+                      // Links are captured with their targets as they were written, which must
+                      // not point outside of the root.
+                      let link = l.clone();
+                      context
+                        .read_link_target_within_root(l)
+                        .map(move |target| Some(PathStat::link(stat_symbolic_path, link, target)))
+                        .to_boxed()
+                    }
+                    SymlinkBehavior::Oblivious => context.canonicalize(stat_symbolic_path, l),
+                  },
                   Stat::Dir(d) => future::ok(Some(PathStat::dir(
                     stat_symbolic_path.to_owned(),
                     d.clone(),
@@ -148,6 +162,7 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
       include,
       exclude,
       strict_match_behavior,
+      symlink_behavior,
     } = path_globs;
 
     if include.is_empty() {
@@ -161,6 +176,7 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
         .flat_map(|entry| entry.to_sourced_globs())
         .collect(),
       exclude,
+      symlink_behavior,
       completed: IndexMap::default(),
       outputs: IndexSet::default(),
     };
@@ -168,11 +184,12 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
       // Request the expansion of all outstanding PathGlobs as a batch.
       let round = future::join_all({
         let exclude = &expansion.exclude;
+        let symlink_behavior = expansion.symlink_behavior;
         let context = &expansion.context;
         expansion
           .todo
           .drain(..)
-          .map(|sourced_glob| context.expand_single(sourced_glob, exclude))
+          .map(|sourced_glob| context.expand_single(sourced_glob, exclude, symlink_behavior))
           .collect::<Vec<_>>()
       });
       round.map(move |single_expansion_results| {
@@ -331,11 +348,12 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
     &self,
     sourced_glob: GlobWithSource,
     exclude: &Arc<GitignoreStyleExcludes>,
+    symlink_behavior: SymlinkBehavior,
   ) -> BoxFuture<SingleExpansionResult, E> {
     match sourced_glob.path_glob.clone() {
       PathGlob::Wildcard { canonical_dir, symbolic_path, wildcard } =>
       // Filter directory listing to return PathStats, with no continuation.
-        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude)
+        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude, symlink_behavior)
         .map(move |path_stats| SingleExpansionResult {
           sourced_glob,
           path_stats,
@@ -344,7 +362,7 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
         .to_boxed(),
       PathGlob::DirWildcard { canonical_dir, symbolic_path, wildcard, remainder } =>
       // Filter directory listing and request additional PathGlobs for matched Dirs.
-        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude)
+        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude, symlink_behavior)
         .and_then(move |path_stats| {
           path_stats.into_iter()
             .filter_map(|ps| match ps {
@@ -353,7 +371,7 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
                   PathGlob::parse_globs(stat, path, &remainder)
                     .map_err(|e| Self::mk_error(e.as_str()))
                 ),
-              PathStat::File { .. } => None,
+              PathStat::File { .. } | PathStat::Link { .. } => None,
             })
             .collect::<Result<Vec<_>, E>>()
         })
@@ -397,6 +415,7 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
         path_stats.pop().map(|ps| match ps {
           PathStat::Dir { stat, .. } => PathStat::dir(symbolic_path, stat),
           PathStat::File { stat, .. } => PathStat::file(symbolic_path, stat),
+          PathStat::Link { stat, target, .. } => PathStat::link(symbolic_path, stat, target),
         })
       })
       .to_boxed()
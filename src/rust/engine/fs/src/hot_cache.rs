@@ -0,0 +1,308 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use bazel_protos;
+use bytes::Bytes;
+use hashing::{Digest, Fingerprint};
+
+///
+/// Counts of how a HotCache has been used, and how full it is.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HotCacheStats {
+  pub entries: usize,
+  pub size_bytes: usize,
+  pub capacity_bytes: usize,
+  pub directory_hits: u64,
+  pub directory_misses: u64,
+  pub file_hits: u64,
+  pub file_misses: u64,
+}
+
+impl HotCacheStats {
+  ///
+  /// The proportion of lookups which were hits, or None if there have been no lookups.
+  ///
+  pub fn hit_rate(&self) -> Option<f64> {
+    let hits = self.directory_hits + self.file_hits;
+    let lookups = hits + self.directory_misses + self.file_misses;
+    if lookups == 0 {
+      None
+    } else {
+      Some(hits as f64 / lookups as f64)
+    }
+  }
+}
+
+enum Value {
+  // Directories are held parsed, because parsing them is much of the cost of loading them.
+  Directory(bazel_protos::remote_execution::Directory),
+  File(Bytes),
+}
+
+struct Entry {
+  value: Value,
+  size_bytes: usize,
+  last_used: u64,
+}
+
+struct Inner {
+  entries: HashMap<Fingerprint, Entry>,
+  // Every entry's fingerprint, keyed by when it was last used, so that the least recently used
+  // entry is first.
+  recency: BTreeMap<u64, Fingerprint>,
+  clock: u64,
+  size_bytes: usize,
+  stats: HotCacheStats,
+}
+
+///
+/// A bounded, in-memory, least-recently-used cache of recently loaded Directories and small files,
+/// which saves reading (and, for Directories, parsing) them from LMDB each time they are used.
+///
+/// Entries are sized by their serialized length. Because the cache is content-addressed, entries
+/// never become stale, but they should be dropped when they may have been garbage collected from
+/// the store beneath the cache, so that the cache doesn't mask their absence.
+///
+pub struct HotCache {
+  capacity_bytes: usize,
+  max_file_bytes: usize,
+  inner: Mutex<Inner>,
+}
+
+impl HotCache {
+  ///
+  /// Makes a cache holding at most capacity_bytes, which only caches files of at most
+  /// max_file_bytes. A capacity of zero disables the cache.
+  ///
+  pub fn new(capacity_bytes: usize, max_file_bytes: usize) -> HotCache {
+    HotCache {
+      capacity_bytes: capacity_bytes,
+      max_file_bytes: max_file_bytes,
+      inner: Mutex::new(Inner {
+        entries: HashMap::new(),
+        recency: BTreeMap::new(),
+        clock: 0,
+        size_bytes: 0,
+        stats: HotCacheStats::default(),
+      }),
+    }
+  }
+
+  pub fn get_directory(
+    &self,
+    fingerprint: &Fingerprint,
+  ) -> Option<bazel_protos::remote_execution::Directory> {
+    let mut inner = self.inner.lock().unwrap();
+    let directory = match inner.touch(fingerprint) {
+      Some(&Value::Directory(ref directory)) => Some(directory.clone()),
+      _ => None,
+    };
+    if directory.is_some() {
+      inner.stats.directory_hits += 1;
+    } else {
+      inner.stats.directory_misses += 1;
+    }
+    directory
+  }
+
+  pub fn get_file(&self, fingerprint: &Fingerprint) -> Option<Bytes> {
+    let mut inner = self.inner.lock().unwrap();
+    let bytes = match inner.touch(fingerprint) {
+      Some(&Value::File(ref bytes)) => Some(bytes.clone()),
+      _ => None,
+    };
+    if bytes.is_some() {
+      inner.stats.file_hits += 1;
+    } else {
+      inner.stats.file_misses += 1;
+    }
+    bytes
+  }
+
+  pub fn insert_directory(
+    &self,
+    digest: Digest,
+    directory: bazel_protos::remote_execution::Directory,
+  ) {
+    self.insert(digest.0, digest.1, Value::Directory(directory))
+  }
+
+  ///
+  /// Caches the given file, unless it is too big to be worth caching.
+  ///
+  pub fn insert_file(&self, fingerprint: Fingerprint, bytes: Bytes) {
+    if bytes.len() <= self.max_file_bytes {
+      self.insert(fingerprint, bytes.len(), Value::File(bytes))
+    }
+  }
+
+  fn insert(&self, fingerprint: Fingerprint, size_bytes: usize, value: Value) {
+    if size_bytes > self.capacity_bytes {
+      return;
+    }
+    let mut inner = self.inner.lock().unwrap();
+    if inner.touch(&fingerprint).is_some() {
+      return;
+    }
+    while inner.size_bytes + size_bytes > self.capacity_bytes {
+      inner.evict_least_recently_used();
+    }
+    inner.clock += 1;
+    let last_used = inner.clock;
+    inner.recency.insert(last_used, fingerprint);
+    inner.size_bytes += size_bytes;
+    inner.entries.insert(
+      fingerprint,
+      Entry {
+        value: value,
+        size_bytes: size_bytes,
+        last_used: last_used,
+      },
+    );
+  }
+
+  ///
+  /// Drops every entry, but keeps counting hits and misses.
+  ///
+  pub fn clear(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.entries.clear();
+    inner.recency.clear();
+    inner.size_bytes = 0;
+  }
+
+  pub fn stats(&self) -> HotCacheStats {
+    let inner = self.inner.lock().unwrap();
+    HotCacheStats {
+      entries: inner.entries.len(),
+      size_bytes: inner.size_bytes,
+      capacity_bytes: self.capacity_bytes,
+      ..inner.stats.clone()
+    }
+  }
+}
+
+impl Inner {
+  ///
+  /// Marks the entry with the given fingerprint (if any) as the most recently used, and returns its
+  /// value.
+  ///
+  fn touch(&mut self, fingerprint: &Fingerprint) -> Option<&Value> {
+    self.clock += 1;
+    let clock = self.clock;
+    match self.entries.get_mut(fingerprint) {
+      Some(entry) => {
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(clock, *fingerprint);
+        entry.last_used = clock;
+        Some(&entry.value)
+      }
+      None => None,
+    }
+  }
+
+  fn evict_least_recently_used(&mut self) {
+    let (last_used, fingerprint) = match self.recency.iter().next() {
+      Some((last_used, fingerprint)) => (*last_used, *fingerprint),
+      None => return,
+    };
+    self.recency.remove(&last_used);
+    if let Some(entry) = self.entries.remove(&fingerprint) {
+      self.size_bytes -= entry.size_bytes;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{HotCache, HotCacheStats};
+  use bytes::Bytes;
+  use testutil::data::{TestData, TestDirectory};
+
+  #[test]
+  fn caches_directories_and_files() {
+    let cache = HotCache::new(1024, 1024);
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    assert_eq!(cache.get_directory(&testdir.fingerprint()), None);
+    assert_eq!(cache.get_file(&roland.fingerprint()), None);
+    cache.insert_directory(testdir.digest(), testdir.directory());
+    cache.insert_file(roland.fingerprint(), roland.bytes());
+    assert_eq!(
+      cache.get_directory(&testdir.fingerprint()),
+      Some(testdir.directory())
+    );
+    assert_eq!(cache.get_file(&roland.fingerprint()), Some(roland.bytes()));
+    // A file isn't a directory, even if it is cached.
+    assert_eq!(cache.get_directory(&roland.fingerprint()), None);
+
+    assert_eq!(
+      cache.stats(),
+      HotCacheStats {
+        entries: 2,
+        size_bytes: testdir.digest().1 + roland.len(),
+        capacity_bytes: 1024,
+        directory_hits: 1,
+        directory_misses: 2,
+        file_hits: 1,
+        file_misses: 1,
+      }
+    );
+    assert_eq!(cache.stats().hit_rate(), Some(0.4));
+  }
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let fourty_chars = TestData::fourty_chars();
+    let cache = HotCache::new(roland.len() + fourty_chars.len(), 1024);
+
+    cache.insert_file(roland.fingerprint(), roland.bytes());
+    cache.insert_file(catnip.fingerprint(), catnip.bytes());
+    // Using roland makes catnip the least recently used.
+    assert_eq!(cache.get_file(&roland.fingerprint()), Some(roland.bytes()));
+    cache.insert_file(fourty_chars.fingerprint(), fourty_chars.bytes());
+
+    assert_eq!(cache.get_file(&catnip.fingerprint()), None);
+    assert_eq!(cache.get_file(&roland.fingerprint()), Some(roland.bytes()));
+    assert_eq!(
+      cache.get_file(&fourty_chars.fingerprint()),
+      Some(fourty_chars.bytes())
+    );
+    assert_eq!(
+      cache.stats().size_bytes,
+      roland.len() + fourty_chars.len()
+    );
+  }
+
+  #[test]
+  fn skips_big_files() {
+    let cache = HotCache::new(1024, 4);
+    let roland = TestData::roland();
+    cache.insert_file(roland.fingerprint(), roland.bytes());
+    assert_eq!(cache.get_file(&roland.fingerprint()), None);
+
+    let cache = HotCache::new(4, 1024);
+    cache.insert_file(roland.fingerprint(), roland.bytes());
+    assert_eq!(cache.get_file(&roland.fingerprint()), None);
+    assert_eq!(cache.stats().entries, 0);
+  }
+
+  #[test]
+  fn clear() {
+    let cache = HotCache::new(1024, 1024);
+    let roland = TestData::roland();
+    cache.insert_file(roland.fingerprint(), roland.bytes());
+    cache.clear();
+    assert_eq!(cache.get_file(&roland.fingerprint()), None);
+    cache.insert_file(roland.fingerprint(), Bytes::from(roland.string()));
+    assert_eq!(cache.get_file(&roland.fingerprint()), Some(roland.bytes()));
+    assert_eq!(cache.stats().size_bytes, roland.len());
+  }
+}
@@ -18,17 +18,30 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![cfg_attr(feature = "cargo-clippy", allow(mutex_atomic))]
 
+mod archive;
+pub use archive::ArchiveFormat;
+mod bundle;
+mod file_cache;
+pub use file_cache::FileCache;
+mod hot_cache;
+pub use hot_cache::HotCacheStats;
 mod glob_matching;
 pub use glob_matching::GlobMatching;
 mod snapshot;
 pub use snapshot::{
-  OneOffStoreFileByDigest, Snapshot, StoreFileByDigest, EMPTY_DIGEST, EMPTY_FINGERPRINT,
+  MergePolicy, OneOffStoreFileByDigest, Snapshot, StoreFileByDigest, EMPTY_DIGEST,
+  EMPTY_FINGERPRINT,
 };
 mod store;
-pub use store::Store;
+pub use store::{
+  DatabaseStats, DatabasesStats, DirectoryDiff, EndpointSelection, EntryStats, EntryTypeStats,
+  Store, StoreStats, MAX_LOCAL_STORE_MAP_SIZE_BYTES, MAX_LOCAL_STORE_SIZE_BYTES,
+};
 mod pool;
 pub use pool::ResettablePool;
 
+pub use bazel_protos::RequestMetadata;
+
 extern crate bazel_protos;
 #[macro_use]
 extern crate boxfuture;
@@ -45,7 +58,9 @@ extern crate indexmap;
 extern crate itertools;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate lmdb;
+extern crate lmdb_sys;
 #[macro_use]
 extern crate log;
 #[cfg(test)]
@@ -53,10 +68,13 @@ extern crate mock;
 extern crate protobuf;
 extern crate resettable;
 extern crate sha2;
+extern crate tar;
 #[cfg(test)]
 extern crate tempfile;
 #[cfg(test)]
 extern crate testutil;
+extern crate zip;
+extern crate zstd;
 
 use std::cmp::min;
 use std::io::{self, Read};
@@ -115,6 +133,16 @@ pub enum PathStat {
     // The canonical Stat that underlies the Path.
     stat: File,
   },
+  // Only produced when expanding PathGlobs with SymlinkBehavior::Aware: otherwise Links are
+  // canonicalized to the Dir or File that they point to.
+  Link {
+    // The symbolic name of some filesystem Path, which is context specific.
+    path: PathBuf,
+    // The canonical Stat that underlies the Path.
+    stat: Link,
+    // The (unresolved) destination of the Link.
+    target: PathBuf,
+  },
 }
 
 impl PathStat {
@@ -132,10 +160,15 @@ impl PathStat {
     }
   }
 
+  pub fn link(path: PathBuf, stat: Link, target: PathBuf) -> PathStat {
+    PathStat::Link { path, stat, target }
+  }
+
   pub fn path(&self) -> &Path {
     match self {
       &PathStat::Dir { ref path, .. } => path.as_path(),
       &PathStat::File { ref path, .. } => path.as_path(),
+      &PathStat::Link { ref path, .. } => path.as_path(),
     }
   }
 }
@@ -442,11 +475,22 @@ impl StrictGlobMatching {
   }
 }
 
+///
+/// Whether expanding PathGlobs should resolve symlinks to the files and directories that they point
+/// to, or capture them as symlinks.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SymlinkBehavior {
+  Aware,
+  Oblivious,
+}
+
 #[derive(Debug)]
 pub struct PathGlobs {
   include: Vec<PathGlobIncludeEntry>,
   exclude: Arc<GitignoreStyleExcludes>,
   strict_match_behavior: StrictGlobMatching,
+  symlink_behavior: SymlinkBehavior,
 }
 
 impl PathGlobs {
@@ -469,9 +513,20 @@ impl PathGlobs {
       include,
       exclude: gitignore_excludes,
       strict_match_behavior,
+      symlink_behavior: SymlinkBehavior::Oblivious,
     })
   }
 
+  ///
+  /// By default, symlinks are resolved to the files and directories that they point to.
+  ///
+  pub fn with_symlink_behavior(self, symlink_behavior: SymlinkBehavior) -> PathGlobs {
+    PathGlobs {
+      symlink_behavior,
+      ..self
+    }
+  }
+
   pub fn from_globs(include: Vec<PathGlob>) -> Result<PathGlobs, String> {
     let include = include
       .into_iter()
@@ -565,6 +620,13 @@ impl PosixFS {
     self.ignore.is_ignored(stat)
   }
 
+  ///
+  /// The absolute path of the given file.
+  ///
+  pub fn file_path(&self, file: &File) -> PathBuf {
+    self.root.0.join(&file.path)
+  }
+
   pub fn read_file(&self, file: &File) -> BoxFuture<FileContent, io::Error> {
     let path = file.path.clone();
     let path_abs = self.root.0.join(&file.path);
@@ -583,31 +645,9 @@ impl PosixFS {
       .to_boxed()
   }
 
-  pub fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, io::Error> {
-    let link_parent = link.0.parent().map(|p| p.to_owned());
+  pub fn read_link_target(&self, link: &Link) -> BoxFuture<PathBuf, io::Error> {
     let link_abs = self.root.0.join(link.0.as_path()).to_owned();
-    self
-      .pool
-      .spawn_fn(move || {
-        link_abs.read_link().and_then(|path_buf| {
-          if path_buf.is_absolute() {
-            Err(io::Error::new(
-              io::ErrorKind::InvalidData,
-              format!("Absolute symlink: {:?}", link_abs),
-            ))
-          } else {
-            link_parent
-              .map(|parent| parent.join(path_buf))
-              .ok_or_else(|| {
-                io::Error::new(
-                  io::ErrorKind::InvalidData,
-                  format!("Symlink without a parent?: {:?}", link_abs),
-                )
-              })
-          }
-        })
-      })
-      .to_boxed()
+    self.pool.spawn_fn(move || link_abs.read_link()).to_boxed()
   }
 
   ///
@@ -683,8 +723,8 @@ impl PosixFS {
 }
 
 impl VFS<io::Error> for Arc<PosixFS> {
-  fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, io::Error> {
-    PosixFS::read_link(self, link)
+  fn read_link_target(&self, link: &Link) -> BoxFuture<PathBuf, io::Error> {
+    PosixFS::read_link_target(self, link)
   }
 
   fn scandir(&self, dir: Dir) -> BoxFuture<Arc<DirectoryListing>, io::Error> {
@@ -745,7 +785,69 @@ impl PathStatGetter<io::Error> for Arc<PosixFS> {
 /// A context for filesystem operations parameterized on an error type 'E'.
 ///
 pub trait VFS<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
-  fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, E>;
+  ///
+  /// The target of the given link exactly as it was written: either relative to the link's parent
+  /// directory, or absolute.
+  ///
+  fn read_link_target(&self, link: &Link) -> BoxFuture<PathBuf, E>;
+
+  ///
+  /// The target of the given link as it was written, relative to the link's parent directory.
+  /// Links with absolute targets, or whose targets use `..` to escape the root, fail, because they
+  /// point outside of the root.
+  ///
+  fn read_link_target_within_root(&self, link: &Link) -> BoxFuture<PathBuf, E> {
+    let link = link.clone();
+    self
+      .read_link_target(&link)
+      .and_then(move |target| {
+        if target.is_absolute() {
+          return Err(Self::mk_error(&format!("Absolute symlink: {:?}", link.0)));
+        }
+        let parent = link
+          .0
+          .parent()
+          .ok_or_else(|| Self::mk_error(&format!("Symlink without a parent?: {:?}", link.0)))?;
+        let mut depth = parent
+          .components()
+          .filter(|component| *component != Component::CurDir)
+          .count();
+        for component in target.components() {
+          match component {
+            Component::ParentDir if depth == 0 => {
+              return Err(Self::mk_error(&format!(
+                "Symlink {:?} has target {:?}, which is outside of the root",
+                link.0, target
+              )))
+            }
+            Component::ParentDir => depth -= 1,
+            Component::CurDir => {}
+            _ => depth += 1,
+          }
+        }
+        Ok(target)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// The destination of the given link relative to the root. Links whose targets point outside of
+  /// the root can't be resolved: see read_link_target_within_root.
+  ///
+  fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, E> {
+    let link = link.clone();
+    self
+      .read_link_target_within_root(&link)
+      .map(move |target| {
+        link
+          .0
+          .parent()
+          .expect("Symlinks within the root have a parent")
+          .join(target)
+      })
+      .to_boxed()
+  }
+
   fn scandir(&self, dir: Dir) -> BoxFuture<Arc<DirectoryListing>, E>;
   fn is_ignored(&self, stat: &Stat) -> bool;
   fn mk_error(msg: &str) -> E;
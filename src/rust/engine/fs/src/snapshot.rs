@@ -9,11 +9,14 @@ use hashing::{Digest, Fingerprint};
 use indexmap::{self, IndexMap};
 use itertools::Itertools;
 use protobuf;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
-use {File, PathStat, PosixFS, Store};
+use {
+  Dir, DirectoryListing, File, GlobMatching, Link, PathGlobs, PathStat, PosixFS, Stat, Store,
+  SymlinkBehavior, VFS,
+};
 
 pub const EMPTY_FINGERPRINT: Fingerprint = Fingerprint([
   0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
@@ -75,6 +78,7 @@ impl Snapshot {
       Vec::new();
     let mut dir_futures: Vec<BoxFuture<bazel_protos::remote_execution::DirectoryNode, String>> =
       Vec::new();
+    let mut symlinks: Vec<bazel_protos::remote_execution::SymlinkNode> = Vec::new();
 
     for (first_component, group) in &path_stats
       .iter()
@@ -83,8 +87,8 @@ impl Snapshot {
     {
       let mut path_group: Vec<PathStat> = group.collect();
       if path_group.len() == 1 && path_group[0].path().components().count() == 1 {
-        // Exactly one entry with exactly one component indicates either a file in this directory,
-        // or an empty directory.
+        // Exactly one entry with exactly one component indicates either a file or symlink in this
+        // directory, or an empty directory.
         // If the child is a non-empty directory, or a file therein, there must be multiple
         // PathStats with that prefix component, and we will handle that in the recursive
         // save_directory call.
@@ -121,6 +125,12 @@ impl Snapshot {
                 .to_boxed(),
             );
           }
+          PathStat::Link { target, .. } => {
+            let mut symlink_node = bazel_protos::remote_execution::SymlinkNode::new();
+            symlink_node.set_name(try_future!(osstring_as_utf8(first_component)));
+            symlink_node.set_target(try_future!(osstring_as_utf8(target.into_os_string())));
+            symlinks.push(symlink_node);
+          }
         }
       } else {
         dir_futures.push(
@@ -145,6 +155,7 @@ impl Snapshot {
         let mut directory = bazel_protos::remote_execution::Directory::new();
         directory.set_directories(protobuf::RepeatedField::from_vec(dirs));
         directory.set_files(protobuf::RepeatedField::from_vec(files));
+        directory.set_symlinks(protobuf::RepeatedField::from_vec(symlinks));
         store.record_directory(&directory, true)
       })
       .to_boxed()
@@ -198,88 +209,132 @@ impl Snapshot {
   /// If a file is present with the same name, but different contents, an error will be returned.
   ///
   pub fn merge_directories(store: Store, dir_digests: Vec<Digest>) -> BoxFuture<Digest, String> {
+    Self::merge_directories_with_policy(store, dir_digests, MergePolicy::IdenticalOnly)
+  }
+
+  ///
+  /// Given Digest(s) representing Directory instances, merge them recursively into a single
+  /// output Directory Digest, resolving files which are present in more than one of them according
+  /// to the given MergePolicy.
+  ///
+  pub fn merge_directories_with_policy(
+    store: Store,
+    dir_digests: Vec<Digest>,
+    policy: MergePolicy,
+  ) -> BoxFuture<Digest, String> {
+    Self::merge_directories_helper(
+      store,
+      dir_digests.into_iter().enumerate().collect(),
+      policy,
+      PathBuf::new(),
+    )
+  }
+
+  ///
+  /// Each Digest is paired with the index of the input it came from, and the path is that of the
+  /// Directories being merged, so that collisions can be described in terms of the original inputs.
+  ///
+  fn merge_directories_helper(
+    store: Store,
+    dir_digests: Vec<(usize, Digest)>,
+    policy: MergePolicy,
+    path: PathBuf,
+  ) -> BoxFuture<Digest, String> {
     if dir_digests.is_empty() {
       return future::ok(EMPTY_DIGEST).to_boxed();
     } else if dir_digests.len() == 1 {
       let mut dir_digests = dir_digests;
-      return future::ok(dir_digests.pop().unwrap()).to_boxed();
+      return future::ok(dir_digests.pop().unwrap().1).to_boxed();
     }
 
     let directories = dir_digests
       .into_iter()
-      .map(|digest| {
+      .map(|(index, digest)| {
         store
           .load_directory(digest)
           .and_then(move |maybe_directory| {
             maybe_directory
+              .map(|directory| (index, directory))
               .ok_or_else(|| format!("Digest {:?} did not exist in the Store.", digest))
           })
       })
       .collect::<Vec<_>>();
     join_all(directories)
       .and_then(move |mut directories| {
-        let mut out_dir = bazel_protos::remote_execution::Directory::new();
+        // A name may be a file, a symlink or a directory, and may be of different kinds in
+        // different inputs, so the nodes of every kind are grouped by name together. Each input
+        // contains a name at most once, and sorting is stable, so same-named nodes remain in input
+        // order.
+        let mut nodes = Itertools::flatten(directories.iter_mut().map(|entry| {
+          let index = entry.0;
+          let directory = &mut entry.1;
+          directory
+            .take_files()
+            .into_iter()
+            .map(Node::File)
+            .chain(directory.take_symlinks().into_iter().map(Node::Symlink))
+            .chain(directory.take_directories().into_iter().map(Node::Directory))
+            .map(move |node| (index, node))
+            .collect::<Vec<_>>()
+        })).collect::<Vec<_>>();
+        nodes.sort_by(|a, b| a.1.name().cmp(b.1.name()));
 
-        // Merge FileNodes.
-        let file_nodes = Itertools::flatten(
-          directories
-            .iter_mut()
-            .map(|directory| directory.take_files().into_iter()),
-        ).sorted_by(|a, b| a.name.cmp(&b.name));
-
-        out_dir.set_files(protobuf::RepeatedField::from_vec(
-          file_nodes.into_iter().dedup().collect(),
-        ));
-        let unique_count = out_dir
-          .get_files()
-          .iter()
-          .map(|v| v.get_name())
-          .dedup()
-          .count();
-        if unique_count != out_dir.get_files().len() {
-          let groups = out_dir
-            .get_files()
-            .iter()
-            .group_by(|f| f.get_name().to_owned());
-          for (file_name, group) in &groups {
-            if group.count() > 1 {
-              return future::err(format!(
-                "Can only merge Directories with no duplicates, but found duplicate files: {}",
-                file_name
-              )).to_boxed();
+        let mut out_dir = bazel_protos::remote_execution::Directory::new();
+        let mut child_directory_groups = vec![];
+        let groups = nodes
+          .into_iter()
+          .group_by(|&(_, ref node)| node.name().to_owned());
+        for (name, group) in &groups {
+          let group = group.collect::<Vec<_>>();
+          // Same-named directories are merged rather than resolved by the policy, unless they
+          // collide with a file or symlink.
+          let all_directories = group.iter().all(|&(_, ref node)| match *node {
+            Node::Directory(_) => true,
+            _ => false,
+          });
+          if !all_directories {
+            match try_future!(policy.resolve(&path.join(&name), group.clone())) {
+              Node::File(file_node) => {
+                out_dir.mut_files().push(file_node);
+                continue;
+              }
+              Node::Symlink(symlink_node) => {
+                out_dir.mut_symlinks().push(symlink_node);
+                continue;
+              }
+              // A directory won, so is merged with any others, ignoring the files and symlinks.
+              Node::Directory(_) => {}
             }
           }
+          let digests = group
+            .into_iter()
+            .filter_map(|(index, node)| match node {
+              Node::Directory(directory_node) => Some((index, directory_node)),
+              _ => None,
+            })
+            .map(|(index, directory_node)| {
+              let digest: Result<Digest, String> = directory_node.get_digest().into();
+              digest.map(|digest| (index, digest))
+            })
+            .collect::<Result<Vec<_>, String>>();
+          child_directory_groups.push((name, try_future!(digests)));
         }
 
-        // Group and recurse for DirectoryNodes.
-        let sorted_child_directories = {
-          let mut merged_directories = Itertools::flatten(
-            directories
-              .iter_mut()
-              .map(|directory| directory.take_directories().into_iter()),
-          ).collect::<Vec<_>>();
-          merged_directories.sort_by(|a, b| a.name.cmp(&b.name));
-          merged_directories
-        };
+        // Recurse for DirectoryNodes.
         let store2 = store.clone();
         join_all(
-          sorted_child_directories
-            .into_iter()
-            .group_by(|d| d.name.clone())
+          child_directory_groups
             .into_iter()
-            .map(move |(child_name, group)| {
-              let store2 = store2.clone();
-              let digests_result = group
-                .map(|d| d.get_digest().into())
-                .collect::<Result<Vec<_>, String>>();
-              future::done(digests_result)
-                .and_then(move |digests| Self::merge_directories(store2.clone(), digests))
-                .map(move |merged_digest| {
+            .map(move |(child_name, digests)| {
+              let child_path = path.join(&child_name);
+              Self::merge_directories_helper(store2.clone(), digests, policy, child_path).map(
+                move |merged_digest| {
                   let mut child_dir = bazel_protos::remote_execution::DirectoryNode::new();
                   child_dir.set_name(child_name);
                   child_dir.set_digest((&merged_digest).into());
                   child_dir
-                })
+                },
+              )
             })
             .collect::<Vec<_>>(),
         ).and_then(move |child_directories| {
@@ -290,6 +345,233 @@ impl Snapshot {
       })
       .to_boxed()
   }
+
+  ///
+  /// Returns a Digest for a Directory which contains the given Directory at the given relative
+  /// path.
+  ///
+  pub fn add_prefix(store: Store, digest: Digest, prefix: PathBuf) -> BoxFuture<Digest, String> {
+    let names = try_future!(prefix_names(&prefix));
+    let mut result: BoxFuture<Digest, String> = future::ok(digest).to_boxed();
+    for name in names.into_iter().rev() {
+      let store = store.clone();
+      result = result
+        .and_then(move |digest| {
+          let mut directory = bazel_protos::remote_execution::Directory::new();
+          directory.mut_directories().push({
+            let mut directory_node = bazel_protos::remote_execution::DirectoryNode::new();
+            directory_node.set_name(name);
+            directory_node.set_digest((&digest).into());
+            directory_node
+          });
+          store.record_directory(&directory, true)
+        })
+        .to_boxed();
+    }
+    result
+  }
+
+  ///
+  /// Returns a Digest for the Directory at the given relative path within the given Directory.
+  ///
+  /// Fails if the given Directory contains anything outside of the prefix.
+  ///
+  pub fn strip_prefix(store: Store, digest: Digest, prefix: PathBuf) -> BoxFuture<Digest, String> {
+    let names = try_future!(prefix_names(&prefix));
+    let mut result: BoxFuture<Digest, String> = future::ok(digest).to_boxed();
+    for name in names {
+      let store = store.clone();
+      let prefix = prefix.clone();
+      result = result
+        .and_then(move |digest| {
+          // An empty Directory contains nothing outside of the prefix, and nothing inside it. It
+          // need not have been stored, so is not loaded.
+          if digest == EMPTY_DIGEST {
+            return future::ok(EMPTY_DIGEST).to_boxed();
+          }
+          store.load_directory(digest).and_then(move |maybe_directory| {
+            let directory = maybe_directory
+              .ok_or_else(|| format!("Digest {:?} did not exist in the Store.", digest))?;
+            let outside_prefix = directory
+              .get_files()
+              .iter()
+              .map(|file_node| file_node.get_name())
+              .chain(
+                directory
+                  .get_directories()
+                  .iter()
+                  .map(|directory_node| directory_node.get_name())
+                  .filter(|directory_name| *directory_name != name),
+              )
+              .chain(
+                directory
+                  .get_symlinks()
+                  .iter()
+                  .map(|symlink_node| symlink_node.get_name()),
+              )
+              .collect::<Vec<_>>();
+            if !outside_prefix.is_empty() {
+              return Err(format!(
+                "Cannot strip prefix {:?} from a Directory which contains paths outside of it: \
+                 {} (in Directory {:?})",
+                prefix,
+                outside_prefix.join(", "),
+                digest
+              ));
+            }
+            let child_digest: Result<Digest, String> = directory
+              .get_directories()
+              .iter()
+              .next()
+              .map(|directory_node| directory_node.get_digest().into())
+              .unwrap_or(Ok(EMPTY_DIGEST));
+            child_digest
+          }).to_boxed()
+        })
+        .to_boxed();
+    }
+    result
+  }
+
+  ///
+  /// Returns a Digest for a Directory which contains only the paths of the given Directory which
+  /// match the given PathGlobs.
+  ///
+  pub fn subset(store: Store, digest: Digest, path_globs: PathGlobs) -> BoxFuture<Digest, String> {
+    let digest_vfs = DigestVFS {
+      store: store.clone(),
+      root: digest,
+    };
+    let file_digester = digest_vfs.clone();
+    // Symlinks are kept as symlinks, rather than being replaced by what they point to.
+    digest_vfs
+      .expand(path_globs.with_symlink_behavior(SymlinkBehavior::Aware))
+      .and_then(move |path_stats| {
+        Snapshot::digest_from_path_stats(store, &file_digester, &path_stats)
+      })
+      .to_boxed()
+  }
+}
+
+///
+/// How `Snapshot::merge_directories_with_policy` treats a file path which is present in more than
+/// one of the Directories being merged.
+///
+/// Directories which are present in more than one input are merged, but a file or symlink which
+/// collides with anything (including a directory) is resolved by the policy. A directory which
+/// wins under LastWins or FirstWins is merged with any other directories at the same path.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MergePolicy {
+  /// Fail if any file is present in more than one input, even with identical contents.
+  Error,
+  /// Fail if any file is present in more than one input with different contents (or kinds).
+  IdenticalOnly,
+  /// Use the file from the last input in which it is present.
+  LastWins,
+  /// Use the file from the first input in which it is present.
+  FirstWins,
+}
+
+impl MergePolicy {
+  pub fn create(policy: &str) -> Result<MergePolicy, String> {
+    match policy {
+      "error" => Ok(MergePolicy::Error),
+      "identical_only" => Ok(MergePolicy::IdenticalOnly),
+      "last_wins" => Ok(MergePolicy::LastWins),
+      "first_wins" => Ok(MergePolicy::FirstWins),
+      _ => Err(format!("Unrecognized merge policy: {}.", policy)),
+    }
+  }
+
+  ///
+  /// Picks one of the (input index, node) pairs which were all found at the given path, which are
+  /// in input order. Nodes of different kinds are never identical, but directories do not collide
+  /// with one another, because they are merged.
+  ///
+  fn resolve(self, path: &Path, mut nodes: Vec<(usize, Node)>) -> Result<Node, String> {
+    if nodes.len() == 1 {
+      return Ok(nodes.pop().unwrap().1);
+    }
+    let collision = match self {
+      MergePolicy::Error | MergePolicy::IdenticalOnly => nodes
+        .iter()
+        .skip(1)
+        .position(|&(_, ref node)| match (node, &nodes[0].1) {
+          (&Node::Directory(_), &Node::Directory(_)) => false,
+          (node, first) => self == MergePolicy::Error || node != first,
+        })
+        .map(|position| position + 1),
+      MergePolicy::LastWins => return Ok(nodes.pop().unwrap().1),
+      MergePolicy::FirstWins => return Ok(nodes.swap_remove(0).1),
+    };
+    match collision {
+      Some(other) => Err(format!(
+        "Can only merge Directories with no duplicates, but found duplicate {} at {:?}: {} \
+         (from input {}) and {} (from input {})",
+        if nodes[0].1.kind() == nodes[other].1.kind() {
+          format!("{}s", nodes[0].1.kind())
+        } else {
+          format!("{} and {}", nodes[0].1.kind(), nodes[other].1.kind())
+        },
+        path,
+        nodes[0].1.describe(),
+        nodes[0].0,
+        nodes[other].1.describe(),
+        nodes[other].0,
+      )),
+      None => Ok(nodes.swap_remove(0).1),
+    }
+  }
+}
+
+///
+/// An entry of a Directory which is being merged.
+///
+#[derive(Clone, PartialEq)]
+enum Node {
+  File(bazel_protos::remote_execution::FileNode),
+  Symlink(bazel_protos::remote_execution::SymlinkNode),
+  Directory(bazel_protos::remote_execution::DirectoryNode),
+}
+
+impl Node {
+  fn name(&self) -> &str {
+    match *self {
+      Node::File(ref file_node) => file_node.get_name(),
+      Node::Symlink(ref symlink_node) => symlink_node.get_name(),
+      Node::Directory(ref directory_node) => directory_node.get_name(),
+    }
+  }
+
+  fn kind(&self) -> &'static str {
+    match *self {
+      Node::File(_) => "file",
+      Node::Symlink(_) => "symlink",
+      Node::Directory(_) => "directory",
+    }
+  }
+
+  fn describe(&self) -> String {
+    match *self {
+      Node::File(ref file_node) => format!(
+        "file {}/{}{}",
+        file_node.get_digest().get_hash(),
+        file_node.get_digest().get_size_bytes(),
+        if file_node.get_is_executable() {
+          " (executable)"
+        } else {
+          ""
+        },
+      ),
+      Node::Symlink(ref symlink_node) => format!("symlink to {:?}", symlink_node.get_target()),
+      Node::Directory(ref directory_node) => format!(
+        "directory {}/{}",
+        directory_node.get_digest().get_hash(),
+        directory_node.get_digest().get_size_bytes(),
+      ),
+    }
+  }
 }
 
 impl fmt::Debug for Snapshot {
@@ -319,11 +601,37 @@ fn paths_of_child_dir(paths: Vec<PathStat>) -> Vec<PathStat> {
           path: path.iter().skip(1).collect(),
           stat: stat,
         },
+        PathStat::Link { path, stat, target } => PathStat::Link {
+          path: path.iter().skip(1).collect(),
+          stat: stat,
+          target: target,
+        },
       })
     })
     .collect()
 }
 
+///
+/// The names of the directories which make up a prefix, which must be a relative path without
+/// `..`.
+///
+fn prefix_names(prefix: &Path) -> Result<Vec<String>, String> {
+  let mut names = Vec::new();
+  for component in prefix.components() {
+    match component {
+      Component::Normal(name) => names.push(osstring_as_utf8(name.to_owned())?),
+      Component::CurDir => (),
+      _ => {
+        return Err(format!(
+          "Prefix must be a relative path without `..`, but got: {:?}",
+          prefix
+        ))
+      }
+    }
+  }
+  Ok(names)
+}
+
 fn osstring_as_utf8(path: OsString) -> Result<String, String> {
   path
     .into_string()
@@ -355,12 +663,151 @@ impl OneOffStoreFileByDigest {
 
 impl StoreFileByDigest<String> for OneOffStoreFileByDigest {
   fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
-    let store = self.store.clone();
     self
-      .posix_fs
-      .read_file(&file)
-      .map_err(move |err| format!("Error reading file {:?}: {:?}", file, err))
-      .and_then(move |content| store.store_file_bytes(content.content, true))
+      .store
+      .store_file_from_path(self.posix_fs.file_path(&file), true)
+      .map_err(move |err| format!("Error storing file {:?}: {}", file, err))
+      .to_boxed()
+  }
+}
+
+///
+/// A read-only VFS over a Directory in the Store, which allows globs to be matched against it (and
+/// its files to be "stored") without materializing it.
+///
+#[derive(Clone)]
+struct DigestVFS {
+  store: Store,
+  root: Digest,
+}
+
+impl DigestVFS {
+  fn load_directory_at(
+    &self,
+    path: &Path,
+  ) -> BoxFuture<bazel_protos::remote_execution::Directory, String> {
+    let mut names = try_future!(prefix_names(path));
+    names.reverse();
+    DigestVFS::load_directory_at_helper(self.store.clone(), self.root, names)
+  }
+
+  // Takes the remaining names in reverse order, so that they can be popped.
+  fn load_directory_at_helper(
+    store: Store,
+    digest: Digest,
+    mut remaining_names: Vec<String>,
+  ) -> BoxFuture<bazel_protos::remote_execution::Directory, String> {
+    store
+      .load_directory(digest)
+      .and_then(move |maybe_directory| {
+        let directory = try_future!(
+          maybe_directory.ok_or_else(|| format!("Digest {:?} did not exist in the Store.", digest))
+        );
+        let name = match remaining_names.pop() {
+          Some(name) => name,
+          None => return future::ok(directory).to_boxed(),
+        };
+        let child_digest: Option<Result<Digest, String>> = directory
+          .get_directories()
+          .iter()
+          .find(|directory_node| directory_node.get_name() == name)
+          .map(|directory_node| directory_node.get_digest().into());
+        match child_digest {
+          Some(child_digest) => DigestVFS::load_directory_at_helper(
+            store,
+            try_future!(child_digest),
+            remaining_names,
+          ),
+          None => future::err(format!(
+            "No directory named {} in Directory {:?}",
+            name, digest
+          )).to_boxed(),
+        }
+      })
+      .to_boxed()
+  }
+}
+
+impl VFS<String> for DigestVFS {
+  fn read_link_target(&self, link: &Link) -> BoxFuture<PathBuf, String> {
+    let parent = link
+      .0
+      .parent()
+      .map(|parent| parent.to_owned())
+      .unwrap_or_else(PathBuf::new);
+    let link = link.clone();
+    self
+      .load_directory_at(&parent)
+      .and_then(move |directory| {
+        directory
+          .get_symlinks()
+          .iter()
+          .find(|symlink_node| link.0.file_name() == Some(OsStr::new(symlink_node.get_name())))
+          .map(|symlink_node| PathBuf::from(symlink_node.get_target()))
+          .ok_or_else(|| format!("No symlink {:?} in Directory", link.0))
+      })
+      .to_boxed()
+  }
+
+  fn scandir(&self, dir: Dir) -> BoxFuture<Arc<DirectoryListing>, String> {
+    self
+      .load_directory_at(&dir.0)
+      .map(move |directory| {
+        let mut stats = directory
+          .get_files()
+          .iter()
+          .map(|file_node| {
+            Stat::File(File {
+              path: dir.0.join(file_node.get_name()),
+              is_executable: file_node.get_is_executable(),
+            })
+          })
+          .chain(
+            directory
+              .get_directories()
+              .iter()
+              .map(|directory_node| Stat::Dir(Dir(dir.0.join(directory_node.get_name())))),
+          )
+          .chain(
+            directory
+              .get_symlinks()
+              .iter()
+              .map(|symlink_node| Stat::Link(Link(dir.0.join(symlink_node.get_name())))),
+          )
+          .collect::<Vec<_>>();
+        stats.sort_by(|s1, s2| s1.path().cmp(s2.path()));
+        Arc::new(DirectoryListing(stats))
+      })
+      .to_boxed()
+  }
+
+  fn is_ignored(&self, _stat: &Stat) -> bool {
+    false
+  }
+
+  fn mk_error(msg: &str) -> String {
+    msg.to_owned()
+  }
+}
+
+impl StoreFileByDigest<String> for DigestVFS {
+  fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
+    let parent = file
+      .path
+      .parent()
+      .map(|parent| parent.to_owned())
+      .unwrap_or_else(PathBuf::new);
+    self
+      .load_directory_at(&parent)
+      .and_then(move |directory| {
+        let file_digest: Option<Result<Digest, String>> = directory
+          .get_files()
+          .iter()
+          .find(|file_node| file.path.file_name() == Some(OsStr::new(file_node.get_name())))
+          .map(|file_node| file_node.get_digest().into());
+        file_digest
+          .unwrap_or_else(|| Err(format!("No file {:?} in Directory", file.path)))
+      })
       .to_boxed()
   }
 }
@@ -370,16 +817,18 @@ mod tests {
   extern crate tempfile;
   use futures::future::Future;
   use hashing::{Digest, Fingerprint};
-  use testutil::data::TestDirectory;
+  use testutil::data::{TestData, TestDirectory};
   use testutil::make_file;
 
   use super::super::{
-    Dir, File, GlobMatching, Path, PathGlobs, PathStat, PosixFS, ResettablePool, Snapshot, Store,
-    StrictGlobMatching,
+    Dir, File, GlobMatching, Link, Path, PathGlobs, PathStat, PosixFS, ResettablePool, Snapshot,
+    Store, StrictGlobMatching, SymlinkBehavior,
   };
-  use super::OneOffStoreFileByDigest;
+  use super::{MergePolicy, OneOffStoreFileByDigest, EMPTY_DIGEST};
+  use bazel_protos;
 
   use std;
+  use std::os::unix::fs::symlink;
   use std::path::PathBuf;
   use std::sync::Arc;
 
@@ -488,6 +937,157 @@ mod tests {
     );
   }
 
+  #[test]
+  fn snapshot_symlinks() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    symlink("roland", dir.path().join("european_burmese")).unwrap();
+    // Dangling symlinks are captured too.
+    symlink("cats", dir.path().join("felines")).unwrap();
+
+    let path_stats = posix_fs
+      .expand(
+        PathGlobs::create(&["*".to_owned()], &[], StrictGlobMatching::Ignore)
+          .unwrap()
+          .with_symlink_behavior(SymlinkBehavior::Aware),
+      )
+      .wait()
+      .unwrap();
+    assert!(path_stats.contains(&PathStat::link(
+      PathBuf::from("felines"),
+      Link(PathBuf::from("felines")),
+      PathBuf::from("cats"),
+    )));
+
+    assert_eq!(
+      Snapshot::from_path_stats(store, &digester, path_stats)
+        .wait()
+        .unwrap()
+        .digest,
+      TestDirectory::containing_roland_and_symlinks().digest()
+    );
+  }
+
+  #[test]
+  fn snapshot_symlinks_within_root() {
+    let (_, dir, posix_fs, _) = setup();
+
+    std::fs::create_dir_all(dir.path().join("cats")).unwrap();
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    symlink("../roland", dir.path().join("cats").join("roland")).unwrap();
+    symlink("cats/../roland", dir.path().join("european_burmese")).unwrap();
+
+    let path_stats = posix_fs
+      .expand(
+        PathGlobs::create(&["**".to_owned()], &[], StrictGlobMatching::Ignore)
+          .unwrap()
+          .with_symlink_behavior(SymlinkBehavior::Aware),
+      )
+      .wait()
+      .unwrap();
+    for (path, target) in &[
+      ("cats/roland", "../roland"),
+      ("european_burmese", "cats/../roland"),
+    ] {
+      assert!(
+        path_stats.contains(&PathStat::link(
+          PathBuf::from(path),
+          Link(PathBuf::from(path)),
+          PathBuf::from(target),
+        )),
+        "Want {} to be captured with target {} in {:?}",
+        path,
+        target,
+        path_stats
+      );
+    }
+  }
+
+  #[test]
+  fn snapshot_symlinks_outside_root() {
+    for (path, target) in &[
+      // e.g. a virtualenv's python, which points at the system's.
+      ("python", "/usr/bin/python3"),
+      ("escape", "../outside"),
+      ("cats/escape", "../dogs/../../outside"),
+    ] {
+      let (_, dir, posix_fs, _) = setup();
+      std::fs::create_dir_all(dir.path().join("cats")).unwrap();
+      symlink(target, dir.path().join(path)).unwrap();
+
+      let err = posix_fs
+        .expand(
+          PathGlobs::create(&["**".to_owned()], &[], StrictGlobMatching::Ignore)
+            .unwrap()
+            .with_symlink_behavior(SymlinkBehavior::Aware),
+        )
+        .wait()
+        .expect_err(&format!("Want error capturing {} -> {}", path, target));
+      assert!(
+        format!("{:?}", err).contains(path),
+        "Want error to name {} but was: {:?}",
+        path,
+        err
+      );
+    }
+  }
+
+  #[test]
+  fn snapshot_resolves_symlinks_by_default() {
+    let (_, dir, posix_fs, _) = setup();
+
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    symlink("roland", dir.path().join("european_burmese")).unwrap();
+    symlink("cats", dir.path().join("felines")).unwrap();
+
+    let european_burmese = PathBuf::from("european_burmese");
+    assert_eq!(
+      expand_all_sorted(posix_fs),
+      vec![
+        PathStat::file(
+          european_burmese.clone(),
+          File {
+            path: PathBuf::from("roland"),
+            is_executable: false,
+          },
+        ),
+        PathStat::file(
+          PathBuf::from("roland"),
+          File {
+            path: PathBuf::from("roland"),
+            is_executable: false,
+          },
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn merge_directories_symlinks() {
+    let (store, _, _, _) = setup();
+
+    let containing_roland = TestDirectory::containing_roland();
+    let containing_roland_and_symlinks = TestDirectory::containing_roland_and_symlinks();
+
+    for directory in &[&containing_roland, &containing_roland_and_symlinks] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Storing directory");
+    }
+
+    let result = Snapshot::merge_directories(
+      store,
+      vec![
+        containing_roland.digest(),
+        containing_roland_and_symlinks.digest(),
+      ],
+    ).wait();
+
+    assert_eq!(result, Ok(containing_roland_and_symlinks.digest()));
+  }
+
   #[test]
   fn merge_directories_two_files() {
     let (store, _, _, _) = setup();
@@ -574,6 +1174,338 @@ mod tests {
     );
   }
 
+  #[test]
+  fn merge_directories_clashing_files_names_inputs() {
+    let (store, _, _, _) = setup();
+
+    let containing_treats = TestDirectory::containing_treats();
+    let containing_roland = TestDirectory::containing_roland();
+    let containing_wrong_roland = TestDirectory::containing_wrong_roland();
+
+    for directory in &[
+      &containing_treats,
+      &containing_roland,
+      &containing_wrong_roland,
+    ] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Storing directory");
+    }
+
+    let err = Snapshot::merge_directories(
+      store,
+      vec![
+        containing_treats.digest(),
+        containing_roland.digest(),
+        containing_wrong_roland.digest(),
+      ],
+    ).wait()
+      .expect_err("Want error merging");
+
+    let roland = TestData::roland().digest();
+    let catnip = TestData::catnip().digest();
+    for expected in &[
+      format!("{}/{} (from input 1)", roland.0, roland.1),
+      format!("{}/{} (from input 2)", catnip.0, catnip.1),
+    ] {
+      assert!(
+        err.contains(expected),
+        "Want error message to contain {} but was: {}",
+        expected,
+        err
+      );
+    }
+  }
+
+  #[test]
+  fn merge_directories_policies() {
+    let (store, _, _, _) = setup();
+
+    let containing_roland = TestDirectory::containing_roland();
+    let containing_wrong_roland = TestDirectory::containing_wrong_roland();
+    let containing_roland_and_treats = TestDirectory::containing_roland_and_treats();
+
+    for directory in &[
+      &containing_roland,
+      &containing_wrong_roland,
+      &containing_roland_and_treats,
+    ] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Storing directory");
+    }
+
+    let merge = |digests: Vec<Digest>, policy: MergePolicy| {
+      Snapshot::merge_directories_with_policy(store.clone(), digests, policy).wait()
+    };
+
+    let clashing = vec![containing_roland.digest(), containing_wrong_roland.digest()];
+    assert_eq!(
+      merge(clashing.clone(), MergePolicy::FirstWins),
+      Ok(containing_roland.digest())
+    );
+    assert_eq!(
+      merge(clashing.clone(), MergePolicy::LastWins),
+      Ok(containing_wrong_roland.digest())
+    );
+    merge(clashing.clone(), MergePolicy::IdenticalOnly).expect_err("Want error merging");
+    merge(clashing, MergePolicy::Error).expect_err("Want error merging");
+
+    let identical = vec![
+      containing_roland.digest(),
+      containing_roland_and_treats.digest(),
+    ];
+    assert_eq!(
+      merge(identical.clone(), MergePolicy::IdenticalOnly),
+      Ok(containing_roland_and_treats.digest())
+    );
+    let err = merge(identical, MergePolicy::Error).expect_err("Want error merging");
+    assert!(
+      err.contains("roland"),
+      "Want error message to contain roland but was: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn merge_directories_file_and_directory() {
+    let (store, _, _, _) = setup();
+
+    // A file at cats, which is a directory in nested.
+    let mut containing_cats_file = bazel_protos::remote_execution::Directory::new();
+    containing_cats_file.mut_files().push({
+      let mut file_node = bazel_protos::remote_execution::FileNode::new();
+      file_node.set_name("cats".to_owned());
+      file_node.set_digest((&TestData::roland().digest()).into());
+      file_node
+    });
+    let containing_cats_file = store
+      .record_directory(&containing_cats_file, false)
+      .wait()
+      .expect("Storing directory");
+    let nested = TestDirectory::nested();
+    for directory in &[&nested, &TestDirectory::containing_roland()] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Storing directory");
+    }
+
+    let merge = |policy: MergePolicy| {
+      Snapshot::merge_directories_with_policy(
+        store.clone(),
+        vec![containing_cats_file, nested.digest()],
+        policy,
+      ).wait()
+    };
+
+    for policy in &[MergePolicy::Error, MergePolicy::IdenticalOnly] {
+      let err = merge(*policy).expect_err("Want error merging");
+      assert!(
+        err.contains("duplicate file and directory at \"cats\""),
+        "Want error message to name the kinds of cats but was: {}",
+        err
+      );
+    }
+    assert_eq!(merge(MergePolicy::FirstWins), Ok(containing_cats_file));
+    assert_eq!(merge(MergePolicy::LastWins), Ok(nested.digest()));
+  }
+
+  #[test]
+  fn merge_directories_file_and_symlink() {
+    let (store, _, _, _) = setup();
+
+    // A symlink at roland, which is a file in containing_roland.
+    let mut containing_roland_symlink = bazel_protos::remote_execution::Directory::new();
+    containing_roland_symlink.mut_symlinks().push({
+      let mut symlink_node = bazel_protos::remote_execution::SymlinkNode::new();
+      symlink_node.set_name("roland".to_owned());
+      symlink_node.set_target("treats".to_owned());
+      symlink_node
+    });
+    let containing_roland_symlink = store
+      .record_directory(&containing_roland_symlink, false)
+      .wait()
+      .expect("Storing directory");
+    let containing_roland = TestDirectory::containing_roland();
+    store
+      .record_directory(&containing_roland.directory(), false)
+      .wait()
+      .expect("Storing directory");
+
+    let merge = |policy: MergePolicy| {
+      Snapshot::merge_directories_with_policy(
+        store.clone(),
+        vec![containing_roland.digest(), containing_roland_symlink],
+        policy,
+      ).wait()
+    };
+
+    for policy in &[MergePolicy::Error, MergePolicy::IdenticalOnly] {
+      let err = merge(*policy).expect_err("Want error merging");
+      assert!(
+        err.contains("duplicate file and symlink at \"roland\""),
+        "Want error message to name the kinds of roland but was: {}",
+        err
+      );
+      assert!(
+        err.contains("symlink to \"treats\" (from input 1)"),
+        "Want error message to describe the symlink but was: {}",
+        err
+      );
+    }
+    assert_eq!(
+      merge(MergePolicy::FirstWins),
+      Ok(containing_roland.digest())
+    );
+    assert_eq!(merge(MergePolicy::LastWins), Ok(containing_roland_symlink));
+  }
+
+  #[test]
+  fn merge_policy_create() {
+    assert_eq!(MergePolicy::create("last_wins"), Ok(MergePolicy::LastWins));
+    MergePolicy::create("most_wins").expect_err("Want error for unknown policy");
+  }
+
+  #[test]
+  fn add_prefix() {
+    let (store, _, _, _) = setup();
+
+    let result = Snapshot::add_prefix(
+      store.clone(),
+      TestDirectory::containing_roland().digest(),
+      PathBuf::from("cats"),
+    ).wait();
+    assert_eq!(result, Ok(TestDirectory::nested().digest()));
+
+    Snapshot::add_prefix(
+      store,
+      TestDirectory::containing_roland().digest(),
+      PathBuf::from("../cats"),
+    ).wait()
+      .expect_err("Want error for prefix containing ..");
+  }
+
+  #[test]
+  fn strip_prefix() {
+    let (store, _, _, _) = setup();
+
+    for directory in &[
+      TestDirectory::nested(),
+      TestDirectory::recursive(),
+      TestDirectory::containing_roland(),
+    ] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Storing directory");
+    }
+
+    let result = Snapshot::strip_prefix(
+      store.clone(),
+      TestDirectory::nested().digest(),
+      PathBuf::from("cats"),
+    ).wait();
+    assert_eq!(result, Ok(TestDirectory::containing_roland().digest()));
+
+    let err = Snapshot::strip_prefix(
+      store,
+      TestDirectory::recursive().digest(),
+      PathBuf::from("cats"),
+    ).wait()
+      .expect_err("Want error stripping prefix");
+    assert!(
+      err.contains("treats"),
+      "Want error message to contain treats but was: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn strip_prefix_through_empty_directory() {
+    let (store, _, _, _) = setup();
+
+    // The empty Directory itself is not stored.
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_directories().push({
+      let mut directory_node = bazel_protos::remote_execution::DirectoryNode::new();
+      directory_node.set_name("cats".to_owned());
+      directory_node.set_digest((&EMPTY_DIGEST).into());
+      directory_node
+    });
+    let digest = store
+      .record_directory(&directory, false)
+      .wait()
+      .expect("Storing directory");
+
+    for prefix in &["cats", "cats/dogs", "cats/dogs/birds"] {
+      let result = Snapshot::strip_prefix(store.clone(), digest, PathBuf::from(prefix)).wait();
+      assert_eq!(result, Ok(EMPTY_DIGEST), "Stripping {}", prefix);
+    }
+  }
+
+  #[test]
+  fn subset() {
+    let (store, _, _, _) = setup();
+
+    for directory in &[
+      TestDirectory::recursive(),
+      TestDirectory::containing_roland(),
+    ] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Storing directory");
+    }
+
+    let subset = |globs: &[&str]| {
+      Snapshot::subset(
+        store.clone(),
+        TestDirectory::recursive().digest(),
+        PathGlobs::create(
+          &globs.iter().map(|g| g.to_string()).collect::<Vec<_>>(),
+          &[],
+          StrictGlobMatching::Ignore,
+        ).unwrap(),
+      ).wait()
+    };
+
+    assert_eq!(
+      subset(&["cats/**"]),
+      Ok(TestDirectory::nested().digest())
+    );
+    assert_eq!(
+      subset(&["treats"]),
+      Ok(TestDirectory::containing_treats().digest())
+    );
+    assert_eq!(
+      subset(&["**"]),
+      Ok(TestDirectory::recursive().digest())
+    );
+    assert_eq!(subset(&["dogs/*"]), Ok(super::EMPTY_DIGEST));
+  }
+
+  #[test]
+  fn subset_keeps_symlinks() {
+    let (store, _, _, _) = setup();
+
+    let containing_roland_and_symlinks = TestDirectory::containing_roland_and_symlinks();
+    store
+      .record_directory(&containing_roland_and_symlinks.directory(), false)
+      .wait()
+      .expect("Storing directory");
+
+    let result = Snapshot::subset(
+      store,
+      containing_roland_and_symlinks.digest(),
+      PathGlobs::create(&["*".to_owned()], &[], StrictGlobMatching::Ignore).unwrap(),
+    ).wait();
+
+    assert_eq!(result, Ok(containing_roland_and_symlinks.digest()));
+  }
+
   #[test]
   fn snapshot_merge_two_files() {
     let (store, tempdir, _, digester) = setup();
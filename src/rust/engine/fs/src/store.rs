@@ -4,27 +4,65 @@ use bazel_protos;
 use boxfuture::{BoxFuture, Boxable};
 use bytes::Bytes;
 use futures::{future, Future};
-use hashing::Digest;
+use hashing::{Digest, Fingerprint, WriterHasher};
 use protobuf::Message;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::os::unix::fs::OpenOptionsExt;
-use std::path::{Path, PathBuf};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::fs::{symlink, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use archive::{self, ArchiveFormat};
+use bundle;
+use file_cache::FileCache;
+use hot_cache::{HotCache, HotCacheStats};
 use pool::ResettablePool;
 
-// This is the maximum size any particular local LMDB store file is allowed to grow to.
+pub use self::remote::EndpointSelection;
+
+// This is the default size of the map of any particular local LMDB store file when it is opened.
 // It doesn't reflect space allocated on disk, or RAM allocated (it may be reflected in VIRT but
 // not RSS). There is no practical upper bound on this number, so we set it ridiculously high.
-const MAX_LOCAL_STORE_SIZE_BYTES: usize = 1024 * 1024 * 1024 * 1024 / 10;
+pub const MAX_LOCAL_STORE_SIZE_BYTES: usize = 1024 * 1024 * 1024 * 1024 / 10;
+
+// This is the most to which the map of a local LMDB store file may grow by default if it fills up.
+pub const MAX_LOCAL_STORE_MAP_SIZE_BYTES: usize = 1024 * 1024 * 1024 * 1024;
+
+// By default, the shards of each local LMDB store (of files, and of Directories) may grow to at
+// most this size between them, so that a store with many shards can't grow unboundedly.
+const MAX_LOCAL_STORE_TOTAL_MAP_SIZE_BYTES: usize = 4 * 1024 * 1024 * 1024 * 1024;
+
+///
+/// The default size to which each of the given number of shards of a local store may grow: an
+/// equal share of MAX_LOCAL_STORE_TOTAL_MAP_SIZE_BYTES, clamped to at most
+/// MAX_LOCAL_STORE_MAP_SIZE_BYTES, and to at least the shards' initial size.
+///
+pub fn default_max_map_size_bytes(shard_count: usize, map_size_bytes: usize) -> usize {
+  max(
+    min(
+      MAX_LOCAL_STORE_TOTAL_MAP_SIZE_BYTES / max(shard_count, 1),
+      MAX_LOCAL_STORE_MAP_SIZE_BYTES,
+    ),
+    map_size_bytes,
+  )
+}
 
 // This is the target number of bytes which should be present in all combined LMDB store files
 // after garbage collection. We almost certainly want to make this configurable.
 const LOCAL_STORE_GC_TARGET_BYTES: usize = 4 * 1024 * 1024 * 1024;
 
+// Files at least this big are stored in a directory of files next to the LMDB store, rather than
+// in LMDB itself, so that they can be stored and materialized without being held in memory.
+const DEFAULT_LARGE_FILE_THRESHOLD_BYTES: usize = 16 * 1024 * 1024;
+
+// The default bound on the memory used to cache recently loaded Directories and small files, and
+// the size of the largest file which is cached.
+const DEFAULT_HOT_CACHE_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_HOT_CACHE_MAX_FILE_BYTES: usize = 64 * 1024;
+
 ///
 /// A content-addressed store of file contents, and Directories.
 ///
@@ -41,6 +79,8 @@ const LOCAL_STORE_GC_TARGET_BYTES: usize = 4 * 1024 * 1024 * 1024;
 pub struct Store {
   local: local::ByteStore,
   remote: Option<remote::ByteStore>,
+  file_cache: Option<FileCache>,
+  hot_cache: Arc<HotCache>,
 }
 
 // Note that Store doesn't implement ByteStore because it operates at a higher level of abstraction,
@@ -55,6 +95,11 @@ impl Store {
     Ok(Store {
       local: local::ByteStore::new(path, pool)?,
       remote: None,
+      file_cache: None,
+      hot_cache: Arc::new(HotCache::new(
+        DEFAULT_HOT_CACHE_BYTES,
+        DEFAULT_HOT_CACHE_MAX_FILE_BYTES,
+      )),
     })
   }
 
@@ -62,10 +107,14 @@ impl Store {
   /// Make a store which uses local storage, and if it is missing a value which it tries to load,
   /// will attempt to back-fill its local storage from a remote CAS.
   ///
+  /// The remote CAS may be served by several addresses (e.g. replicas), which requests are spread
+  /// across according to endpoint_selection, and which fail over to each other.
+  ///
   pub fn with_remote<P: AsRef<Path>>(
     path: P,
     pool: Arc<ResettablePool>,
-    cas_address: String,
+    cas_addresses: Vec<String>,
+    endpoint_selection: EndpointSelection,
     thread_count: usize,
     chunk_size_bytes: usize,
     timeout: Duration,
@@ -73,14 +122,151 @@ impl Store {
     Ok(Store {
       local: local::ByteStore::new(path, pool)?,
       remote: Some(remote::ByteStore::new(
-        cas_address,
+        cas_addresses,
+        endpoint_selection,
         thread_count,
         chunk_size_bytes,
         timeout,
+      )?),
+      file_cache: None,
+      hot_cache: Arc::new(HotCache::new(
+        DEFAULT_HOT_CACHE_BYTES,
+        DEFAULT_HOT_CACHE_MAX_FILE_BYTES,
       )),
     })
   }
 
+  ///
+  /// Make materialize_directory link files into place from the given cache of read-only files
+  /// (which should be on the same filesystem as the directories being materialized), rather than
+  /// writing out their contents each time. See FileCache for how files are linked.
+  ///
+  /// Note that hardlinked files share their contents with the cache, so are not writable, and
+  /// that the cache is not garbage collected along with this store.
+  ///
+  pub fn with_file_cache(self, file_cache: FileCache) -> Store {
+    Store {
+      file_cache: Some(file_cache),
+      ..self
+    }
+  }
+
+  ///
+  /// Store blobs of at least the given size zstd-compressed in the local store, where doing so
+  /// makes them smaller. Compression is transparent to readers.
+  ///
+  pub fn with_compression(self, threshold_bytes: usize) -> Store {
+    Store {
+      local: self.local.with_compression(threshold_bytes),
+      ..self
+    }
+  }
+
+  ///
+  /// Store files of at least the given size in a directory of files next to the local store,
+  /// rather than in LMDB. Such files can be stored and materialized without being held in memory.
+  ///
+  pub fn with_large_file_threshold(self, threshold_bytes: usize) -> Store {
+    Store {
+      local: self.local.with_large_file_threshold(threshold_bytes),
+      ..self
+    }
+  }
+
+  ///
+  /// Shard the local store's LMDB databases across the given number of environments (a power of
+  /// two, no greater than 16), each of whose maps starts at map_size_bytes. An existing store must
+  /// be opened with the number of shards it was created with.
+  ///
+  /// If a shard fills up, its map is grown, up to max_map_size_bytes (by default, see
+  /// default_max_map_size_bytes). If it is full at that size, everything in it which is neither
+  /// leased nor pinned is garbage collected to make space.
+  ///
+  pub fn with_lmdb_config(
+    self,
+    shard_count: usize,
+    map_size_bytes: usize,
+    max_map_size_bytes: Option<usize>,
+  ) -> Result<Store, String> {
+    let max_map_size_bytes = max_map_size_bytes
+      .unwrap_or_else(|| default_max_map_size_bytes(shard_count, map_size_bytes));
+    let config = local::LmdbConfig::new(shard_count, map_size_bytes, max_map_size_bytes)?;
+    Ok(Store {
+      local: self.local.with_lmdb_config(config),
+      ..self
+    })
+  }
+
+  ///
+  /// Ask the remote store (if any) what it supports, and configure how we talk to it to match:
+  /// failing if it can't be used at all (e.g. because it doesn't use SHA-256 digests), rather
+  /// than failing obscurely when it is first used. See remote::ByteStore::negotiate_capabilities.
+  ///
+  pub fn negotiate_remote_capabilities(self) -> Result<Store, String> {
+    let remote = match self.remote {
+      Some(ref remote) => Some(remote.clone().negotiate_capabilities()?),
+      None => None,
+    };
+    Ok(Store { remote, ..self })
+  }
+
+  ///
+  /// Send the given RequestMetadata with every request to the remote store (if any), so that it
+  /// can tell which tool and invocation it is serving.
+  ///
+  pub fn with_request_metadata(self, request_metadata: bazel_protos::RequestMetadata) -> Store {
+    Store {
+      remote: self
+        .remote
+        .map(|remote| remote.with_request_metadata(request_metadata)),
+      ..self
+    }
+  }
+
+  ///
+  /// A clone of this store whose requests to the remote store (if any) are attributed to the
+  /// action with the given digest.
+  ///
+  pub fn for_action(&self, action_digest: Digest) -> Store {
+    Store {
+      remote: self
+        .remote
+        .as_ref()
+        .map(|remote| remote.for_action(action_digest)),
+      ..self.clone()
+    }
+  }
+
+  ///
+  /// Cache at most capacity_bytes of recently loaded Directories, and files of at most
+  /// max_file_bytes, in memory. A capacity of zero disables the cache.
+  ///
+  pub fn with_hot_cache(self, capacity_bytes: usize, max_file_bytes: usize) -> Store {
+    Store {
+      hot_cache: Arc::new(HotCache::new(capacity_bytes, max_file_bytes)),
+      ..self
+    }
+  }
+
+  ///
+  /// Layer the existing local store at the given path (e.g. a shared cache on a read-only mount)
+  /// under this store's local storage. Anything missing locally is looked for there before being
+  /// fetched from the remote, but nothing is ever written to it.
+  ///
+  /// The existing store must have been created with the same shard count as this store's.
+  ///
+  pub fn with_read_only_local_store<P: AsRef<Path>>(
+    self,
+    path: P,
+    pool: Arc<ResettablePool>,
+  ) -> Result<Store, String> {
+    let fallback = local::ByteStore::new_read_only(path, pool)?;
+    Ok(Store {
+      local: self.local.with_read_only_fallback(fallback)?,
+      ..self
+    })
+  }
+
   ///
   /// LMDB Environments aren't safe to be re-used after forking, so we need to drop them before
   /// forking and re-create them afterwards.
@@ -102,12 +288,83 @@ impl Store {
   pub fn store_file_bytes(&self, bytes: Bytes, initial_lease: bool) -> BoxFuture<Digest, String> {
     let len = bytes.len();
     self
-      .local
-      .store_bytes(EntryType::File, bytes, initial_lease)
+      .store_bytes_locally(EntryType::File, bytes, initial_lease)
       .map(move |fingerprint| Digest(fingerprint, len))
       .to_boxed()
   }
 
+  ///
+  /// Store the file at the given path locally. Large files are streamed into the store, rather than
+  /// being read into memory.
+  ///
+  pub fn store_file_from_path(
+    &self,
+    path: PathBuf,
+    initial_lease: bool,
+  ) -> BoxFuture<Digest, String> {
+    self.writing_locally(move |local| local.store_file_from_path(path.clone(), initial_lease))
+  }
+
+  fn store_bytes_locally(
+    &self,
+    entry_type: EntryType,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> BoxFuture<Fingerprint, String> {
+    self.writing_locally(move |local| local.store_bytes(entry_type, bytes.clone(), initial_lease))
+  }
+
+  ///
+  /// Runs the given write to the local store. If the shard it writes to is full, and has already
+  /// grown as large as it may, garbage collects that shard and then retries the write once.
+  ///
+  fn writing_locally<T, F>(&self, write: F) -> BoxFuture<T, String>
+  where
+    T: Send + 'static,
+    F: Fn(&local::ByteStore) -> BoxFuture<T, local::StoreError> + Send + 'static,
+  {
+    let store = self.clone();
+    write(&self.local)
+      .or_else(move |err| match err {
+        local::StoreError::ShardFull(entry_type, fingerprint, _) => {
+          try_future!(store.collect_full_shard(entry_type, fingerprint));
+          write(&store.local).map_err(|err| err.to_string()).to_boxed()
+        }
+        err => future::err(err.to_string()).to_boxed(),
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Garbage collects everything which is neither leased nor pinned from the local store's shard
+  /// of the given type which holds the given fingerprint, to make space in it.
+  ///
+  /// Note: This performs IO on the calling thread.
+  ///
+  fn collect_full_shard(
+    &self,
+    entry_type: EntryType,
+    fingerprint: Fingerprint,
+  ) -> Result<(), String> {
+    warn!(
+      "The local store shard holding {:?} {} is full: garbage collecting everything in it which \
+       isn't in use",
+      entry_type, fingerprint
+    );
+    let protected = self.pinned_fingerprints()?;
+    // As in garbage_collect, the cache is cleared both before and after the shrink.
+    self.hot_cache.clear();
+    let shrunk = self.local.shrink_full_shard(entry_type, &fingerprint, &protected);
+    self.hot_cache.clear();
+    match shrunk {
+      Ok(size) => {
+        debug!("Garbage collection shrank the full shard to {} bytes", size);
+        Ok(())
+      }
+      Err(err) => Err(format!("Garbage collecting a full shard failed: {}", err)),
+    }
+  }
+
   ///
   /// Loads the bytes of the file with the passed fingerprint from the local store and back-fill
   /// from remote when necessary and possible (i.e. when remote is configured), and returns the
@@ -118,19 +375,55 @@ impl Store {
     digest: Digest,
     f: F,
   ) -> BoxFuture<Option<T>, String> {
+    if let Some(bytes) = self.hot_cache.get_file(&digest.0) {
+      return future::ok(Some(f(bytes))).to_boxed();
+    }
     // No transformation or verification is needed for files, so we pass in a pair of functions
     // which always succeed, whether the underlying bytes are coming from a local or remote store.
     // Unfortunately, we need to be a little verbose to do this.
+    //
+    // Only locally stored bytes are cached: bytes from the remote store haven't yet been verified.
     let f_local = Arc::new(f);
     let f_remote = f_local.clone();
+    let hot_cache = self.hot_cache.clone();
     self.load_bytes_with(
       EntryType::File,
       digest,
-      move |v: Bytes| Ok(f_local(v)),
+      move |v: Bytes| {
+        hot_cache.insert_file(digest.0, v.clone());
+        Ok(f_local(v))
+      },
       move |v: Bytes| Ok(f_remote(v)),
     )
   }
 
+  ///
+  /// Loads at most length bytes of the file with the passed digest, starting at offset, from the
+  /// local store, or from the remote store if it is not present locally. Fewer bytes are returned
+  /// if the range extends past the end of the file.
+  ///
+  /// Unlike loading a whole file, reading a range from the remote store does not back-fill the
+  /// local store.
+  ///
+  pub fn load_file_bytes_range(
+    &self,
+    digest: Digest,
+    offset: usize,
+    length: usize,
+  ) -> BoxFuture<Option<Bytes>, String> {
+    let offset = min(offset, digest.1);
+    let remote = self.remote.clone();
+    self
+      .local
+      .load_file_bytes_range(digest.0, offset, length)
+      .and_then(move |maybe_bytes| match (maybe_bytes, remote) {
+        (Some(bytes), _) => future::ok(Some(bytes)).to_boxed(),
+        (None, None) => future::ok(None).to_boxed(),
+        (None, Some(remote)) => remote.load_bytes_range(digest, offset, length),
+      })
+      .to_boxed()
+  }
+
   ///
   /// Save the bytes of the Directory proto locally, without regard for any of the
   /// contents of any FileNodes or DirectoryNodes therein (i.e. does not require that its
@@ -141,15 +434,15 @@ impl Store {
     directory: &bazel_protos::remote_execution::Directory,
     initial_lease: bool,
   ) -> BoxFuture<Digest, String> {
-    let local = self.local.clone();
+    let store = self.clone();
     future::result(
       directory
         .write_to_bytes()
         .map_err(|e| format!("Error serializing directory proto {:?}: {:?}", directory, e)),
     ).and_then(move |bytes| {
       let len = bytes.len();
-      local
-        .store_bytes(EntryType::Directory, Bytes::from(bytes), initial_lease)
+      store
+        .store_bytes_locally(EntryType::Directory, Bytes::from(bytes), initial_lease)
         .map(move |fingerprint| Digest(fingerprint, len))
     })
       .to_boxed()
@@ -166,6 +459,10 @@ impl Store {
     &self,
     digest: Digest,
   ) -> BoxFuture<Option<bazel_protos::remote_execution::Directory>, String> {
+    if let Some(directory) = self.hot_cache.get_directory(&digest.0) {
+      return future::ok(Some(directory)).to_boxed();
+    }
+    let hot_cache = self.hot_cache.clone();
     self.load_bytes_with(
       EntryType::Directory,
       digest,
@@ -179,6 +476,7 @@ impl Store {
             digest, e
           )
         })?;
+        hot_cache.insert_directory(digest, directory.clone());
         Ok(directory)
       },
       // Eagerly verify that CAS-returned Directories are canonical, so that we don't write them
@@ -213,7 +511,7 @@ impl Store {
     f_local: FLocal,
     f_remote: FRemote,
   ) -> BoxFuture<Option<T>, String> {
-    let local = self.local.clone();
+    let store = self.clone();
     let maybe_remote = self.remote.clone();
     self
       .local
@@ -228,8 +526,8 @@ impl Store {
               Some(bytes) => future::done(f_remote(bytes.clone()))
                 .and_then(move |value| {
                   let len = bytes.len();
-                  local
-                    .store_bytes(entry_type, bytes, true)
+                  store
+                    .store_bytes_locally(entry_type, bytes, true)
                     .and_then(move |stored_fingerprint| {
                       let stored_digest = Digest(stored_fingerprint, len);
                       if digest == stored_digest {
@@ -265,14 +563,23 @@ impl Store {
 
     let mut expanding_futures = Vec::new();
 
+    // Directories which were recently found in the remote along with everything beneath them
+    // aren't expanded, checked or uploaded again.
+    let remote_for_pruning = remote.clone();
+    let is_known_present: Arc<Fn(&Digest) -> bool + Send + Sync> =
+      Arc::new(move |digest: &Digest| remote_for_pruning.is_tree_known_present(digest));
+
     let mut expanded_digests = HashMap::new();
     for digest in digests {
+      if remote.is_tree_known_present(&digest) {
+        continue;
+      }
       match self.local.entry_type(&digest.0) {
         Ok(Some(EntryType::File)) => {
           expanded_digests.insert(digest, EntryType::File);
         }
         Ok(Some(EntryType::Directory)) => {
-          expanding_futures.push(self.expand_directory(digest));
+          expanding_futures.push(self.expand_directory_pruning(digest, is_known_present.clone()));
         }
         Ok(None) => {
           return future::err(format!("Failed to upload digest {:?}: Not found", digest)).to_boxed()
@@ -286,6 +593,7 @@ impl Store {
     let local = self.local.clone();
     let remote = remote.clone();
     let remote2 = remote.clone();
+    let remote3 = remote.clone();
     future::join_all(expanding_futures)
       .map(move |futures| {
         for mut digests in futures {
@@ -295,36 +603,52 @@ impl Store {
         }
         expanded_digests
       })
-      .and_then(move |digests| {
+      .and_then(move |mut digests| {
+        // Once everything has been uploaded, every Directory is known to be present along with
+        // everything beneath it.
+        let trees: Vec<Digest> = digests
+          .iter()
+          .filter(|&(_, entry_type)| *entry_type == EntryType::Directory)
+          .map(|(digest, _)| *digest)
+          .collect();
+        digests.retain(|digest, _| !remote.is_known_present(digest));
         if Store::upload_is_faster_than_checking_whether_to_upload(&digests) {
-          return Ok((digests.keys().cloned().collect(), digests));
+          return Ok((digests.keys().cloned().collect(), digests, trees));
         }
         remote
           .list_missing_digests(digests.keys())
-          .map(|filtered_digests| (filtered_digests, digests))
+          .map(|filtered_digests| (filtered_digests, digests, trees))
       })
-      .and_then(move |(filtered_digests, digest_entry_types)| {
+      .and_then(move |(filtered_digests, digest_entry_types, trees)| {
         future::join_all(
           filtered_digests
             .into_iter()
             .map(move |digest| {
               let entry_type = digest_entry_types[&digest];
-              let remote = remote2.clone();
               local
-                .load_bytes_with(entry_type, digest.0, move |bytes| remote.store_bytes(bytes))
-                .and_then(move |maybe_future| match maybe_future {
-                  Some(future) => Ok(future),
-                  None => Err(format!("Failed to upload digest {:?}: Not found", digest)),
+                .load_bytes_with(entry_type, digest.0, |bytes| bytes)
+                .and_then(move |maybe_bytes| {
+                  maybe_bytes
+                    .ok_or_else(|| format!("Failed to upload digest {:?}: Not found", digest))
                 })
             })
             .collect::<Vec<_>>(),
-        )
+        ).and_then(move |blobs| remote2.store_all_bytes(blobs))
+          .map(move |_| remote3.record_trees_present(trees.iter()))
       })
-      .and_then(future::join_all)
-      .map(|_| ())
       .to_boxed()
   }
 
+  ///
+  /// Forgets that the given digests are present in the remote store, e.g. because the remote has
+  /// reported that they are missing, so that ensure_remote_has_recursive will upload them again.
+  ///
+  pub fn forget_remote_has(&self, digests: &[Digest]) {
+    if let Some(ref remote) = self.remote {
+      remote.forget_present(digests);
+    }
+  }
+
   ///
   /// Download a directory from Remote ByteStore recursively to the local one. Called only with the
   /// Digest of a Directory.
@@ -367,9 +691,161 @@ impl Store {
     self.local.lease_all(digests)
   }
 
+  ///
+  /// Statistics about the local store, and the in-memory cache in front of it. This reads every
+  /// entry's lease, so is not free for large stores.
+  ///
+  pub fn stats(&self) -> Result<StoreStats, String> {
+    let mut stats = self.local.stats()?;
+    stats.hot_cache = self.hot_cache.stats();
+    Ok(stats)
+  }
+
+  ///
+  /// Statistics about the in-memory cache in front of the local store, which are cheap to compute.
+  ///
+  pub fn hot_cache_stats(&self) -> HotCacheStats {
+    self.hot_cache.stats()
+  }
+
+  ///
+  /// Statistics which LMDB maintains about each of the local store's databases. Unlike `stats`,
+  /// these are read without visiting any entries.
+  ///
+  pub fn databases_stats(&self) -> Result<DatabasesStats, String> {
+    self.local.databases_stats()
+  }
+
+  ///
+  /// Pins the file or Directory with the given digest under the given label, so that garbage
+  /// collection never removes it (or anything reachable from it) until it is unpinned. Replaces any
+  /// existing pin with the same label.
+  ///
+  /// The pinned digest must be present in the local store. Anything reachable from a pinned
+  /// Directory which is missing locally is fetched from the remote store, if there is one, and the
+  /// pin fails (without being recorded) if anything can't be found.
+  ///
+  pub fn pin(&self, label: String, digest: Digest) -> BoxFuture<(), String> {
+    let local = self.local.clone();
+    let store = self.clone();
+    match try_future!(self.local.entry_type(&digest.0)) {
+      Some(EntryType::File) => {
+        future::result(local.pin(&label, EntryType::File, digest)).to_boxed()
+      }
+      Some(EntryType::Directory) => self
+        .expand_directory(digest)
+        .and_then(move |digests| {
+          future::join_all(
+            digests
+              .into_iter()
+              .filter(|&(_, entry_type)| entry_type == EntryType::File)
+              .map(|(file_digest, _)| store.ensure_local_has_file(file_digest))
+              .collect::<Vec<_>>(),
+          )
+        })
+        .map_err(move |e| format!("Cannot pin {:?}: {}", digest, e))
+        .and_then(move |_| local.pin(&label, EntryType::Directory, digest))
+        .to_boxed(),
+      None => future::err(format!(
+        "Cannot pin {:?}: it was not found in the local store",
+        digest
+      )).to_boxed(),
+    }
+  }
+
+  ///
+  /// Fetches the file with the given digest from the remote store (if there is one) if it is
+  /// missing locally, failing if it can't be found.
+  ///
+  fn ensure_local_has_file(&self, digest: Digest) -> BoxFuture<(), String> {
+    if try_future!(self.local.entry_type(&digest.0)).is_some() {
+      return future::ok(()).to_boxed();
+    }
+    self
+      .load_bytes_with(EntryType::File, digest, |_| Ok(()), |_| Ok(()))
+      .and_then(move |maybe_file| {
+        maybe_file.ok_or_else(|| format!("File with digest {:?} not found", digest))
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Removes the pin with the given label, returning false if there was no such pin.
+  ///
+  pub fn unpin(&self, label: &str) -> Result<bool, String> {
+    self.local.unpin(label)
+  }
+
+  ///
+  /// Every pin, as its label and the digest it pins, ordered by label.
+  ///
+  pub fn pins(&self) -> Result<Vec<(String, Digest)>, String> {
+    Ok(
+      self
+        .local
+        .pins()?
+        .into_iter()
+        .map(|(label, _, digest)| (label, digest))
+        .collect(),
+    )
+  }
+
+  ///
+  /// The fingerprints of every pinned entry, and of everything reachable from pinned Directories.
+  ///
+  /// Only the local store is consulted: anything which is missing from it can't be garbage
+  /// collected anyway, so Directories which are missing locally are skipped rather than fetched.
+  ///
+  /// Note: This performs IO on the calling thread.
+  ///
+  fn pinned_fingerprints(&self) -> Result<HashSet<Fingerprint>, String> {
+    let mut fingerprints = HashSet::new();
+    let mut directories = Vec::new();
+    for (_, entry_type, digest) in self.local.pins()? {
+      if fingerprints.insert(digest.0) && entry_type == EntryType::Directory {
+        directories.push(digest.0);
+      }
+    }
+    while let Some(fingerprint) = directories.pop() {
+      let bytes = match self
+        .local
+        .load_bytes_blocking(EntryType::Directory, fingerprint)?
+      {
+        Some(bytes) => bytes,
+        None => continue,
+      };
+      let mut directory = bazel_protos::remote_execution::Directory::new();
+      directory.merge_from_bytes(&bytes).map_err(|e| {
+        format!(
+          "LMDB corruption: Directory bytes for {} were not valid: {:?}",
+          fingerprint, e
+        )
+      })?;
+      for file_node in directory.get_files() {
+        let digest: Result<Digest, String> = file_node.get_digest().into();
+        fingerprints.insert(digest?.0);
+      }
+      for directory_node in directory.get_directories() {
+        let digest: Result<Digest, String> = directory_node.get_digest().into();
+        let digest = digest?;
+        if fingerprints.insert(digest.0) {
+          directories.push(digest.0);
+        }
+      }
+    }
+    Ok(fingerprints)
+  }
+
   pub fn garbage_collect(&self) -> Result<(), String> {
     let target = LOCAL_STORE_GC_TARGET_BYTES;
-    match self.local.shrink(target) {
+    let protected = self.pinned_fingerprints()?;
+    // Anything in the cache may be about to be collected. Loads which run concurrently with the
+    // shrink may repopulate the cache with blobs which it then collects, so the cache is cleared
+    // again afterwards.
+    self.hot_cache.clear();
+    let shrunk = self.local.shrink_protecting(target, &protected);
+    self.hot_cache.clear();
+    match shrunk {
       Ok(size) => {
         if size > target {
           return Err(format!(
@@ -404,10 +880,22 @@ impl Store {
   }
 
   pub fn expand_directory(&self, digest: Digest) -> BoxFuture<HashMap<Digest, EntryType>, String> {
+    self.expand_directory_pruning(digest, Arc::new(|_: &Digest| false))
+  }
+
+  ///
+  /// As expand_directory, but skips (and doesn't include) any Directory for which prune returns
+  /// true, along with everything beneath it.
+  ///
+  fn expand_directory_pruning(
+    &self,
+    digest: Digest,
+    prune: Arc<Fn(&Digest) -> bool + Send + Sync>,
+  ) -> BoxFuture<HashMap<Digest, EntryType>, String> {
     let accumulator = Arc::new(Mutex::new(HashMap::new()));
 
     self
-      .expand_directory_helper(digest, accumulator.clone())
+      .expand_directory_helper(digest, accumulator.clone(), prune)
       .map(|()| {
         Arc::try_unwrap(accumulator)
           .expect("Arc should have been unwrappable")
@@ -421,7 +909,11 @@ impl Store {
     &self,
     digest: Digest,
     accumulator: Arc<Mutex<HashMap<Digest, EntryType>>>,
+    prune: Arc<Fn(&Digest) -> bool + Send + Sync>,
   ) -> BoxFuture<(), String> {
+    if prune(&digest) {
+      return future::ok(()).to_boxed();
+    }
     let store = self.clone();
     self
       .load_directory(digest)
@@ -442,6 +934,7 @@ impl Store {
                 store.clone().expand_directory_helper(
                   try_future!(subdir.get_digest().into()),
                   accumulator.clone(),
+                  prune.clone(),
                 )
               })
               .collect::<Vec<_>>(),
@@ -454,8 +947,8 @@ impl Store {
   }
 
   ///
-  /// Lays out the directory and all of its contents (files and directories) on disk so that a
-  /// process which uses the directory structure can run.
+  /// Lays out the directory and all of its contents (files, directories and symlinks) on disk so
+  /// that a process which uses the directory structure can run.
   ///
   pub fn materialize_directory(
     &self,
@@ -470,6 +963,12 @@ impl Store {
         directory_opt.ok_or_else(|| format!("Directory with digest {:?} not found", digest))
       })
       .and_then(move |directory| {
+        for symlink_node in directory.get_symlinks() {
+          try_future!(create_symlink(
+            &destination.join(symlink_node.get_name()),
+            symlink_node.get_target()
+          ));
+        }
         let file_futures = directory
           .get_files()
           .iter()
@@ -493,6 +992,7 @@ impl Store {
         future::join_all(file_futures)
           .join(future::join_all(directory_futures))
           .map(|_| ())
+          .to_boxed()
       })
       .to_boxed()
   }
@@ -502,6 +1002,77 @@ impl Store {
     destination: PathBuf,
     digest: Digest,
     is_executable: bool,
+  ) -> BoxFuture<(), String> {
+    // Files in the large file store are copied directly from disk, rather than loaded into memory.
+    let store = self.clone();
+    let file_cache = self.file_cache.clone();
+    let large_file_destination = destination.clone();
+    self
+      .local
+      .with_large_file_path(digest.0, move |path| {
+        let mut source =
+          fs::File::open(&path).map_err(|e| format!("Error opening {:?}: {:?}", path, e))?;
+        match file_cache {
+          Some(file_cache) => file_cache.store_and_materialize_from(
+            digest,
+            is_executable,
+            &mut source,
+            &large_file_destination,
+          ),
+          None => OpenOptions::new()
+            .create(true)
+            .write(true)
+            .mode(if is_executable { 0o755 } else { 0o644 })
+            .open(&large_file_destination)
+            .and_then(|mut f| io::copy(&mut source, &mut f))
+            .map(|_| ())
+            .map_err(|e| format!("Error writing file {:?}: {:?}", large_file_destination, e)),
+        }
+      })
+      .and_then(move |copied| match copied {
+        Some(()) => future::ok(()).to_boxed(),
+        None => store.materialize_file_bytes(destination, digest, is_executable),
+      })
+      .to_boxed()
+  }
+
+  fn materialize_file_bytes(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    is_executable: bool,
+  ) -> BoxFuture<(), String> {
+    match self.file_cache {
+      Some(ref file_cache) => {
+        let store = self.clone();
+        let file_cache = file_cache.clone();
+        file_cache
+          .materialize(digest, is_executable, destination.clone())
+          .and_then(move |linked| {
+            if linked {
+              return future::ok(()).to_boxed();
+            }
+            store
+              .load_file_bytes_with(digest, move |bytes| {
+                file_cache.store_and_materialize(digest, is_executable, &bytes, &destination)
+              })
+              .and_then(move |result| match result {
+                Some(result) => result,
+                None => Err(format!("File with digest {:?} not found", digest)),
+              })
+              .to_boxed()
+          })
+          .to_boxed()
+      }
+      None => self.write_file(destination, digest, is_executable),
+    }
+  }
+
+  fn write_file(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    is_executable: bool,
   ) -> BoxFuture<(), String> {
     self
       .load_file_bytes_with(digest, move |bytes| {
@@ -521,30 +1092,347 @@ impl Store {
       .to_boxed()
   }
 
-  // Returns files sorted by their path.
-  pub fn contents_for_directory(
+  ///
+  /// Makes the destination match the directory with the given digest, without touching files which
+  /// are already up to date: only files whose contents differ are written, and files whose
+  /// contents match but whose executable bits don't are fixed in place.
+  ///
+  /// Anything on disk which is not in the directory is removed. If a managed prefix (relative to
+  /// the destination) is given, only things below it are removed, and anything else is left alone
+  /// unless it is in the way of something in the directory.
+  ///
+  pub fn sync_directory(
     &self,
-    directory: &bazel_protos::remote_execution::Directory,
-  ) -> BoxFuture<Vec<FileContent>, String> {
-    let accumulator = Arc::new(Mutex::new(HashMap::new()));
-    self
-      .contents_for_directory_helper(directory, PathBuf::new(), accumulator.clone())
-      .map(|()| {
-        let map = Arc::try_unwrap(accumulator).unwrap().into_inner().unwrap();
-        let mut vec: Vec<FileContent> = map
-          .into_iter()
-          .map(|(path, content)| FileContent { path, content })
-          .collect();
-        vec.sort_by(|l, r| l.path.cmp(&r.path));
-        vec
-      })
-      .to_boxed()
+    destination: PathBuf,
+    digest: Digest,
+    managed_prefix: Option<PathBuf>,
+  ) -> BoxFuture<(), String> {
+    let managed_root = match managed_prefix {
+      Some(prefix) => {
+        let escapes = prefix.is_absolute()
+          || prefix
+            .components()
+            .any(|component| component == Component::ParentDir);
+        if escapes {
+          return future::err(format!(
+            "Managed prefix must be a relative path without `..`, but got: {:?}",
+            prefix
+          )).to_boxed();
+        }
+        destination.join(prefix)
+      }
+      None => destination.clone(),
+    };
+    self.sync_directory_helper(destination, digest, Arc::new(managed_root))
   }
 
-  // Assumes that all fingerprints it encounters are valid.
-  fn contents_for_directory_helper(
+  fn sync_directory_helper(
     &self,
-    directory: &bazel_protos::remote_execution::Directory,
+    destination: PathBuf,
+    digest: Digest,
+    managed_root: Arc<PathBuf>,
+  ) -> BoxFuture<(), String> {
+    let store = self.clone();
+    self
+      .load_directory(digest)
+      .and_then(move |directory_opt| {
+        directory_opt.ok_or_else(|| format!("Directory with digest {:?} not found", digest))
+      })
+      .and_then(move |directory| {
+        if let Ok(metadata) = fs::symlink_metadata(&destination) {
+          if !metadata.is_dir() {
+            try_future!(remove_path(&destination, &metadata));
+          }
+        }
+        try_future!(super::safe_create_dir_all(&destination));
+
+        let expected_names: HashSet<&str> = directory
+          .get_files()
+          .iter()
+          .map(|file_node| file_node.get_name())
+          .chain(
+            directory
+              .get_directories()
+              .iter()
+              .map(|directory_node| directory_node.get_name()),
+          )
+          .chain(
+            directory
+              .get_symlinks()
+              .iter()
+              .map(|symlink_node| symlink_node.get_name()),
+          )
+          .collect();
+        let entries = try_future!(
+          fs::read_dir(&destination)
+            .map_err(|e| format!("Error listing directory {:?}: {:?}", destination, e))
+        );
+        for entry in entries {
+          let entry =
+            try_future!(entry.map_err(|e| format!("Error listing {:?}: {:?}", destination, e)));
+          let path = entry.path();
+          let is_expected = entry
+            .file_name()
+            .to_str()
+            .map(|name| expected_names.contains(name))
+            .unwrap_or(false);
+          if !is_expected && path.starts_with(&*managed_root) {
+            let metadata = try_future!(
+              fs::symlink_metadata(&path)
+                .map_err(|e| format!("Error reading metadata of {:?}: {:?}", path, e))
+            );
+            try_future!(remove_path(&path, &metadata));
+          }
+        }
+
+        for symlink_node in directory.get_symlinks() {
+          try_future!(sync_symlink(
+            &destination.join(symlink_node.get_name()),
+            symlink_node.get_target()
+          ));
+        }
+        let file_futures = directory
+          .get_files()
+          .iter()
+          .map(|file_node| {
+            let path = destination.join(file_node.get_name());
+            let digest = try_future!(file_node.get_digest().into());
+            store.sync_file(path, digest, file_node.is_executable)
+          })
+          .collect::<Vec<_>>();
+        let directory_futures = directory
+          .get_directories()
+          .iter()
+          .map(|directory_node| {
+            let path = destination.join(directory_node.get_name());
+            let digest = try_future!(directory_node.get_digest().into());
+            store.sync_directory_helper(path, digest, managed_root.clone())
+          })
+          .collect::<Vec<_>>();
+        future::join_all(file_futures)
+          .join(future::join_all(directory_futures))
+          .map(|_| ())
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  fn sync_file(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    is_executable: bool,
+  ) -> BoxFuture<(), String> {
+    // Files in the large file store are compared by hashing the existing file as it is streamed,
+    // rather than by loading the stored file into memory.
+    let store = self.clone();
+    let store2 = self.clone();
+    let large_file_destination = destination.clone();
+    let destination2 = destination.clone();
+    self
+      .local
+      .with_large_file_path(digest.0, move |_| {
+        sync_existing_file(&large_file_destination, digest.1, is_executable, |path| {
+          file_has_fingerprint(path, digest.0)
+        })
+      })
+      .and_then(move |synced| match synced {
+        Some(synced) => future::ok(Some(Ok(synced))).to_boxed(),
+        None => store.load_file_bytes_with(digest, move |bytes| {
+          sync_existing_file(&destination, bytes.len(), is_executable, |path| {
+            fs::read(path).map(|contents| contents[..] == bytes[..])
+          })
+        }),
+      })
+      .and_then(move |sync_result| match sync_result {
+        Some(Ok(true)) => future::ok(()).to_boxed(),
+        Some(Ok(false)) => store2.materialize_file(destination2, digest, is_executable),
+        Some(Err(e)) => future::err(e).to_boxed(),
+        None => future::err(format!("File with digest {:?} not found", digest)).to_boxed(),
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Compares two Directories, returning which paths were added, removed and modified going from
+  /// the left to the right. Subtrees which are identical (by digest) are not traversed.
+  ///
+  pub fn diff_directories(&self, left: Digest, right: Digest) -> BoxFuture<DirectoryDiff, String> {
+    let accumulator = Arc::new(Mutex::new(DirectoryDiff::default()));
+    self
+      .diff_directories_helper(left, right, PathBuf::new(), accumulator.clone())
+      .map(|()| {
+        let mut diff = Arc::try_unwrap(accumulator)
+          .expect("Arc should have been unwrappable")
+          .into_inner()
+          .unwrap();
+        diff.added.sort();
+        diff.removed.sort();
+        diff.modified.sort();
+        diff.executable_changed.sort();
+        diff
+      })
+      .to_boxed()
+  }
+
+  fn diff_directories_helper(
+    &self,
+    left: Digest,
+    right: Digest,
+    path_so_far: PathBuf,
+    accumulator: Arc<Mutex<DirectoryDiff>>,
+  ) -> BoxFuture<(), String> {
+    if left == right {
+      return future::ok(()).to_boxed();
+    }
+    let store = self.clone();
+    let load = |digest: Digest| {
+      self.load_directory(digest).and_then(move |maybe_directory| {
+        maybe_directory.ok_or_else(|| format!("Directory with digest {:?} not found", digest))
+      })
+    };
+    load(left)
+      .join(load(right))
+      .and_then(move |(left_directory, right_directory)| {
+        let left_entries = try_future!(DiffEntry::all(&left_directory));
+        let right_entries = try_future!(DiffEntry::all(&right_directory));
+
+        let mut subdirectory_futures = Vec::new();
+        {
+          let mut diff = accumulator.lock().unwrap();
+          for (name, left_entry) in &left_entries {
+            let path = path_so_far.join(name);
+            match (left_entry, right_entries.get(name)) {
+              (_, None) => diff.removed.push(path),
+              (
+                &DiffEntry::File(left_digest, left_executable),
+                Some(&DiffEntry::File(right_digest, right_executable)),
+              ) => {
+                if left_digest != right_digest {
+                  diff.modified.push(path.clone());
+                }
+                if left_executable != right_executable {
+                  diff.executable_changed.push(path);
+                }
+              }
+              (&DiffEntry::Directory(left_digest), Some(&DiffEntry::Directory(right_digest))) => {
+                subdirectory_futures.push(store.diff_directories_helper(
+                  left_digest,
+                  right_digest,
+                  path,
+                  accumulator.clone(),
+                ))
+              }
+              (
+                &DiffEntry::Symlink(ref left_target),
+                Some(&DiffEntry::Symlink(ref right_target)),
+              ) => {
+                if left_target != right_target {
+                  diff.modified.push(path);
+                }
+              }
+              // A path changed between being a file, a directory and a symlink.
+              (_, Some(_)) => diff.modified.push(path),
+            }
+          }
+          for name in right_entries.keys() {
+            if !left_entries.contains_key(name) {
+              diff.added.push(path_so_far.join(name));
+            }
+          }
+        }
+        future::join_all(subdirectory_futures)
+          .map(|_| ())
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Writes the Directory with the given digest to the writer as a tar or zip archive, returning
+  /// the writer once the archive is complete.
+  ///
+  /// Archives are deterministic: entries are sorted by path, and timestamps and ownership are
+  /// zeroed, so that only paths, contents, executable bits and symlinks are recorded. Zip archives
+  /// cannot contain symlinks. Writing a zip archive requires seeking, so the writer must be
+  /// seekable (e.g. a File) for either format.
+  ///
+  pub fn export_archive<W: Write + Seek + Send + 'static>(
+    &self,
+    digest: Digest,
+    format: ArchiveFormat,
+    writer: W,
+  ) -> BoxFuture<W, String> {
+    archive::export(self.clone(), digest, format, writer)
+  }
+
+  ///
+  /// Stores the files of a tar or zip archive, and Directories for the directories in it,
+  /// returning the digest of the top-level Directory. Nothing is extracted to disk.
+  ///
+  /// The archive is read synchronously, so this must be called on a thread which is allowed to
+  /// block. Reading a zip archive requires seeking, so the reader must be seekable (e.g. a File).
+  ///
+  pub fn import_archive<R: Read + Seek>(
+    &self,
+    format: ArchiveFormat,
+    reader: R,
+  ) -> BoxFuture<Digest, String> {
+    archive::import(self.clone(), format, reader)
+  }
+
+  ///
+  /// Writes every file and Directory reachable from the given roots (which may be files or
+  /// Directories) to the writer as a single self-describing bundle, which can be imported into
+  /// another store with import_bundle. Returns the writer once the bundle is complete.
+  ///
+  /// Bundles are tar archives containing a manifest of their roots, followed by an entry for each
+  /// file and Directory, named by its digest. They are deterministic.
+  ///
+  pub fn export_bundle<W: Write + Send + 'static>(
+    &self,
+    roots: Vec<Digest>,
+    writer: W,
+  ) -> BoxFuture<W, String> {
+    bundle::export(self.clone(), roots, writer)
+  }
+
+  ///
+  /// Stores every entry of a bundle written by export_bundle, returning its roots. Every entry is
+  /// verified against its digest, and the bundle must contain everything reachable from its
+  /// roots. Nothing is stored unless the whole bundle is valid.
+  ///
+  /// The bundle is read synchronously, so this must be called on a thread which is allowed to
+  /// block.
+  ///
+  pub fn import_bundle<R: Read>(&self, reader: R) -> BoxFuture<Vec<Digest>, String> {
+    bundle::import(self.clone(), reader)
+  }
+
+  // Returns files sorted by their path.
+  pub fn contents_for_directory(
+    &self,
+    directory: &bazel_protos::remote_execution::Directory,
+  ) -> BoxFuture<Vec<FileContent>, String> {
+    let accumulator = Arc::new(Mutex::new(HashMap::new()));
+    self
+      .contents_for_directory_helper(directory, PathBuf::new(), accumulator.clone())
+      .map(|()| {
+        let map = Arc::try_unwrap(accumulator).unwrap().into_inner().unwrap();
+        let mut vec: Vec<FileContent> = map
+          .into_iter()
+          .map(|(path, content)| FileContent { path, content })
+          .collect();
+        vec.sort_by(|l, r| l.path.cmp(&r.path));
+        vec
+      })
+      .to_boxed()
+  }
+
+  // Assumes that all fingerprints it encounters are valid.
+  fn contents_for_directory_helper(
+    &self,
+    directory: &bazel_protos::remote_execution::Directory,
     path_so_far: PathBuf,
     contents_wrapped: Arc<Mutex<HashMap<PathBuf, Bytes>>>,
   ) -> BoxFuture<(), String> {
@@ -597,6 +1485,262 @@ impl Store {
   }
 }
 
+///
+/// Statistics about a set of entries in the local store. Sizes are those of the entries as stored,
+/// i.e. after compression.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EntryStats {
+  pub entries: usize,
+  pub size_bytes: usize,
+  // Entries whose leases have not yet expired. Every other entry may be garbage collected.
+  pub leased_entries: usize,
+  pub expired_entries: usize,
+  // The earliest and latest times (in seconds since the epoch) at which the entries' leases expire.
+  pub oldest_lease_secs_since_epoch: Option<u64>,
+  pub newest_lease_secs_since_epoch: Option<u64>,
+}
+
+impl EntryStats {
+  fn record(&mut self, size_bytes: usize, lease_until_secs_since_epoch: u64, now_secs: u64) {
+    self.entries += 1;
+    self.size_bytes += size_bytes;
+    if lease_until_secs_since_epoch > now_secs {
+      self.leased_entries += 1;
+    } else {
+      self.expired_entries += 1;
+    }
+    // 0 indicates unleased.
+    if lease_until_secs_since_epoch != 0 {
+      self.record_leases(
+        Some(lease_until_secs_since_epoch),
+        Some(lease_until_secs_since_epoch),
+      );
+    }
+  }
+
+  fn merge(&mut self, other: &EntryStats) {
+    self.entries += other.entries;
+    self.size_bytes += other.size_bytes;
+    self.leased_entries += other.leased_entries;
+    self.expired_entries += other.expired_entries;
+    self.record_leases(
+      other.oldest_lease_secs_since_epoch,
+      other.newest_lease_secs_since_epoch,
+    );
+  }
+
+  fn record_leases(&mut self, oldest: Option<u64>, newest: Option<u64>) {
+    self.oldest_lease_secs_since_epoch = match (self.oldest_lease_secs_since_epoch, oldest) {
+      (Some(a), Some(b)) => Some(min(a, b)),
+      (a, b) => a.or(b),
+    };
+    self.newest_lease_secs_since_epoch = match (self.newest_lease_secs_since_epoch, newest) {
+      (Some(a), Some(b)) => Some(max(a, b)),
+      (a, b) => a.or(b),
+    };
+  }
+}
+
+///
+/// Statistics about the entries of one type in the local store, in total and per LMDB shard.
+/// Shards are keyed by the first byte of the fingerprints they contain, with its low four bits
+/// cleared.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EntryTypeStats {
+  pub total: EntryStats,
+  pub shards: BTreeMap<u8, EntryStats>,
+}
+
+///
+/// Statistics which LMDB maintains about one of the local store's databases (summed over its
+/// shards), which are cheap to read. Sizes are those of the pages the database occupies, so do not
+/// include files in the large file store.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DatabaseStats {
+  pub entries: usize,
+  pub size_bytes: usize,
+}
+
+impl DatabaseStats {
+  fn merge(&mut self, other: &DatabaseStats) {
+    self.entries += other.entries;
+    self.size_bytes += other.size_bytes;
+  }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DatabasesStats {
+  pub files: DatabaseStats,
+  pub file_leases: DatabaseStats,
+  pub file_encodings: DatabaseStats,
+  pub directories: DatabaseStats,
+  pub directory_leases: DatabaseStats,
+  pub directory_encodings: DatabaseStats,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StoreStats {
+  pub files: EntryTypeStats,
+  pub directories: EntryTypeStats,
+  pub hot_cache: HotCacheStats,
+}
+
+impl StoreStats {
+  pub fn total(&self) -> EntryStats {
+    let mut total = self.files.total.clone();
+    total.merge(&self.directories.total);
+    total
+  }
+}
+
+///
+/// The differences between two Directories, as paths relative to their roots.
+///
+/// A directory which is only present on one side is listed as a single path, rather than
+/// being expanded into its contents.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DirectoryDiff {
+  pub added: Vec<PathBuf>,
+  pub removed: Vec<PathBuf>,
+  // Files whose contents differ, symlinks whose targets differ, and paths which changed between
+  // being a file, a directory and a symlink.
+  pub modified: Vec<PathBuf>,
+  // Files whose executable bit differs (regardless of whether their contents do).
+  pub executable_changed: Vec<PathBuf>,
+}
+
+impl DirectoryDiff {
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty()
+      && self.removed.is_empty()
+      && self.modified.is_empty()
+      && self.executable_changed.is_empty()
+  }
+}
+
+enum DiffEntry {
+  File(Digest, bool),
+  Directory(Digest),
+  Symlink(String),
+}
+
+impl DiffEntry {
+  fn all(
+    directory: &bazel_protos::remote_execution::Directory,
+  ) -> Result<BTreeMap<String, DiffEntry>, String> {
+    let mut entries = BTreeMap::new();
+    for file_node in directory.get_files() {
+      let digest: Result<Digest, String> = file_node.get_digest().into();
+      entries.insert(
+        file_node.get_name().to_owned(),
+        DiffEntry::File(digest?, file_node.is_executable),
+      );
+    }
+    for directory_node in directory.get_directories() {
+      let digest: Result<Digest, String> = directory_node.get_digest().into();
+      entries.insert(
+        directory_node.get_name().to_owned(),
+        DiffEntry::Directory(digest?),
+      );
+    }
+    for symlink_node in directory.get_symlinks() {
+      entries.insert(
+        symlink_node.get_name().to_owned(),
+        DiffEntry::Symlink(symlink_node.get_target().to_owned()),
+      );
+    }
+    Ok(entries)
+  }
+}
+
+///
+/// If the file at the path is of the given size and has_contents confirms that it has the expected
+/// contents, fixes its executable bit if necessary and returns true. Otherwise, removes whatever is
+/// at the path and returns false.
+///
+fn sync_existing_file<F: FnOnce(&Path) -> io::Result<bool>>(
+  path: &Path,
+  size: usize,
+  is_executable: bool,
+  has_contents: F,
+) -> Result<bool, String> {
+  let metadata = match fs::symlink_metadata(path) {
+    Ok(metadata) => metadata,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+    Err(e) => return Err(format!("Error reading metadata of {:?}: {:?}", path, e)),
+  };
+  let is_up_to_date =
+    metadata.is_file() && metadata.len() == size as u64 && has_contents(path).unwrap_or(false);
+  if is_up_to_date {
+    let mode = metadata.permissions().mode();
+    if (mode & 0o100 == 0o100) == is_executable {
+      return Ok(true);
+    }
+    // Hardlinked files share their permissions with every other link to them, so are replaced
+    // rather than modified.
+    if metadata.nlink() == 1 {
+      let mode = if is_executable {
+        mode | 0o111
+      } else {
+        mode & !0o111
+      };
+      fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .map_err(|e| format!("Error setting permissions of {:?}: {:?}", path, e))?;
+      return Ok(true);
+    }
+  }
+  remove_path(path, &metadata)?;
+  Ok(false)
+}
+
+///
+/// Hashes the file at the path as it is streamed, without holding it in memory.
+///
+fn file_has_fingerprint(path: &Path, fingerprint: Fingerprint) -> io::Result<bool> {
+  let mut hasher = WriterHasher::new(io::sink());
+  io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+  Ok(hasher.finish() == fingerprint)
+}
+
+fn create_symlink(path: &Path, target: &str) -> Result<(), String> {
+  symlink(target, path)
+    .map_err(|e| format!("Error creating symlink {:?} to {:?}: {:?}", path, target, e))
+}
+
+///
+/// Leaves a symlink at the path alone if it already has the given target, and otherwise replaces
+/// whatever is at the path with one.
+///
+fn sync_symlink(path: &Path, target: &str) -> Result<(), String> {
+  match fs::symlink_metadata(path) {
+    Ok(metadata) => {
+      if metadata.file_type().is_symlink() {
+        let existing_target = fs::read_link(path)
+          .map_err(|e| format!("Error reading symlink {:?}: {:?}", path, e))?;
+        if existing_target == Path::new(target) {
+          return Ok(());
+        }
+      }
+      remove_path(path, &metadata)?;
+    }
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+    Err(e) => return Err(format!("Error reading metadata of {:?}: {:?}", path, e)),
+  }
+  create_symlink(path, target)
+}
+
+fn remove_path(path: &Path, metadata: &fs::Metadata) -> Result<(), String> {
+  if metadata.is_dir() {
+    fs::remove_dir_all(path)
+  } else {
+    fs::remove_file(path)
+  }.map_err(|e| format!("Error removing {:?}: {:?}", path, e))
+}
+
 // Only public for testing.
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Ord, PartialOrd)]
 pub enum EntryType {
@@ -604,69 +1748,342 @@ pub enum EntryType {
   File,
 }
 
+///
+/// The part of the given bytes which is at most length bytes long and starts at offset.
+///
+fn byte_range(bytes: &Bytes, offset: usize, length: usize) -> Bytes {
+  let begin = min(offset, bytes.len());
+  let end = min(begin.saturating_add(length), bytes.len());
+  bytes.slice(begin, end)
+}
+
 mod local {
-  use super::EntryType;
+  use super::{
+    DatabaseStats, DatabasesStats, EntryStats, EntryType, EntryTypeStats, HotCacheStats,
+    StoreStats,
+  };
 
   use boxfuture::{BoxFuture, Boxable};
   use byteorder::{ByteOrder, LittleEndian};
   use bytes::Bytes;
   use digest::{Digest as DigestTrait, FixedOutput};
-  use futures::future;
-  use hashing::{Digest, Fingerprint};
-  use lmdb::Error::{KeyExist, NotFound};
+  use futures::{future, Future};
+  use hashing::{Digest, Fingerprint, WriterHasher, FINGERPRINT_SIZE};
+  use lmdb::Error::{KeyExist, MapFull, MapResized, NotFound};
   use lmdb::{
-    self, Cursor, Database, DatabaseFlags, Environment, RwTransaction, Transaction, WriteFlags,
-    NO_OVERWRITE, NO_SYNC, NO_TLS,
+    self, Cursor, Database, DatabaseFlags, Environment, RoTransaction, RwTransaction, Transaction,
+    WriteFlags, NO_LOCK, NO_OVERWRITE, NO_SYNC, NO_TLS, READ_ONLY,
   };
+  use lmdb_sys;
   use resettable::Resettable;
   use sha2::Sha256;
-  use std::collections::{BinaryHeap, HashMap};
+  use std::cell::RefCell;
+  use std::cmp::min;
+  use std::collections::{BinaryHeap, HashMap, HashSet};
   use std::fmt;
-  use std::path::Path;
-  use std::sync::Arc;
+  use std::fs;
+  use std::io::{self, Read, Seek, SeekFrom, Write};
+  use std::mem;
+  use std::ops::{Deref, DerefMut};
+  use std::path::{Path, PathBuf};
+  use std::process;
+  use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+  use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
   use std::time;
+  use zstd;
 
   use super::super::EMPTY_FINGERPRINT;
-  use super::MAX_LOCAL_STORE_SIZE_BYTES;
+  use super::{
+    byte_range, default_max_map_size_bytes, DEFAULT_LARGE_FILE_THRESHOLD_BYTES,
+    MAX_LOCAL_STORE_SIZE_BYTES,
+  };
   use pool::ResettablePool;
 
   #[derive(Clone)]
   pub struct ByteStore {
     inner: Arc<InnerStore>,
+    // If set, blobs of at least this many bytes are stored zstd-compressed (if that makes them
+    // smaller).
+    compression_threshold_bytes: Option<usize>,
+    // Files of at least this many bytes are stored in the large file store.
+    large_file_threshold_bytes: usize,
+    // A read-only store which is consulted for anything not found in this one.
+    fallback: Option<Box<ByteStore>>,
   }
 
-  struct InnerStore {
-    pool: Arc<ResettablePool>,
-    // Store directories separately from files because:
-    //  1. They may have different lifetimes.
-    //  2. It's nice to know whether we should be able to parse something as a proto.
-    file_dbs: Resettable<Result<Arc<ShardedLmdb>, String>>,
-    directory_dbs: Resettable<Result<Arc<ShardedLmdb>, String>>,
-  }
+  // The marker stored in the encodings database for a compressed blob. Blobs without a marker
+  // (including all of those stored before compression was supported) are stored raw.
+  const ZSTD_ENCODING: u8 = 1;
 
-  impl ByteStore {
-    pub fn new<P: AsRef<Path>>(path: P, pool: Arc<ResettablePool>) -> Result<ByteStore, String> {
-      let root = path.as_ref();
-      let files_root = root.join("files");
+  // The zstd compression level to use: 3 is zstd's default, and a good balance between speed and
+  // compression ratio.
+  const ZSTD_LEVEL: i32 = 3;
+
+  // The marker stored in the encodings database for a file which is stored in the large file store
+  // rather than in LMDB. Its content in LMDB is its size, as a little-endian u64.
+  const LARGE_FILE_ENCODING: u8 = 2;
+
+  // The types of pinned entries, as stored in the pin database.
+  const PIN_FILE: u8 = 0;
+  const PIN_DIRECTORY: u8 = 1;
+
+  // Used to give temporary files in the large file store unique names within this process.
+  static TEMP_FILE_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+  ///
+  /// Why a write to the store failed.
+  ///
+  #[derive(Clone, Debug, Eq, PartialEq)]
+  pub enum StoreError {
+    // The shard which the entry of the given type with the given fingerprint belongs in is full,
+    // and has already grown to its maximum size: garbage collecting it may make space.
+    ShardFull(EntryType, Fingerprint, String),
+    Other(String),
+  }
+
+  impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match *self {
+        StoreError::ShardFull(_, _, ref message) | StoreError::Other(ref message) => {
+          write!(f, "{}", message)
+        }
+      }
+    }
+  }
+
+  impl From<String> for StoreError {
+    fn from(err: String) -> StoreError {
+      StoreError::Other(err)
+    }
+  }
+
+  struct InnerStore {
+    pool: Arc<ResettablePool>,
+    // Store directories separately from files because:
+    //  1. They may have different lifetimes.
+    //  2. It's nice to know whether we should be able to parse something as a proto.
+    file_dbs: Resettable<Result<Arc<ShardedLmdb>, String>>,
+    directory_dbs: Resettable<Result<Arc<ShardedLmdb>, String>>,
+    // Files which are too big to comfortably be stored in LMDB, sharded in the same way as the
+    // LMDB stores, and named by their fingerprint. Each is referenced by an entry in file_dbs.
+    large_files_root: PathBuf,
+    pin_db: Resettable<Result<Arc<PinLmdb>, String>>,
+    root: PathBuf,
+    read_only: bool,
+    config: LmdbConfig,
+  }
+
+  impl InnerStore {
+    fn new(
+      root: &Path,
+      pool: Arc<ResettablePool>,
+      read_only: bool,
+      config: LmdbConfig,
+    ) -> InnerStore {
+      let files_root = root.join("files");
       let directories_root = root.join("directories");
-      Ok(ByteStore {
-        inner: Arc::new(InnerStore {
-          pool: pool,
-          file_dbs: Resettable::new(move || ShardedLmdb::new(&files_root).map(Arc::new)),
-          directory_dbs: Resettable::new(move || ShardedLmdb::new(&directories_root).map(Arc::new)),
+      let pins_root = root.join("pins");
+      InnerStore {
+        pool: pool,
+        file_dbs: Resettable::new(move || {
+          ShardedLmdb::new(&files_root, read_only, config).map(Arc::new)
+        }),
+        directory_dbs: Resettable::new(move || {
+          ShardedLmdb::new(&directories_root, read_only, config).map(Arc::new)
         }),
+        large_files_root: root.join("large_files"),
+        pin_db: Resettable::new(move || PinLmdb::new(&pins_root).map(Arc::new)),
+        root: root.to_owned(),
+        read_only: read_only,
+        config: config,
+      }
+    }
+  }
+
+  ///
+  /// How the LMDB stores of a local store are laid out.
+  ///
+  #[derive(Clone, Copy, Debug)]
+  pub struct LmdbConfig {
+    // The number of LMDB environments each type of entry is sharded across, which allows that many
+    // concurrent writers. A power of two, no greater than 16.
+    shard_count: usize,
+    // The size of each shard's map when it is opened. This is reserved as address space, but is
+    // only allocated on disk as it is used.
+    map_size_bytes: usize,
+    // The size to which each shard's map may grow when it fills up.
+    max_map_size_bytes: usize,
+  }
+
+  impl LmdbConfig {
+    pub fn new(
+      shard_count: usize,
+      map_size_bytes: usize,
+      max_map_size_bytes: usize,
+    ) -> Result<LmdbConfig, String> {
+      if !shard_count.is_power_of_two() || shard_count > 16 {
+        return Err(format!(
+          "The local store shard count must be a power of two no greater than 16, but was {}",
+          shard_count
+        ));
+      }
+      if max_map_size_bytes < map_size_bytes {
+        return Err(format!(
+          "The local store's maximum map size ({} bytes) must be at least its initial map size \
+           ({} bytes)",
+          max_map_size_bytes, map_size_bytes
+        ));
+      }
+      Ok(LmdbConfig {
+        shard_count,
+        map_size_bytes,
+        max_map_size_bytes,
+      })
+    }
+  }
+
+  impl Default for LmdbConfig {
+    fn default() -> LmdbConfig {
+      LmdbConfig {
+        shard_count: 16,
+        map_size_bytes: MAX_LOCAL_STORE_SIZE_BYTES,
+        max_map_size_bytes: default_max_map_size_bytes(16, MAX_LOCAL_STORE_SIZE_BYTES),
+      }
+    }
+  }
+
+  impl ByteStore {
+    pub fn new<P: AsRef<Path>>(path: P, pool: Arc<ResettablePool>) -> Result<ByteStore, String> {
+      Self::new_with_mode(path.as_ref(), pool, false)
+    }
+
+    ///
+    /// Opens an existing store which will only be read from, such as a shared cache on a read-only
+    /// mount. Nothing else may write to the store while it is open.
+    ///
+    /// Attempts to write to (or lease, pin, or garbage collect from) the store fail.
+    ///
+    pub fn new_read_only<P: AsRef<Path>>(
+      path: P,
+      pool: Arc<ResettablePool>,
+    ) -> Result<ByteStore, String> {
+      Self::new_with_mode(path.as_ref(), pool, true)
+    }
+
+    fn new_with_mode(
+      root: &Path,
+      pool: Arc<ResettablePool>,
+      read_only: bool,
+    ) -> Result<ByteStore, String> {
+      Ok(ByteStore {
+        inner: Arc::new(InnerStore::new(
+          root,
+          pool,
+          read_only,
+          LmdbConfig::default(),
+        )),
+        compression_threshold_bytes: None,
+        large_file_threshold_bytes: DEFAULT_LARGE_FILE_THRESHOLD_BYTES,
+        fallback: None,
+      })
+    }
+
+    ///
+    /// Lay out the LMDB stores (including those of the fallback, if any) as described by the given
+    /// config, rather than the default. This must be called before the store is used.
+    ///
+    pub fn with_lmdb_config(self, config: LmdbConfig) -> ByteStore {
+      ByteStore {
+        inner: Arc::new(InnerStore::new(
+          &self.inner.root,
+          self.inner.pool.clone(),
+          self.inner.read_only,
+          config,
+        )),
+        fallback: self
+          .fallback
+          .map(|fallback| Box::new(fallback.with_lmdb_config(config))),
+        ..self
+      }
+    }
+
+    ///
+    /// Consult the given read-only store for anything which isn't found in this one. Everything
+    /// is written only to this store, and garbage collection and stats only consider this store.
+    ///
+    /// The fallback's LMDB stores are laid out in the same way as this store's. Its own fallback
+    /// (if any) is not consulted.
+    ///
+    pub fn with_read_only_fallback(self, fallback: ByteStore) -> Result<ByteStore, String> {
+      if !fallback.inner.read_only {
+        return Err("A fallback local store must be opened read-only".to_string());
+      }
+      let config = self.inner.config;
+      Ok(ByteStore {
+        fallback: Some(Box::new(fallback.with_lmdb_config(config))),
+        ..self
       })
     }
 
+    ///
+    /// Store files of at least the given size in the large file store, rather than in LMDB.
+    ///
+    pub fn with_large_file_threshold(self, threshold_bytes: usize) -> ByteStore {
+      ByteStore {
+        large_file_threshold_bytes: threshold_bytes,
+        ..self
+      }
+    }
+
+    ///
+    /// Store blobs of at least the given size zstd-compressed. Blobs are decompressed transparently
+    /// when they are loaded, and blobs which were stored uncompressed remain loadable.
+    ///
+    pub fn with_compression(self, threshold_bytes: usize) -> ByteStore {
+      ByteStore {
+        compression_threshold_bytes: Some(threshold_bytes),
+        ..self
+      }
+    }
+
     pub fn reset_prefork(&self) {
       self.inner.file_dbs.reset();
       self.inner.directory_dbs.reset();
+      self.inner.pin_db.reset();
+      if let Some(ref fallback) = self.fallback {
+        fallback.reset_prefork();
+      }
+    }
+
+    fn check_writable(&self) -> Result<(), String> {
+      if self.inner.read_only {
+        Err("Cannot modify a read-only local store".to_string())
+      } else {
+        Ok(())
+      }
+    }
+
+    fn dbs(&self, entry_type: EntryType) -> &Resettable<Result<Arc<ShardedLmdb>, String>> {
+      match entry_type {
+        EntryType::File => &self.inner.file_dbs,
+        EntryType::Directory => &self.inner.directory_dbs,
+      }
     }
 
     // Note: This performs IO on the calling thread. Hopefully the IO is small enough not to matter.
     pub fn entry_type(&self, fingerprint: &Fingerprint) -> Result<Option<EntryType>, String> {
+      match self.own_entry_type(fingerprint)? {
+        Some(entry_type) => Ok(Some(entry_type)),
+        None => match self.fallback {
+          Some(ref fallback) => fallback.own_entry_type(fingerprint),
+          None => Ok(None),
+        },
+      }
+    }
+
+    fn own_entry_type(&self, fingerprint: &Fingerprint) -> Result<Option<EntryType>, String> {
       {
-        let (env, directory_database, _) = self.inner.directory_dbs.get()?.get(fingerprint);
+        let (env, directory_database, _, _) = self.inner.directory_dbs.get()?.get(fingerprint);
         let txn = env
           .begin_ro_txn()
           .map_err(|err| format!("Failed to begin read transaction: {:?}", err))?;
@@ -681,7 +2098,7 @@ mod local {
           }
         };
       }
-      let (env, file_database, _) = self.inner.file_dbs.get()?.get(fingerprint);
+      let (env, file_database, _, _) = self.inner.file_dbs.get()?.get(fingerprint);
       let txn = env
         .begin_ro_txn()
         .map_err(|err| format!("Failed to begin read transaction: {}", err))?;
@@ -702,9 +2119,10 @@ mod local {
       &self,
       digests: Ds,
     ) -> Result<(), String> {
+      self.check_writable()?;
       let until = Self::default_lease_until_secs_since_epoch();
       for digest in digests {
-        let (env, _, lease_database) = self.inner.file_dbs.get()?.get(&digest.0);
+        let (env, _, lease_database, _) = self.inner.file_dbs.get()?.get(&digest.0);
         env
           .begin_rw_txn()
           .and_then(|mut txn| self.lease(lease_database, &digest.0, until, &mut txn))
@@ -732,30 +2150,160 @@ mod local {
       txn.put(database, &fingerprint.as_ref(), &buf, WriteFlags::empty())
     }
 
+    ///
+    /// Pins the entry with the given digest under the given label, replacing any existing pin with
+    /// that label. Pinned entries are never garbage collected, but it is up to the caller to
+    /// protect anything reachable from a pinned Directory: see shrink_protecting.
+    ///
+    pub fn pin(&self, label: &str, entry_type: EntryType, digest: Digest) -> Result<(), String> {
+      self.check_writable()?;
+      let mut value = Vec::with_capacity(1 + FINGERPRINT_SIZE + 8);
+      value.push(match entry_type {
+        EntryType::File => PIN_FILE,
+        EntryType::Directory => PIN_DIRECTORY,
+      });
+      value.extend_from_slice(digest.0.as_ref());
+      let mut size = [0; 8];
+      LittleEndian::write_u64(&mut size, digest.1 as u64);
+      value.extend_from_slice(&size);
+
+      let pin_db = self.inner.pin_db.get()?;
+      pin_db
+        .env
+        .begin_rw_txn()
+        .and_then(|mut txn| {
+          txn.put(pin_db.database, &label, &value, WriteFlags::empty())?;
+          txn.commit()
+        })
+        .map_err(|err| format!("Error pinning {:?} as {}: {}", digest, label, err))
+    }
+
+    ///
+    /// Removes the pin with the given label, returning false if there was no such pin.
+    ///
+    pub fn unpin(&self, label: &str) -> Result<bool, String> {
+      self.check_writable()?;
+      let pin_db = self.inner.pin_db.get()?;
+      let del_res = pin_db.env.begin_rw_txn().and_then(|mut txn| {
+        txn.del(pin_db.database, &label, None)?;
+        txn.commit()
+      });
+      match del_res {
+        Ok(()) => Ok(true),
+        Err(NotFound) => Ok(false),
+        Err(err) => Err(format!("Error unpinning {}: {}", label, err)),
+      }
+    }
+
+    ///
+    /// Every pin, as its label, the type of the pinned entry, and its digest, ordered by label.
+    ///
+    pub fn pins(&self) -> Result<Vec<(String, EntryType, Digest)>, String> {
+      let pin_db = self.inner.pin_db.get()?;
+      let txn = pin_db
+        .env
+        .begin_ro_txn()
+        .map_err(|err| format!("Failed to begin read transaction: {}", err))?;
+      let mut cursor = txn
+        .open_ro_cursor(pin_db.database)
+        .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
+      let mut pins = Vec::new();
+      for (key, value) in cursor.iter() {
+        let label = String::from_utf8(key.to_vec())
+          .map_err(|err| format!("Pin label was not valid UTF-8: {}", err))?;
+        if value.len() != 1 + FINGERPRINT_SIZE + 8 {
+          return Err(format!("Pin {} had malformed value {:?}", label, value));
+        }
+        let entry_type = match value[0] {
+          PIN_FILE => EntryType::File,
+          PIN_DIRECTORY => EntryType::Directory,
+          other => return Err(format!("Pin {} had unknown entry type {}", label, other)),
+        };
+        let digest = Digest(
+          Fingerprint::from_bytes_unsafe(&value[1..1 + FINGERPRINT_SIZE]),
+          LittleEndian::read_u64(&value[1 + FINGERPRINT_SIZE..]) as usize,
+        );
+        pins.push((label, entry_type, digest));
+      }
+      Ok(pins)
+    }
+
     ///
     /// Attempts to shrink the stored files to be no bigger than target_bytes
     /// (excluding lmdb overhead).
     ///
     /// Returns the size it was shrunk to, which may be larger than target_bytes.
     ///
+    /// Sizes are those of the blobs as stored, i.e. after compression.
+    ///
     /// Ignores directories. TODO: Shrink directories.
     ///
     /// TODO: Use LMDB database statistics when lmdb-rs exposes them.
     ///
     pub fn shrink(&self, target_bytes: usize) -> Result<usize, String> {
+      self.shrink_protecting(target_bytes, &HashSet::new())
+    }
+
+    ///
+    /// As shrink, but treats the given fingerprints as though they were leased, so never collects
+    /// them.
+    ///
+    pub fn shrink_protecting(
+      &self,
+      target_bytes: usize,
+      protected: &HashSet<Fingerprint>,
+    ) -> Result<usize, String> {
+      self.check_writable()?;
       let mut used_bytes: usize = 0;
       let mut fingerprints_by_expired_ago = BinaryHeap::new();
 
-      self.aged_fingerprints(
-        EntryType::File,
-        &mut used_bytes,
-        &mut fingerprints_by_expired_ago,
-      )?;
-      self.aged_fingerprints(
-        EntryType::Directory,
+      for &entry_type in &[EntryType::File, EntryType::Directory] {
+        Self::aged_fingerprints(
+          entry_type,
+          &self.dbs(entry_type).get()?.all_lmdbs(),
+          protected,
+          &mut used_bytes,
+          &mut fingerprints_by_expired_ago,
+        )?;
+      }
+      self.collect_until(target_bytes, used_bytes, fingerprints_by_expired_ago)
+    }
+
+    ///
+    /// As shrink_protecting, but only considers the shard of the given type which holds the given
+    /// fingerprint, which is shrunk to half of the size to which shards may grow.
+    ///
+    pub fn shrink_full_shard(
+      &self,
+      entry_type: EntryType,
+      fingerprint: &Fingerprint,
+      protected: &HashSet<Fingerprint>,
+    ) -> Result<usize, String> {
+      self.check_writable()?;
+      let mut used_bytes: usize = 0;
+      let mut fingerprints_by_expired_ago = BinaryHeap::new();
+
+      let dbs = self.dbs(entry_type).get()?;
+      Self::aged_fingerprints(
+        entry_type,
+        &[dbs.get(fingerprint)],
+        protected,
         &mut used_bytes,
         &mut fingerprints_by_expired_ago,
       )?;
+      self.collect_until(dbs.max_map_size_bytes / 2, used_bytes, fingerprints_by_expired_ago)
+    }
+
+    ///
+    /// Collects the entries which expired longest ago, until the given number of used bytes has
+    /// shrunk to target_bytes, or there is nothing left which can be collected.
+    ///
+    fn collect_until(
+      &self,
+      target_bytes: usize,
+      mut used_bytes: usize,
+      mut fingerprints_by_expired_ago: BinaryHeap<AgedFingerprint>,
+    ) -> Result<usize, String> {
       while used_bytes > target_bytes {
         let aged_fingerprint = fingerprints_by_expired_ago
           .pop()
@@ -764,44 +2312,64 @@ mod local {
           // Ran out of expired blobs - everything remaining is leased and cannot be collected.
           return Ok(used_bytes);
         }
-        let lmdbs = match aged_fingerprint.entry_type {
-          EntryType::File => self.inner.file_dbs.clone(),
-          EntryType::Directory => self.inner.directory_dbs.clone(),
-        };
-        let (env, database, lease_database) = lmdbs.get()?.get(&aged_fingerprint.fingerprint);
+        debug!(
+          "Garbage collecting {:?} {} ({} bytes), whose lease expired {} seconds ago",
+          aged_fingerprint.entry_type,
+          aged_fingerprint.fingerprint,
+          aged_fingerprint.size_bytes,
+          aged_fingerprint.expired_seconds_ago
+        );
+        let (env, database, lease_database, encoding_database) = self
+          .dbs(aged_fingerprint.entry_type)
+          .get()?
+          .get(&aged_fingerprint.fingerprint);
         {
           env
             .begin_rw_txn()
             .and_then(|mut txn| {
               txn.del(database, &aged_fingerprint.fingerprint.as_ref(), None)?;
 
-              txn
-                .del(lease_database, &aged_fingerprint.fingerprint.as_ref(), None)
-                .or_else(|err| match err {
-                  NotFound => Ok(()),
-                  err => Err(err),
-                })?;
+              for db in [Some(lease_database), encoding_database]
+                .iter()
+                .filter_map(|db| *db)
+              {
+                txn
+                  .del(db, &aged_fingerprint.fingerprint.as_ref(), None)
+                  .or_else(|err| match err {
+                    NotFound => Ok(()),
+                    err => Err(err),
+                  })?;
+              }
               used_bytes -= aged_fingerprint.size_bytes;
               txn.commit()
             })
             .map_err(|err| format!("Error garbage collecting: {}", err))?;
         }
+        if aged_fingerprint.is_large_file {
+          let path = self.large_file_path(&aged_fingerprint.fingerprint);
+          match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+              return Err(format!(
+                "Error garbage collecting large file {:?}: {}",
+                path, err
+              ))
+            }
+          }
+        }
       }
       Ok(used_bytes)
     }
 
     fn aged_fingerprints(
-      &self,
       entry_type: EntryType,
+      lmdbs: &[(Arc<ShardEnv>, Database, Database, Option<Database>)],
+      protected: &HashSet<Fingerprint>,
       used_bytes: &mut usize,
       fingerprints_by_expired_ago: &mut BinaryHeap<AgedFingerprint>,
     ) -> Result<(), String> {
-      let database = match entry_type {
-        EntryType::File => self.inner.file_dbs.clone(),
-        EntryType::Directory => self.inner.directory_dbs.clone(),
-      };
-
-      for &(ref env, ref database, ref lease_database) in &database.get()?.all_lmdbs() {
+      for &(ref env, ref database, ref lease_database, ref encoding_database) in lmdbs {
         let txn = env
           .begin_ro_txn()
           .map_err(|err| format!("Error beginning transaction to garbage collect: {}", err))?;
@@ -809,19 +2377,15 @@ mod local {
           .open_ro_cursor(*database)
           .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
         for (key, bytes) in cursor.iter() {
-          *used_bytes += bytes.len();
+          let (size_bytes, is_large_file) = stored_size(&*txn, *encoding_database, key, bytes);
+          *used_bytes += size_bytes;
 
           // Random access into the lease_database is slower than iterating, but hopefully garbage
           // collection is rare enough that we can get away with this, rather than do two passes
           // here (either to populate leases into pre-populated AgedFingerprints, or to read sizes
           // when we delete from lmdb to track how much we've freed).
-          let lease_until_unix_timestamp = txn
-            .get(*lease_database, &key)
-            .map(|b| LittleEndian::read_u64(b))
-            .unwrap_or_else(|e| match e {
-              NotFound => 0,
-              e => panic!("Error reading lease, probable lmdb corruption: {:?}", e),
-            });
+          let lease_until_unix_timestamp =
+            lease_until_secs_since_epoch(&*txn, *lease_database, key);
 
           let leased_until =
             time::UNIX_EPOCH + time::Duration::from_secs(lease_until_unix_timestamp);
@@ -832,129 +2396,599 @@ mod local {
               // 0 indicates unleased.
               .unwrap_or(0);
 
+          let fingerprint = Fingerprint::from_bytes_unsafe(key);
+          let expired_seconds_ago = if protected.contains(&fingerprint) {
+            0
+          } else {
+            expired_seconds_ago
+          };
+
           fingerprints_by_expired_ago.push(AgedFingerprint {
             expired_seconds_ago: expired_seconds_ago,
-            fingerprint: Fingerprint::from_bytes_unsafe(key),
-            size_bytes: bytes.len(),
+            fingerprint: fingerprint,
+            size_bytes: size_bytes,
             entry_type: entry_type,
+            is_large_file: is_large_file,
           });
         }
       }
       Ok(())
     }
 
+    pub fn stats(&self) -> Result<StoreStats, String> {
+      Ok(StoreStats {
+        files: self.entry_type_stats(EntryType::File)?,
+        directories: self.entry_type_stats(EntryType::Directory)?,
+        hot_cache: HotCacheStats::default(),
+      })
+    }
+
+    pub fn databases_stats(&self) -> Result<DatabasesStats, String> {
+      let (files, file_leases, file_encodings) = Self::shards_stats(&self.inner.file_dbs.get()?)?;
+      let (directories, directory_leases, directory_encodings) =
+        Self::shards_stats(&self.inner.directory_dbs.get()?)?;
+      Ok(DatabasesStats {
+        files,
+        file_leases,
+        file_encodings,
+        directories,
+        directory_leases,
+        directory_encodings,
+      })
+    }
+
+    ///
+    /// Sums the stats of the content, lease and encoding databases of each shard.
+    ///
+    fn shards_stats(
+      dbs: &ShardedLmdb,
+    ) -> Result<(DatabaseStats, DatabaseStats, DatabaseStats), String> {
+      let mut content_stats = DatabaseStats::default();
+      let mut lease_stats = DatabaseStats::default();
+      let mut encoding_stats = DatabaseStats::default();
+      for (_, (env, content_database, lease_database, encoding_database)) in dbs.shards() {
+        let txn = env
+          .begin_ro_txn()
+          .map_err(|err| format!("Error beginning transaction to read stats: {}", err))?;
+        content_stats.merge(&database_stats(&*txn, content_database)?);
+        lease_stats.merge(&database_stats(&*txn, lease_database)?);
+        if let Some(encoding_database) = encoding_database {
+          encoding_stats.merge(&database_stats(&*txn, encoding_database)?);
+        }
+      }
+      Ok((content_stats, lease_stats, encoding_stats))
+    }
+
+    fn entry_type_stats(&self, entry_type: EntryType) -> Result<EntryTypeStats, String> {
+      let database = match entry_type {
+        EntryType::File => self.inner.file_dbs.clone(),
+        EntryType::Directory => self.inner.directory_dbs.clone(),
+      };
+      let now_secs = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("Surely you're not before the unix epoch?")
+        .as_secs();
+
+      let mut stats = EntryTypeStats::default();
+      for (shard, (env, database, lease_database, encoding_database)) in database.get()?.shards() {
+        let txn = env
+          .begin_ro_txn()
+          .map_err(|err| format!("Error beginning transaction to read stats: {}", err))?;
+        let mut shard_stats = EntryStats::default();
+        {
+          let mut cursor = txn
+            .open_ro_cursor(database)
+            .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
+          for (key, bytes) in cursor.iter() {
+            let (size_bytes, _) = stored_size(&*txn, encoding_database, key, bytes);
+            shard_stats.record(
+              size_bytes,
+              lease_until_secs_since_epoch(&*txn, lease_database, key),
+              now_secs,
+            );
+          }
+        }
+        stats.total.merge(&shard_stats);
+        stats.shards.insert(shard, shard_stats);
+      }
+      Ok(stats)
+    }
+
     pub fn store_bytes(
       &self,
       entry_type: EntryType,
       bytes: Bytes,
       initial_lease: bool,
-    ) -> BoxFuture<Fingerprint, String> {
-      let dbs = match entry_type {
-        EntryType::Directory => self.inner.directory_dbs.clone(),
-        EntryType::File => self.inner.file_dbs.clone(),
-      };
+    ) -> BoxFuture<Fingerprint, StoreError> {
+      let bytestore = self.clone();
+      self
+        .inner
+        .pool
+        .spawn_fn(move || bytestore.store_bytes_sync(entry_type, &bytes, initial_lease))
+        .to_boxed()
+    }
 
+    ///
+    /// Stores the file at the given path. Files which belong in the large file store are streamed
+    /// into it (and hashed as they are streamed), rather than being read into memory.
+    ///
+    pub fn store_file_from_path(
+      &self,
+      path: PathBuf,
+      initial_lease: bool,
+    ) -> BoxFuture<Digest, StoreError> {
       let bytestore = self.clone();
       self
         .inner
         .pool
         .spawn_fn(move || {
-          let fingerprint = {
-            let mut hasher = Sha256::default();
-            hasher.input(&bytes);
-            Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
-          };
-
-          let (env, content_database, lease_database) = dbs.get()?.get(&fingerprint);
-          let put_res = env.begin_rw_txn().and_then(|mut txn| {
-            txn.put(content_database, &fingerprint, &bytes, NO_OVERWRITE)?;
-            if initial_lease {
-              bytestore.lease(
-                lease_database,
-                &fingerprint,
-                Self::default_lease_until_secs_since_epoch(),
-                &mut txn,
-              )?;
-            }
-            txn.commit()
-          });
-
-          match put_res {
-            Ok(()) => Ok(fingerprint),
-            Err(KeyExist) => Ok(fingerprint),
-            Err(err) => Err(format!(
-              "Error storing fingerprint {}: {}",
-              fingerprint, err
-            )),
+          let mut file =
+            fs::File::open(&path).map_err(|e| format!("Error opening {:?}: {}", path, e))?;
+          let len = file
+            .metadata()
+            .map_err(|e| format!("Error reading metadata of {:?}: {}", path, e))?
+            .len() as usize;
+          if len < bytestore.large_file_threshold_bytes {
+            let mut bytes = Vec::with_capacity(len);
+            file
+              .read_to_end(&mut bytes)
+              .map_err(|e| format!("Error reading {:?}: {}", path, e))?;
+            return bytestore
+              .store_bytes_sync(EntryType::File, &bytes, initial_lease)
+              .map(|fingerprint| Digest(fingerprint, bytes.len()));
           }
+          bytestore.store_large_file(initial_lease, |temp_file| {
+            let mut hasher = WriterHasher::new(temp_file);
+            io::copy(&mut file, &mut hasher)?;
+            Ok(hasher.finish())
+          })
         })
         .to_boxed()
     }
 
-    pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
+    fn store_bytes_sync(
       &self,
       entry_type: EntryType,
-      fingerprint: Fingerprint,
-      f: F,
-    ) -> BoxFuture<Option<T>, String> {
-      if fingerprint == EMPTY_FINGERPRINT {
-        // Avoid expensive I/O for this super common case.
-        // Also, this allows some client-provided operations (like merging snapshots) to work
-        // without needing to first store the empty snapshot.
-        return future::ok(Some(f(Bytes::new()))).to_boxed();
-      }
+      bytes: &[u8],
+      initial_lease: bool,
+    ) -> Result<Fingerprint, StoreError> {
+      self.check_writable()?;
 
-      let dbs = match entry_type {
-        EntryType::Directory => self.inner.directory_dbs.clone(),
-        EntryType::File => self.inner.file_dbs.clone(),
+      let fingerprint = {
+        let mut hasher = Sha256::default();
+        hasher.input(bytes);
+        Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
       };
 
-      self
-        .inner
-        .pool
-        .spawn_fn(move || {
-          let (env, db, _) = dbs.get()?.get(&fingerprint);
-          let ro_txn = env
-            .begin_ro_txn()
-            .map_err(|err| format!("Failed to begin read transaction: {}", err));
-          ro_txn.and_then(|txn| match txn.get(db, &fingerprint) {
-            Ok(bytes) => Ok(Some(f(Bytes::from(bytes)))),
-            Err(NotFound) => Ok(None),
-            Err(err) => Err(format!(
-              "Error loading fingerprint {}: {}",
-              fingerprint, err,
-            )),
+      if entry_type == EntryType::File && bytes.len() >= self.large_file_threshold_bytes {
+        return self
+          .store_large_file(initial_lease, |temp_file| {
+            temp_file.write_all(bytes)?;
+            Ok(fingerprint)
           })
-        })
-        .to_boxed()
-    }
-  }
+          .map(|digest| digest.0);
+      }
 
-  // Each LMDB directory can have at most one concurrent writer.
-  // We use this type to shard storage into 16 LMDB directories, based on the first 4 bits of the
-  // fingerprint being stored, so that we can write to them in parallel.
-  #[derive(Clone)]
-  struct ShardedLmdb {
-    // First Database is content, second is leases.
-    lmdbs: HashMap<u8, (Arc<Environment>, Database, Database)>,
-  }
+      // Compressed bytes are only worth storing if they are actually smaller.
+      let compressed = match self.compression_threshold_bytes {
+        Some(threshold) if bytes.len() >= threshold => {
+          let compressed = zstd::encode_all(bytes, ZSTD_LEVEL)
+            .map_err(|e| format!("Error compressing fingerprint {}: {}", fingerprint, e))?;
+          if compressed.len() < bytes.len() {
+            Some(compressed)
+          } else {
+            None
+          }
+        }
+        _ => None,
+      };
 
-  impl ShardedLmdb {
-    pub fn new(root_path: &Path) -> Result<ShardedLmdb, String> {
-      debug!("Initializing ShardedLmdb at root {:?}", root_path);
-      let mut lmdbs = HashMap::new();
+      let dbs = self.dbs(entry_type).get()?;
+      let (env, content_database, lease_database, encoding_database) = dbs.get(&fingerprint);
+      let put_res = dbs.growing_if_full(&fingerprint, || {
+        env.begin_rw_txn().and_then(|mut txn| {
+          match compressed {
+            Some(ref compressed) => {
+              txn.put(content_database, &fingerprint, compressed, NO_OVERWRITE)?;
+              txn.put(
+                encoding_database.expect("Writable stores have an encodings database"),
+                &fingerprint,
+                &[ZSTD_ENCODING],
+                WriteFlags::empty(),
+              )?;
+            }
+            None => txn.put(content_database, &fingerprint, &bytes, NO_OVERWRITE)?,
+          }
+          if initial_lease {
+            self.lease(
+              lease_database,
+              &fingerprint,
+              Self::default_lease_until_secs_since_epoch(),
+              &mut txn,
+            )?;
+          }
+          txn.commit()
+        })
+      });
 
-      for b in 0x00..0x10 {
-        let key = b << 4;
+      match put_res {
+        Ok(()) => Ok(fingerprint),
+        Err(KeyExist) => Ok(fingerprint),
+        Err(MapFull) => Err(StoreError::ShardFull(
+          entry_type,
+          fingerprint,
+          dbs.map_full_error(&fingerprint),
+        )),
+        Err(err) => Err(StoreError::Other(format!(
+          "Error storing fingerprint {}: {}",
+          fingerprint, err
+        ))),
+      }
+    }
 
-        let dirname = {
-          let mut s = String::new();
-          fmt::Write::write_fmt(&mut s, format_args!("{:x}", key)).unwrap();
-          s[0..1].to_owned()
-        };
+    ///
+    /// Writes a file into the large file store using the given function (which returns the
+    /// file's fingerprint), and references it from LMDB.
+    ///
+    /// The file is written to a temporary path before being moved into place, so that concurrent
+    /// readers never observe a partially written file.
+    ///
+    fn store_large_file<F: FnOnce(&mut fs::File) -> io::Result<Fingerprint>>(
+      &self,
+      initial_lease: bool,
+      write: F,
+    ) -> Result<Digest, StoreError> {
+      self.check_writable()?;
+      let root = &self.inner.large_files_root;
+      super::super::safe_create_dir_all(root)?;
+      let temp_path = root.join(format!(
+        ".tmp-{}-{}",
+        process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+      ));
+      let result = fs::File::create(&temp_path)
+        .and_then(|mut temp_file| {
+          let fingerprint = write(&mut temp_file)?;
+          let len = temp_file.metadata()?.len() as usize;
+          Ok(Digest(fingerprint, len))
+        })
+        .map_err(|e| StoreError::Other(format!("Error writing to large file store: {}", e)))
+        .and_then(|digest| {
+          let path = self.large_file_path(&digest.0);
+          path
+            .parent()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Large files have a parent"))
+            .and_then(super::super::safe_create_dir_all_ioerror)
+            .and_then(|()| fs::rename(&temp_path, &path))
+            .map_err(|e| format!("Error adding {:?} to large file store: {}", digest, e))?;
+          self.record_large_file(digest, initial_lease)?;
+          Ok(digest)
+        });
+      if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+      }
+      result
+    }
+
+    fn record_large_file(&self, digest: Digest, initial_lease: bool) -> Result<(), StoreError> {
+      let file_dbs = self.inner.file_dbs.get()?;
+      let (env, content_database, lease_database, encoding_database) = file_dbs.get(&digest.0);
+      let mut size = [0; 8];
+      LittleEndian::write_u64(&mut size, digest.1 as u64);
+      let put_res = file_dbs.growing_if_full(&digest.0, || {
+        env.begin_rw_txn().and_then(|mut txn| {
+          txn.put(content_database, &digest.0, &size, NO_OVERWRITE)?;
+          txn.put(
+            encoding_database.expect("Writable stores have an encodings database"),
+            &digest.0,
+            &[LARGE_FILE_ENCODING],
+            WriteFlags::empty(),
+          )?;
+          if initial_lease {
+            self.lease(
+              lease_database,
+              &digest.0,
+              Self::default_lease_until_secs_since_epoch(),
+              &mut txn,
+            )?;
+          }
+          txn.commit()
+        })
+      });
+
+      match put_res {
+        Ok(()) => Ok(()),
+        Err(KeyExist) => {
+          // The file was already stored. If it was stored in LMDB (because it was stored before
+          // the threshold was lowered), the copy we just wrote is redundant.
+          if !self.is_large_file(&digest.0)? {
+            let _ = fs::remove_file(self.large_file_path(&digest.0));
+          }
+          Ok(())
+        }
+        Err(MapFull) => Err(StoreError::ShardFull(
+          EntryType::File,
+          digest.0,
+          file_dbs.map_full_error(&digest.0),
+        )),
+        Err(err) => Err(StoreError::Other(format!(
+          "Error storing digest {:?}: {}",
+          digest, err
+        ))),
+      }
+    }
+
+    fn large_file_path(&self, fingerprint: &Fingerprint) -> PathBuf {
+      let hex = fingerprint.to_hex();
+      self.inner.large_files_root.join(&hex[0..1]).join(hex)
+    }
+
+    ///
+    /// Loads at most length bytes of the file with the given fingerprint, starting at offset.
+    /// Files in the large file store are read from disk without being loaded whole.
+    ///
+    pub fn load_file_bytes_range(
+      &self,
+      fingerprint: Fingerprint,
+      offset: usize,
+      length: usize,
+    ) -> BoxFuture<Option<Bytes>, String> {
+      let bytestore = self.clone();
+      self
+        .with_large_file_path(fingerprint, move |path| {
+          let mut bytes = Vec::new();
+          fs::File::open(&path)
+            .and_then(|mut file| {
+              file.seek(SeekFrom::Start(offset as u64))?;
+              file.take(length as u64).read_to_end(&mut bytes)
+            })
+            .map(|_| Bytes::from(bytes))
+            .map_err(|err| format!("Error reading large file {:?}: {}", path, err))
+        })
+        .and_then(move |maybe_bytes| match maybe_bytes {
+          Some(bytes) => future::ok(Some(bytes)).to_boxed(),
+          None => bytestore.load_bytes_with(EntryType::File, fingerprint, move |bytes| {
+            byte_range(&bytes, offset, length)
+          }),
+        })
+        .to_boxed()
+    }
+
+    fn is_large_file(&self, fingerprint: &Fingerprint) -> Result<bool, String> {
+      let (env, _, _, encoding_db) = self.inner.file_dbs.get()?.get(fingerprint);
+      let txn = env
+        .begin_ro_txn()
+        .map_err(|err| format!("Failed to begin read transaction: {}", err))?;
+      match get_encoding(&*txn, encoding_db, fingerprint.as_ref()) {
+        Ok(encoding) => Ok(encoding == [LARGE_FILE_ENCODING]),
+        Err(NotFound) => Ok(false),
+        Err(err) => Err(format!(
+          "Error loading encoding of fingerprint {}: {}",
+          fingerprint, err
+        )),
+      }
+    }
+
+    ///
+    /// If the file with the given fingerprint is in the large file store, returns the result of
+    /// applying f to its path, so that it can be streamed rather than loaded into memory. f is run
+    /// on a thread which is allowed to block.
+    ///
+    pub fn with_large_file_path<
+      T: Send + 'static,
+      F: FnOnce(PathBuf) -> Result<T, String> + Send + 'static,
+    >(
+      &self,
+      fingerprint: Fingerprint,
+      f: F,
+    ) -> BoxFuture<Option<T>, String> {
+      let bytestore = self.clone();
+      self
+        .inner
+        .pool
+        .spawn_fn(move || {
+          if bytestore.is_large_file(&fingerprint)? {
+            return f(bytestore.large_file_path(&fingerprint)).map(Some);
+          }
+          match bytestore.fallback {
+            Some(ref fallback) if fallback.is_large_file(&fingerprint)? => {
+              f(fallback.large_file_path(&fingerprint)).map(Some)
+            }
+            _ => Ok(None),
+          }
+        })
+        .to_boxed()
+    }
+
+    pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
+      &self,
+      entry_type: EntryType,
+      fingerprint: Fingerprint,
+      f: F,
+    ) -> BoxFuture<Option<T>, String> {
+      match self.fallback {
+        Some(ref fallback) => {
+          let fallback = fallback.clone();
+          let f = Arc::new(f);
+          let fallback_f = f.clone();
+          self
+            .load_own_bytes_with(entry_type, fingerprint, move |bytes| f(bytes))
+            .and_then(move |maybe_value| match maybe_value {
+              Some(value) => future::ok(Some(value)).to_boxed(),
+              None => fallback.load_own_bytes_with(entry_type, fingerprint, move |bytes| {
+                fallback_f(bytes)
+              }),
+            })
+            .to_boxed()
+        }
+        None => self.load_own_bytes_with(entry_type, fingerprint, f),
+      }
+    }
+
+    fn load_own_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
+      &self,
+      entry_type: EntryType,
+      fingerprint: Fingerprint,
+      f: F,
+    ) -> BoxFuture<Option<T>, String> {
+      if fingerprint == EMPTY_FINGERPRINT {
+        // Avoid expensive I/O for this super common case.
+        // Also, this allows some client-provided operations (like merging snapshots) to work
+        // without needing to first store the empty snapshot.
+        return future::ok(Some(f(Bytes::new()))).to_boxed();
+      }
+
+      let bytestore = self.clone();
+      self
+        .inner
+        .pool
+        .spawn_fn(move || {
+          bytestore
+            .load_bytes_sync(entry_type, fingerprint)
+            .map(|maybe_bytes| maybe_bytes.map(|bytes| f(bytes)))
+        })
+        .to_boxed()
+    }
+
+    ///
+    /// As load_bytes_with, but returns the bytes themselves.
+    ///
+    /// Note: This performs IO on the calling thread.
+    ///
+    pub fn load_bytes_blocking(
+      &self,
+      entry_type: EntryType,
+      fingerprint: Fingerprint,
+    ) -> Result<Option<Bytes>, String> {
+      if fingerprint == EMPTY_FINGERPRINT {
+        return Ok(Some(Bytes::new()));
+      }
+      match self.load_bytes_sync(entry_type, fingerprint)? {
+        Some(bytes) => Ok(Some(bytes)),
+        None => match self.fallback {
+          Some(ref fallback) => fallback.load_bytes_sync(entry_type, fingerprint),
+          None => Ok(None),
+        },
+      }
+    }
+
+    ///
+    /// Reads a file from the large file store into a buffer of its recorded size, hashing it as it
+    /// is streamed so that a file which has been modified on disk is never returned.
+    ///
+    fn read_large_file(&self, digest: Digest) -> Result<Bytes, String> {
+      let path = self.large_file_path(&digest.0);
+      let mut contents = Vec::with_capacity(digest.1);
+      let fingerprint = fs::File::open(&path)
+        .and_then(|file| {
+          let mut hasher = WriterHasher::new(&mut contents);
+          io::copy(&mut file.take(digest.1 as u64 + 1), &mut hasher)?;
+          Ok(hasher.finish())
+        })
+        .map_err(|err| format!("Error reading large file {:?}: {}", path, err))?;
+      if fingerprint != digest.0 || contents.len() != digest.1 {
+        return Err(format!(
+          "Large file {:?} does not match its digest {:?}",
+          path, digest
+        ));
+      }
+      Ok(Bytes::from(contents))
+    }
+
+    // Note: This performs IO on the calling thread.
+    fn load_bytes_sync(
+      &self,
+      entry_type: EntryType,
+      fingerprint: Fingerprint,
+    ) -> Result<Option<Bytes>, String> {
+      let dbs = match entry_type {
+        EntryType::Directory => self.inner.directory_dbs.clone(),
+        EntryType::File => self.inner.file_dbs.clone(),
+      };
+      let (env, db, _, encoding_db) = dbs.get()?.get(&fingerprint);
+      let ro_txn = env
+        .begin_ro_txn()
+        .map_err(|err| format!("Failed to begin read transaction: {}", err));
+      ro_txn.and_then(|txn| match txn.get(db, &fingerprint) {
+        Ok(bytes) => match get_encoding(&*txn, encoding_db, fingerprint.as_ref()) {
+          Err(NotFound) => Ok(Some(Bytes::from(bytes))),
+          Ok(&[ZSTD_ENCODING]) => zstd::decode_all(bytes)
+            .map(|decompressed| Some(Bytes::from(decompressed)))
+            .map_err(|err| format!("Error decompressing fingerprint {}: {}", fingerprint, err)),
+          Ok(&[LARGE_FILE_ENCODING]) => self
+            .read_large_file(Digest(fingerprint, LittleEndian::read_u64(bytes) as usize))
+            .map(Some),
+          Ok(encoding) => Err(format!(
+            "Unknown encoding {:?} for fingerprint {}",
+            encoding, fingerprint
+          )),
+          Err(err) => Err(format!(
+            "Error loading encoding of fingerprint {}: {}",
+            fingerprint, err
+          )),
+        },
+        Err(NotFound) => Ok(None),
+        Err(err) => Err(format!(
+          "Error loading fingerprint {}: {}",
+          fingerprint, err,
+        )),
+      })
+    }
+  }
+
+  // Each LMDB directory can have at most one concurrent writer.
+  // We use this type to shard storage into (by default 16) LMDB directories, based on the first
+  // bits of the fingerprint being stored, so that we can write to them in parallel.
+  #[derive(Clone)]
+  struct ShardedLmdb {
+    // First Database is content, second is leases, third is encodings.
+    lmdbs: HashMap<u8, (Arc<ShardEnv>, Database, Database, Option<Database>)>,
+    // The bits of the first byte of a fingerprint which determine its shard.
+    shard_mask: u8,
+    root_path: PathBuf,
+    // The size to which each shard's map may grow.
+    max_map_size_bytes: usize,
+  }
+
+  impl ShardedLmdb {
+    ///
+    /// If read_only, the store must already exist, and must not be written to by anything else
+    /// while it is open (e.g. because it is on a read-only mount): no locks are taken, because
+    /// taking them needs write access to the store's lock files.
+    ///
+    /// A store must always be opened with the number of shards it was created with.
+    ///
+    pub fn new(
+      root_path: &Path,
+      read_only: bool,
+      config: LmdbConfig,
+    ) -> Result<ShardedLmdb, String> {
+      debug!("Initializing ShardedLmdb at root {:?}", root_path);
+      let mut lmdbs = HashMap::new();
+
+      // The shard count is a power of two, so the shard of a fingerprint is given by its first
+      // log2(shard_count) bits.
+      let shard_bits = config.shard_count.trailing_zeros();
+      let shard_mask = !(0xFF_u8 >> shard_bits);
+      let keys: Vec<u8> = (0..config.shard_count)
+        .map(|shard| ((shard << 8) / config.shard_count) as u8)
+        .collect();
+      let dirnames: Vec<String> = keys
+        .iter()
+        .map(|key| {
+          let mut s = String::new();
+          fmt::Write::write_fmt(&mut s, format_args!("{:02x}", key)).unwrap();
+          s[0..1].to_owned()
+        })
+        .collect();
+      check_shard_dirs(root_path, &dirnames)?;
+
+      for (&key, dirname) in keys.iter().zip(dirnames) {
         let dir = root_path.join(dirname);
-        super::super::safe_create_dir_all(&dir)
-          .map_err(|err| format!("Error making directory for store at {:?}: {:?}", dir, err))?;
+        if !read_only {
+          super::super::safe_create_dir_all(&dir)
+            .map_err(|err| format!("Error making directory for store at {:?}: {:?}", dir, err))?;
+        }
         debug!("Making ShardedLmdb env for {:?}", dir);
         let env = Environment::new()
             // NO_SYNC
@@ -987,109 +3021,544 @@ mod local {
             // The only down-side is that you need to make sure that any individual OS thread must
             // not try to perform multiple write transactions concurrently. Fortunately, this
             // property holds for us.
-            .set_flags(NO_SYNC | NO_TLS)
-            // 2 DBs; one for file contents, one for leases.
-            .set_max_dbs(2)
-            .set_map_size(MAX_LOCAL_STORE_SIZE_BYTES)
+            .set_flags(if read_only {
+              READ_ONLY | NO_LOCK | NO_TLS
+            } else {
+              NO_SYNC | NO_TLS
+            })
+            // 3 DBs; one for file contents, one for leases, one for encodings.
+            .set_max_dbs(3)
+            .set_map_size(config.map_size_bytes)
             .open(&dir)
             .map_err(|e| format!("Error making env for store at {:?}: {}", dir, e))?;
 
         debug!("Making ShardedLmdb content database for {:?}", dir);
-        let content_database = env
-          .create_db(Some("content"), DatabaseFlags::empty())
-          .map_err(|e| {
-            format!(
-              "Error creating/opening content database at {:?}: {}",
-              dir, e
-            )
-          })?;
+        let content_database = open_or_create_db(&env, "content", read_only).map_err(|e| {
+          format!(
+            "Error creating/opening content database at {:?}: {}",
+            dir, e
+          )
+        })?;
 
         debug!("Making ShardedLmdb lease database for {:?}", dir);
-        let lease_database = env
-          .create_db(Some("leases"), DatabaseFlags::empty())
-          .map_err(|e| {
-            format!(
-              "Error creating/opening content database at {:?}: {}",
+        let lease_database = open_or_create_db(&env, "leases", read_only).map_err(|e| {
+          format!(
+            "Error creating/opening content database at {:?}: {}",
+            dir, e
+          )
+        })?;
+
+        debug!("Making ShardedLmdb encoding database for {:?}", dir);
+        // Stores created before compression was supported have no encodings database, which is
+        // equivalent to an empty one. Read-only stores can't create one, so make do without.
+        let encoding_database = match open_or_create_db(&env, "encodings", read_only) {
+          Ok(database) => Some(database),
+          Err(NotFound) if read_only => None,
+          Err(e) => {
+            return Err(format!(
+              "Error creating/opening encoding database at {:?}: {}",
               dir, e
-            )
-          })?;
+            ))
+          }
+        };
 
-        lmdbs.insert(key, (Arc::new(env), content_database, lease_database));
+        lmdbs.insert(
+          key,
+          (
+            Arc::new(ShardEnv::new(env, config.map_size_bytes)),
+            content_database,
+            lease_database,
+            encoding_database,
+          ),
+        );
       }
 
-      Ok(ShardedLmdb { lmdbs })
+      Ok(ShardedLmdb {
+        lmdbs,
+        shard_mask,
+        root_path: root_path.to_owned(),
+        max_map_size_bytes: config.max_map_size_bytes,
+      })
+    }
+
+    // First Database is content, second is leases, third is encodings (which read-only stores may
+    // lack).
+    pub fn get(
+      &self,
+      fingerprint: &Fingerprint,
+    ) -> (Arc<ShardEnv>, Database, Database, Option<Database>) {
+      self.lmdbs[&(fingerprint.0[0] & self.shard_mask)].clone()
+    }
+
+    ///
+    /// Runs the given write to the shard containing the given fingerprint. If it fails because the
+    /// shard is full, grows the shard and retries it, until the shard reaches its maximum size.
+    ///
+    pub fn growing_if_full<T, F: Fn() -> Result<T, lmdb::Error>>(
+      &self,
+      fingerprint: &Fingerprint,
+      write: F,
+    ) -> Result<T, lmdb::Error> {
+      let (env, _, _, _) = self.get(fingerprint);
+      loop {
+        match write() {
+          Err(MapFull) => match env.grow(self.max_map_size_bytes) {
+            Ok(true) => {}
+            Ok(false) => return Err(MapFull),
+            Err(err) => {
+              warn!("Error growing local store shard under {:?}: {}", self.root_path, err);
+              return Err(MapFull);
+            }
+          },
+          res => return res,
+        }
+      }
     }
 
-    // First Database is content, second is leases.
-    pub fn get(&self, fingerprint: &Fingerprint) -> (Arc<Environment>, Database, Database) {
-      self.lmdbs[&(fingerprint.0[0] & 0xF0)].clone()
+    ///
+    /// The error to report when a write to the shard containing the given fingerprint failed
+    /// because the shard was full, and could not grow any further.
+    ///
+    pub fn map_full_error(&self, fingerprint: &Fingerprint) -> String {
+      let key = fingerprint.0[0] & self.shard_mask;
+      format!(
+        "The local store shard for fingerprints starting {:02x} under {:?} is full: it has grown \
+         to its maximum size of {} bytes, and garbage collecting everything in it which wasn't \
+         leased or pinned didn't free enough space. Configure a larger maximum map size for the \
+         local store, or remove unwanted pins.",
+        key, self.root_path, self.max_map_size_bytes
+      )
     }
 
-    pub fn all_lmdbs(&self) -> Vec<(Arc<Environment>, Database, Database)> {
+    pub fn all_lmdbs(&self) -> Vec<(Arc<ShardEnv>, Database, Database, Option<Database>)> {
       self.lmdbs.values().cloned().collect()
     }
+
+    // Every shard, keyed in the same way as by get.
+    pub fn shards(&self) -> Vec<(u8, (Arc<ShardEnv>, Database, Database, Option<Database>))> {
+      let mut shards: Vec<_> = self
+        .lmdbs
+        .iter()
+        .map(|(shard, lmdb)| (*shard, lmdb.clone()))
+        .collect();
+      shards.sort_by_key(|&(shard, _)| shard);
+      shards
+    }
   }
 
-  #[derive(Eq, PartialEq, Ord, PartialOrd)]
-  struct AgedFingerprint {
-    // expired_seconds_ago must be the first field for the Ord implementation.
-    expired_seconds_ago: u64,
-    fingerprint: Fingerprint,
-    size_bytes: usize,
-    entry_type: EntryType,
+  ///
+  /// An LMDB environment whose map can grow while it is open.
+  ///
+  /// LMDB only allows a map to be resized while no transactions are open in the process, so every
+  /// transaction holds a read lock on resize_lock for as long as it is open, and resizing takes
+  /// it for writing.
+  ///
+  struct ShardEnv {
+    env: Environment,
+    resize_lock: RwLock<()>,
+    map_size_bytes: AtomicUsize,
   }
 
-  #[cfg(test)]
-  pub mod tests {
-    use super::super::super::safe_create_dir_all;
-    use super::{ByteStore, EntryType, ResettablePool};
-    use bytes::Bytes;
-    use futures::Future;
-    use hashing::{Digest, Fingerprint};
-    use lmdb::{DatabaseFlags, Environment, Transaction, WriteFlags};
-    use std::path::Path;
-    use std::sync::Arc;
-    use tempfile::TempDir;
-    use testutil::data::{TestData, TestDirectory};
+  impl ShardEnv {
+    fn new(env: Environment, map_size_bytes: usize) -> ShardEnv {
+      ShardEnv {
+        env,
+        resize_lock: RwLock::new(()),
+        map_size_bytes: AtomicUsize::new(map_size_bytes),
+      }
+    }
 
-    #[test]
-    fn save_file() {
-      let dir = TempDir::new().unwrap();
+    pub fn begin_ro_txn(&self) -> Result<Locked<RoTransaction>, lmdb::Error> {
+      self.begin_txn(Environment::begin_ro_txn)
+    }
 
-      let testdata = TestData::roland();
-      assert_eq!(
-        new_store(dir.path())
-          .store_bytes(EntryType::File, testdata.bytes(), false)
-          .wait(),
-        Ok(testdata.fingerprint())
-      );
+    pub fn begin_rw_txn(&self) -> Result<Locked<RwTransaction>, lmdb::Error> {
+      self.begin_txn(Environment::begin_rw_txn)
     }
 
-    #[test]
-    fn save_file_is_idempotent() {
-      let dir = TempDir::new().unwrap();
+    fn begin_txn<'env, T, F>(&'env self, begin: F) -> Result<Locked<'env, T>, lmdb::Error>
+    where
+      F: Fn(&'env Environment) -> Result<T, lmdb::Error>,
+    {
+      loop {
+        {
+          // The lock only guards the map, which is not invalidated by a panic.
+          let resize_guard = self
+            .resize_lock
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+          match begin(&self.env) {
+            Ok(txn) => {
+              return Ok(Locked {
+                txn,
+                _resize_guard: resize_guard,
+                _open: OpenTransaction::new(self),
+              })
+            }
+            Err(MapResized) => {}
+            Err(err) => return Err(err),
+          }
+        }
+        // Another process grew the map: adopt its size before trying again.
+        let _resize_guard = self.lock_for_resize();
+        self.set_map_size(0)?;
+      }
+    }
 
-      let testdata = TestData::roland();
-      new_store(dir.path())
-        .store_bytes(EntryType::File, testdata.bytes(), false)
-        .wait()
-        .unwrap();
-      assert_eq!(
-        new_store(dir.path())
-          .store_bytes(EntryType::File, testdata.bytes(), false)
-          .wait(),
-        Ok(testdata.fingerprint())
+    ///
+    /// Takes resize_lock for writing. This would deadlock if the calling thread had a transaction
+    /// open in this environment (because that holds resize_lock for reading), which debug builds
+    /// catch.
+    ///
+    fn lock_for_resize(&self) -> RwLockWriteGuard<()> {
+      debug_assert!(
+        OpenTransaction::count(self) == 0,
+        "Cannot resize a local store shard while this thread has a transaction open in it"
       );
+      self
+        .resize_lock
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
     }
 
-    #[test]
-    fn save_file_collision_preserves_first() {
-      let dir = TempDir::new().unwrap();
-
-      let bogus_value = Bytes::new();
-      let realdata = TestData::roland();
+    ///
+    /// Doubles the size of the map, up to max_map_size_bytes. Returns false if it was already that
+    /// big.
+    ///
+    pub fn grow(&self, max_map_size_bytes: usize) -> Result<bool, lmdb::Error> {
+      let _resize_guard = self.lock_for_resize();
+      let map_size_bytes = self.map_size_bytes.load(Ordering::SeqCst);
+      if map_size_bytes >= max_map_size_bytes {
+        return Ok(false);
+      }
+      let new_map_size_bytes = min(map_size_bytes.saturating_mul(2), max_map_size_bytes);
+      debug!(
+        "Growing local store shard from {} to {} bytes",
+        map_size_bytes, new_map_size_bytes
+      );
+      self.set_map_size(new_map_size_bytes)?;
+      Ok(true)
+    }
 
-      let sharded_dir = dir
+    ///
+    /// Sets the size of the map, where a size of zero adopts the size which another process set.
+    /// Must only be called while holding resize_lock for writing.
+    ///
+    fn set_map_size(&self, map_size_bytes: usize) -> Result<(), lmdb::Error> {
+      let mut info: lmdb_sys::MDB_envinfo = unsafe { mem::zeroed() };
+      let rc = unsafe {
+        match lmdb_sys::mdb_env_set_mapsize(self.env.env(), map_size_bytes) {
+          0 => lmdb_sys::mdb_env_info(self.env.env(), &mut info),
+          rc => rc,
+        }
+      };
+      if rc != 0 {
+        return Err(lmdb::Error::from_err_code(rc));
+      }
+      self.map_size_bytes.store(info.me_mapsize, Ordering::SeqCst);
+      Ok(())
+    }
+  }
+
+  ///
+  /// A transaction, which prevents its environment's map from being resized while it is open.
+  ///
+  pub struct Locked<'env, T> {
+    // Declared before _resize_guard, so that the transaction ends before the lock is released.
+    txn: T,
+    _resize_guard: RwLockReadGuard<'env, ()>,
+    _open: OpenTransaction,
+  }
+
+  thread_local! {
+    // In debug builds, the number of transactions which this thread has open in each ShardEnv
+    // (keyed by its address).
+    static OPEN_TRANSACTIONS: RefCell<HashMap<usize, usize>> = RefCell::new(HashMap::new());
+  }
+
+  ///
+  /// Counts a transaction as open in a ShardEnv on the current thread until it is dropped, so that
+  /// debug builds can catch attempts to resize the map which would deadlock.
+  ///
+  struct OpenTransaction {
+    env_key: usize,
+  }
+
+  impl OpenTransaction {
+    fn new(env: &ShardEnv) -> OpenTransaction {
+      let env_key = env as *const ShardEnv as usize;
+      if cfg!(debug_assertions) {
+        OPEN_TRANSACTIONS.with(|open| *open.borrow_mut().entry(env_key).or_insert(0) += 1);
+      }
+      OpenTransaction { env_key }
+    }
+
+    fn count(env: &ShardEnv) -> usize {
+      let env_key = env as *const ShardEnv as usize;
+      OPEN_TRANSACTIONS.with(|open| open.borrow().get(&env_key).cloned().unwrap_or(0))
+    }
+  }
+
+  impl Drop for OpenTransaction {
+    fn drop(&mut self) {
+      if cfg!(debug_assertions) {
+        OPEN_TRANSACTIONS.with(|open| {
+          let mut open = open.borrow_mut();
+          let closed_all = match open.get_mut(&self.env_key) {
+            Some(count) => {
+              *count -= 1;
+              *count == 0
+            }
+            None => false,
+          };
+          if closed_all {
+            open.remove(&self.env_key);
+          }
+        });
+      }
+    }
+  }
+
+  impl<'env> Locked<'env, RwTransaction<'env>> {
+    pub fn commit(self) -> Result<(), lmdb::Error> {
+      self.txn.commit()
+    }
+  }
+
+  impl<'env, T> Deref for Locked<'env, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+      &self.txn
+    }
+  }
+
+  impl<'env, T> DerefMut for Locked<'env, T> {
+    fn deref_mut(&mut self) -> &mut T {
+      &mut self.txn
+    }
+  }
+
+  ///
+  /// Named pins, which protect entries from garbage collection. Keys are labels, and values are the
+  /// type of the pinned entry (as a single byte) followed by its fingerprint and its size (as a
+  /// little-endian u64).
+  ///
+  /// Unlike the content stores, writes are synced, because pins can't be recreated from elsewhere.
+  ///
+  struct PinLmdb {
+    env: Environment,
+    database: Database,
+  }
+
+  impl PinLmdb {
+    fn new(root_path: &Path) -> Result<PinLmdb, String> {
+      super::super::safe_create_dir_all(root_path)
+        .map_err(|err| format!("Error making directory for pins at {:?}: {:?}", root_path, err))?;
+      debug!("Making pin env for {:?}", root_path);
+      let env = Environment::new()
+        // See ShardedLmdb for why NO_TLS is needed.
+        .set_flags(NO_TLS)
+        .set_max_dbs(1)
+        .open(root_path)
+        .map_err(|e| format!("Error making env for pins at {:?}: {}", root_path, e))?;
+      let database = env
+        .create_db(Some("pins"), DatabaseFlags::empty())
+        .map_err(|e| {
+          format!(
+            "Error creating/opening pin database at {:?}: {}",
+            root_path, e
+          )
+        })?;
+      Ok(PinLmdb { env, database })
+    }
+  }
+
+  ///
+  /// Fails if the given directory already contains shards other than those named, which means that
+  /// the store was created with a different number of shards.
+  ///
+  fn check_shard_dirs(root_path: &Path, dirnames: &[String]) -> Result<(), String> {
+    let entries = match fs::read_dir(root_path) {
+      Ok(entries) => entries,
+      Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(err) => return Err(format!("Error listing store at {:?}: {}", root_path, err)),
+    };
+    let mut existing = HashSet::new();
+    for entry in entries {
+      let entry = entry.map_err(|err| format!("Error listing store at {:?}: {}", root_path, err))?;
+      let name = entry.file_name().to_string_lossy().into_owned();
+      if name.len() == 1 && u8::from_str_radix(&name, 16).is_ok() {
+        existing.insert(name);
+      }
+    }
+    let expected: HashSet<String> = dirnames.iter().cloned().collect();
+    if existing.is_empty() || existing == expected {
+      Ok(())
+    } else {
+      Err(format!(
+        "The store at {:?} was created with {} shards, but {} were configured",
+        root_path,
+        existing.len(),
+        dirnames.len()
+      ))
+    }
+  }
+
+  ///
+  /// Opens the named database in the given environment, creating it if it doesn't exist and the
+  /// environment is writable.
+  ///
+  fn open_or_create_db(
+    env: &Environment,
+    name: &str,
+    read_only: bool,
+  ) -> Result<Database, lmdb::Error> {
+    if read_only {
+      env.open_db(Some(name))
+    } else {
+      env.create_db(Some(name), DatabaseFlags::empty())
+    }
+  }
+
+  ///
+  /// The encoding of an entry, which is NotFound for entries stored raw, and for all entries in a
+  /// store which has no encodings database.
+  ///
+  fn get_encoding<'txn, T: Transaction>(
+    txn: &'txn T,
+    encoding_database: Option<Database>,
+    key: &[u8],
+  ) -> Result<&'txn [u8], lmdb::Error> {
+    match encoding_database {
+      Some(encoding_database) => txn.get(encoding_database, &key),
+      None => Err(NotFound),
+    }
+  }
+
+  ///
+  /// The size of an entry as stored, and whether it is in the large file store. Large files are
+  /// only referenced from LMDB, by their size.
+  ///
+  ///
+  /// Reads the stats which LMDB keeps in the database's root page, without visiting its entries.
+  ///
+  fn database_stats<T: Transaction>(txn: &T, database: Database) -> Result<DatabaseStats, String> {
+    let mut stat: lmdb_sys::MDB_stat = unsafe { mem::zeroed() };
+    let rc = unsafe { lmdb_sys::mdb_stat(txn.txn(), database.dbi(), &mut stat) };
+    if rc != 0 {
+      return Err(format!(
+        "Error reading database stats: {}",
+        lmdb::Error::from_err_code(rc)
+      ));
+    }
+    let pages = stat.ms_branch_pages + stat.ms_leaf_pages + stat.ms_overflow_pages;
+    Ok(DatabaseStats {
+      entries: stat.ms_entries,
+      size_bytes: pages * stat.ms_psize as usize,
+    })
+  }
+
+  fn stored_size<T: Transaction>(
+    txn: &T,
+    encoding_database: Option<Database>,
+    key: &[u8],
+    bytes: &[u8],
+  ) -> (usize, bool) {
+    let is_large_file = get_encoding(txn, encoding_database, key)
+      .map(|encoding| encoding == [LARGE_FILE_ENCODING])
+      .unwrap_or_else(|e| match e {
+        NotFound => false,
+        e => panic!("Error reading encoding, probable lmdb corruption: {:?}", e),
+      });
+    if is_large_file {
+      (LittleEndian::read_u64(bytes) as usize, true)
+    } else {
+      (bytes.len(), false)
+    }
+  }
+
+  ///
+  /// When the lease on an entry expires, in seconds since the epoch. 0 indicates unleased.
+  ///
+  fn lease_until_secs_since_epoch<T: Transaction>(
+    txn: &T,
+    lease_database: Database,
+    key: &[u8],
+  ) -> u64 {
+    txn
+      .get(lease_database, &key)
+      .map(|b| LittleEndian::read_u64(b))
+      .unwrap_or_else(|e| match e {
+        NotFound => 0,
+        e => panic!("Error reading lease, probable lmdb corruption: {:?}", e),
+      })
+  }
+
+  #[derive(Eq, PartialEq, Ord, PartialOrd)]
+  struct AgedFingerprint {
+    // expired_seconds_ago must be the first field for the Ord implementation.
+    expired_seconds_ago: u64,
+    fingerprint: Fingerprint,
+    size_bytes: usize,
+    entry_type: EntryType,
+    is_large_file: bool,
+  }
+
+  #[cfg(test)]
+  pub mod tests {
+    use super::super::super::safe_create_dir_all;
+    use super::{
+      ByteStore, DatabaseStats, EntryType, LmdbConfig, ResettablePool, StoreError, StoreStats,
+    };
+    use bytes::Bytes;
+    use futures::Future;
+    use hashing::{Digest, Fingerprint};
+    use lmdb::{DatabaseFlags, Environment, Transaction, WriteFlags};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use testutil::data::{TestData, TestDirectory};
+
+    #[test]
+    fn save_file() {
+      let dir = TempDir::new().unwrap();
+
+      let testdata = TestData::roland();
+      assert_eq!(
+        new_store(dir.path())
+          .store_bytes(EntryType::File, testdata.bytes(), false)
+          .wait(),
+        Ok(testdata.fingerprint())
+      );
+    }
+
+    #[test]
+    fn save_file_is_idempotent() {
+      let dir = TempDir::new().unwrap();
+
+      let testdata = TestData::roland();
+      new_store(dir.path())
+        .store_bytes(EntryType::File, testdata.bytes(), false)
+        .wait()
+        .unwrap();
+      assert_eq!(
+        new_store(dir.path())
+          .store_bytes(EntryType::File, testdata.bytes(), false)
+          .wait(),
+        Ok(testdata.fingerprint())
+      );
+    }
+
+    #[test]
+    fn save_file_collision_preserves_first() {
+      let dir = TempDir::new().unwrap();
+
+      let bogus_value = Bytes::new();
+      let realdata = TestData::roland();
+
+      let sharded_dir = dir
         .path()
         .join("files")
         .join(&realdata.fingerprint().to_hex()[0..1]);
@@ -1200,440 +3669,1509 @@ mod local {
     }
 
     #[test]
-    fn garbage_collect_nothing_to_do() {
+    fn roundtrip_compressed_file() {
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
-      let bytes = Bytes::from("0123456789");
+      let testdata = TestData::new(&"European Burmese".repeat(100));
+
+      let store = new_store(dir.path()).with_compression(1024);
       store
-        .store_bytes(EntryType::File, bytes.clone(), false)
+        .store_bytes(EntryType::File, testdata.bytes(), true)
         .wait()
         .expect("Error storing");
-      store.shrink(10).expect("Error shrinking");
       assert_eq!(
-        load_bytes(
-          &store,
-          EntryType::File,
-          Fingerprint::from_hex_string(
-            "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
-          ).unwrap(),
-        ),
-        Ok(Some(bytes))
+        load_file_bytes(&store, testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
+      );
+      // Nothing can be collected, because the file is leased, so this reports its stored size.
+      let stored_size = store.shrink(0).expect("Error shrinking");
+      assert!(
+        stored_size < testdata.len(),
+        "Stored size {} should have been less than {}",
+        stored_size,
+        testdata.len()
+      );
+
+      // Compressed blobs are still readable without compression configured.
+      assert_eq!(
+        load_file_bytes(&new_store(dir.path()), testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
       );
     }
 
     #[test]
-    fn garbage_collect_nothing_to_do_with_lease() {
+    fn small_files_are_not_compressed() {
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
-      let bytes = Bytes::from("0123456789");
+      let testdata = TestData::roland();
+
+      let store = new_store(dir.path()).with_compression(1024);
       store
-        .store_bytes(EntryType::File, bytes.clone(), false)
+        .store_bytes(EntryType::File, testdata.bytes(), true)
         .wait()
         .expect("Error storing");
-      let file_fingerprint = Fingerprint::from_hex_string(
-        "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
-      ).unwrap();
-      let file_digest = Digest(file_fingerprint, 10);
-      store
-        .lease_all(vec![file_digest].iter())
-        .expect("Error leasing");
-      store.shrink(10).expect("Error shrinking");
+      assert_eq!(store.shrink(0), Ok(testdata.len()));
       assert_eq!(
-        load_bytes(&store, EntryType::File, file_fingerprint),
-        Ok(Some(bytes))
+        load_file_bytes(&store, testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
       );
     }
 
     #[test]
-    fn garbage_collect_remove_one_of_two_files_no_leases() {
+    fn uncompressed_files_are_loadable_with_compression() {
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
-      let bytes_1 = Bytes::from("0123456789");
-      let fingerprint_1 = Fingerprint::from_hex_string(
-        "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
-      ).unwrap();
-      let bytes_2 = Bytes::from("9876543210");
-      let fingerprint_2 = Fingerprint::from_hex_string(
-        "7619ee8cea49187f309616e30ecf54be072259b43760f1f550a644945d5572f2",
-      ).unwrap();
-      store
-        .store_bytes(EntryType::File, bytes_1.clone(), false)
-        .wait()
-        .expect("Error storing");
-      store
-        .store_bytes(EntryType::File, bytes_2.clone(), false)
+      let testdata = TestData::new(&"European Burmese".repeat(100));
+
+      new_store(dir.path())
+        .store_bytes(EntryType::File, testdata.bytes(), false)
         .wait()
         .expect("Error storing");
-      store.shrink(10).expect("Error shrinking");
-      let mut entries = Vec::new();
-      entries
-        .push(load_bytes(&store, EntryType::File, fingerprint_1).expect("Error loading bytes"));
-      entries
-        .push(load_bytes(&store, EntryType::File, fingerprint_2).expect("Error loading bytes"));
       assert_eq!(
-        1,
-        entries.iter().filter(|maybe| maybe.is_some()).count(),
-        "Want one Some but got: {:?}",
-        entries
+        load_file_bytes(
+          &new_store(dir.path()).with_compression(0),
+          testdata.fingerprint()
+        ),
+        Ok(Some(testdata.bytes()))
       );
     }
 
     #[test]
-    fn garbage_collect_remove_both_files_no_leases() {
+    fn garbage_collect_compressed_file() {
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
-      let bytes_1 = Bytes::from("0123456789");
-      let fingerprint_1 = Fingerprint::from_hex_string(
-        "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
-      ).unwrap();
-      let bytes_2 = Bytes::from("9876543210");
-      let fingerprint_2 = Fingerprint::from_hex_string(
-        "7619ee8cea49187f309616e30ecf54be072259b43760f1f550a644945d5572f2",
-      ).unwrap();
+      let testdata = TestData::new(&"European Burmese".repeat(100));
+
+      let store = new_store(dir.path()).with_compression(0);
       store
-        .store_bytes(EntryType::File, bytes_1.clone(), false)
+        .store_bytes(EntryType::File, testdata.bytes(), false)
         .wait()
         .expect("Error storing");
-      store
-        .store_bytes(EntryType::File, bytes_2.clone(), false)
+      assert_eq!(store.shrink(0), Ok(0));
+      assert_eq!(load_file_bytes(&store, testdata.fingerprint()), Ok(None));
+
+      // Storing the same file again uncompressed must not find a stale encoding marker.
+      new_store(dir.path())
+        .store_bytes(EntryType::File, testdata.bytes(), false)
         .wait()
         .expect("Error storing");
-      store.shrink(1).expect("Error shrinking");
-      assert_eq!(
-        load_bytes(&store, EntryType::File, fingerprint_1),
-        Ok(None),
-        "Should have garbage collected {:?}",
-        fingerprint_1
-      );
       assert_eq!(
-        load_bytes(&store, EntryType::File, fingerprint_2),
-        Ok(None),
-        "Should have garbage collected {:?}",
-        fingerprint_2
+        load_file_bytes(&new_store(dir.path()), testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
       );
     }
 
     #[test]
-    fn garbage_collect_remove_one_of_two_directories_no_leases() {
+    fn roundtrip_large_file() {
       let dir = TempDir::new().unwrap();
+      let testdata = TestData::new(&"European Burmese".repeat(100));
 
-      let testdir = TestDirectory::containing_roland();
-      let other_testdir = TestDirectory::containing_dnalor();
-
-      let store = new_store(dir.path());
-      store
-        .store_bytes(EntryType::Directory, testdir.bytes(), false)
-        .wait()
-        .expect("Error storing");
+      let store = new_store(dir.path()).with_large_file_threshold(1024);
       store
-        .store_bytes(EntryType::Directory, other_testdir.bytes(), false)
+        .store_bytes(EntryType::File, testdata.bytes(), true)
         .wait()
         .expect("Error storing");
-      store.shrink(80).expect("Error shrinking");
-      let mut entries = Vec::new();
-      entries.push(
-        load_bytes(&store, EntryType::Directory, testdir.fingerprint())
-          .expect("Error loading bytes"),
+      let path = large_file_path(dir.path(), testdata.fingerprint());
+      assert_eq!(fs::read(&path).unwrap(), testdata.bytes().to_vec());
+      assert_eq!(
+        load_file_bytes(&store, testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
       );
-      entries.push(
-        load_bytes(&store, EntryType::Directory, other_testdir.fingerprint())
-          .expect("Error loading bytes"),
+      // Large files are accounted for by their size on disk.
+      assert_eq!(store.shrink(0), Ok(testdata.len()));
+
+      // Large files are still readable without a threshold configured.
+      assert_eq!(
+        load_file_bytes(&new_store(dir.path()), testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
       );
       assert_eq!(
-        1,
-        entries.iter().filter(|maybe| maybe.is_some()).count(),
-        "Want one Some but got: {:?}",
-        entries
+        store
+          .with_large_file_path(testdata.fingerprint(), |path| Ok(path))
+          .wait(),
+        Ok(Some(path))
       );
     }
 
     #[test]
-    fn garbage_collect_remove_file_with_leased_directory() {
+    fn modified_large_file_is_not_loaded() {
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
-
-      let testdir = TestDirectory::containing_roland();
-      let testdata = TestData::fourty_chars();
+      let testdata = TestData::new(&"European Burmese".repeat(100));
 
+      let store = new_store(dir.path()).with_large_file_threshold(1024);
       store
-        .store_bytes(EntryType::Directory, testdir.bytes(), true)
+        .store_bytes(EntryType::File, testdata.bytes(), true)
         .wait()
         .expect("Error storing");
+      fs::write(
+        large_file_path(dir.path(), testdata.fingerprint()),
+        "European Burmeze".repeat(100),
+      ).unwrap();
+      load_file_bytes(&store, testdata.fingerprint()).expect_err("Want error");
+    }
+
+    #[test]
+    fn small_files_are_not_large_files() {
+      let dir = TempDir::new().unwrap();
+      let testdata = TestData::roland();
 
+      let store = new_store(dir.path()).with_large_file_threshold(1024);
       store
-        .store_bytes(EntryType::File, testdata.bytes(), false)
+        .store_bytes(EntryType::File, testdata.bytes(), true)
         .wait()
         .expect("Error storing");
+      assert!(!large_file_path(dir.path(), testdata.fingerprint()).exists());
+      assert_eq!(
+        store
+          .with_large_file_path(testdata.fingerprint(), |path| Ok(path))
+          .wait(),
+        Ok(None)
+      );
+      assert_eq!(
+        load_file_bytes(&store, testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
+      );
+    }
 
-      store.shrink(80).expect("Error shrinking");
+    #[test]
+    fn store_large_file_from_path() {
+      let dir = TempDir::new().unwrap();
+      let file_dir = TempDir::new().unwrap();
+      let testdata = TestData::new(&"European Burmese".repeat(100));
+      let file_path = file_dir.path().join("cats");
+      fs::write(&file_path, &testdata.bytes()).unwrap();
 
+      let store = new_store(dir.path()).with_large_file_threshold(1024);
       assert_eq!(
-        load_bytes(&store, EntryType::File, testdata.fingerprint()),
-        Ok(None),
-        "File was present when it should've been garbage collected"
+        store.store_file_from_path(file_path, true).wait(),
+        Ok(testdata.digest())
       );
+      assert!(large_file_path(dir.path(), testdata.fingerprint()).exists());
       assert_eq!(
-        load_bytes(&store, EntryType::Directory, testdir.fingerprint()),
-        Ok(Some(testdir.bytes())),
-        "Directory was missing despite lease"
+        load_file_bytes(&store, testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
       );
     }
 
     #[test]
-    fn garbage_collect_remove_file_while_leased_file() {
+    fn store_small_file_from_path() {
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
+      let file_dir = TempDir::new().unwrap();
+      let testdata = TestData::roland();
+      let file_path = file_dir.path().join("roland");
+      fs::write(&file_path, &testdata.bytes()).unwrap();
 
-      let testdir = TestDirectory::containing_roland();
+      let store = new_store(dir.path()).with_large_file_threshold(1024);
+      assert_eq!(
+        store.store_file_from_path(file_path, true).wait(),
+        Ok(testdata.digest())
+      );
+      assert!(!large_file_path(dir.path(), testdata.fingerprint()).exists());
+      assert_eq!(
+        load_file_bytes(&store, testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
+      );
+    }
+
+    #[test]
+    fn garbage_collect_large_file() {
+      let dir = TempDir::new().unwrap();
+      let testdata = TestData::new(&"European Burmese".repeat(100));
 
+      let store = new_store(dir.path()).with_large_file_threshold(1024);
       store
-        .store_bytes(EntryType::Directory, testdir.bytes(), false)
+        .store_bytes(EntryType::File, testdata.bytes(), false)
         .wait()
         .expect("Error storing");
-      let fourty_chars = TestData::fourty_chars();
-      store
-        .store_bytes(EntryType::File, fourty_chars.bytes(), true)
+      assert_eq!(store.shrink(0), Ok(0));
+      assert!(!large_file_path(dir.path(), testdata.fingerprint()).exists());
+      assert_eq!(load_file_bytes(&store, testdata.fingerprint()), Ok(None));
+
+      // Storing the same file again below the threshold must not find a stale encoding marker.
+      new_store(dir.path())
+        .store_bytes(EntryType::File, testdata.bytes(), false)
         .wait()
         .expect("Error storing");
+      assert_eq!(
+        load_file_bytes(&new_store(dir.path()), testdata.fingerprint()),
+        Ok(Some(testdata.bytes()))
+      );
+    }
 
-      store.shrink(80).expect("Error shrinking");
+    #[test]
+    fn load_file_bytes_range() {
+      let dir = TempDir::new().unwrap();
+      let testdata = TestData::roland();
 
+      let store = new_store(dir.path());
+      store
+        .store_bytes(EntryType::File, testdata.bytes(), false)
+        .wait()
+        .expect("Error storing");
       assert_eq!(
-        load_bytes(&store, EntryType::File, fourty_chars.fingerprint()),
-        Ok(Some(fourty_chars.bytes())),
-        "File was missing despite lease"
+        store
+          .load_file_bytes_range(testdata.fingerprint(), 2, 3)
+          .wait(),
+        Ok(Some(testdata.bytes().slice(2, 5)))
       );
+      // Ranges are truncated at the end of the file.
       assert_eq!(
-        load_bytes(&store, EntryType::Directory, testdir.fingerprint()),
-        Ok(None),
-        "Directory was present when it should've been garbage collected"
+        store
+          .load_file_bytes_range(testdata.fingerprint(), 2, 1000)
+          .wait(),
+        Ok(Some(testdata.bytes().slice_from(2)))
+      );
+      assert_eq!(
+        store
+          .load_file_bytes_range(testdata.fingerprint(), 1000, 3)
+          .wait(),
+        Ok(Some(Bytes::new()))
+      );
+      assert_eq!(
+        store
+          .load_file_bytes_range(TestData::catnip().fingerprint(), 2, 3)
+          .wait(),
+        Ok(None)
       );
     }
 
     #[test]
-    fn garbage_collect_fail_because_too_many_leases() {
-      let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
+    fn load_file_bytes_range_compressed_and_large_files() {
+      let testdata = TestData::new(&"European Burmese".repeat(100));
+      let compressed_dir = TempDir::new().unwrap();
+      let large_dir = TempDir::new().unwrap();
+
+      let stores = vec![
+        new_store(compressed_dir.path()).with_compression(1024),
+        new_store(large_dir.path()).with_large_file_threshold(1024),
+      ];
+      for store in stores {
+        store
+          .store_bytes(EntryType::File, testdata.bytes(), false)
+          .wait()
+          .expect("Error storing");
+        assert_eq!(
+          store
+            .load_file_bytes_range(testdata.fingerprint(), 1000, 100)
+            .wait(),
+          Ok(Some(testdata.bytes().slice(1000, 1100)))
+        );
+        assert_eq!(
+          store
+            .load_file_bytes_range(testdata.fingerprint(), 1550, 100)
+            .wait(),
+          Ok(Some(testdata.bytes().slice_from(1550)))
+        );
+      }
+    }
 
+    #[test]
+    fn stats() {
+      let dir = TempDir::new().unwrap();
+      let roland = TestData::roland();
+      let catnip = TestData::catnip();
       let testdir = TestDirectory::containing_roland();
-      let fourty_chars = TestData::fourty_chars();
+
+      let store = new_store(dir.path());
+      assert_eq!(store.stats(), Ok(StoreStats::default()));
 
       store
-        .store_bytes(EntryType::Directory, testdir.bytes(), true)
+        .store_bytes(EntryType::File, roland.bytes(), true)
         .wait()
         .expect("Error storing");
       store
-        .store_bytes(EntryType::File, fourty_chars.bytes(), true)
+        .store_bytes(EntryType::File, catnip.bytes(), false)
         .wait()
         .expect("Error storing");
-
       store
-        .store_bytes(EntryType::File, TestData::roland().bytes(), false)
+        .store_bytes(EntryType::Directory, testdir.bytes(), true)
         .wait()
         .expect("Error storing");
 
-      assert_eq!(store.shrink(80), Ok(160));
-
+      let stats = store.stats().expect("Error reading stats");
+      assert_eq!(stats.files.total.entries, 2);
+      assert_eq!(stats.files.total.size_bytes, roland.len() + catnip.len());
+      assert_eq!(stats.files.total.leased_entries, 1);
+      assert_eq!(stats.files.total.expired_entries, 1);
+      assert!(stats.files.total.oldest_lease_secs_since_epoch.is_some());
       assert_eq!(
-        load_bytes(&store, EntryType::File, fourty_chars.fingerprint()),
-        Ok(Some(fourty_chars.bytes())),
-        "Leased file should still be present"
+        stats.files.shards.values().map(|s| s.entries).sum::<usize>(),
+        2
       );
       assert_eq!(
-        load_bytes(&store, EntryType::Directory, testdir.fingerprint()),
-        Ok(Some(testdir.bytes())),
-        "Leased directory should still be present"
+        stats.files.shards[&(roland.fingerprint().0[0] & 0xF0)].leased_entries,
+        1
       );
-      // Whether the unleased file is present is undefined.
+
+      assert_eq!(stats.directories.total.entries, 1);
+      assert_eq!(stats.directories.total.size_bytes, testdir.bytes().len());
+      assert_eq!(stats.directories.total.leased_entries, 1);
+      assert_eq!(stats.total().entries, 3);
+
+      // Shrinking is reflected in the stats.
+      store.shrink(0).expect("Error shrinking");
+      let stats = store.stats().expect("Error reading stats");
+      assert_eq!(stats.files.total.entries, 1);
+      assert_eq!(stats.files.total.expired_entries, 0);
     }
 
     #[test]
-    fn entry_type_for_file() {
-      let testdata = TestData::roland();
-      let testdir = TestDirectory::containing_roland();
+    fn databases_stats() {
       let dir = TempDir::new().unwrap();
+      let roland = TestData::roland();
+      let catnip = TestData::catnip();
+      let testdir = TestDirectory::containing_roland();
+
       let store = new_store(dir.path());
+      let stats = store.databases_stats().expect("Error reading stats");
+      assert_eq!(stats.files, DatabaseStats::default());
+      assert_eq!(stats.directories, DatabaseStats::default());
+
       store
-        .store_bytes(EntryType::Directory, testdir.bytes(), false)
+        .store_bytes(EntryType::File, roland.bytes(), true)
         .wait()
         .expect("Error storing");
       store
-        .store_bytes(EntryType::File, testdata.bytes(), false)
+        .store_bytes(EntryType::File, catnip.bytes(), false)
         .wait()
         .expect("Error storing");
-      assert_eq!(
-        store.entry_type(&testdata.fingerprint()),
-        Ok(Some(EntryType::File))
-      )
+      store
+        .store_bytes(EntryType::Directory, testdir.bytes(), true)
+        .wait()
+        .expect("Error storing");
+
+      let stats = store.databases_stats().expect("Error reading stats");
+      assert_eq!(stats.files.entries, 2);
+      assert!(stats.files.size_bytes > 0);
+      assert_eq!(stats.file_leases.entries, 1);
+      assert_eq!(stats.directories.entries, 1);
+      assert_eq!(stats.directory_leases.entries, 1);
     }
 
     #[test]
-    fn entry_type_for_directory() {
-      let testdata = TestData::roland();
-      let testdir = TestDirectory::containing_roland();
+    fn pins() {
       let dir = TempDir::new().unwrap();
+      let roland = TestData::roland();
+      let testdir = TestDirectory::containing_roland();
+
       let store = new_store(dir.path());
+      assert_eq!(store.pins(), Ok(vec![]));
       store
-        .store_bytes(EntryType::Directory, testdir.bytes(), false)
-        .wait()
-        .expect("Error storing");
+        .pin("toolchain", EntryType::Directory, testdir.digest())
+        .expect("Error pinning");
       store
-        .store_bytes(EntryType::File, testdata.bytes(), false)
-        .wait()
-        .expect("Error storing");
+        .pin("release 1.2", EntryType::File, roland.digest())
+        .expect("Error pinning");
       assert_eq!(
-        store.entry_type(&testdir.fingerprint()),
-        Ok(Some(EntryType::Directory))
-      )
-    }
+        store.pins(),
+        Ok(vec![
+          ("release 1.2".to_owned(), EntryType::File, roland.digest()),
+          ("toolchain".to_owned(), EntryType::Directory, testdir.digest()),
+        ])
+      );
 
-    #[test]
-    fn entry_type_for_missing() {
-      let testdata = TestData::roland();
-      let testdir = TestDirectory::containing_roland();
-      let dir = TempDir::new().unwrap();
+      // Pins persist, and re-pinning a label replaces its pin.
       let store = new_store(dir.path());
       store
-        .store_bytes(EntryType::Directory, testdir.bytes(), false)
-        .wait()
-        .expect("Error storing");
-      store
-        .store_bytes(EntryType::File, testdata.bytes(), false)
-        .wait()
-        .expect("Error storing");
+        .pin("toolchain", EntryType::File, roland.digest())
+        .expect("Error pinning");
+      assert_eq!(store.unpin("release 1.2"), Ok(true));
+      assert_eq!(store.unpin("release 1.2"), Ok(false));
       assert_eq!(
-        store.entry_type(&TestDirectory::recursive().fingerprint()),
-        Ok(None)
-      )
+        store.pins(),
+        Ok(vec![("toolchain".to_owned(), EntryType::File, roland.digest())])
+      );
     }
 
     #[test]
-    pub fn empty_file_is_known() {
+    fn shrink_protecting() {
       let dir = TempDir::new().unwrap();
+      let roland = TestData::roland();
+      let catnip = TestData::catnip();
+
       let store = new_store(dir.path());
-      let empty_file = TestData::empty();
-      assert_eq!(
+      for testdata in &[&roland, &catnip] {
         store
-          .load_bytes_with(EntryType::File, empty_file.fingerprint(), |b| b)
-          .wait(),
-        Ok(Some(empty_file.bytes())),
-      )
+          .store_bytes(EntryType::File, testdata.bytes(), false)
+          .wait()
+          .expect("Error storing");
+      }
+      let protected = vec![roland.fingerprint()].into_iter().collect();
+      assert_eq!(store.shrink_protecting(0, &protected), Ok(roland.len()));
+      assert_eq!(
+        load_file_bytes(&store, roland.fingerprint()),
+        Ok(Some(roland.bytes()))
+      );
+      assert_eq!(load_file_bytes(&store, catnip.fingerprint()), Ok(None));
     }
 
     #[test]
-    pub fn empty_directory_is_known() {
+    fn lmdb_config_shards() {
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
-      let empty_dir = TestDirectory::empty();
-      assert_eq!(
+      let roland = TestData::roland();
+      let catnip = TestData::catnip();
+      let config = LmdbConfig::new(4, 1024 * 1024, 1024 * 1024).unwrap();
+
+      let store = new_store(dir.path()).with_lmdb_config(config);
+      for testdata in &[&roland, &catnip] {
         store
-          .load_bytes_with(EntryType::Directory, empty_dir.fingerprint(), |b| b)
-          .wait(),
-        Ok(Some(empty_dir.bytes())),
-      )
-    }
+          .store_bytes(EntryType::File, testdata.bytes(), false)
+          .wait()
+          .expect("Error storing");
+      }
+      let mut shards: Vec<_> = fs::read_dir(dir.path().join("files"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+      shards.sort();
+      assert_eq!(shards, vec!["0", "4", "8", "c"]);
+      assert_eq!(
+        load_file_bytes(&store, catnip.fingerprint()),
+        Ok(Some(catnip.bytes()))
+      );
 
-    pub fn new_store<P: AsRef<Path>>(dir: P) -> ByteStore {
-      ByteStore::new(dir, Arc::new(ResettablePool::new("test-pool-".to_string()))).unwrap()
-    }
+      let err = load_file_bytes(&new_store(dir.path()), roland.fingerprint())
+        .expect_err("Want error");
+      assert!(err.contains("created with 4 shards"), "Bad error: {}", err);
 
-    pub fn load_file_bytes(
-      store: &ByteStore,
-      fingerprint: Fingerprint,
-    ) -> Result<Option<Bytes>, String> {
-      load_bytes(&store, EntryType::File, fingerprint)
+      assert!(LmdbConfig::new(3, 1024 * 1024, 1024 * 1024).is_err());
+      assert!(LmdbConfig::new(32, 1024 * 1024, 1024 * 1024).is_err());
+      assert!(LmdbConfig::new(4, 1024 * 1024, 1024).is_err());
     }
 
-    pub fn load_directory_proto_bytes(
-      store: &ByteStore,
-      fingerprint: Fingerprint,
-    ) -> Result<Option<Bytes>, String> {
-      load_bytes(&store, EntryType::Directory, fingerprint)
+    #[test]
+    fn default_max_map_size_is_clamped() {
+      let tib = 1024 * 1024 * 1024 * 1024;
+      assert_eq!(super::default_max_map_size_bytes(1, 1024), tib);
+      assert_eq!(super::default_max_map_size_bytes(16, 1024), tib / 4);
+      assert_eq!(super::default_max_map_size_bytes(16, tib / 2), tib / 2);
     }
 
-    fn load_bytes(
-      store: &ByteStore,
-      entry_type: EntryType,
-      fingerprint: Fingerprint,
-    ) -> Result<Option<Bytes>, String> {
-      store.load_bytes_with(entry_type, fingerprint, |b| b).wait()
-    }
-  }
-}
+    #[test]
+    fn full_shard_grows() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path())
+        .with_lmdb_config(LmdbConfig::new(1, 128 * 1024, 1024 * 1024).unwrap());
 
-mod remote {
-  use super::EntryType;
+      // Leased entries can't be garbage collected, so the shard must grow to make space for them.
+      let fingerprints: Vec<_> = (0..32)
+        .map(|i| {
+          store
+            .store_bytes(EntryType::File, Bytes::from(vec![i; 8 * 1024]), true)
+            .wait()
+            .expect("Error storing")
+        })
+        .collect();
+      assert_eq!(
+        load_file_bytes(&store, fingerprints[0]),
+        Ok(Some(Bytes::from(vec![0; 8 * 1024])))
+      );
 
-  use bazel_protos;
-  use boxfuture::{BoxFuture, Boxable};
-  use bytes::{Bytes, BytesMut};
-  use digest::{Digest as DigestTrait, FixedOutput};
-  use futures::{self, future, Future, Sink, Stream};
-  use grpcio;
+      // But it can't grow past its maximum size.
+      let err = (32..255)
+        .map(|i| {
+          store
+            .store_bytes(EntryType::File, Bytes::from(vec![i; 8 * 1024]), true)
+            .wait()
+        })
+        .find(|res| res.is_err())
+        .expect("Want error")
+        .expect_err("Want error");
+      match err {
+        StoreError::ShardFull(EntryType::File, _, ref message) => {
+          assert!(message.contains("is full"), "Bad error: {}", message)
+        }
+        err => panic!("Want ShardFull error, got {:?}", err),
+      }
+    }
+
+    // Without the check (i.e. in release builds), this would deadlock.
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "while this thread has a transaction open")]
+    fn growing_with_open_transaction_panics() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let file_dbs = store.inner.file_dbs.get().unwrap();
+      let (env, _, _, _) = file_dbs.get(&TestData::roland().fingerprint());
+      let _txn = env.begin_ro_txn().unwrap();
+      let _ = env.grow(1024 * 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn read_only_fallback() {
+      let shared_dir = TempDir::new().unwrap();
+      let dir = TempDir::new().unwrap();
+      let roland = TestData::roland();
+      let catnip = TestData::catnip();
+      let testdir = TestDirectory::containing_roland();
+      let large = Bytes::from(vec![7; 1024]);
+      let large_fingerprint = {
+        let shared_store = new_store(shared_dir.path()).with_large_file_threshold(1024);
+        shared_store
+          .store_bytes(EntryType::File, roland.bytes(), false)
+          .wait()
+          .expect("Error storing");
+        shared_store
+          .store_bytes(EntryType::Directory, testdir.bytes(), false)
+          .wait()
+          .expect("Error storing");
+        shared_store
+          .store_bytes(EntryType::File, large.clone(), false)
+          .wait()
+          .expect("Error storing")
+      };
+
+      let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+      let shared_store = ByteStore::new_read_only(shared_dir.path(), pool.clone()).unwrap();
+      assert!(
+        shared_store
+          .store_bytes(EntryType::File, catnip.bytes(), false)
+          .wait()
+          .is_err()
+      );
+      assert!(new_store(dir.path()).with_read_only_fallback(new_store(dir.path())).is_err());
+
+      let store = new_store(dir.path())
+        .with_read_only_fallback(shared_store)
+        .unwrap();
+      assert_eq!(
+        load_file_bytes(&store, roland.fingerprint()),
+        Ok(Some(roland.bytes()))
+      );
+      assert_eq!(
+        load_directory_proto_bytes(&store, testdir.fingerprint()),
+        Ok(Some(testdir.bytes()))
+      );
+      assert_eq!(load_file_bytes(&store, large_fingerprint), Ok(Some(large)));
+      assert_eq!(
+        store
+          .load_file_bytes_range(large_fingerprint, 1020, 10)
+          .wait(),
+        Ok(Some(Bytes::from(vec![7; 4])))
+      );
+      assert_eq!(
+        store.entry_type(&testdir.fingerprint()),
+        Ok(Some(EntryType::Directory))
+      );
+
+      // Writes, and garbage collection, only affect the writable store.
+      store
+        .store_bytes(EntryType::File, catnip.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      assert_eq!(store.shrink(0), Ok(0));
+      assert_eq!(
+        load_file_bytes(&store, roland.fingerprint()),
+        Ok(Some(roland.bytes()))
+      );
+      assert_eq!(load_file_bytes(&store, catnip.fingerprint()), Ok(None));
+    }
+
+    #[test]
+    fn read_only_fallback_uses_lmdb_config() {
+      let shared_dir = TempDir::new().unwrap();
+      let dir = TempDir::new().unwrap();
+      let roland = TestData::roland();
+      let config = LmdbConfig::new(4, 1024 * 1024, 1024 * 1024).unwrap();
+      new_store(shared_dir.path())
+        .with_lmdb_config(config)
+        .store_bytes(EntryType::File, roland.bytes(), false)
+        .wait()
+        .expect("Error storing");
+
+      let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+      let shared_store = ByteStore::new_read_only(shared_dir.path(), pool).unwrap();
+      let store = new_store(dir.path())
+        .with_lmdb_config(config)
+        .with_read_only_fallback(shared_store)
+        .unwrap();
+      assert_eq!(
+        load_file_bytes(&store, roland.fingerprint()),
+        Ok(Some(roland.bytes()))
+      );
+    }
+
+    #[test]
+    fn read_only_store_without_encoding_database() {
+      // Stores created before compression was supported have no encodings database.
+      let dir = TempDir::new().unwrap();
+      let roland = TestData::roland();
+      for shard in 0..16 {
+        let dirname = format!("{:x}", shard);
+        let sharded_dir = dir.path().join("files").join(&dirname);
+        safe_create_dir_all(&sharded_dir).expect("Making temp dir");
+
+        let env = Environment::new()
+          .set_max_dbs(2)
+          .open(&sharded_dir)
+          .unwrap();
+        let database = env
+          .create_db(Some("content"), DatabaseFlags::empty())
+          .unwrap();
+        env
+          .create_db(Some("leases"), DatabaseFlags::empty())
+          .unwrap();
+        if roland.fingerprint().to_hex()[0..1] == dirname[..] {
+          env
+            .begin_rw_txn()
+            .and_then(|mut txn| {
+              txn
+                .put(
+                  database,
+                  &roland.fingerprint(),
+                  &roland.bytes(),
+                  WriteFlags::empty(),
+                )
+                .and_then(|()| txn.commit())
+            })
+            .unwrap();
+        }
+      }
+
+      let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+      let store = ByteStore::new_read_only(dir.path(), pool).unwrap();
+      assert_eq!(
+        load_file_bytes(&store, roland.fingerprint()),
+        Ok(Some(roland.bytes()))
+      );
+      assert_eq!(load_file_bytes(&store, TestData::catnip().fingerprint()), Ok(None));
+    }
+
+    #[test]
+    fn garbage_collect_nothing_to_do() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let bytes = Bytes::from("0123456789");
+      store
+        .store_bytes(EntryType::File, bytes.clone(), false)
+        .wait()
+        .expect("Error storing");
+      store.shrink(10).expect("Error shrinking");
+      assert_eq!(
+        load_bytes(
+          &store,
+          EntryType::File,
+          Fingerprint::from_hex_string(
+            "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
+          ).unwrap(),
+        ),
+        Ok(Some(bytes))
+      );
+    }
+
+    #[test]
+    fn garbage_collect_nothing_to_do_with_lease() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let bytes = Bytes::from("0123456789");
+      store
+        .store_bytes(EntryType::File, bytes.clone(), false)
+        .wait()
+        .expect("Error storing");
+      let file_fingerprint = Fingerprint::from_hex_string(
+        "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
+      ).unwrap();
+      let file_digest = Digest(file_fingerprint, 10);
+      store
+        .lease_all(vec![file_digest].iter())
+        .expect("Error leasing");
+      store.shrink(10).expect("Error shrinking");
+      assert_eq!(
+        load_bytes(&store, EntryType::File, file_fingerprint),
+        Ok(Some(bytes))
+      );
+    }
+
+    #[test]
+    fn garbage_collect_remove_one_of_two_files_no_leases() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let bytes_1 = Bytes::from("0123456789");
+      let fingerprint_1 = Fingerprint::from_hex_string(
+        "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
+      ).unwrap();
+      let bytes_2 = Bytes::from("9876543210");
+      let fingerprint_2 = Fingerprint::from_hex_string(
+        "7619ee8cea49187f309616e30ecf54be072259b43760f1f550a644945d5572f2",
+      ).unwrap();
+      store
+        .store_bytes(EntryType::File, bytes_1.clone(), false)
+        .wait()
+        .expect("Error storing");
+      store
+        .store_bytes(EntryType::File, bytes_2.clone(), false)
+        .wait()
+        .expect("Error storing");
+      store.shrink(10).expect("Error shrinking");
+      let mut entries = Vec::new();
+      entries
+        .push(load_bytes(&store, EntryType::File, fingerprint_1).expect("Error loading bytes"));
+      entries
+        .push(load_bytes(&store, EntryType::File, fingerprint_2).expect("Error loading bytes"));
+      assert_eq!(
+        1,
+        entries.iter().filter(|maybe| maybe.is_some()).count(),
+        "Want one Some but got: {:?}",
+        entries
+      );
+    }
+
+    #[test]
+    fn garbage_collect_remove_both_files_no_leases() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let bytes_1 = Bytes::from("0123456789");
+      let fingerprint_1 = Fingerprint::from_hex_string(
+        "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
+      ).unwrap();
+      let bytes_2 = Bytes::from("9876543210");
+      let fingerprint_2 = Fingerprint::from_hex_string(
+        "7619ee8cea49187f309616e30ecf54be072259b43760f1f550a644945d5572f2",
+      ).unwrap();
+      store
+        .store_bytes(EntryType::File, bytes_1.clone(), false)
+        .wait()
+        .expect("Error storing");
+      store
+        .store_bytes(EntryType::File, bytes_2.clone(), false)
+        .wait()
+        .expect("Error storing");
+      store.shrink(1).expect("Error shrinking");
+      assert_eq!(
+        load_bytes(&store, EntryType::File, fingerprint_1),
+        Ok(None),
+        "Should have garbage collected {:?}",
+        fingerprint_1
+      );
+      assert_eq!(
+        load_bytes(&store, EntryType::File, fingerprint_2),
+        Ok(None),
+        "Should have garbage collected {:?}",
+        fingerprint_2
+      );
+    }
+
+    #[test]
+    fn garbage_collect_remove_one_of_two_directories_no_leases() {
+      let dir = TempDir::new().unwrap();
+
+      let testdir = TestDirectory::containing_roland();
+      let other_testdir = TestDirectory::containing_dnalor();
+
+      let store = new_store(dir.path());
+      store
+        .store_bytes(EntryType::Directory, testdir.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      store
+        .store_bytes(EntryType::Directory, other_testdir.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      store.shrink(80).expect("Error shrinking");
+      let mut entries = Vec::new();
+      entries.push(
+        load_bytes(&store, EntryType::Directory, testdir.fingerprint())
+          .expect("Error loading bytes"),
+      );
+      entries.push(
+        load_bytes(&store, EntryType::Directory, other_testdir.fingerprint())
+          .expect("Error loading bytes"),
+      );
+      assert_eq!(
+        1,
+        entries.iter().filter(|maybe| maybe.is_some()).count(),
+        "Want one Some but got: {:?}",
+        entries
+      );
+    }
+
+    #[test]
+    fn garbage_collect_remove_file_with_leased_directory() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+
+      let testdir = TestDirectory::containing_roland();
+      let testdata = TestData::fourty_chars();
+
+      store
+        .store_bytes(EntryType::Directory, testdir.bytes(), true)
+        .wait()
+        .expect("Error storing");
+
+      store
+        .store_bytes(EntryType::File, testdata.bytes(), false)
+        .wait()
+        .expect("Error storing");
+
+      store.shrink(80).expect("Error shrinking");
+
+      assert_eq!(
+        load_bytes(&store, EntryType::File, testdata.fingerprint()),
+        Ok(None),
+        "File was present when it should've been garbage collected"
+      );
+      assert_eq!(
+        load_bytes(&store, EntryType::Directory, testdir.fingerprint()),
+        Ok(Some(testdir.bytes())),
+        "Directory was missing despite lease"
+      );
+    }
+
+    #[test]
+    fn garbage_collect_remove_file_while_leased_file() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+
+      let testdir = TestDirectory::containing_roland();
+
+      store
+        .store_bytes(EntryType::Directory, testdir.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      let fourty_chars = TestData::fourty_chars();
+      store
+        .store_bytes(EntryType::File, fourty_chars.bytes(), true)
+        .wait()
+        .expect("Error storing");
+
+      store.shrink(80).expect("Error shrinking");
+
+      assert_eq!(
+        load_bytes(&store, EntryType::File, fourty_chars.fingerprint()),
+        Ok(Some(fourty_chars.bytes())),
+        "File was missing despite lease"
+      );
+      assert_eq!(
+        load_bytes(&store, EntryType::Directory, testdir.fingerprint()),
+        Ok(None),
+        "Directory was present when it should've been garbage collected"
+      );
+    }
+
+    #[test]
+    fn garbage_collect_fail_because_too_many_leases() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+
+      let testdir = TestDirectory::containing_roland();
+      let fourty_chars = TestData::fourty_chars();
+
+      store
+        .store_bytes(EntryType::Directory, testdir.bytes(), true)
+        .wait()
+        .expect("Error storing");
+      store
+        .store_bytes(EntryType::File, fourty_chars.bytes(), true)
+        .wait()
+        .expect("Error storing");
+
+      store
+        .store_bytes(EntryType::File, TestData::roland().bytes(), false)
+        .wait()
+        .expect("Error storing");
+
+      assert_eq!(store.shrink(80), Ok(160));
+
+      assert_eq!(
+        load_bytes(&store, EntryType::File, fourty_chars.fingerprint()),
+        Ok(Some(fourty_chars.bytes())),
+        "Leased file should still be present"
+      );
+      assert_eq!(
+        load_bytes(&store, EntryType::Directory, testdir.fingerprint()),
+        Ok(Some(testdir.bytes())),
+        "Leased directory should still be present"
+      );
+      // Whether the unleased file is present is undefined.
+    }
+
+    #[test]
+    fn entry_type_for_file() {
+      let testdata = TestData::roland();
+      let testdir = TestDirectory::containing_roland();
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      store
+        .store_bytes(EntryType::Directory, testdir.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      store
+        .store_bytes(EntryType::File, testdata.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      assert_eq!(
+        store.entry_type(&testdata.fingerprint()),
+        Ok(Some(EntryType::File))
+      )
+    }
+
+    #[test]
+    fn entry_type_for_directory() {
+      let testdata = TestData::roland();
+      let testdir = TestDirectory::containing_roland();
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      store
+        .store_bytes(EntryType::Directory, testdir.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      store
+        .store_bytes(EntryType::File, testdata.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      assert_eq!(
+        store.entry_type(&testdir.fingerprint()),
+        Ok(Some(EntryType::Directory))
+      )
+    }
+
+    #[test]
+    fn entry_type_for_missing() {
+      let testdata = TestData::roland();
+      let testdir = TestDirectory::containing_roland();
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      store
+        .store_bytes(EntryType::Directory, testdir.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      store
+        .store_bytes(EntryType::File, testdata.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      assert_eq!(
+        store.entry_type(&TestDirectory::recursive().fingerprint()),
+        Ok(None)
+      )
+    }
+
+    #[test]
+    pub fn empty_file_is_known() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let empty_file = TestData::empty();
+      assert_eq!(
+        store
+          .load_bytes_with(EntryType::File, empty_file.fingerprint(), |b| b)
+          .wait(),
+        Ok(Some(empty_file.bytes())),
+      )
+    }
+
+    #[test]
+    pub fn empty_directory_is_known() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let empty_dir = TestDirectory::empty();
+      assert_eq!(
+        store
+          .load_bytes_with(EntryType::Directory, empty_dir.fingerprint(), |b| b)
+          .wait(),
+        Ok(Some(empty_dir.bytes())),
+      )
+    }
+
+    pub fn new_store<P: AsRef<Path>>(dir: P) -> ByteStore {
+      ByteStore::new(dir, Arc::new(ResettablePool::new("test-pool-".to_string()))).unwrap()
+    }
+
+    pub fn load_file_bytes(
+      store: &ByteStore,
+      fingerprint: Fingerprint,
+    ) -> Result<Option<Bytes>, String> {
+      load_bytes(&store, EntryType::File, fingerprint)
+    }
+
+    fn large_file_path(dir: &Path, fingerprint: Fingerprint) -> PathBuf {
+      let hex = fingerprint.to_hex();
+      dir.join("large_files").join(&hex[0..1]).join(hex)
+    }
+
+    pub fn load_directory_proto_bytes(
+      store: &ByteStore,
+      fingerprint: Fingerprint,
+    ) -> Result<Option<Bytes>, String> {
+      load_bytes(&store, EntryType::Directory, fingerprint)
+    }
+
+    fn load_bytes(
+      store: &ByteStore,
+      entry_type: EntryType,
+      fingerprint: Fingerprint,
+    ) -> Result<Option<Bytes>, String> {
+      store.load_bytes_with(entry_type, fingerprint, |b| b).wait()
+    }
+  }
+}
+
+mod remote {
+  use super::{byte_range, EntryType};
+
+  use bazel_protos;
+  use boxfuture::{BoxFuture, Boxable};
+  use bytes::{Bytes, BytesMut};
+  use digest::{Digest as DigestTrait, FixedOutput};
+  use futures::{self, future, Future, Sink, Stream};
+  use grpcio;
   use hashing::{Digest, Fingerprint};
   use resettable::Resettable;
   use sha2::Sha256;
   use std::cmp::min;
-  use std::collections::HashSet;
-  use std::sync::Arc;
-  use std::time::Duration;
+  use std::collections::{HashMap, HashSet, VecDeque};
+  use std::mem;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Arc, Mutex};
+  use std::time::{Duration, Instant};
+  use std::vec;
+
+  // How many digests are remembered as being present in the CAS, and for how long. The CAS may
+  // evict blobs at any time, so this is kept short: it is intended to save re-checking the same
+  // digests across the actions of a single run.
+  const PRESENCE_CACHE_CAPACITY: usize = 100_000;
+  const PRESENCE_CACHE_TTL_SECS: u64 = 5 * 60;
+
+  // How long an endpoint which failed a request is passed over for, doubling with each consecutive
+  // failure up to the maximum.
+  const UNHEALTHY_ENDPOINT_BACKOFF_SECS: u64 = 5;
+  const MAX_UNHEALTHY_ENDPOINT_BACKOFF_SECS: u64 = 5 * 60;
+
+  // What each blob adds to the size of a BatchUpdateBlobs request on top of its content (its digest
+  // and the message framing), which is allowed for when packing blobs into batches.
+  const BATCH_BLOB_OVERHEAD_BYTES: usize = 128;
 
+  ///
+  /// How requests are spread across the endpoints of a remote store.
+  ///
+  #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+  pub enum EndpointSelection {
+    // Each request starts with the next endpoint in turn.
+    RoundRobin,
+    // Each request starts with the first endpoint, and only uses the others if it is unhealthy.
+    PrimarySecondary,
+  }
+
+  ///
+  /// A remote store, served by one or more endpoints, each of which must serve the same content
+  /// (e.g. replicas of one CAS).
+  ///
+  /// A request which fails on one endpoint is retried on the next, and the failed endpoint is
+  /// treated as unhealthy for a while: unhealthy endpoints are only tried after all of the healthy
+  /// ones. A blob which an endpoint reports as missing is treated as missing, not as a failure.
+  ///
   #[derive(Clone)]
   pub struct ByteStore {
-    byte_stream_client: Resettable<Arc<bazel_protos::bytestream_grpc::ByteStreamClient>>,
-    cas_client:
-      Resettable<Arc<bazel_protos::remote_execution_grpc::ContentAddressableStorageClient>>,
+    endpoints: Vec<Endpoint>,
+    selection: EndpointSelection,
+    next_endpoint: Arc<AtomicUsize>,
     chunk_size_bytes: usize,
     upload_timeout: Duration,
+    // If set, small blobs are uploaded together in BatchUpdateBlobs requests of at most this size.
+    max_batch_total_size_bytes: Option<usize>,
     env: Resettable<Arc<grpcio::Environment>>,
-    channel: Resettable<grpcio::Channel>,
+    // Blobs which have recently been uploaded to, or found in, the CAS.
+    present_blobs: Arc<Mutex<PresenceCache>>,
+    // Directories which have recently been found in the CAS along with everything beneath them.
+    present_trees: Arc<Mutex<PresenceCache>>,
+    // Sent with every request, if set, so that the server can tell who it is serving.
+    request_metadata: Option<Arc<bazel_protos::RequestMetadata>>,
+    // The action which requests are being made for, if any, which is sent in request_metadata.
+    action_digest: Option<Digest>,
+    // Whether the server accepts UpdateActionResult requests, as reported by its capabilities.
+    action_cache_updates_enabled: bool,
   }
 
   impl ByteStore {
     pub fn new(
-      cas_address: String,
+      cas_addresses: Vec<String>,
+      selection: EndpointSelection,
       thread_count: usize,
-      chunk_size_bytes: usize,
-      upload_timeout: Duration,
-    ) -> ByteStore {
-      let env = Resettable::new(move || Arc::new(grpcio::Environment::new(thread_count)));
-      let env2 = env.clone();
-      let channel =
-        Resettable::new(move || grpcio::ChannelBuilder::new(env2.get()).connect(&cas_address));
-      let channel2 = channel.clone();
-      let channel3 = channel.clone();
-      let byte_stream_client = Resettable::new(move || {
-        Arc::new(bazel_protos::bytestream_grpc::ByteStreamClient::new(
-          channel2.get(),
-        ))
-      });
-      let cas_client = Resettable::new(move || {
-        Arc::new(
-          bazel_protos::remote_execution_grpc::ContentAddressableStorageClient::new(channel3.get()),
-        )
-      });
-      ByteStore {
-        byte_stream_client,
-        cas_client,
+      chunk_size_bytes: usize,
+      upload_timeout: Duration,
+    ) -> Result<ByteStore, String> {
+      if cas_addresses.is_empty() {
+        return Err("A remote store needs at least one address".to_owned());
+      }
+      let env = Resettable::new(move || Arc::new(grpcio::Environment::new(thread_count)));
+      let endpoints = cas_addresses
+        .into_iter()
+        .map(|cas_address| Endpoint::new(cas_address, env.clone()))
+        .collect();
+      Ok(ByteStore {
+        endpoints,
+        selection,
+        next_endpoint: Arc::new(AtomicUsize::new(0)),
         chunk_size_bytes,
         upload_timeout,
+        max_batch_total_size_bytes: None,
         env,
-        channel,
+        present_blobs: Arc::new(Mutex::new(PresenceCache::new(
+          PRESENCE_CACHE_CAPACITY,
+          Duration::from_secs(PRESENCE_CACHE_TTL_SECS),
+        ))),
+        present_trees: Arc::new(Mutex::new(PresenceCache::new(
+          PRESENCE_CACHE_CAPACITY,
+          Duration::from_secs(PRESENCE_CACHE_TTL_SECS),
+        ))),
+        request_metadata: None,
+        action_digest: None,
+        action_cache_updates_enabled: true,
+      })
+    }
+
+    ///
+    /// Sends the given RequestMetadata with every request made to the server.
+    ///
+    pub fn with_request_metadata(
+      self,
+      request_metadata: bazel_protos::RequestMetadata,
+    ) -> ByteStore {
+      ByteStore {
+        request_metadata: Some(Arc::new(request_metadata)),
+        ..self
+      }
+    }
+
+    ///
+    /// A clone of this store whose requests are attributed to the action with the given digest.
+    ///
+    pub fn for_action(&self, action_digest: Digest) -> ByteStore {
+      ByteStore {
+        action_digest: Some(action_digest),
+        ..self.clone()
+      }
+    }
+
+    fn call_option(&self) -> grpcio::CallOption {
+      match self.request_metadata {
+        Some(ref request_metadata) => request_metadata.call_option(self.action_digest.as_ref()),
+        None => grpcio::CallOption::default(),
+      }
+    }
+
+    ///
+    /// Whether the server accepts results being written to its action cache, which is assumed
+    /// unless its capabilities say otherwise.
+    ///
+    /// Nothing writes to the action cache yet: remote execution servers cache the results of the
+    /// actions they run themselves, and the results of local execution are never uploaded. Anything
+    /// which starts doing so must check this first.
+    ///
+    pub fn action_cache_updates_enabled(&self) -> bool {
+      self.action_cache_updates_enabled
+    }
+
+    ///
+    /// Asks the server what it supports, and configures this store to match, failing if the server
+    /// can't be used at all. Servers which don't implement the Capabilities service are assumed to
+    /// support what we need, and are used as they are.
+    ///
+    /// If the server advertises a maximum batch size, chunks are kept within it, and small blobs
+    /// are uploaded in batches of at most that size. Whether the server accepts action cache
+    /// updates is recorded: see action_cache_updates_enabled.
+    ///
+    pub fn negotiate_capabilities(self) -> Result<ByteStore, String> {
+      let call_option = self.call_option().timeout(self.upload_timeout);
+      let maybe_capabilities = self.with_failover_sync(|endpoint| {
+        match endpoint.capabilities_client.get().get_capabilities_opt(
+          &bazel_protos::remote_execution::GetCapabilitiesRequest::new(),
+          call_option.clone(),
+        ) {
+          Ok(capabilities) => Ok(Some(capabilities)),
+          Err(grpcio::Error::RpcFailure(grpcio::RpcStatus {
+            status: grpcio::RpcStatusCode::Unimplemented,
+            ..
+          })) => Ok(None),
+          Err(err) => Err(EndpointError::grpc(
+            format!(
+              "Error from server in response to GetCapabilities request: {:?}",
+              err
+            ),
+            &err,
+          )),
+        }
+      })?;
+      let cache_capabilities = match maybe_capabilities {
+        Some(mut capabilities) => capabilities.take_cache_capabilities(),
+        None => {
+          debug!("Remote store doesn't implement GetCapabilities; assuming it supports SHA-256");
+          return Ok(self);
+        }
+      };
+
+      if !cache_capabilities
+        .get_digest_function()
+        .contains(&bazel_protos::remote_execution::DigestFunction::SHA256)
+      {
+        return Err(format!(
+          "The remote store doesn't support SHA-256 digests, which are the only kind we use. \
+           It supports: {:?}",
+          cache_capabilities.get_digest_function()
+        ));
+      }
+      let action_cache_updates_enabled = cache_capabilities
+        .get_action_cache_update_capabilities()
+        .get_update_enabled();
+      debug!(
+        "Remote store capabilities: max batch size {} bytes, action cache updates enabled: {}",
+        cache_capabilities.get_max_batch_total_size_bytes(),
+        action_cache_updates_enabled
+      );
+
+      // A max batch size of 0 means that the server has no limit, but it may also come from a
+      // server which predates batching, so we only batch when the server gives a limit.
+      let max_batch_total_size_bytes = cache_capabilities.get_max_batch_total_size_bytes();
+      if max_batch_total_size_bytes <= 0 {
+        return Ok(ByteStore {
+          action_cache_updates_enabled,
+          ..self
+        });
+      }
+      let max_batch_total_size_bytes = max_batch_total_size_bytes as usize;
+      let chunk_size_bytes = min(self.chunk_size_bytes, max_batch_total_size_bytes);
+      Ok(ByteStore {
+        chunk_size_bytes,
+        max_batch_total_size_bytes: Some(max_batch_total_size_bytes),
+        action_cache_updates_enabled,
+        ..self
+      })
+    }
+
+    ///
+    /// The endpoints which a request should try, in the order it should try them.
+    ///
+    fn endpoints_to_try(&self) -> Vec<Endpoint> {
+      let first = match self.selection {
+        EndpointSelection::RoundRobin => {
+          self.next_endpoint.fetch_add(1, Ordering::Relaxed) % self.endpoints.len()
+        }
+        EndpointSelection::PrimarySecondary => 0,
+      };
+      // Unhealthy endpoints are still tried (after every healthy one), in case they've recovered.
+      let (healthy, unhealthy): (Vec<&Endpoint>, Vec<&Endpoint>) = self.endpoints[first..]
+        .iter()
+        .chain(self.endpoints[..first].iter())
+        .partition(|endpoint| endpoint.is_healthy());
+      healthy.into_iter().chain(unhealthy).cloned().collect()
+    }
+
+    ///
+    /// Makes a request to each endpoint in turn until one of them succeeds. Errors which aren't the
+    /// fault of the endpoint (see EndpointError) are returned straight away, without trying any
+    /// other endpoint or marking the endpoint as unhealthy.
+    ///
+    fn with_failover<T: Send + 'static, F>(&self, f: F) -> BoxFuture<T, String>
+    where
+      F: Fn(&Endpoint) -> BoxFuture<T, EndpointError> + Send + 'static,
+    {
+      future::loop_fn(
+        (self.endpoints_to_try().into_iter(), Vec::new()),
+        move |(mut endpoints, mut errors): (vec::IntoIter<Endpoint>, Vec<(String, String)>)| {
+          match endpoints.next() {
+            Some(endpoint) => {
+              let request = f(&endpoint);
+              request
+                .then(move |result| -> Result<future::Loop<T, _>, String> {
+                  match result {
+                    Ok(value) => {
+                      endpoint.record_success();
+                      Ok(future::Loop::Break(value))
+                    }
+                    Err(ref err) if !err.fail_over => Err(err.message.clone()),
+                    Err(err) => {
+                      endpoint.record_failure(&err.message, !endpoints.as_slice().is_empty());
+                      errors.push((endpoint.address, err.message));
+                      Ok(future::Loop::Continue((endpoints, errors)))
+                    }
+                  }
+                })
+                .to_boxed()
+            }
+            None => future::err(failover_error(errors)).to_boxed(),
+          }
+        },
+      ).to_boxed()
+    }
+
+    ///
+    /// As with_failover, but for synchronous requests.
+    ///
+    fn with_failover_sync<T, F: Fn(&Endpoint) -> Result<T, EndpointError>>(
+      &self,
+      f: F,
+    ) -> Result<T, String> {
+      let mut endpoints = self.endpoints_to_try().into_iter();
+      let mut errors = Vec::new();
+      while let Some(endpoint) = endpoints.next() {
+        match f(&endpoint) {
+          Ok(value) => {
+            endpoint.record_success();
+            return Ok(value);
+          }
+          Err(ref err) if !err.fail_over => return Err(err.message.clone()),
+          Err(err) => {
+            endpoint.record_failure(&err.message, !endpoints.as_slice().is_empty());
+            errors.push((endpoint.address, err.message));
+          }
+        }
+      }
+      Err(failover_error(errors))
+    }
+
+    ///
+    /// Whether the blob with the given digest was recently known to be present in the CAS.
+    ///
+    pub fn is_known_present(&self, digest: &Digest) -> bool {
+      self.present_blobs.lock().unwrap().contains(digest)
+    }
+
+    ///
+    /// Whether the Directory with the given digest, and everything beneath it, was recently known
+    /// to be present in the CAS.
+    ///
+    pub fn is_tree_known_present(&self, digest: &Digest) -> bool {
+      self.present_trees.lock().unwrap().contains(digest)
+    }
+
+    ///
+    /// Records that each of the given Directories, and everything beneath them, is present in the
+    /// CAS.
+    ///
+    pub fn record_trees_present<'a, Digests: Iterator<Item = &'a Digest>>(&self, digests: Digests) {
+      let mut present_trees = self.present_trees.lock().unwrap();
+      for digest in digests {
+        present_trees.insert(*digest);
+      }
+    }
+
+    ///
+    /// Forgets that the given digests are present in the CAS (e.g. because the server has reported
+    /// them missing). Because any Directory may contain them, every tree is forgotten too.
+    ///
+    pub fn forget_present(&self, digests: &[Digest]) {
+      {
+        let mut present_blobs = self.present_blobs.lock().unwrap();
+        for digest in digests {
+          present_blobs.remove(digest);
+        }
       }
+      self.present_trees.lock().unwrap().clear();
     }
 
     pub fn reset_threadpool(&self) {
-      self.channel.reset();
+      for endpoint in &self.endpoints {
+        endpoint.channel.reset();
+      }
       self.env.reset();
-      self.cas_client.reset();
-      self.byte_stream_client.reset();
+      for endpoint in &self.endpoints {
+        endpoint.cas_client.reset();
+        endpoint.byte_stream_client.reset();
+        endpoint.capabilities_client.reset();
+      }
     }
 
     pub fn store_bytes(&self, bytes: Bytes) -> BoxFuture<Digest, String> {
+      let store = self.clone();
+      self.with_failover(move |endpoint| store.store_bytes_to(endpoint, bytes.clone()))
+    }
+
+    ///
+    /// Uploads all of the given blobs: batching small ones together if the server supports it, and
+    /// streaming the rest individually.
+    ///
+    pub fn store_all_bytes(&self, blobs: Vec<Bytes>) -> BoxFuture<(), String> {
+      let mut uploads = Vec::new();
+      let mut batch = Vec::new();
+      let mut batch_size_bytes = 0;
+      for bytes in blobs {
+        let size_bytes = bytes.len() + BATCH_BLOB_OVERHEAD_BYTES;
+        match self.max_batch_total_size_bytes {
+          Some(max_batch_total_size_bytes) if size_bytes <= max_batch_total_size_bytes => {
+            if batch_size_bytes + size_bytes > max_batch_total_size_bytes {
+              uploads.push(self.store_batch(mem::replace(&mut batch, Vec::new())));
+              batch_size_bytes = 0;
+            }
+            batch_size_bytes += size_bytes;
+            batch.push(bytes);
+          }
+          _ => uploads.push(self.store_bytes(bytes).map(|_| ()).to_boxed()),
+        }
+      }
+      if !batch.is_empty() {
+        uploads.push(self.store_batch(batch));
+      }
+      future::join_all(uploads).map(|_| ()).to_boxed()
+    }
+
+    fn store_batch(&self, blobs: Vec<Bytes>) -> BoxFuture<(), String> {
+      let mut request = bazel_protos::remote_execution::BatchUpdateBlobsRequest::new();
+      let mut digests = Vec::new();
+      for bytes in blobs {
+        let mut hasher = Sha256::default();
+        hasher.input(&bytes);
+        let fingerprint = Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice());
+        let digest = Digest(fingerprint, bytes.len());
+        let mut blob_request = bazel_protos::remote_execution::UpdateBlobRequest::new();
+        blob_request.set_content_digest((&digest).into());
+        blob_request.set_data(bytes);
+        request.mut_requests().push(blob_request);
+        digests.push(digest);
+      }
+      let call_option = self.call_option().timeout(self.upload_timeout);
+      let present_blobs = self.present_blobs.clone();
+      self.with_failover(move |endpoint| {
+        let present_blobs = present_blobs.clone();
+        let digests = digests.clone();
+        match endpoint
+          .cas_client
+          .get()
+          .batch_update_blobs_async_opt(&request, call_option.clone())
+        {
+          // As in store_bytes_to, the client is kept alive until the call completes.
+          Ok(receiver) => future::ok(endpoint.cas_client.get())
+            .join(receiver.map_err(|err| {
+              EndpointError::grpc(
+                format!(
+                  "Error from server in response to BatchUpdateBlobs request: {:?}",
+                  err
+                ),
+                &err,
+              )
+            }))
+            .and_then(move |(_client, response)| {
+              for blob_response in response.get_responses() {
+                if blob_response.get_status().get_code() != bazel_protos::code::Code::OK as i32 {
+                  return Err(EndpointError::other(format!(
+                    "Error from server when uploading digest {:?} in a batch: {:?}",
+                    blob_response.get_blob_digest(),
+                    blob_response.get_status()
+                  )));
+                }
+              }
+              let mut present_blobs = present_blobs.lock().unwrap();
+              for digest in digests {
+                present_blobs.insert(digest);
+              }
+              Ok(())
+            })
+            .to_boxed(),
+          Err(err) => future::err(EndpointError::grpc(
+            format!(
+              "Error attempting to make BatchUpdateBlobs request: {:?}",
+              err
+            ),
+            &err,
+          )).to_boxed(),
+        }
+      })
+    }
+
+    fn store_bytes_to(
+      &self,
+      endpoint: &Endpoint,
+      bytes: Bytes,
+    ) -> BoxFuture<Digest, EndpointError> {
       let mut hasher = Sha256::default();
       hasher.input(&bytes);
       let fingerprint = Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice());
       let len = bytes.len();
+      let present_blobs = self.present_blobs.clone();
       let resource_name = format!(
         "{}/uploads/{}/blobs/{}/{}",
         "",
@@ -1641,14 +5179,17 @@ mod remote {
         fingerprint,
         bytes.len()
       );
-      match self
+      match endpoint
         .byte_stream_client
         .get()
-        .write_opt(grpcio::CallOption::default().timeout(self.upload_timeout))
+        .write_opt(self.call_option().timeout(self.upload_timeout))
       {
-        Err(err) => future::err(format!(
-          "Error attempting to connect to upload fingerprint {}: {:?}",
-          fingerprint, err
+        Err(err) => future::err(EndpointError::grpc(
+          format!(
+            "Error attempting to connect to upload fingerprint {}: {:?}",
+            fingerprint, err
+          ),
+          &err,
         )).to_boxed(),
         Ok((sender, receiver)) => {
           let chunk_size_bytes = self.chunk_size_bytes;
@@ -1673,115 +5214,408 @@ mod remote {
               },
             );
 
-          future::ok(self.byte_stream_client.get())
-            .join(sender.send_all(stream).map_err(move |e| {
-              format!(
-                "Error attempting to upload fingerprint {}: {:?}",
-                fingerprint, e
-              )
-            }))
-            .and_then(move |_| {
-              receiver.map_err(move |e| {
-                format!(
-                  "Error from server when uploading fingerprint {}: {:?}",
-                  fingerprint, e
-                )
-              })
-            })
-            .and_then(move |received| {
-              if received.get_committed_size() == len as i64 {
-                Ok(Digest(fingerprint, len))
-              } else {
-                Err(format!(
-                  "Uploading file with fingerprint {}: want commited size {} but got {}",
-                  fingerprint,
-                  len,
-                  received.get_committed_size()
-                ))
-              }
-            })
-            .to_boxed()
-        }
+          future::ok(endpoint.byte_stream_client.get())
+            .join(sender.send_all(stream).map_err(move |e| {
+              EndpointError::grpc(
+                format!(
+                  "Error attempting to upload fingerprint {}: {:?}",
+                  fingerprint, e
+                ),
+                &e,
+              )
+            }))
+            .and_then(move |_| {
+              receiver.map_err(move |e| {
+                EndpointError::grpc(
+                  format!(
+                    "Error from server when uploading fingerprint {}: {:?}",
+                    fingerprint, e
+                  ),
+                  &e,
+                )
+              })
+            })
+            .and_then(move |received| {
+              if received.get_committed_size() == len as i64 {
+                let digest = Digest(fingerprint, len);
+                present_blobs.lock().unwrap().insert(digest);
+                Ok(digest)
+              } else {
+                Err(EndpointError::other(format!(
+                  "Uploading file with fingerprint {}: want commited size {} but got {}",
+                  fingerprint,
+                  len,
+                  received.get_committed_size()
+                )))
+              }
+            })
+            .to_boxed()
+        }
+      }
+    }
+
+    pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
+      &self,
+      _entry_type: EntryType,
+      digest: Digest,
+      f: F,
+    ) -> BoxFuture<Option<T>, String> {
+      // 0 means no limit.
+      self
+        .read(digest, 0, 0)
+        .map(move |maybe_bytes| maybe_bytes.map(f))
+        .to_boxed()
+    }
+
+    ///
+    /// Loads at most length bytes of the blob with the given digest, starting at offset, without
+    /// downloading the rest of the blob.
+    ///
+    pub fn load_bytes_range(
+      &self,
+      digest: Digest,
+      offset: usize,
+      length: usize,
+    ) -> BoxFuture<Option<Bytes>, String> {
+      // A read_limit of 0 means no limit, so an empty range cannot be asked for: it is returned
+      // without checking that the server has the blob.
+      if length == 0 {
+        return future::ok(Some(Bytes::new())).to_boxed();
+      }
+      // Servers may return more than was asked for.
+      self
+        .read(digest, offset, length)
+        .map(move |maybe_bytes| maybe_bytes.map(|bytes| byte_range(&bytes, 0, length)))
+        .to_boxed()
+    }
+
+    fn read(
+      &self,
+      digest: Digest,
+      offset: usize,
+      limit: usize,
+    ) -> BoxFuture<Option<Bytes>, String> {
+      let call_option = self.call_option();
+      self.with_failover(move |endpoint| {
+        ByteStore::read_from(endpoint, call_option.clone(), digest, offset, limit)
+      })
+    }
+
+    fn read_from(
+      endpoint: &Endpoint,
+      call_option: grpcio::CallOption,
+      digest: Digest,
+      offset: usize,
+      limit: usize,
+    ) -> BoxFuture<Option<Bytes>, EndpointError> {
+      let mut req = bazel_protos::bytestream::ReadRequest::new();
+      req.set_resource_name(format!("/blobs/{}/{}", digest.0, digest.1));
+      req.set_read_offset(offset as i64);
+      req.set_read_limit(limit as i64);
+      match endpoint.byte_stream_client.get().read_opt(&req, call_option) {
+        Ok(stream) => {
+          let capacity = if limit == 0 {
+            digest.1.saturating_sub(offset)
+          } else {
+            min(limit, digest.1.saturating_sub(offset))
+          };
+          // We shouldn't have to pass around the client here, it's a workaround for
+          // https://github.com/pingcap/grpc-rs/issues/123
+          future::ok(endpoint.byte_stream_client.get())
+            .join(
+              stream.fold(BytesMut::with_capacity(capacity), move |mut bytes, r| {
+                bytes.extend_from_slice(&r.data);
+                future::ok::<_, grpcio::Error>(bytes)
+              }),
+            )
+            .map(|(_client, bytes)| Some(bytes.freeze()))
+            .or_else(|e| match e {
+              grpcio::Error::RpcFailure(grpcio::RpcStatus {
+                status: grpcio::RpcStatusCode::NotFound,
+                ..
+              }) => Ok(None),
+              _ => Err(EndpointError::grpc(
+                format!(
+                  "Error from server in response to CAS read request: {:?}",
+                  e
+                ),
+                &e,
+              )),
+            })
+            .to_boxed()
+        }
+        Err(err) => future::err(EndpointError::grpc(
+          format!(
+            "Error making CAS read request for {:?}: {:?}",
+            digest, err
+          ),
+          &err,
+        )).to_boxed(),
+      }
+    }
+
+    pub fn list_missing_digests<'a, Digests: Iterator<Item = &'a Digest>>(
+      &self,
+      digests: Digests,
+    ) -> Result<HashSet<Digest>, String> {
+      // Digests which were recently found in the CAS aren't checked again.
+      let unknown_digests: Vec<Digest> = digests
+        .filter(|digest| !self.is_known_present(digest))
+        .cloned()
+        .collect();
+      if unknown_digests.is_empty() {
+        return Ok(HashSet::new());
+      }
+      let mut request = bazel_protos::remote_execution::FindMissingBlobsRequest::new();
+      for digest in &unknown_digests {
+        request.mut_blob_digests().push(digest.into());
+      }
+      let call_option = self.call_option().timeout(self.upload_timeout);
+      let missing_digests: HashSet<Digest> = self.with_failover_sync(|endpoint| {
+        endpoint
+          .cas_client
+          .get()
+          .find_missing_blobs_opt(&request, call_option.clone())
+          .map_err(|err| {
+            EndpointError::grpc(
+              format!(
+                "Error from server in response to find_missing_blobs_request: {:?}",
+                err
+              ),
+              &err,
+            )
+          })
+          .and_then(|response| {
+            response
+              .get_missing_blob_digests()
+              .iter()
+              .map(|digest| digest.into())
+              .collect::<Result<HashSet<Digest>, String>>()
+              .map_err(EndpointError::other)
+          })
+      })?;
+      let mut present_blobs = self.present_blobs.lock().unwrap();
+      for digest in unknown_digests {
+        if !missing_digests.contains(&digest) {
+          present_blobs.insert(digest);
+        }
+      }
+      Ok(missing_digests)
+    }
+  }
+
+  ///
+  /// An error from a request to one endpoint, and whether it was the endpoint's fault (i.e. it
+  /// couldn't be reached, or was unavailable or overloaded), in which case another endpoint should
+  /// be tried. Any other error (e.g. a rejected request) would fail in the same way on every
+  /// endpoint.
+  ///
+  struct EndpointError {
+    message: String,
+    fail_over: bool,
+  }
+
+  impl EndpointError {
+    fn grpc(message: String, err: &grpcio::Error) -> EndpointError {
+      let fail_over = match *err {
+        grpcio::Error::RpcFailure(ref status) => match status.status {
+          grpcio::RpcStatusCode::Unavailable
+          | grpcio::RpcStatusCode::DeadlineExceeded
+          | grpcio::RpcStatusCode::ResourceExhausted => true,
+          _ => false,
+        },
+        // Responses which couldn't be decoded came from a server which is reachable.
+        grpcio::Error::Codec(_) => false,
+        // Everything else is a failure of the transport.
+        _ => true,
+      };
+      EndpointError { message, fail_over }
+    }
+
+    fn other(message: String) -> EndpointError {
+      EndpointError {
+        message,
+        fail_over: false,
+      }
+    }
+  }
+
+  ///
+  /// Combines the errors from every endpoint which a request failed on into one error.
+  ///
+  fn failover_error(mut errors: Vec<(String, String)>) -> String {
+    if errors.len() == 1 {
+      return errors.pop().unwrap().1;
+    }
+    format!(
+      "Request failed on all {} remote store endpoints: {}",
+      errors.len(),
+      errors
+        .into_iter()
+        .map(|(address, err)| format!("{}: {}", address, err))
+        .collect::<Vec<_>>()
+        .join("; ")
+    )
+  }
+
+  ///
+  /// One address of a remote store, with its own connection, and a record of how healthy it is
+  /// which is shared between clones.
+  ///
+  #[derive(Clone)]
+  struct Endpoint {
+    address: String,
+    channel: Resettable<grpcio::Channel>,
+    byte_stream_client: Resettable<Arc<bazel_protos::bytestream_grpc::ByteStreamClient>>,
+    cas_client:
+      Resettable<Arc<bazel_protos::remote_execution_grpc::ContentAddressableStorageClient>>,
+    capabilities_client: Resettable<Arc<bazel_protos::remote_execution_grpc::CapabilitiesClient>>,
+    health: Arc<Mutex<EndpointHealth>>,
+  }
+
+  impl Endpoint {
+    fn new(address: String, env: Resettable<Arc<grpcio::Environment>>) -> Endpoint {
+      let address2 = address.clone();
+      let channel =
+        Resettable::new(move || grpcio::ChannelBuilder::new(env.get()).connect(&address2));
+      let channel2 = channel.clone();
+      let channel3 = channel.clone();
+      let channel4 = channel.clone();
+      let byte_stream_client = Resettable::new(move || {
+        Arc::new(bazel_protos::bytestream_grpc::ByteStreamClient::new(
+          channel2.get(),
+        ))
+      });
+      let cas_client = Resettable::new(move || {
+        Arc::new(
+          bazel_protos::remote_execution_grpc::ContentAddressableStorageClient::new(channel3.get()),
+        )
+      });
+      let capabilities_client = Resettable::new(move || {
+        Arc::new(bazel_protos::remote_execution_grpc::CapabilitiesClient::new(
+          channel4.get(),
+        ))
+      });
+      Endpoint {
+        address,
+        channel,
+        byte_stream_client,
+        cas_client,
+        capabilities_client,
+        health: Arc::new(Mutex::new(EndpointHealth {
+          consecutive_failures: 0,
+          unhealthy_until: None,
+        })),
+      }
+    }
+
+    fn is_healthy(&self) -> bool {
+      match self.health.lock().unwrap().unhealthy_until {
+        Some(unhealthy_until) => Instant::now() >= unhealthy_until,
+        None => true,
       }
     }
 
-    pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
-      &self,
-      _entry_type: EntryType,
-      digest: Digest,
-      f: F,
-    ) -> BoxFuture<Option<T>, String> {
-      match self.byte_stream_client.get().read(&{
-        let mut req = bazel_protos::bytestream::ReadRequest::new();
-        req.set_resource_name(format!("/blobs/{}/{}", digest.0, digest.1));
-        req.set_read_offset(0);
-        // 0 means no limit.
-        req.set_read_limit(0);
-        req
-      }) {
-        Ok(stream) => {
-          // We shouldn't have to pass around the client here, it's a workaround for
-          // https://github.com/pingcap/grpc-rs/issues/123
-          future::ok(self.byte_stream_client.get())
-            .join(
-              stream.fold(BytesMut::with_capacity(digest.1), move |mut bytes, r| {
-                bytes.extend_from_slice(&r.data);
-                future::ok::<_, grpcio::Error>(bytes)
-              }),
-            )
-            .map(|(_client, bytes)| Some(bytes.freeze()))
-            .or_else(|e| match e {
-              grpcio::Error::RpcFailure(grpcio::RpcStatus {
-                status: grpcio::RpcStatusCode::NotFound,
-                ..
-              }) => Ok(None),
-              _ => Err(format!(
-                "Error from server in response to CAS read request: {:?}",
-                e
-              )),
-            })
-            .map(move |maybe_bytes| maybe_bytes.map(f))
-            .to_boxed()
-        }
-        Err(err) => future::err(format!(
-          "Error making CAS read request for {:?}: {:?}",
-          digest, err
-        )).to_boxed(),
+    fn record_success(&self) {
+      let mut health = self.health.lock().unwrap();
+      health.consecutive_failures = 0;
+      health.unhealthy_until = None;
+    }
+
+    fn record_failure(&self, err: &str, failing_over: bool) {
+      let backoff = {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        let doublings = min(health.consecutive_failures - 1, 16);
+        let backoff = Duration::from_secs(min(
+          UNHEALTHY_ENDPOINT_BACKOFF_SECS << doublings,
+          MAX_UNHEALTHY_ENDPOINT_BACKOFF_SECS,
+        ));
+        health.unhealthy_until = Some(Instant::now() + backoff);
+        backoff
+      };
+      if failing_over {
+        warn!(
+          "Remote store endpoint {} failed (passing over it for {:?}), trying another: {}",
+          self.address, backoff, err
+        );
+      }
+    }
+  }
+
+  struct EndpointHealth {
+    consecutive_failures: u32,
+    unhealthy_until: Option<Instant>,
+  }
+
+  ///
+  /// A bounded set of digests, each of which is forgotten a fixed time after it was added. When the
+  /// set is full, the digests which were added longest ago are forgotten first.
+  ///
+  struct PresenceCache {
+    capacity: usize,
+    ttl: Duration,
+    added: HashMap<Digest, Instant>,
+    // Digests in the order they were added, with the time they were added. Digests which have been
+    // removed (or expired and been re-added) may still appear, with a time which no longer matches
+    // that in added.
+    order: VecDeque<(Instant, Digest)>,
+  }
+
+  impl PresenceCache {
+    fn new(capacity: usize, ttl: Duration) -> PresenceCache {
+      PresenceCache {
+        capacity,
+        ttl,
+        added: HashMap::new(),
+        order: VecDeque::new(),
       }
     }
 
-    pub fn list_missing_digests<'a, Digests: Iterator<Item = &'a Digest>>(
-      &self,
-      digests: Digests,
-    ) -> Result<HashSet<Digest>, String> {
-      let mut request = bazel_protos::remote_execution::FindMissingBlobsRequest::new();
-      for digest in digests {
-        request.mut_blob_digests().push(digest.into());
+    fn contains(&self, digest: &Digest) -> bool {
+      match self.added.get(digest) {
+        Some(added_at) => added_at.elapsed() < self.ttl,
+        None => false,
       }
-      self
-        .cas_client
-        .get()
-        .find_missing_blobs(&request)
-        .map_err(|err| {
-          format!(
-            "Error from server in response to find_missing_blobs_request: {:?}",
-            err
-          )
-        })
-        .and_then(|response| {
-          response
-            .get_missing_blob_digests()
-            .iter()
-            .map(|digest| digest.into())
-            .collect()
-        })
+    }
+
+    fn insert(&mut self, digest: Digest) {
+      if self.contains(&digest) {
+        return;
+      }
+      let now = Instant::now();
+      self.added.insert(digest, now);
+      self.order.push_back((now, digest));
+      loop {
+        let (added_at, oldest) = match self.order.front() {
+          Some(&front) => front,
+          None => break,
+        };
+        if self.added.len() <= self.capacity && added_at.elapsed() < self.ttl {
+          break;
+        }
+        self.order.pop_front();
+        if self.added.get(&oldest) == Some(&added_at) {
+          self.added.remove(&oldest);
+        }
+      }
+    }
+
+    fn remove(&mut self, digest: &Digest) {
+      self.added.remove(digest);
+    }
+
+    fn clear(&mut self) {
+      self.added.clear();
+      self.order.clear();
     }
   }
 
   #[cfg(test)]
   mod tests {
     use super::super::EntryType;
-    use super::ByteStore;
+    use super::{ByteStore, EndpointSelection};
+    use bazel_protos;
     use bytes::Bytes;
     use futures::Future;
     use hashing::Digest;
@@ -1803,6 +5637,51 @@ mod remote {
       );
     }
 
+    #[test]
+    fn loads_file_range() {
+      let testdata = TestData::roland();
+      let cas = new_cas(3);
+
+      assert_eq!(
+        new_byte_store(&cas)
+          .load_bytes_range(testdata.digest(), 2, 5)
+          .wait(),
+        Ok(Some(testdata.bytes().slice(2, 7)))
+      );
+      assert_eq!(
+        new_byte_store(&cas)
+          .load_bytes_range(testdata.digest(), 2, 1000)
+          .wait(),
+        Ok(Some(testdata.bytes().slice_from(2)))
+      );
+    }
+
+    #[test]
+    fn loads_empty_file_range_without_reading() {
+      let testdata = TestData::roland();
+      let cas = new_cas(3);
+
+      assert_eq!(
+        new_byte_store(&cas)
+          .load_bytes_range(testdata.digest(), 2, 0)
+          .wait(),
+        Ok(Some(Bytes::new()))
+      );
+      assert_eq!(cas.read_request_count(), 0);
+    }
+
+    #[test]
+    fn missing_file_range() {
+      let cas = StubCAS::empty();
+
+      assert_eq!(
+        new_byte_store(&cas)
+          .load_bytes_range(TestData::roland().digest(), 2, 5)
+          .wait(),
+        Ok(None)
+      );
+    }
+
     #[test]
     fn missing_file() {
       let cas = StubCAS::empty();
@@ -1926,7 +5805,13 @@ mod remote {
     fn write_file_multiple_chunks() {
       let cas = StubCAS::empty();
 
-      let store = ByteStore::new(cas.address(), 1, 10 * 1024, Duration::from_secs(5));
+      let store = ByteStore::new(
+        vec![cas.address()],
+        EndpointSelection::PrimarySecondary,
+        1,
+        10 * 1024,
+        Duration::from_secs(5),
+      ).unwrap();
 
       let all_the_henries = big_file_bytes();
 
@@ -1955,58 +5840,278 @@ mod remote {
     }
 
     #[test]
-    fn write_empty_file() {
-      let empty_file = TestData::empty();
+    fn write_empty_file() {
+      let empty_file = TestData::empty();
+      let cas = StubCAS::empty();
+
+      let store = new_byte_store(&cas);
+      assert_eq!(
+        store.store_bytes(empty_file.bytes()).wait(),
+        Ok(empty_file.digest())
+      );
+
+      let blobs = cas.blobs.lock().unwrap();
+      assert_eq!(
+        blobs.get(&empty_file.fingerprint()),
+        Some(&empty_file.bytes())
+      );
+    }
+
+    #[test]
+    fn write_file_errors() {
+      let cas = StubCAS::always_errors();
+
+      let store = new_byte_store(&cas);
+      let error = store
+        .store_bytes(TestData::roland().bytes())
+        .wait()
+        .expect_err("Want error");
+      assert!(
+        error.contains("Error from server"),
+        format!("Bad error message, got: {}", error)
+      );
+      assert!(
+        error.contains("StubCAS is configured to always fail"),
+        format!("Bad error message, got: {}", error)
+      );
+    }
+
+    #[test]
+    fn write_connection_error() {
+      let store = ByteStore::new(
+        vec!["doesnotexist.example".to_owned()],
+        EndpointSelection::PrimarySecondary,
+        1,
+        10 * 1024 * 1024,
+        Duration::from_secs(1),
+      ).unwrap();
+      let error = store
+        .store_bytes(TestData::roland().bytes())
+        .wait()
+        .expect_err("Want error");
+      assert!(
+        error.contains("Error attempting to upload fingerprint"),
+        format!("Bad error message, got: {}", error)
+      );
+    }
+
+    #[test]
+    fn fails_over_to_healthy_endpoint() {
+      let bad_cas = StubCAS::always_errors();
+      let good_cas = new_cas(10);
+      let store = ByteStore::new(
+        vec![bad_cas.address(), good_cas.address()],
+        EndpointSelection::PrimarySecondary,
+        1,
+        10 * 1024 * 1024,
+        Duration::from_secs(1),
+      ).unwrap();
+
+      let testdata = TestData::roland();
+      assert_eq!(
+        load_file_bytes(&store, testdata.digest()),
+        Ok(Some(testdata.bytes()))
+      );
+      assert_eq!(
+        store.list_missing_digests(vec![testdata.digest()].iter()),
+        Ok(HashSet::new())
+      );
+      // The failed endpoint is passed over for a while, even though it is the primary.
+      assert_eq!(
+        store.store_bytes(TestData::catnip().bytes()).wait(),
+        Ok(TestData::catnip().digest())
+      );
+      assert_eq!(bad_cas.write_message_sizes.lock().unwrap().len(), 0);
+      assert_eq!(good_cas.write_message_sizes.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn does_not_fail_over_rejected_requests() {
+      let rejecting_cas = StubCAS::always_rejects();
+      let good_cas = new_cas(10);
+      let store = ByteStore::new(
+        vec![rejecting_cas.address(), good_cas.address()],
+        EndpointSelection::PrimarySecondary,
+        1,
+        10 * 1024 * 1024,
+        Duration::from_secs(1),
+      ).unwrap();
+
+      // The request would fail in the same way anywhere, so the error is returned as it is, and the
+      // endpoint is still used for the next request.
+      for _ in 0..2 {
+        let error = load_file_bytes(&store, TestData::roland().digest()).expect_err("Want error");
+        assert!(
+          error.contains("StubCAS is configured to reject every request"),
+          format!("Bad error message, got: {}", error)
+        );
+      }
+      assert_eq!(good_cas.read_request_count(), 0);
+    }
+
+    #[test]
+    fn round_robin_spreads_requests() {
+      let cas1 = StubCAS::empty();
+      let cas2 = StubCAS::empty();
+      let store = ByteStore::new(
+        vec![cas1.address(), cas2.address()],
+        EndpointSelection::RoundRobin,
+        1,
+        10 * 1024 * 1024,
+        Duration::from_secs(1),
+      ).unwrap();
+
+      for testdata in &[TestData::roland(), TestData::catnip()] {
+        assert_eq!(
+          store.store_bytes(testdata.bytes()).wait(),
+          Ok(testdata.digest())
+        );
+      }
+      assert_eq!(cas1.write_message_sizes.lock().unwrap().len(), 1);
+      assert_eq!(cas2.write_message_sizes.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn all_endpoints_failing() {
+      let cas1 = StubCAS::always_errors();
+      let cas2 = StubCAS::always_errors();
+      let store = ByteStore::new(
+        vec![cas1.address(), cas2.address()],
+        EndpointSelection::RoundRobin,
+        1,
+        10 * 1024 * 1024,
+        Duration::from_secs(1),
+      ).unwrap();
+
+      let error = load_file_bytes(&store, TestData::roland().digest()).expect_err("Want error");
+      assert!(
+        error.contains("Request failed on all 2 remote store endpoints"),
+        format!("Bad error message, got: {}", error)
+      );
+      assert!(
+        error.contains(&cas1.address()) && error.contains(&cas2.address()),
+        format!("Bad error message, got: {}", error)
+      );
+    }
+
+    #[test]
+    fn needs_an_endpoint() {
+      assert!(
+        ByteStore::new(
+          vec![],
+          EndpointSelection::RoundRobin,
+          1,
+          10 * 1024 * 1024,
+          Duration::from_secs(1),
+        ).is_err()
+      );
+    }
+
+    #[test]
+    fn batches_uploads_when_server_supports_it() {
+      let cas = StubCAS::with_capabilities(capabilities(
+        bazel_protos::remote_execution::DigestFunction::SHA256,
+        1024,
+      ));
+      let store = new_byte_store(&cas)
+        .negotiate_capabilities()
+        .expect("Error negotiating capabilities");
+
+      assert_eq!(
+        store
+          .store_all_bytes(vec![
+            TestData::roland().bytes(),
+            TestData::catnip().bytes(),
+            big_file_bytes(),
+          ])
+          .wait(),
+        Ok(())
+      );
+      let blobs = cas.blobs.lock().unwrap();
+      assert_eq!(
+        blobs.get(&TestData::roland().fingerprint()),
+        Some(&TestData::roland().bytes())
+      );
+      assert_eq!(
+        blobs.get(&TestData::catnip().fingerprint()),
+        Some(&TestData::catnip().bytes())
+      );
+      assert_eq!(blobs.get(&big_file_fingerprint()), Some(&big_file_bytes()));
+      // Only the big file was streamed, in chunks which fit in a batch.
+      let write_message_sizes = cas.write_message_sizes.lock().unwrap();
+      assert_eq!(
+        write_message_sizes.iter().sum::<usize>(),
+        big_file_bytes().len()
+      );
+      assert!(write_message_sizes.iter().all(|size| *size <= 1024));
+    }
+
+    #[test]
+    fn does_not_batch_without_capabilities() {
       let cas = StubCAS::empty();
+      let store = new_byte_store(&cas)
+        .negotiate_capabilities()
+        .expect("Error negotiating capabilities");
 
-      let store = new_byte_store(&cas);
-      assert_eq!(
-        store.store_bytes(empty_file.bytes()).wait(),
-        Ok(empty_file.digest())
-      );
-
-      let blobs = cas.blobs.lock().unwrap();
       assert_eq!(
-        blobs.get(&empty_file.fingerprint()),
-        Some(&empty_file.bytes())
+        store
+          .store_all_bytes(vec![TestData::roland().bytes(), TestData::catnip().bytes()])
+          .wait(),
+        Ok(())
       );
+      assert_eq!(cas.write_message_sizes.lock().unwrap().len(), 2);
     }
 
     #[test]
-    fn write_file_errors() {
-      let cas = StubCAS::always_errors();
-
-      let store = new_byte_store(&cas);
-      let error = store
-        .store_bytes(TestData::roland().bytes())
-        .wait()
-        .expect_err("Want error");
+    fn rejects_server_without_sha256() {
+      let cas = StubCAS::with_capabilities(capabilities(
+        bazel_protos::remote_execution::DigestFunction::SHA1,
+        0,
+      ));
+      let error = new_byte_store(&cas)
+        .negotiate_capabilities()
+        .err()
+        .expect("Want error");
       assert!(
-        error.contains("Error from server"),
-        format!("Bad error message, got: {}", error)
-      );
-      assert!(
-        error.contains("StubCAS is configured to always fail"),
+        error.contains("doesn't support SHA-256"),
         format!("Bad error message, got: {}", error)
       );
     }
 
     #[test]
-    fn write_connection_error() {
-      let store = ByteStore::new(
-        "doesnotexist.example".to_owned(),
-        1,
-        10 * 1024 * 1024,
-        Duration::from_secs(1),
-      );
-      let error = store
-        .store_bytes(TestData::roland().bytes())
-        .wait()
-        .expect_err("Want error");
-      assert!(
-        error.contains("Error attempting to upload fingerprint"),
-        format!("Bad error message, got: {}", error)
+    fn records_whether_action_cache_updates_are_enabled() {
+      let mut updatable_capabilities =
+        capabilities(bazel_protos::remote_execution::DigestFunction::SHA256, 0);
+      updatable_capabilities
+        .mut_cache_capabilities()
+        .mut_action_cache_update_capabilities()
+        .set_update_enabled(true);
+      let cas = StubCAS::with_capabilities(updatable_capabilities);
+      let store = new_byte_store(&cas)
+        .negotiate_capabilities()
+        .expect("Error negotiating capabilities");
+      assert!(store.action_cache_updates_enabled());
+
+      // The server is still usable for everything else if it doesn't accept updates.
+      let cas = StubCAS::with_capabilities(capabilities(
+        bazel_protos::remote_execution::DigestFunction::SHA256,
+        1024,
+      ));
+      let store = new_byte_store(&cas)
+        .negotiate_capabilities()
+        .expect("Error negotiating capabilities");
+      assert!(!store.action_cache_updates_enabled());
+      assert_eq!(
+        store.store_bytes(TestData::roland().bytes()).wait(),
+        Ok(TestData::roland().digest())
       );
+
+      // Servers which don't report their capabilities are assumed to accept updates.
+      let cas = StubCAS::empty();
+      let store = new_byte_store(&cas)
+        .negotiate_capabilities()
+        .expect("Error negotiating capabilities");
+      assert!(store.action_cache_updates_enabled());
     }
 
     #[test]
@@ -2052,8 +6157,26 @@ mod remote {
       );
     }
 
+    fn capabilities(
+      digest_function: bazel_protos::remote_execution::DigestFunction,
+      max_batch_total_size_bytes: i64,
+    ) -> bazel_protos::remote_execution::ServerCapabilities {
+      let mut cache_capabilities = bazel_protos::remote_execution::CacheCapabilities::new();
+      cache_capabilities.mut_digest_function().push(digest_function);
+      cache_capabilities.set_max_batch_total_size_bytes(max_batch_total_size_bytes);
+      let mut capabilities = bazel_protos::remote_execution::ServerCapabilities::new();
+      capabilities.set_cache_capabilities(cache_capabilities);
+      capabilities
+    }
+
     fn new_byte_store(cas: &StubCAS) -> ByteStore {
-      ByteStore::new(cas.address(), 1, 10 * 1024 * 1024, Duration::from_secs(1))
+      ByteStore::new(
+        vec![cas.address()],
+        EndpointSelection::PrimarySecondary,
+        1,
+        10 * 1024 * 1024,
+        Duration::from_secs(1),
+      ).unwrap()
     }
 
     pub fn load_file_bytes(store: &ByteStore, digest: Digest) -> Result<Option<Bytes>, String> {
@@ -2079,7 +6202,7 @@ mod remote {
 
 #[cfg(test)]
 mod tests {
-  use super::{local, EntryType, FileContent, Store};
+  use super::{local, EndpointSelection, EntryType, FileCache, FileContent, Store};
 
   use bazel_protos;
   use bytes::Bytes;
@@ -2091,10 +6214,10 @@ mod tests {
   use protobuf::Message;
   use sha2::Sha256;
   use std;
-  use std::collections::HashMap;
+  use std::collections::{HashMap, HashSet};
   use std::fs::File;
   use std::io::Read;
-  use std::os::unix::fs::PermissionsExt;
+  use std::os::unix::fs::{MetadataExt, PermissionsExt};
   use std::path::{Path, PathBuf};
   use std::sync::Arc;
   use std::time::Duration;
@@ -2158,13 +6281,252 @@ mod tests {
     Store::with_remote(
       dir,
       Arc::new(ResettablePool::new("test-pool-".to_string())),
-      cas_address,
+      vec![cas_address],
+      EndpointSelection::PrimarySecondary,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
     ).unwrap()
   }
 
+  #[test]
+  fn pin_protects_reachable_entries() {
+    let dir = TempDir::new().unwrap();
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+
+    let store = new_local_store(dir.path());
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error storing directory");
+    for testdata in &[&roland, &catnip] {
+      store
+        .store_file_bytes(testdata.bytes(), false)
+        .wait()
+        .expect("Error storing file");
+    }
+    store
+      .pin("toolchain".to_owned(), testdir.digest())
+      .wait()
+      .expect("Error pinning");
+    assert_eq!(
+      store.pins(),
+      Ok(vec![("toolchain".to_owned(), testdir.digest())])
+    );
+
+    let protected = store.pinned_fingerprints().expect("Error expanding pins");
+    assert_eq!(
+      protected,
+      vec![testdir.fingerprint(), roland.fingerprint()]
+        .into_iter()
+        .collect::<HashSet<_>>()
+    );
+    store
+      .local
+      .shrink_protecting(0, &protected)
+      .expect("Error shrinking");
+    assert_eq!(load_file_bytes(&store, roland.digest()), Ok(Some(roland.bytes())));
+    assert_eq!(load_file_bytes(&store, catnip.digest()), Ok(None));
+    assert_eq!(
+      store.load_directory(testdir.digest()).wait(),
+      Ok(Some(testdir.directory()))
+    );
+
+    assert_eq!(store.unpin("toolchain"), Ok(true));
+    assert_eq!(store.pinned_fingerprints(), Ok(HashSet::new()));
+  }
+
+  #[test]
+  fn pinned_fingerprints_skips_missing_directories() {
+    let dir = TempDir::new().unwrap();
+    let nested = TestDirectory::nested();
+    let containing_roland = TestDirectory::containing_roland();
+
+    let store = new_local_store(dir.path());
+    store
+      .store_file_bytes(TestData::roland().bytes(), false)
+      .wait()
+      .expect("Error storing file");
+    store
+      .record_directory(&nested.directory(), true)
+      .wait()
+      .expect("Error storing directory");
+    store
+      .record_directory(&containing_roland.directory(), false)
+      .wait()
+      .expect("Error storing directory");
+    store
+      .pin("toolchain".to_owned(), nested.digest())
+      .wait()
+      .expect("Error pinning");
+    store.local.shrink(0).expect("Error shrinking");
+    assert_eq!(store.load_directory(containing_roland.digest()).wait(), Ok(None));
+
+    // The missing Directory is still protected, but what it contains is unknown.
+    assert_eq!(
+      store.pinned_fingerprints(),
+      Ok(
+        vec![nested.fingerprint(), containing_roland.fingerprint()]
+          .into_iter()
+          .collect::<HashSet<_>>()
+      )
+    );
+    store.garbage_collect().expect("Error garbage collecting");
+  }
+
+  #[test]
+  fn full_shard_is_garbage_collected() {
+    let dir = TempDir::new().unwrap();
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+
+    let store = new_local_store(dir.path())
+      .with_lmdb_config(1, 1024 * 1024, Some(1024 * 1024))
+      .expect("Error configuring store");
+    for testdata in &[&roland, &catnip] {
+      store
+        .store_file_bytes(testdata.bytes(), false)
+        .wait()
+        .expect("Error storing file");
+    }
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error storing directory");
+    store
+      .pin("roland".to_owned(), roland.digest())
+      .wait()
+      .expect("Error pinning");
+    // Populate the hot cache.
+    assert_eq!(load_file_bytes(&store, catnip.digest()), Ok(Some(catnip.bytes())));
+
+    // When the shard of files fills up with leased files, catnip is the only thing which can be
+    // collected to make space, and that isn't enough.
+    let err = (0..32)
+      .map(|i| {
+        store
+          .store_file_bytes(Bytes::from(vec![i; 64 * 1024]), true)
+          .wait()
+      })
+      .find(|res| res.is_err())
+      .expect("Want error")
+      .expect_err("Want error");
+    assert!(err.contains("is full"), "Bad error: {}", err);
+
+    assert_eq!(load_file_bytes(&store, catnip.digest()), Ok(None));
+    assert_eq!(load_file_bytes(&store, roland.digest()), Ok(Some(roland.bytes())));
+    // Only the full shard is garbage collected.
+    assert_eq!(
+      store.load_directory(testdir.digest()).wait(),
+      Ok(Some(testdir.directory()))
+    );
+  }
+
+  #[test]
+  fn pin_missing_digest() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+    let err = store
+      .pin("toolchain".to_owned(), TestData::roland().digest())
+      .wait()
+      .expect_err("Want error");
+    assert!(err.contains("not found"), "Bad error message: {}", err);
+    assert_eq!(store.pins(), Ok(vec![]));
+  }
+
+  #[test]
+  fn pin_fetches_missing_files() {
+    let dir = TempDir::new().unwrap();
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    let cas = StubCAS::with_roland_and_directory(1024);
+    let store = new_store(dir.path(), cas.address());
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error storing directory");
+    store
+      .pin("toolchain".to_owned(), testdir.digest())
+      .wait()
+      .expect("Error pinning");
+    assert_eq!(
+      load_file_bytes(&new_local_store(dir.path()), roland.digest()),
+      Ok(Some(roland.bytes()))
+    );
+    assert_eq!(
+      store.pins(),
+      Ok(vec![("toolchain".to_owned(), testdir.digest())])
+    );
+  }
+
+  #[test]
+  fn pin_missing_file() {
+    let dir = TempDir::new().unwrap();
+    let testdir = TestDirectory::containing_roland();
+
+    let cas = StubCAS::empty();
+    for store in &[
+      new_local_store(dir.path()),
+      new_store(dir.path(), cas.address()),
+    ] {
+      store
+        .record_directory(&testdir.directory(), false)
+        .wait()
+        .expect("Error storing directory");
+      let err = store
+        .pin("toolchain".to_owned(), testdir.digest())
+        .wait()
+        .expect_err("Want error");
+      assert!(err.contains("not found"), "Bad error message: {}", err);
+      assert_eq!(store.pins(), Ok(vec![]));
+    }
+  }
+
+  #[test]
+  fn hot_cache_serves_repeated_loads() {
+    let dir = TempDir::new().unwrap();
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    let store = new_local_store(dir.path());
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error storing file");
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error storing directory");
+    for _ in 0..2 {
+      assert_eq!(
+        load_file_bytes(&store, roland.digest()),
+        Ok(Some(roland.bytes()))
+      );
+      assert_eq!(
+        store.load_directory(testdir.digest()).wait(),
+        Ok(Some(testdir.directory()))
+      );
+    }
+    let hot_cache = store.stats().expect("Error reading stats").hot_cache;
+    assert_eq!(hot_cache.entries, 2);
+    assert_eq!(
+      (hot_cache.file_hits, hot_cache.file_misses),
+      (1, 1)
+    );
+    assert_eq!(
+      (hot_cache.directory_hits, hot_cache.directory_misses),
+      (1, 1)
+    );
+
+    // Garbage collection empties the cache.
+    store.garbage_collect().expect("Error garbage collecting");
+    assert_eq!(store.stats().expect("Error reading stats").hot_cache.entries, 0);
+  }
+
   #[test]
   fn load_file_prefers_local() {
     let dir = TempDir::new().unwrap();
@@ -2225,6 +6587,38 @@ mod tests {
     );
   }
 
+  #[test]
+  fn load_file_range_falls_back_without_backfilling() {
+    let dir = TempDir::new().unwrap();
+
+    let testdata = TestData::roland();
+
+    let cas = new_cas(1024);
+    let store = new_store(dir.path(), cas.address());
+    assert_eq!(
+      store.load_file_bytes_range(testdata.digest(), 1, 4).wait(),
+      Ok(Some(testdata.bytes().slice(1, 5))),
+      "Read from CAS"
+    );
+    assert_eq!(1, cas.read_request_count());
+    assert_eq!(
+      local::tests::load_file_bytes(&local::tests::new_store(dir.path()), testdata.fingerprint()),
+      Ok(None),
+      "Range reads don't back-fill"
+    );
+
+    store
+      .store_file_bytes(testdata.bytes(), false)
+      .wait()
+      .expect("Error storing");
+    assert_eq!(
+      store.load_file_bytes_range(testdata.digest(), 1, 4).wait(),
+      Ok(Some(testdata.bytes().slice(1, 5))),
+      "Read from local store"
+    );
+    assert_eq!(1, cas.read_request_count());
+  }
+
   #[test]
   fn load_directory_falls_back_and_backfills() {
     let dir = TempDir::new().unwrap();
@@ -2642,6 +7036,56 @@ mod tests {
     );
   }
 
+  #[test]
+  fn does_not_recheck_trees_recently_uploaded() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let testdata = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    new_local_store(dir.path())
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error storing directory locally");
+    new_local_store(dir.path())
+      .store_file_bytes(testdata.bytes(), false)
+      .wait()
+      .expect("Error storing file locally");
+
+    let store = new_store(dir.path(), cas.address());
+    store
+      .ensure_remote_has_recursive(vec![testdir.digest()])
+      .wait()
+      .expect("Error uploading directory");
+    assert_eq!(cas.write_message_sizes.lock().unwrap().len(), 2);
+
+    // The same Store remembers that the remote has the tree, so doesn't check or upload it again.
+    cas.blobs.lock().unwrap().clear();
+    store
+      .ensure_remote_has_recursive(vec![testdir.digest()])
+      .wait()
+      .expect("Error uploading directory");
+    assert_eq!(cas.write_message_sizes.lock().unwrap().len(), 2);
+    assert_eq!(cas.blobs.lock().unwrap().get(&testdir.fingerprint()), None);
+
+    // Until it is told that they are missing.
+    store.forget_remote_has(&[testdir.digest(), testdata.digest()]);
+    store
+      .ensure_remote_has_recursive(vec![testdir.digest()])
+      .wait()
+      .expect("Error uploading directory");
+    assert_eq!(cas.write_message_sizes.lock().unwrap().len(), 4);
+    assert_eq!(
+      cas.blobs.lock().unwrap().get(&testdir.fingerprint()),
+      Some(&testdir.bytes())
+    );
+    assert_eq!(
+      cas.blobs.lock().unwrap().get(&testdata.fingerprint()),
+      Some(&testdata.bytes())
+    );
+  }
+
   #[test]
   fn uploads_files_recursively_when_under_three_digests_ignoring_items_already_in_cas() {
     let dir = TempDir::new().unwrap();
@@ -2849,6 +7293,38 @@ mod tests {
     assert!(!is_executable(&file));
   }
 
+  #[test]
+  fn materialize_large_file() {
+    let cache_dir = TempDir::new().unwrap();
+    let testdata = TestData::new(&"European Burmese".repeat(100));
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path()).with_large_file_threshold(1024);
+    let cached_store = store
+      .clone()
+      .with_file_cache(
+        FileCache::new(
+          cache_dir.path().to_owned(),
+          Arc::new(ResettablePool::new("test-pool-".to_string())),
+        ).expect("Error creating file cache"),
+      );
+    store
+      .store_file_bytes(testdata.bytes(), false)
+      .wait()
+      .expect("Error saving bytes");
+
+    for store in &[store, cached_store] {
+      let materialize_dir = TempDir::new().unwrap();
+      let file = materialize_dir.path().join("file");
+      store
+        .materialize_file(file.clone(), testdata.digest(), true)
+        .wait()
+        .expect("Error materializing file");
+      assert_eq!(file_contents(&file), testdata.bytes());
+      assert!(is_executable(&file));
+    }
+  }
+
   #[test]
   fn materialize_file_executable() {
     let materialize_dir = TempDir::new().unwrap();
@@ -2936,6 +7412,47 @@ mod tests {
     );
   }
 
+  #[test]
+  fn materialize_directory_symlinks() {
+    let materialize_dir = TempDir::new().unwrap();
+
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland_and_symlinks();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error saving file bytes");
+
+    store
+      .materialize_directory(materialize_dir.path().to_owned(), testdir.digest())
+      .wait()
+      .expect("Error materializing");
+
+    assert_eq!(
+      list_dir(materialize_dir.path()),
+      vec!["european_burmese", "felines", "roland"]
+    );
+    assert_eq!(
+      std::fs::read_link(materialize_dir.path().join("european_burmese")).unwrap(),
+      PathBuf::from("roland")
+    );
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("european_burmese")),
+      roland.bytes()
+    );
+    assert_eq!(
+      std::fs::read_link(materialize_dir.path().join("felines")).unwrap(),
+      PathBuf::from("cats")
+    );
+  }
+
   #[test]
   fn materialize_directory_executable() {
     let materialize_dir = TempDir::new().unwrap();
@@ -2972,6 +7489,393 @@ mod tests {
     assert!(!is_executable(&materialize_dir.path().join("food")));
   }
 
+  #[test]
+  fn materialize_directory_with_file_cache() {
+    let cache_dir = TempDir::new().unwrap();
+
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::with_mixed_executable_files();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path())
+      .with_file_cache(
+        FileCache::new(
+          cache_dir.path().to_owned(),
+          Arc::new(ResettablePool::new("test-pool-".to_string())),
+        ).expect("Error creating file cache"),
+      );
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(catnip.bytes(), false)
+      .wait()
+      .expect("Error saving catnip file bytes");
+
+    // The first materialization populates the cache, and the second links from it.
+    for _ in 0..2 {
+      let materialize_dir = TempDir::new().unwrap();
+      store
+        .materialize_directory(materialize_dir.path().to_owned(), testdir.digest())
+        .wait()
+        .expect("Error materializing");
+
+      assert_eq!(list_dir(materialize_dir.path()), vec!["feed", "food"]);
+      assert_eq!(
+        file_contents(&materialize_dir.path().join("feed")),
+        catnip.bytes()
+      );
+      assert_eq!(
+        file_contents(&materialize_dir.path().join("food")),
+        catnip.bytes()
+      );
+      assert!(is_executable(&materialize_dir.path().join("feed")));
+      assert!(!is_executable(&materialize_dir.path().join("food")));
+    }
+
+    // The cache is garbage collected separately from the store.
+    store.garbage_collect().expect("Error garbage collecting");
+    let shard = cache_dir.path().join(&catnip.fingerprint().to_hex()[0..2]);
+    assert_eq!(list_dir(&shard).len(), 2);
+  }
+
+  #[test]
+  fn materialize_directory_with_file_cache_after_input_modified() {
+    let cache_dir = TempDir::new().unwrap();
+
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::with_mixed_executable_files();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path())
+      .with_file_cache(
+        FileCache::new(
+          cache_dir.path().to_owned(),
+          Arc::new(ResettablePool::new("test-pool-".to_string())),
+        ).expect("Error creating file cache"),
+      );
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(catnip.bytes(), false)
+      .wait()
+      .expect("Error saving catnip file bytes");
+
+    // A process in the first sandbox rewrites one of its inputs in place, keeping its size and
+    // mode, as a process which ignores the mode of a (possibly hardlinked) input could.
+    let first_sandbox = TempDir::new().unwrap();
+    store
+      .materialize_directory(first_sandbox.path().to_owned(), testdir.digest())
+      .wait()
+      .expect("Error materializing");
+    let food = first_sandbox.path().join("food");
+    let mut modified = catnip.bytes().to_vec();
+    modified.reverse();
+    let mode = std::fs::metadata(&food).unwrap().permissions().mode();
+    std::fs::set_permissions(&food, std::fs::Permissions::from_mode(0o644)).unwrap();
+    std::fs::write(&food, &modified).unwrap();
+    std::fs::set_permissions(&food, std::fs::Permissions::from_mode(mode)).unwrap();
+
+    let second_sandbox = TempDir::new().unwrap();
+    store
+      .materialize_directory(second_sandbox.path().to_owned(), testdir.digest())
+      .wait()
+      .expect("Error materializing");
+    assert_eq!(
+      file_contents(&second_sandbox.path().join("food")),
+      catnip.bytes()
+    );
+    assert_eq!(
+      file_contents(&second_sandbox.path().join("feed")),
+      catnip.bytes()
+    );
+  }
+
+  #[test]
+  fn sync_directory() {
+    let materialize_dir = TempDir::new().unwrap();
+    let root = materialize_dir.path();
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    store
+      .record_directory(&recursive_testdir.directory(), false)
+      .wait()
+      .expect("Error saving recursive Directory");
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error saving file bytes");
+    store
+      .store_file_bytes(catnip.bytes(), false)
+      .wait()
+      .expect("Error saving catnip file bytes");
+
+    // An up to date file with the wrong executable bit, an out of date file, and stale files.
+    std::fs::create_dir_all(root.join("cats").join("stale_dir")).unwrap();
+    std::fs::write(root.join("cats").join("roland"), roland.bytes()).unwrap();
+    make_executable(&root.join("cats").join("roland"));
+    std::fs::write(root.join("treats"), roland.bytes()).unwrap();
+    std::fs::write(root.join("stale"), roland.bytes()).unwrap();
+    let roland_inode = inode(&root.join("cats").join("roland"));
+
+    store
+      .sync_directory(root.to_owned(), recursive_testdir.digest(), None)
+      .wait()
+      .expect("Error syncing");
+
+    assert_eq!(list_dir(root), vec!["cats", "treats"]);
+    assert_eq!(list_dir(&root.join("cats")), vec!["roland"]);
+    assert_eq!(file_contents(&root.join("treats")), catnip.bytes());
+    assert_eq!(file_contents(&root.join("cats").join("roland")), roland.bytes());
+    assert!(!is_executable(&root.join("cats").join("roland")));
+    assert_eq!(inode(&root.join("cats").join("roland")), roland_inode);
+  }
+
+  #[test]
+  fn sync_large_file() {
+    let materialize_dir = TempDir::new().unwrap();
+    let file = materialize_dir.path().join("file");
+    let testdata = TestData::new(&"European Burmese".repeat(100));
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path()).with_large_file_threshold(1024);
+    store
+      .store_file_bytes(testdata.bytes(), false)
+      .wait()
+      .expect("Error saving bytes");
+
+    // An up to date file is kept.
+    std::fs::write(&file, testdata.bytes()).unwrap();
+    let inode_before = inode(&file);
+    store
+      .sync_file(file.clone(), testdata.digest(), false)
+      .wait()
+      .expect("Error syncing");
+    assert_eq!(inode(&file), inode_before);
+
+    // An out of date file of the same size is replaced.
+    std::fs::write(&file, "European Burmeze".repeat(100)).unwrap();
+    store
+      .sync_file(file.clone(), testdata.digest(), false)
+      .wait()
+      .expect("Error syncing");
+    assert_eq!(file_contents(&file), testdata.bytes());
+  }
+
+  #[test]
+  fn sync_directory_with_managed_prefix() {
+    let materialize_dir = TempDir::new().unwrap();
+    let root = materialize_dir.path();
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    store
+      .record_directory(&recursive_testdir.directory(), false)
+      .wait()
+      .expect("Error saving recursive Directory");
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error saving file bytes");
+    store
+      .store_file_bytes(catnip.bytes(), false)
+      .wait()
+      .expect("Error saving catnip file bytes");
+
+    std::fs::create_dir_all(root.join("cats")).unwrap();
+    std::fs::write(root.join("cats").join("stale"), roland.bytes()).unwrap();
+    std::fs::write(root.join("unmanaged"), roland.bytes()).unwrap();
+
+    store
+      .sync_directory(
+        root.to_owned(),
+        recursive_testdir.digest(),
+        Some(PathBuf::from("cats")),
+      )
+      .wait()
+      .expect("Error syncing");
+
+    assert_eq!(list_dir(root), vec!["cats", "treats", "unmanaged"]);
+    assert_eq!(list_dir(&root.join("cats")), vec!["roland"]);
+
+    store
+      .sync_directory(
+        root.to_owned(),
+        recursive_testdir.digest(),
+        Some(PathBuf::from("../cats")),
+      )
+      .wait()
+      .expect_err("Want error for escaping managed prefix");
+  }
+
+  #[test]
+  fn diff_directories() {
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+
+    // Directory structure:
+    //
+    // /cats/roland (with catnip's contents)
+    // /roland (a directory)
+    // /treats
+    let changed = {
+      let mut directory = bazel_protos::remote_execution::Directory::new();
+      for &(name, digest) in &[
+        ("cats", TestDirectory::containing_wrong_roland().digest()),
+        ("roland", TestDirectory::containing_treats().digest()),
+      ] {
+        directory.mut_directories().push({
+          let mut subdir = bazel_protos::remote_execution::DirectoryNode::new();
+          subdir.set_name(name.to_owned());
+          subdir.set_digest((&digest).into());
+          subdir
+        });
+      }
+      directory
+    };
+    for directory in vec![
+      TestDirectory::recursive().directory(),
+      TestDirectory::containing_roland().directory(),
+      TestDirectory::containing_roland_and_treats().directory(),
+      TestDirectory::containing_wrong_roland().directory(),
+    ] {
+      store
+        .record_directory(&directory, false)
+        .wait()
+        .expect("Error saving Directory");
+    }
+    let changed_digest = store
+      .record_directory(&changed, false)
+      .wait()
+      .expect("Error saving Directory");
+
+    assert_eq!(
+      store
+        .diff_directories(
+          TestDirectory::recursive().digest(),
+          TestDirectory::containing_roland_and_treats().digest(),
+        )
+        .wait(),
+      Ok(super::DirectoryDiff {
+        added: vec![PathBuf::from("roland")],
+        removed: vec![PathBuf::from("cats")],
+        modified: vec![],
+        executable_changed: vec![],
+      })
+    );
+
+    assert_eq!(
+      store
+        .diff_directories(TestDirectory::containing_roland_and_treats().digest(), changed_digest)
+        .wait(),
+      Ok(super::DirectoryDiff {
+        added: vec![PathBuf::from("cats")],
+        removed: vec![PathBuf::from("treats")],
+        modified: vec![PathBuf::from("roland")],
+        executable_changed: vec![],
+      })
+    );
+
+    assert_eq!(
+      store
+        .diff_directories(TestDirectory::recursive().digest(), changed_digest)
+        .wait(),
+      Ok(super::DirectoryDiff {
+        added: vec![PathBuf::from("roland")],
+        removed: vec![PathBuf::from("treats")],
+        modified: vec![PathBuf::from("cats/roland")],
+        executable_changed: vec![],
+      })
+    );
+  }
+
+  #[test]
+  fn diff_directories_executable() {
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+
+    // Directory structure:
+    //
+    // /feed
+    // /food (executable)
+    let flipped = {
+      let mut directory = TestDirectory::with_mixed_executable_files().directory();
+      for file in directory.mut_files().iter_mut() {
+        let is_executable = file.get_is_executable();
+        file.set_is_executable(!is_executable);
+      }
+      directory
+    };
+    store
+      .record_directory(&TestDirectory::with_mixed_executable_files().directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    let flipped_digest = store
+      .record_directory(&flipped, false)
+      .wait()
+      .expect("Error saving Directory");
+
+    assert_eq!(
+      store
+        .diff_directories(
+          TestDirectory::with_mixed_executable_files().digest(),
+          flipped_digest,
+        )
+        .wait(),
+      Ok(super::DirectoryDiff {
+        added: vec![],
+        removed: vec![],
+        modified: vec![],
+        executable_changed: vec![PathBuf::from("feed"), PathBuf::from("food")],
+      })
+    );
+  }
+
+  #[test]
+  fn diff_identical_directories_does_not_load_them() {
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+
+    let diff = store
+      .diff_directories(
+        TestDirectory::recursive().digest(),
+        TestDirectory::recursive().digest(),
+      )
+      .wait()
+      .expect("Error diffing");
+    assert!(diff.is_empty());
+
+    store
+      .diff_directories(
+        TestDirectory::recursive().digest(),
+        TestDirectory::nested().digest(),
+      )
+      .wait()
+      .expect_err("Want error for unknown Directories");
+  }
+
   #[test]
   fn works_after_reset_prefork() {
     let dir = TempDir::new().unwrap();
@@ -3118,6 +8022,17 @@ mod tests {
     Bytes::from(contents)
   }
 
+  fn make_executable(path: &Path) {
+    let mut permissions = std::fs::metadata(path).unwrap().permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | 0o111);
+    std::fs::set_permissions(path, permissions).unwrap();
+  }
+
+  fn inode(path: &Path) -> u64 {
+    std::fs::metadata(path).unwrap().ino()
+  }
+
   fn is_executable(path: &Path) -> bool {
     std::fs::metadata(path)
       .expect("Getting metadata")
@@ -8,5 +8,7 @@ mod gen;
 pub use gen::*;
 
 mod conversions;
+mod request_metadata;
+pub use request_metadata::{RequestMetadata, REQUEST_METADATA_HEADER};
 mod verification;
 pub use verification::verify_directory_canonical;
@@ -0,0 +1,94 @@
+use grpcio;
+use hashing;
+use protobuf::Message;
+use remote_execution;
+
+// The header which carries a serialized RequestMetadata, as described in remote_execution.proto.
+pub const REQUEST_METADATA_HEADER: &str = "build.bazel.remote.execution.v2.requestmetadata-bin";
+
+///
+/// Identifies the tool, and the invocation of it, on whose behalf calls to remote execution
+/// services are made, so that those services can log and group calls by them.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RequestMetadata {
+  pub tool_name: String,
+  pub tool_version: String,
+  // Ties together the calls made by one invocation of the tool.
+  pub tool_invocation_id: String,
+  // Ties together several invocations of the tool, e.g. all of those in one CI run.
+  pub correlated_invocations_id: String,
+}
+
+impl RequestMetadata {
+  ///
+  /// The RequestMetadata proto for a call, which is made for the action with the given digest if
+  /// any.
+  ///
+  pub fn to_proto(
+    &self,
+    action_digest: Option<&hashing::Digest>,
+  ) -> remote_execution::RequestMetadata {
+    let mut tool_details = remote_execution::ToolDetails::new();
+    tool_details.set_tool_name(self.tool_name.clone());
+    tool_details.set_tool_version(self.tool_version.clone());
+    let mut metadata = remote_execution::RequestMetadata::new();
+    metadata.set_tool_details(tool_details);
+    if let Some(action_digest) = action_digest {
+      metadata.set_action_id(action_digest.0.to_hex());
+    }
+    metadata.set_tool_invocation_id(self.tool_invocation_id.clone());
+    metadata.set_correlated_invocations_id(self.correlated_invocations_id.clone());
+    metadata
+  }
+
+  ///
+  /// Options for a call which send this metadata with it, for the action with the given digest if
+  /// any.
+  ///
+  pub fn call_option(&self, action_digest: Option<&hashing::Digest>) -> grpcio::CallOption {
+    let bytes = self
+      .to_proto(action_digest)
+      .write_to_bytes()
+      .expect("Error serializing RequestMetadata");
+    let mut headers = grpcio::MetadataBuilder::with_capacity(1);
+    headers
+      .add_bytes(REQUEST_METADATA_HEADER, &bytes)
+      .expect("Error adding RequestMetadata header");
+    grpcio::CallOption::default().headers(headers.build())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::RequestMetadata;
+  use hashing;
+
+  #[test]
+  fn to_proto() {
+    let request_metadata = RequestMetadata {
+      tool_name: "pants".to_owned(),
+      tool_version: "1.2.3".to_owned(),
+      tool_invocation_id: "invocation".to_owned(),
+      correlated_invocations_id: "ci-run".to_owned(),
+    };
+
+    let proto = request_metadata.to_proto(None);
+    assert_eq!(proto.get_tool_details().get_tool_name(), "pants");
+    assert_eq!(proto.get_tool_details().get_tool_version(), "1.2.3");
+    assert_eq!(proto.get_tool_invocation_id(), "invocation");
+    assert_eq!(proto.get_correlated_invocations_id(), "ci-run");
+    assert_eq!(proto.get_action_id(), "");
+
+    let action_digest = hashing::Digest(
+      hashing::Fingerprint::from_hex_string(
+        "0123456789abcdeffedcba98765432100000000000000000ffffffffffffffff",
+      ).unwrap(),
+      10,
+    );
+    assert_eq!(
+      request_metadata.to_proto(Some(&action_digest)).get_action_id(),
+      "0123456789abcdeffedcba98765432100000000000000000ffffffffffffffff"
+    );
+  }
+}
@@ -5,21 +5,37 @@ use std::collections::HashSet;
 
 pub fn verify_directory_canonical(directory: &remote_execution::Directory) -> Result<(), String> {
   verify_no_unknown_fields(directory)?;
-  verify_nodes(directory.get_files(), |n| n.get_name(), |n| n.get_digest())?;
+  verify_nodes(
+    directory.get_files(),
+    |n| n.get_name(),
+    |n| Some(n.get_digest()),
+  )?;
   verify_nodes(
     directory.get_directories(),
     |n| n.get_name(),
-    |n| n.get_digest(),
+    |n| Some(n.get_digest()),
   )?;
+  verify_nodes(directory.get_symlinks(), |n| n.get_name(), |_| None)?;
   let file_names: HashSet<&str> = directory
     .get_files()
     .iter()
     .map(|file| file.get_name())
     .chain(directory.get_directories().iter().map(|dir| dir.get_name()))
+    .chain(
+      directory
+        .get_symlinks()
+        .iter()
+        .map(|symlink| symlink.get_name()),
+    )
     .collect();
-  if file_names.len() != directory.get_files().len() + directory.get_directories().len() {
+  if file_names.len()
+    != directory.get_files().len()
+      + directory.get_directories().len()
+      + directory.get_symlinks().len()
+  {
     return Err(format!(
-      "Children must be unique, but a path was both a file and a directory: {:?}",
+      "Children must be unique, but a path was more than one of a file, a directory and a \
+       symlink: {:?}",
       directory
     ));
   }
@@ -34,12 +50,14 @@ fn verify_nodes<Node, GetName, GetDigest>(
 where
   Node: protobuf::Message,
   GetName: Fn(&Node) -> &str,
-  GetDigest: Fn(&Node) -> &remote_execution::Digest,
+  GetDigest: Fn(&Node) -> Option<&remote_execution::Digest>,
 {
   let mut prev: Option<&Node> = None;
   for node in nodes {
     verify_no_unknown_fields(node)?;
-    verify_no_unknown_fields(get_digest(node))?;
+    if let Some(digest) = get_digest(node) {
+      verify_no_unknown_fields(digest)?;
+    }
     if get_name(node).contains('/') {
       return Err(format!(
         "All children must have one path segment, but found {}",
@@ -72,7 +90,7 @@ fn verify_no_unknown_fields(message: &protobuf::Message) -> Result<(), String> {
 
 #[cfg(test)]
 mod canonical_directory_tests {
-  use super::remote_execution::{Digest, Directory, DirectoryNode, FileNode};
+  use super::remote_execution::{Digest, Directory, DirectoryNode, FileNode, SymlinkNode};
   use super::verify_directory_canonical;
   use protobuf::Message;
 
@@ -313,6 +331,84 @@ mod canonical_directory_tests {
     verify_directory_canonical(&directory).expect_err("Want error");
   }
 
+  #[test]
+  fn canonical_directory_with_symlinks() {
+    let mut directory = Directory::new();
+    directory.mut_files().push({
+      let mut file = FileNode::new();
+      file.set_name("roland".to_owned());
+      file.set_digest({
+        let mut digest = Digest::new();
+        digest.set_size_bytes(FILE_SIZE);
+        digest.set_hash(HASH.to_owned());
+        digest
+      });
+      file
+    });
+    directory.mut_symlinks().push({
+      let mut symlink = SymlinkNode::new();
+      symlink.set_name("european_burmese".to_owned());
+      symlink.set_target("roland".to_owned());
+      symlink
+    });
+    directory.mut_symlinks().push({
+      let mut symlink = SymlinkNode::new();
+      symlink.set_name("felines".to_owned());
+      symlink.set_target("../cats".to_owned());
+      symlink
+    });
+
+    assert_eq!(Ok(()), verify_directory_canonical(&directory));
+  }
+
+  #[test]
+  fn duplicate_path_in_file_and_symlink() {
+    let mut directory = Directory::new();
+    directory.mut_files().push({
+      let mut file = FileNode::new();
+      file.set_name("roland".to_owned());
+      file.set_digest({
+        let mut digest = Digest::new();
+        digest.set_size_bytes(FILE_SIZE);
+        digest.set_hash(HASH.to_owned());
+        digest
+      });
+      file
+    });
+    directory.mut_symlinks().push({
+      let mut symlink = SymlinkNode::new();
+      symlink.set_name("roland".to_owned());
+      symlink.set_target("simba".to_owned());
+      symlink
+    });
+
+    verify_directory_canonical(&directory).expect_err("Want error");
+  }
+
+  #[test]
+  fn unsorted_path_in_symlink() {
+    let mut directory = Directory::new();
+    directory.mut_symlinks().push({
+      let mut symlink = SymlinkNode::new();
+      symlink.set_name("simba".to_owned());
+      symlink.set_target("roland".to_owned());
+      symlink
+    });
+    directory.mut_symlinks().push({
+      let mut symlink = SymlinkNode::new();
+      symlink.set_name("roland".to_owned());
+      symlink.set_target("simba".to_owned());
+      symlink
+    });
+
+    let error = verify_directory_canonical(&directory).expect_err("Want error");
+    assert!(
+      error.contains("simba was before roland"),
+      "Bad error message: {}",
+      error
+    );
+  }
+
   #[test]
   fn unsorted_path_in_directory() {
     let mut directory = Directory::new();
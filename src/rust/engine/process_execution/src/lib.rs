@@ -26,6 +26,7 @@ extern crate futures;
 extern crate futures_timer;
 extern crate grpcio;
 extern crate hashing;
+extern crate libc;
 #[macro_use]
 extern crate log;
 #[cfg(test)]
@@ -38,18 +39,22 @@ extern crate tempfile;
 #[cfg(test)]
 extern crate testutil;
 extern crate tokio_codec;
+extern crate tokio_io;
 extern crate tokio_process;
 
 use boxfuture::BoxFuture;
 use bytes::Bytes;
 use std::collections::{BTreeMap, BTreeSet};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
 use async_semaphore::AsyncSemaphore;
 
 pub mod local;
+pub mod named_caches;
+mod reaper;
 pub mod remote;
+pub mod sandbox;
 
 ///
 /// A process to be executed.
@@ -88,12 +93,120 @@ pub struct ExecuteProcessRequest {
   /// execution.
   ///
   pub jdk_home: Option<PathBuf>,
+
+  ///
+  /// If present, the process will be run with this directory (which is relative to the root of
+  /// input_files) as its current working directory. output_files and output_directories are
+  /// resolved relative to it, as per the Remote Execution API.
+  ///
+  /// Must be a relative path which does not escape the input root; see
+  /// `validate_working_directory`.
+  ///
+  pub working_directory: Option<PathBuf>,
+
+  ///
+  /// If present, the digest of a file (which must be present in the Store) whose content will be
+  /// provided to the process as its stdin. Otherwise, stdin will be empty.
+  ///
+  /// For remote execution, the file is provided as .pants-stdin in the input root (which must not
+  /// already contain it), and the process is run by a /bin/sh which redirects its stdin from it:
+  /// see `remote::redirect_stdin`.
+  ///
+  pub stdin: Option<hashing::Digest>,
+
+  ///
+  /// Limits on the resources which the process may use. Only enforced for local execution.
+  ///
+  pub resource_limits: ResourceLimits,
+
+  ///
+  /// Whether to report the resource usage of the process in its result. Only available for local
+  /// execution, where it requires the process to be spawned via a reaper: see `reaper::apply`.
+  ///
+  pub report_resource_usage: bool,
+
+  ///
+  /// Caches which persist between processes, by name, and the paths (relative to the input root)
+  /// at which they should be made available. Named caches are neither inputs nor outputs of the
+  /// process, so they do not affect its remote Action digest: they are only made available for
+  /// local execution.
+  ///
+  pub named_caches: BTreeMap<String, PathBuf>,
+}
+
+///
+/// Limits on the resources which a process may use, which are applied as rlimits when it is
+/// spawned. Limits which are None are not enforced.
+///
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct ResourceLimits {
+  ///
+  /// The CPU time (user + system) after which the process will be killed (RLIMIT_CPU).
+  ///
+  pub cpu_seconds: Option<u64>,
+  ///
+  /// The maximum size of the virtual memory of the process (RLIMIT_AS).
+  ///
+  pub address_space_bytes: Option<u64>,
+  ///
+  /// One more than the highest file descriptor number which the process may open (RLIMIT_NOFILE).
+  ///
+  pub open_files: Option<u64>,
+  ///
+  /// The maximum size of any file which the process writes (RLIMIT_FSIZE), and of its combined
+  /// stdout and stderr: a process which writes more to them is killed, and fails to run.
+  ///
+  pub max_output_bytes: Option<u64>,
+}
+
+///
+/// The resources used by a process (and any of its descendants which it waited for).
+///
+/// NB: On Linux, the maximum resident set size of a process includes that of the process which
+/// forked it, as it was before the fork exec'd: for processes spawned by pantsd it is therefore at
+/// least the size of pantsd, and only the usage of processes which are larger than that is
+/// meaningful.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ResourceUsage {
+  pub user_time: std::time::Duration,
+  pub system_time: std::time::Duration,
+  pub max_rss_bytes: u64,
+}
+
+///
+/// Checks that a working_directory is relative, and does not traverse outside of the input root.
+///
+pub fn validate_working_directory(working_directory: &Path) -> Result<(), String> {
+  validate_relative_path("working_directory", working_directory)
+}
+
+///
+/// Checks that a path (described by `what` in errors) is relative, and does not traverse outside of
+/// the directory which it is relative to.
+///
+pub fn validate_relative_path(what: &str, path: &Path) -> Result<(), String> {
+  for component in path.components() {
+    match component {
+      Component::Normal(_) | Component::CurDir => {}
+      Component::RootDir | Component::Prefix(_) => {
+        return Err(format!("{} must be relative, but got: {:?}", what, path))
+      }
+      Component::ParentDir => {
+        return Err(format!("{} must not contain `..`, but got: {:?}", what, path))
+      }
+    }
+  }
+  Ok(())
 }
 
 ///
 /// The result of running a process.
 ///
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// The resource usage of a process differs between runs, so is ignored when comparing results: a
+/// result is otherwise unchanged if a process is re-run.
+///
+#[derive(Clone, Debug, Eq)]
 pub struct FallibleExecuteProcessResult {
   pub stdout: Bytes,
   pub stderr: Bytes,
@@ -102,12 +215,30 @@ pub struct FallibleExecuteProcessResult {
   // It's unclear whether this should be a Snapshot or a digest of a Directory. A Directory digest
   // is handy, so let's try that out for now.
   pub output_directory: hashing::Digest,
+
+  // Only available for local execution.
+  pub resource_usage: Option<ResourceUsage>,
+}
+
+impl PartialEq for FallibleExecuteProcessResult {
+  fn eq(&self, other: &FallibleExecuteProcessResult) -> bool {
+    self.stdout == other.stdout
+      && self.stderr == other.stderr
+      && self.exit_code == other.exit_code
+      && self.output_directory == other.output_directory
+  }
 }
 
 pub trait CommandRunner: Send + Sync {
   fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String>;
 
   fn reset_prefork(&self);
+
+  ///
+  /// Fails if this CommandRunner can't run processes at all (e.g. because it uses a server which
+  /// doesn't support what we need), so that this can be reported before anything is run.
+  ///
+  fn check_capabilities(&self) -> Result<(), String>;
 }
 
 ///
@@ -136,4 +267,8 @@ impl CommandRunner for BoundedCommandRunner {
   fn reset_prefork(&self) {
     self.inner.reset_prefork();
   }
+
+  fn check_capabilities(&self) -> Result<(), String> {
+    self.inner.check_capabilities()
+  }
 }
@@ -4,18 +4,23 @@ extern crate tempfile;
 use boxfuture::{BoxFuture, Boxable};
 use fs::{self, GlobMatching, PathGlobs, PathStatGetter, Snapshot, StrictGlobMatching};
 use futures::{future, Future, Stream};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
+use std::io;
 use std::ops::Neg;
 use std::os::unix::{fs::symlink, process::ExitStatusExt};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::Arc;
 
 use tokio_codec::{BytesCodec, FramedRead};
+use tokio_io::io::write_all;
 use tokio_process::CommandExt;
 
-use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
+use super::named_caches::NamedCaches;
+use super::reaper::{self, UsageReport};
+use super::sandbox::Sandbox;
+use super::{ExecuteProcessRequest, FallibleExecuteProcessResult, ResourceLimits};
 
 use bytes::{Bytes, BytesMut};
 
@@ -24,6 +29,8 @@ pub struct CommandRunner {
   fs_pool: Arc<fs::ResettablePool>,
   work_dir: PathBuf,
   cleanup_local_dirs: bool,
+  sandbox: Option<Sandbox>,
+  named_caches: NamedCaches,
 }
 
 impl CommandRunner {
@@ -32,15 +39,57 @@ impl CommandRunner {
     fs_pool: Arc<fs::ResettablePool>,
     work_dir: PathBuf,
     cleanup_local_dirs: bool,
+    sandbox: Option<Sandbox>,
   ) -> CommandRunner {
+    let named_caches = NamedCaches::new(work_dir.join("named_caches"));
     CommandRunner {
       store,
       fs_pool,
       work_dir,
       cleanup_local_dirs,
+      sandbox,
+      named_caches,
     }
   }
 
+  ///
+  /// Makes the (already locked) named caches available at their relative paths in the input root:
+  /// via symlinks, or for a sandboxed process (which cannot see the caches) by returning mounts
+  /// which the sandbox should make.
+  ///
+  fn link_named_caches(
+    named_caches: &NamedCaches,
+    requested: &BTreeMap<String, PathBuf>,
+    input_root: &Path,
+    sandboxed: bool,
+  ) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+    let mut mounts = vec![];
+    for (name, relative_path) in requested {
+      let cache_path = named_caches.path(name);
+      let link_path = input_root.join(relative_path);
+      if link_path.symlink_metadata().is_ok() {
+        return Err(format!(
+          "Cannot make named cache {} available at {:?}, because it is already present in the \
+           input files",
+          name, relative_path
+        ));
+      }
+      if sandboxed {
+        fs::safe_create_dir_all_ioerror(&link_path)
+          .map_err(|err| format!("Error making mount point for named cache {}: {:?}", name, err))?;
+        mounts.push((cache_path, link_path));
+      } else {
+        if let Some(parent) = link_path.parent() {
+          fs::safe_create_dir_all_ioerror(parent)
+            .map_err(|err| format!("Error making parent of named cache {}: {:?}", name, err))?;
+        }
+        symlink(&cache_path, &link_path)
+          .map_err(|err| format!("Error making symlink for named cache {}: {:?}", name, err))?;
+      }
+    }
+    Ok(mounts)
+  }
+
   fn construct_output_snapshot(
     store: fs::Store,
     posix_fs: Arc<fs::PosixFS>,
@@ -89,6 +138,8 @@ impl CommandRunner {
 
 struct StreamedHermeticCommand {
   inner: Command,
+  stdin: Option<Bytes>,
+  max_output_bytes: Option<u64>,
 }
 
 ///
@@ -102,7 +153,7 @@ enum ChildOutput {
 }
 
 ///
-/// A streaming command that accepts no input stream and does not consult the `PATH`.
+/// A streaming command that accepts an optional fixed input and does not consult the `PATH`.
 ///
 impl StreamedHermeticCommand {
   fn new<S: AsRef<OsStr>>(program: S) -> StreamedHermeticCommand {
@@ -112,7 +163,11 @@ impl StreamedHermeticCommand {
         // It would be really nice not to have to manually set PATH but this is sadly the only way
         // to stop automatic PATH searching.
         .env("PATH", "");
-    StreamedHermeticCommand { inner }
+    StreamedHermeticCommand {
+      inner,
+      stdin: None,
+      max_output_bytes: None,
+    }
   }
 
   fn args<I, S>(&mut self, args: I) -> &mut StreamedHermeticCommand
@@ -139,10 +194,65 @@ impl StreamedHermeticCommand {
     self
   }
 
+  ///
+  /// Applies the given resource limits and, if report_usage is set, arranges for the resource
+  /// usage of the process to be reported via the returned UsageReport.
+  ///
+  /// The rlimit on output bytes bounds only the files which the process writes, so the combined
+  /// size of its stdout and stderr is additionally bounded while they are streamed.
+  ///
+  /// NB: Must be called before `sandbox`, so that the usage of the whole sandbox is reported.
+  ///
+  fn limits(
+    &mut self,
+    limits: &ResourceLimits,
+    report_usage: bool,
+  ) -> Result<Option<UsageReport>, String> {
+    self.max_output_bytes = limits.max_output_bytes;
+    reaper::apply(&mut self.inner, limits, report_usage)
+  }
+
+  ///
+  /// Runs the command inside of the given Sandbox, rooted at sandbox_root.
+  ///
+  fn sandbox(
+    &mut self,
+    sandbox: &Sandbox,
+    sandbox_root: &Path,
+    input_root: &Path,
+    cwd: &Path,
+    writable_mounts: &[(PathBuf, PathBuf)],
+  ) -> Result<&mut StreamedHermeticCommand, String> {
+    sandbox.apply(
+      &mut self.inner,
+      sandbox_root,
+      input_root,
+      cwd,
+      writable_mounts,
+    )?;
+    Ok(self)
+  }
+
+  ///
+  /// Sets the bytes which will be written to the stdin of the process. If unset, the process will
+  /// have a null stdin.
+  ///
+  fn stdin(&mut self, bytes: Bytes) -> &mut StreamedHermeticCommand {
+    self.stdin = Some(bytes);
+    self
+  }
+
   fn stream(&mut self) -> Result<impl Stream<Item = ChildOutput, Error = String> + Send, String> {
+    let maybe_stdin = self.stdin.take();
+    let max_output_bytes = self.max_output_bytes;
+    let stdin_cfg = if maybe_stdin.is_some() {
+      Stdio::piped()
+    } else {
+      Stdio::null()
+    };
     self
       .inner
-      .stdin(Stdio::null())
+      .stdin(stdin_cfg)
       .stdout(Stdio::piped())
       .stderr(Stdio::piped())
       .spawn_async()
@@ -152,6 +262,20 @@ impl StreamedHermeticCommand {
           .map(|bytes| ChildOutput::Stdout(bytes.into()));
         let stderr_stream = FramedRead::new(child.stderr().take().unwrap(), BytesCodec::new())
           .map(|bytes| ChildOutput::Stderr(bytes.into()));
+        // Write stdin concurrently with consuming the outputs, so that a process which interleaves
+        // reading and writing cannot deadlock. A process which exits without consuming all of its
+        // stdin is not an error.
+        let stdin_stream = match maybe_stdin {
+          Some(bytes) => write_all(child.stdin().take().unwrap(), bytes)
+            .then(|res| match res {
+              Ok(_) => Ok(()),
+              Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+              Err(e) => Err(e),
+            })
+            .to_boxed(),
+          None => future::ok(()).to_boxed(),
+        }.into_stream()
+          .filter_map(|()| None::<ChildOutput>);
         let exit_stream = child.into_stream().map(|exit_status| {
           ChildOutput::Exit(
             exit_status
@@ -161,11 +285,31 @@ impl StreamedHermeticCommand {
           )
         });
 
+        // If the process produces too much output, the stream fails, which drops (and so kills)
+        // the child.
+        let mut output_bytes: u64 = 0;
         Ok(
           stdout_stream
             .select(stderr_stream)
             .select(exit_stream)
-            .map_err(|e| format!("Failed to consume process outputs: {:?}", e)),
+            .select(stdin_stream)
+            .map_err(|e| format!("Failed to consume process outputs: {:?}", e))
+            .and_then(move |child_output| {
+              match child_output {
+                ChildOutput::Stdout(ref bytes) | ChildOutput::Stderr(ref bytes) => {
+                  output_bytes += bytes.len() as u64;
+                }
+                ChildOutput::Exit(_) => {}
+              }
+              match max_output_bytes {
+                Some(limit) if output_bytes > limit => Err(format!(
+                  "Process exceeded its limit of {} bytes of output on stdout and stderr, so was \
+                   killed",
+                  limit
+                )),
+                _ => Ok(child_output),
+              }
+            }),
         )
       })
   }
@@ -226,10 +370,65 @@ impl super::CommandRunner for CommandRunner {
         })
     );
     let workdir_path = workdir.path().to_owned();
-    let workdir_path2 = workdir_path.clone();
     let workdir_path3 = workdir_path.clone();
+    let workdir_path4 = workdir_path.clone();
+    let (maybe_sandbox, maybe_sandbox_root) = match self.sandbox {
+      Some(ref sandbox) => {
+        let sandbox_root = try_future!(
+          tempfile::Builder::new()
+            .prefix("process-sandbox")
+            .tempdir_in(&self.work_dir)
+            .map_err(|err| format!("Error making tempdir for process sandbox: {:?}", err))
+        );
+        let sandbox_root_path = sandbox_root.path().to_owned();
+        (Some((sandbox.clone(), sandbox_root_path)), Some(sandbox_root))
+      }
+      None => (None, None),
+    };
+    // The process runs in (and its outputs are relative to) the working_directory, if any.
+    let cwd_path = match req.working_directory {
+      Some(ref working_directory) => {
+        try_future!(super::validate_working_directory(working_directory));
+        workdir_path.join(working_directory)
+      }
+      None => workdir_path.clone(),
+    };
+    let cwd_path2 = cwd_path.clone();
+    for (name, relative_path) in &req.named_caches {
+      try_future!(NamedCaches::validate_name(name));
+      try_future!(super::validate_relative_path("named cache path", relative_path));
+      if !relative_path
+        .components()
+        .any(|component| component != Component::CurDir)
+      {
+        return future::err(format!(
+          "Named cache {} must be made available below the root of the input files",
+          name
+        )).to_boxed();
+      }
+      // The caches are symlinked into the input root, so outputs within them (or which contain
+      // them) would capture the content of the cache, which is not an output of the process.
+      let cache_path = normalize_relative_path(relative_path);
+      for output_path in req.output_files.iter().chain(req.output_directories.iter()) {
+        let output_path = normalize_relative_path(&match req.working_directory {
+          Some(ref working_directory) => working_directory.join(output_path),
+          None => output_path.clone(),
+        });
+        if output_path.starts_with(&cache_path) || cache_path.starts_with(&output_path) {
+          return future::err(format!(
+            "Output {:?} must not be within or contain named cache {}, which is at {:?}",
+            output_path, name, relative_path
+          )).to_boxed();
+        }
+      }
+    }
     let store = self.store.clone();
+    let store2 = self.store.clone();
     let fs_pool = self.fs_pool.clone();
+    let fs_pool2 = self.fs_pool.clone();
+    let named_caches = self.named_caches.clone();
+    let named_caches2 = self.named_caches.clone();
+    let requested_named_caches = req.named_caches;
 
     let env = req.env;
     let output_file_paths = req.output_files;
@@ -238,6 +437,10 @@ impl super::CommandRunner for CommandRunner {
     let argv = req.argv;
     let req_description = req.description;
     let maybe_jdk_home = req.jdk_home;
+    let maybe_stdin_digest = req.stdin;
+    let resource_limits = req.resource_limits;
+    let report_resource_usage = req.report_resource_usage;
+    let req_description2 = req_description.clone();
     self
       .store
       .materialize_directory(workdir_path.clone(), req.input_files)
@@ -248,25 +451,83 @@ impl super::CommandRunner for CommandRunner {
           Ok(())
         }
       })
-      .and_then(move |()| {
-        StreamedHermeticCommand::new(&argv[0])
-          .args(&argv[1..])
-          .current_dir(&workdir_path)
-          .envs(env)
+      .and_then(move |()| match maybe_stdin_digest {
+        Some(digest) => store2
+          .load_file_bytes_with(digest, |bytes| bytes)
+          .and_then(move |maybe_bytes| {
+            maybe_bytes
+              .map(Some)
+              .ok_or_else(|| format!("Could not find stdin file {:?} in the Store", digest))
+          })
+          .to_boxed(),
+        None => future::ok(None).to_boxed(),
+      })
+      .and_then(move |maybe_stdin| {
+        let names: Vec<String> = requested_named_caches.keys().cloned().collect();
+        named_caches
+          .lock(fs_pool2, &names)
+          .map(move |named_cache_locks| (maybe_stdin, requested_named_caches, named_cache_locks))
+      })
+      .and_then(move |(maybe_stdin, requested_named_caches, named_cache_locks)| {
+        fs::safe_create_dir_all_ioerror(&cwd_path).map_err(|err| {
+          format!(
+            "Error making working directory for local execution: {:?}",
+            err
+          )
+        })?;
+        let named_cache_mounts = CommandRunner::link_named_caches(
+          &named_caches2,
+          &requested_named_caches,
+          &workdir_path4,
+          maybe_sandbox.is_some(),
+        )?;
+        let mut command = StreamedHermeticCommand::new(&argv[0]);
+        command.args(&argv[1..]).current_dir(&cwd_path).envs(env);
+        let usage_report = command.limits(&resource_limits, report_resource_usage)?;
+        if let Some((sandbox, sandbox_root)) = maybe_sandbox {
+          command.sandbox(
+            &sandbox,
+            &sandbox_root,
+            &workdir_path4,
+            &cwd_path,
+            &named_cache_mounts,
+          )?;
+        }
+        if let Some(stdin) = maybe_stdin {
+          command.stdin(stdin);
+        }
+        command
           .stream()
+          .map(|stream| (stream, usage_report, named_cache_locks))
       })
       // NB: We fully buffer up the `Stream` above into final `ChildResults` below and so could
       // instead be using `CommandExt::output_async` above to avoid the `ChildResults::collect_from`
       // code. The idea going forward though is we eventually want to pass incremental results on
       // down the line for streaming process results to console logs, etc. as tracked by:
       //   https://github.com/pantsbuild/pants/issues/6089
-      .and_then(ChildResults::collect_from)
-      .and_then(move |child_results| {
+      .and_then(|(stream, usage_report, named_cache_locks)| {
+        ChildResults::collect_from(stream).map(move |child_results| {
+          // The process has exited, so it is no longer using the named caches.
+          drop(named_cache_locks);
+          let resource_usage = usage_report.and_then(|usage_report| match usage_report.read() {
+            Ok(usage) => {
+              debug!("Resource usage of {:?}: {:?}", req_description2, usage);
+              Some(usage)
+            }
+            Err(err) => {
+              warn!("{}", err);
+              None
+            }
+          });
+          (child_results, resource_usage)
+        })
+      })
+      .and_then(move |(child_results, resource_usage)| {
         let output_snapshot = if output_file_paths.is_empty() && output_dir_paths.is_empty() {
           future::ok(fs::Snapshot::empty()).to_boxed()
         } else {
           // Use no ignore patterns, because we are looking for explicitly listed paths.
-          future::done(fs::PosixFS::new(workdir_path2, fs_pool, &[]))
+          future::done(fs::PosixFS::new(cwd_path2, fs_pool, &[]))
             .map_err(|err| {
               format!(
                 "Error making posix_fs to fetch local process execution output files: {}",
@@ -291,10 +552,13 @@ impl super::CommandRunner for CommandRunner {
             stderr: child_results.stderr,
             exit_code: child_results.exit_code,
             output_directory: snapshot.digest,
+            resource_usage: resource_usage,
           })
           .to_boxed()
       })
       .then(move |result| {
+        // The sandbox root only contains mount points, so it is always cleaned up.
+        drop(maybe_sandbox_root);
         // Force workdir not to get dropped until after we've ingested the outputs
         if !cleanup_local_dirs {
           // This consumes the `TempDir` without deleting directory on the filesystem, meaning
@@ -314,6 +578,27 @@ impl super::CommandRunner for CommandRunner {
     self.store.reset_prefork();
     self.fs_pool.reset();
   }
+
+  fn check_capabilities(&self) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+///
+/// Lexically resolves the `.` and `..` components of a path which is relative to the input root.
+///
+fn normalize_relative_path(path: &Path) -> PathBuf {
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => {}
+      Component::ParentDir => {
+        normalized.pop();
+      }
+      component => normalized.push(component.as_os_str()),
+    }
+  }
+  normalized
 }
 
 #[cfg(test)]
@@ -321,10 +606,11 @@ mod tests {
   extern crate tempfile;
   extern crate testutil;
 
-  use super::super::CommandRunner as CommandRunnerTrait;
-  use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
+  use super::super::{CommandRunner as CommandRunnerTrait, ResourceLimits, ResourceUsage};
+  use super::{ExecuteProcessRequest, FallibleExecuteProcessResult, NamedCaches};
   use fs;
   use futures::Future;
+  use libc;
   use std;
   use std::collections::{BTreeMap, BTreeSet};
   use std::env;
@@ -348,6 +634,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "echo foo".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -357,6 +648,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       }
     )
   }
@@ -373,6 +665,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "echo foo and fail".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -382,6 +679,7 @@ mod tests {
         stderr: as_bytes("bar"),
         exit_code: 1,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       }
     )
   }
@@ -399,6 +697,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "kill self".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -408,6 +711,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: -15,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       }
     )
   }
@@ -428,6 +732,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "run env".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
 
     let stdout = String::from_utf8(result.unwrap().stdout.to_vec()).unwrap();
@@ -464,6 +773,11 @@ mod tests {
         timeout: Duration::from_millis(1000),
         description: "run env".to_string(),
         jdk_home: None,
+        working_directory: None,
+        stdin: None,
+        resource_limits: ResourceLimits::default(),
+        report_resource_usage: false,
+        named_caches: BTreeMap::new(),
       }
     }
 
@@ -484,6 +798,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "echo foo".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     }).expect_err("Want Err");
   }
 
@@ -502,6 +821,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
     assert_eq!(
       result.unwrap(),
@@ -510,6 +834,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       }
     )
   }
@@ -529,6 +854,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -538,6 +868,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
+        resource_usage: None,
       }
     )
   }
@@ -562,6 +893,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -571,6 +907,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
+        resource_usage: None,
       }
     )
   }
@@ -596,6 +933,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "treats-roland".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -605,6 +947,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
+        resource_usage: None,
       }
     )
   }
@@ -628,6 +971,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "echo foo".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -637,6 +985,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 1,
         output_directory: TestDirectory::containing_roland().digest(),
+        resource_usage: None,
       }
     )
   }
@@ -658,6 +1007,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "echo-roland".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -667,6 +1021,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
+        resource_usage: None,
       }
     )
   }
@@ -687,6 +1042,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "cat roland".to_string(),
       jdk_home: Some(preserved_work_tmpdir.path().to_path_buf()),
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     });
     assert_eq!(
       result,
@@ -695,10 +1055,425 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       })
     )
   }
 
+  #[test]
+  fn working_directory() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        format!("echo -n {} > {}", TestData::roland().string(), "roland"),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: vec![PathBuf::from("roland")].into_iter().collect(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "echo roland in cats".to_string(),
+      jdk_home: None,
+      working_directory: Some(PathBuf::from("cats")),
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
+    });
+
+    // Output paths are relative to the working directory.
+    assert_eq!(
+      result.unwrap(),
+      FallibleExecuteProcessResult {
+        stdout: as_bytes(""),
+        stderr: as_bytes(""),
+        exit_code: 0,
+        output_directory: TestDirectory::containing_roland().digest(),
+        resource_usage: None,
+      }
+    )
+  }
+
+  #[test]
+  fn working_directory_outside_input_root() {
+    let error = run_command_locally(ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "-n", "foo"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "echo foo".to_string(),
+      jdk_home: None,
+      working_directory: Some(PathBuf::from("cats/../..")),
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
+    }).expect_err("Want Err");
+    assert!(error.contains("working_directory"), error);
+  }
+
+  #[test]
+  fn stdin() {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    let roland = TestData::roland();
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Saving file bytes");
+    let runner = super::CommandRunner {
+      store: store,
+      fs_pool: pool,
+      work_dir: work_dir.path().to_owned(),
+      cleanup_local_dirs: true,
+      sandbox: None,
+      named_caches: NamedCaches::new(work_dir.path().join("named_caches")),
+    };
+
+    let result = runner
+      .run(ExecuteProcessRequest {
+        argv: owned_string_vec(&["/bin/cat"]),
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "cat stdin".to_string(),
+        jdk_home: None,
+        working_directory: None,
+        stdin: Some(roland.digest()),
+        resource_limits: ResourceLimits::default(),
+        report_resource_usage: false,
+        named_caches: BTreeMap::new(),
+      })
+      .wait();
+
+    assert_eq!(
+      result.unwrap(),
+      FallibleExecuteProcessResult {
+        stdout: roland.bytes(),
+        stderr: as_bytes(""),
+        exit_code: 0,
+        output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
+      }
+    )
+  }
+
+  #[test]
+  fn stdin_missing() {
+    let error = run_command_locally(ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/cat"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "cat stdin".to_string(),
+      jdk_home: None,
+      working_directory: None,
+      stdin: Some(TestData::roland().digest()),
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
+    }).expect_err("Want Err");
+    assert!(error.contains("stdin"), error);
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn sandbox() {
+    if !user_namespaces_supported() {
+      return;
+    }
+    let host_file = TempDir::new().unwrap();
+    let host_file_path = host_file.path().join("roland");
+    std::fs::write(&host_file_path, TestData::roland().bytes()).expect("Writing temporary file");
+
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let runner = super::CommandRunner {
+      store: fs::Store::local_only(store_dir.path(), pool.clone()).unwrap(),
+      fs_pool: pool,
+      work_dir: work_dir.path().to_owned(),
+      cleanup_local_dirs: true,
+      sandbox: Some(super::Sandbox::new(super::Sandbox::default_read_only_paths()).unwrap()),
+      named_caches: NamedCaches::new(work_dir.path().join("named_caches")),
+    };
+
+    // Files outside of the input root are not visible, but outputs are still captured, and we are
+    // PID 1.
+    let result = runner
+      .run(ExecuteProcessRequest {
+        argv: vec![
+          find_bash(),
+          "-c".to_owned(),
+          format!(
+            "echo -n {} > roland ; echo -n $$ ; test -e {}",
+            TestData::roland().string(),
+            host_file_path.display()
+          ),
+        ],
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: vec![PathBuf::from("roland")].into_iter().collect(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "sandboxed".to_string(),
+        jdk_home: None,
+        working_directory: None,
+        stdin: None,
+        resource_limits: ResourceLimits::default(),
+        report_resource_usage: false,
+        named_caches: BTreeMap::new(),
+      })
+      .wait();
+
+    assert_eq!(
+      result.unwrap(),
+      FallibleExecuteProcessResult {
+        stdout: as_bytes("1"),
+        stderr: as_bytes(""),
+        exit_code: 1,
+        output_directory: TestDirectory::containing_roland().digest(),
+        resource_usage: None,
+      }
+    )
+  }
+
+  #[test]
+  fn resource_usage() {
+    let request = |report_resource_usage: bool| ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "-n", "foo"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "echo foo".to_string(),
+      jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: report_resource_usage,
+      named_caches: BTreeMap::new(),
+    };
+
+    let result = run_command_locally(request(true)).unwrap();
+    let usage = result.resource_usage.expect("Want resource usage");
+    assert!(usage.max_rss_bytes > 0);
+    assert!(usage.user_time + usage.system_time < Duration::from_secs(10));
+
+    let unreported = run_command_locally(request(false)).unwrap();
+    assert_eq!(unreported.resource_usage, None);
+  }
+
+  #[test]
+  fn resource_usage_ignored_for_equality() {
+    let request = ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "-n", "foo"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "echo foo".to_string(),
+      jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: true,
+      named_caches: BTreeMap::new(),
+    };
+
+    let first = run_command_locally(request.clone()).unwrap();
+    let mut second = run_command_locally(request).unwrap();
+    // Ensure that the usage differs, even if the runs happened to use identical resources.
+    let first_usage = first.resource_usage.expect("Want resource usage");
+    second.resource_usage = Some(ResourceUsage {
+      max_rss_bytes: first_usage.max_rss_bytes + 1,
+      ..first_usage
+    });
+    assert_eq!(first, second);
+  }
+
+  #[test]
+  fn max_output_bytes() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        format!("echo -n {} > roland", TestData::roland().string()),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "write roland".to_string(),
+      jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits {
+        max_output_bytes: Some(4),
+        ..ResourceLimits::default()
+      },
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
+    });
+
+    assert_eq!(result.unwrap().exit_code, -libc::SIGXFSZ);
+  }
+
+  #[test]
+  fn max_output_bytes_streamed() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        "while true; do echo roland; echo roland >&2; done".to_owned(),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "echo roland forever".to_string(),
+      jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits {
+        max_output_bytes: Some(1024),
+        ..ResourceLimits::default()
+      },
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
+    });
+
+    assert_eq!(
+      result,
+      Err(
+        "Process exceeded its limit of 1024 bytes of output on stdout and stderr, so was killed"
+          .to_owned()
+      )
+    );
+  }
+
+  #[test]
+  fn named_caches() {
+    let work_dir = TempDir::new().unwrap();
+    let request = |command: &str| ExecuteProcessRequest {
+      argv: vec![find_bash(), "-c".to_owned(), command.to_owned()],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "named cache".to_string(),
+      jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: vec![("pip".to_owned(), PathBuf::from(".cache/pip"))]
+        .into_iter()
+        .collect(),
+    };
+
+    // Writes to the cache persist between executions, but are not captured as outputs.
+    let result = run_command_locally_in_dir_with_cleanup(
+      request(&format!(
+        "echo -n {} > .cache/pip/roland",
+        TestData::roland().string()
+      )),
+      work_dir.path().to_owned(),
+    );
+    assert_eq!(result.unwrap().exit_code, 0);
+    assert!(work_dir.path().join("named_caches").join("pip").join("roland").exists());
+
+    let result = run_command_locally_in_dir_with_cleanup(
+      request("cat .cache/pip/roland"),
+      work_dir.path().to_owned(),
+    );
+    assert_eq!(
+      result.unwrap(),
+      FallibleExecuteProcessResult {
+        stdout: TestData::roland().bytes(),
+        stderr: as_bytes(""),
+        exit_code: 0,
+        output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
+      }
+    )
+  }
+
+  #[test]
+  fn named_cache_containing_outputs() {
+    let request = |output_files: Vec<&str>, output_directories: Vec<&str>| ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/true"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: output_files.into_iter().map(PathBuf::from).collect(),
+      output_directories: output_directories.into_iter().map(PathBuf::from).collect(),
+      timeout: Duration::from_millis(1000),
+      description: "named cache containing outputs".to_string(),
+      jdk_home: None,
+      working_directory: Some(PathBuf::from("cats")),
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: vec![("pip".to_owned(), PathBuf::from("cats/.cache/pip"))]
+        .into_iter()
+        .collect(),
+    };
+
+    for (output_files, output_directories) in vec![
+      (vec![".cache/pip/roland"], vec![]),
+      (vec!["./.cache/pip/../pip/roland"], vec![]),
+      (vec![], vec![".cache/pip/wheels"]),
+      (vec![], vec![".cache"]),
+      (vec![], vec!["../cats"]),
+    ] {
+      let error = run_command_locally(request(output_files.clone(), output_directories.clone()))
+        .expect_err(&format!(
+          "Want Err for {:?} {:?}",
+          output_files, output_directories
+        ));
+      assert!(error.contains("named cache pip"), error);
+    }
+
+    let result = run_command_locally(request(vec![".cache/roland"], vec![".cache/wheels"]));
+    assert_eq!(result.unwrap().exit_code, 0);
+  }
+
+  #[test]
+  fn named_cache_with_invalid_name() {
+    let error = run_command_locally(ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/true"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "invalid named cache".to_string(),
+      jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: vec![("../escape".to_owned(), PathBuf::from("cache"))]
+        .into_iter()
+        .collect(),
+    }).expect_err("Want Err");
+    assert!(error.contains("Named cache"), error);
+  }
+
   #[test]
   fn test_directory_preservation() {
     let preserved_work_tmpdir = TempDir::new().unwrap();
@@ -718,6 +1493,11 @@ mod tests {
         timeout: Duration::from_millis(1000),
         description: "bash".to_string(),
         jdk_home: None,
+        working_directory: None,
+        stdin: None,
+        resource_limits: ResourceLimits::default(),
+        report_resource_usage: false,
+        named_caches: BTreeMap::new(),
       },
       preserved_work_root.clone(),
       false,
@@ -753,6 +1533,11 @@ mod tests {
         timeout: Duration::from_millis(1000),
         description: "failing execution".to_string(),
         jdk_home: None,
+        working_directory: None,
+        stdin: None,
+        resource_limits: ResourceLimits::default(),
+        report_resource_usage: false,
+        named_caches: BTreeMap::new(),
       },
       preserved_work_root.clone(),
       false,
@@ -785,15 +1570,33 @@ mod tests {
     let store_dir = TempDir::new().unwrap();
     let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
     let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    let named_caches = NamedCaches::new(dir.join("named_caches"));
     let runner = super::CommandRunner {
       store: store,
       fs_pool: pool,
       work_dir: dir,
       cleanup_local_dirs: cleanup,
+      sandbox: None,
+      named_caches: named_caches,
     };
     runner.run(req).wait()
   }
 
+  ///
+  /// Sandboxing relies on unprivileged user namespaces, which are disabled on some systems.
+  ///
+  #[cfg(target_os = "linux")]
+  fn user_namespaces_supported() -> bool {
+    let disabled_by = |path: &str| {
+      std::fs::read_to_string(path)
+        .map(|value| value.trim() == "0")
+        .unwrap_or(false)
+    };
+    Path::new("/proc/self/ns/user").exists()
+      && !disabled_by("/proc/sys/kernel/unprivileged_userns_clone")
+      && !disabled_by("/proc/sys/user/max_user_namespaces")
+  }
+
   fn find_bash() -> String {
     which("bash")
       .expect("No bash on PATH")
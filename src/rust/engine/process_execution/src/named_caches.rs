@@ -0,0 +1,297 @@
+use boxfuture::{BoxFuture, Boxable};
+use fs::ResettablePool;
+use futures::{future, Future};
+use futures_timer::Delay;
+use libc;
+
+use std::cmp::min;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How long to wait before trying again to lock named caches which are in use, doubling with each
+// attempt up to the maximum.
+const LOCK_BACKOFF_INCR_MILLIS: u64 = 10;
+const LOCK_BACKOFF_MAX_MILLIS: u64 = 1000;
+
+///
+/// A directory of caches which persist between local process executions, and which are made
+/// available to processes by name.
+///
+/// Each cache has a lock file next to it (locked using flock, so that concurrent pants processes
+/// also respect it). Processes may use a cache concurrently, so hold shared locks while they do:
+/// the lock is only held exclusively while the cache is created, or by anything which cleans it up.
+///
+#[derive(Clone, Debug)]
+pub struct NamedCaches {
+  root: PathBuf,
+}
+
+impl NamedCaches {
+  pub fn new(root: PathBuf) -> NamedCaches {
+    NamedCaches { root }
+  }
+
+  ///
+  /// Named caches are directories under the root, so their names must be simple.
+  ///
+  pub fn validate_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+      && !name.starts_with('.')
+      && name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if valid {
+      Ok(())
+    } else {
+      Err(format!(
+        "Named cache names may only contain alphanumerics, `_`, `-` and `.` (but not start with \
+         `.`), but got: {:?}",
+        name
+      ))
+    }
+  }
+
+  pub fn path(&self, name: &str) -> PathBuf {
+    self.root.join(name)
+  }
+
+  fn lock_path(&self, name: &str) -> PathBuf {
+    self.root.join(format!("{}.lock", name))
+  }
+
+  ///
+  /// Creates (if necessary) and takes shared locks on the given named caches, once all of them are
+  /// available. The locks are released when the returned value is dropped.
+  ///
+  /// Locks are taken without blocking (on the given pool, because creating the caches blocks), and
+  /// all of them are released again if any is exclusively locked, so that processes which share
+  /// more than one cache cannot deadlock, and no thread is tied up waiting for the cache to be
+  /// created or cleaned up.
+  ///
+  pub fn lock(
+    &self,
+    pool: Arc<ResettablePool>,
+    names: &[String],
+  ) -> BoxFuture<NamedCacheLocks, String> {
+    let named_caches = self.clone();
+    let mut sorted_names = names.to_vec();
+    sorted_names.sort();
+    sorted_names.dedup();
+
+    future::loop_fn(LOCK_BACKOFF_INCR_MILLIS, move |backoff_millis| {
+      let named_caches = named_caches.clone();
+      let sorted_names = sorted_names.clone();
+      pool
+        .spawn_fn(move || named_caches.try_lock(&sorted_names))
+        .and_then(move |maybe_locks| match maybe_locks {
+          Some(locks) => future::ok(future::Loop::Break(locks)).to_boxed(),
+          None => Delay::new(Duration::from_millis(backoff_millis))
+            .map_err(|e| format!("Error waiting to lock named caches: {}", e))
+            .map(move |()| {
+              future::Loop::Continue(min(backoff_millis * 2, LOCK_BACKOFF_MAX_MILLIS))
+            })
+            .to_boxed(),
+        })
+    }).to_boxed()
+  }
+
+  ///
+  /// Creates (if necessary) and takes shared locks on all of the given named caches, or returns
+  /// None (holding none of the locks) if any of them is exclusively locked.
+  ///
+  /// A cache which does not exist yet is created while holding its lock exclusively, which is then
+  /// converted to a shared lock.
+  ///
+  fn try_lock(&self, names: &[String]) -> Result<Option<NamedCacheLocks>, String> {
+    let mut files = Vec::with_capacity(names.len());
+    for name in names {
+      NamedCaches::validate_name(name)?;
+      let path = self.path(name);
+      let lock_path = self.lock_path(name);
+      fs::create_dir_all(&self.root)
+        .map_err(|e| format!("Error creating named caches {:?}: {:?}", self.root, e))?;
+      let file = File::create(&lock_path)
+        .map_err(|e| format!("Error creating named cache lock {:?}: {:?}", lock_path, e))?;
+      // Dropping the files releases the locks taken so far.
+      if !path.is_dir() {
+        if !try_flock(&file, libc::LOCK_EX)
+          .map_err(|e| format!("Error locking named cache {:?}: {:?}", lock_path, e))?
+        {
+          return Ok(None);
+        }
+        fs::create_dir_all(&path)
+          .map_err(|e| format!("Error creating named cache {:?}: {:?}", path, e))?;
+      }
+      if !try_flock(&file, libc::LOCK_SH)
+        .map_err(|e| format!("Error locking named cache {:?}: {:?}", lock_path, e))?
+      {
+        return Ok(None);
+      }
+      files.push(file);
+    }
+    Ok(Some(NamedCacheLocks { _files: files }))
+  }
+}
+
+///
+/// Takes (or converts to) the given kind of flock on the file without blocking, returning false if
+/// it is held incompatibly by another file.
+///
+fn try_flock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+  loop {
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+      return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.kind() {
+      io::ErrorKind::Interrupted => continue,
+      io::ErrorKind::WouldBlock => return Ok(false),
+      _ => return Err(err),
+    }
+  }
+}
+
+///
+/// Holds locks on named caches: closing the files releases the locks.
+///
+pub struct NamedCacheLocks {
+  _files: Vec<File>,
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempfile;
+
+  use super::{try_flock, NamedCaches};
+  use fs::ResettablePool;
+  use futures::Future;
+  use libc;
+  use std::fs::File;
+  use std::sync::mpsc;
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  #[test]
+  fn invalid_names() {
+    for name in &["", ".hidden", "../escape", "a/b", "spaces are bad"] {
+      NamedCaches::validate_name(name).expect_err(&format!("Want Err for {:?}", name));
+    }
+    NamedCaches::validate_name("ivy-2.4.0_cache").expect("Want Ok");
+  }
+
+  #[test]
+  fn lock_creates_caches() {
+    let dir = TempDir::new().unwrap();
+    let named_caches = NamedCaches::new(dir.path().to_owned());
+    let _locks = named_caches
+      .lock(new_pool(), &["pip".to_owned(), "ivy".to_owned()])
+      .wait()
+      .unwrap();
+    assert!(named_caches.path("pip").is_dir());
+    assert!(named_caches.path("ivy").is_dir());
+  }
+
+  #[test]
+  fn lock_is_shared() {
+    let dir = TempDir::new().unwrap();
+    let named_caches = NamedCaches::new(dir.path().to_owned());
+    let _locks = named_caches
+      .lock(new_pool(), &["pip".to_owned()])
+      .wait()
+      .unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    let named_caches2 = named_caches.clone();
+    let thread = thread::spawn(move || {
+      let _locks = named_caches2
+        .lock(new_pool(), &["pip".to_owned()])
+        .wait()
+        .unwrap();
+      sender.send(()).unwrap();
+    });
+
+    receiver
+      .recv_timeout(Duration::from_secs(10))
+      .expect("Lock should have been acquired while shared");
+    thread.join().unwrap();
+  }
+
+  #[test]
+  fn lock_waits_for_exclusive_lock() {
+    let dir = TempDir::new().unwrap();
+    let named_caches = NamedCaches::new(dir.path().to_owned());
+    drop(
+      named_caches
+        .lock(new_pool(), &["pip".to_owned()])
+        .wait()
+        .unwrap(),
+    );
+    let cleanup_lock = lock_exclusively(&named_caches, "pip");
+
+    let (sender, receiver) = mpsc::channel();
+    let named_caches2 = named_caches.clone();
+    let thread = thread::spawn(move || {
+      let _locks = named_caches2
+        .lock(new_pool(), &["pip".to_owned()])
+        .wait()
+        .unwrap();
+      sender.send(()).unwrap();
+    });
+
+    assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    drop(cleanup_lock);
+    receiver
+      .recv_timeout(Duration::from_secs(10))
+      .expect("Lock should have been acquired once released");
+    thread.join().unwrap();
+  }
+
+  #[test]
+  fn waiting_for_a_lock_holds_no_others() {
+    let dir = TempDir::new().unwrap();
+    let named_caches = NamedCaches::new(dir.path().to_owned());
+    drop(
+      named_caches
+        .lock(new_pool(), &["ivy".to_owned(), "pip".to_owned()])
+        .wait()
+        .unwrap(),
+    );
+    let pip_cleanup_lock = lock_exclusively(&named_caches, "pip");
+
+    // Waiting for pip does not hold ivy, which remains available to be locked exclusively.
+    let named_caches2 = named_caches.clone();
+    let thread = thread::spawn(move || {
+      named_caches2
+        .lock(new_pool(), &["ivy".to_owned(), "pip".to_owned()])
+        .wait()
+        .unwrap()
+    });
+    thread::sleep(Duration::from_millis(100));
+    let ivy_cleanup_lock = lock_exclusively(&named_caches, "ivy");
+
+    drop(ivy_cleanup_lock);
+    drop(pip_cleanup_lock);
+    thread.join().unwrap();
+  }
+
+  ///
+  /// Locks a cache exclusively, as something cleaning it up would, waiting for any shared locks.
+  ///
+  fn lock_exclusively(named_caches: &NamedCaches, name: &str) -> File {
+    let file = File::create(named_caches.lock_path(name)).unwrap();
+    while !try_flock(&file, libc::LOCK_EX).unwrap() {
+      thread::sleep(Duration::from_millis(10));
+    }
+    file
+  }
+
+  fn new_pool() -> Arc<ResettablePool> {
+    Arc::new(ResettablePool::new("test-pool-".to_string()))
+  }
+}
@@ -0,0 +1,226 @@
+use libc;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::ptr;
+use std::time::Duration;
+
+use super::{ResourceLimits, ResourceUsage};
+
+// ru_maxrss is reported in kilobytes on Linux, but in bytes on macOS.
+#[cfg(target_os = "macos")]
+const MAX_RSS_UNIT_BYTES: u64 = 1;
+#[cfg(not(target_os = "macos"))]
+const MAX_RSS_UNIT_BYTES: u64 = 1024;
+
+// user_time secs, user_time micros, system_time secs, system_time micros, max_rss.
+const REPORT_LEN: usize = 5;
+
+///
+/// Configures command to apply the given limits and, if report_usage is set, to run as the only
+/// child of a small "reaper" process which waits for it and reports its resource usage: the usage
+/// of a particular process is only available to the process which waits for it, and the waiting
+/// for processes that we spawn is owned by tokio-process.
+///
+/// The reaper exits in the same way as the process (with the same code, or by the same signal),
+/// and kills the process if it is itself killed.
+///
+/// The returned UsageReport (which is present iff report_usage is set) should be read once the
+/// spawned process has exited. If there are no limits and no usage is to be reported, the command
+/// is left unchanged.
+///
+pub fn apply(
+  command: &mut Command,
+  limits: &ResourceLimits,
+  report_usage: bool,
+) -> Result<Option<UsageReport>, String> {
+  let rlimits: Vec<_> = vec![
+    (libc::RLIMIT_CPU, limits.cpu_seconds),
+    (libc::RLIMIT_AS, limits.address_space_bytes),
+    (libc::RLIMIT_NOFILE, limits.open_files),
+    (libc::RLIMIT_FSIZE, limits.max_output_bytes),
+  ].into_iter()
+    .filter_map(|(resource, maybe_limit)| {
+      maybe_limit.map(|limit| {
+        (
+          resource,
+          libc::rlimit {
+            rlim_cur: limit as libc::rlim_t,
+            rlim_max: limit as libc::rlim_t,
+          },
+        )
+      })
+    })
+    .collect();
+
+  if !report_usage {
+    if !rlimits.is_empty() {
+      unsafe {
+        command.before_exec(move || {
+          for &(resource, ref rlimit) in &rlimits {
+            check(libc::setrlimit(resource, rlimit))?;
+          }
+          Ok(())
+        });
+      }
+    }
+    return Ok(None);
+  }
+
+  let (reader, writer) = pipe()?;
+  let max_fd = max_fd();
+  unsafe {
+    // NB: The writer is owned by the Command, and so is closed in this process when the Command is
+    // dropped after spawning.
+    command.before_exec(move || {
+      for &(resource, ref rlimit) in &rlimits {
+        check(libc::setrlimit(resource, rlimit))?;
+      }
+      reap(writer.as_raw_fd(), max_fd)
+    });
+  }
+  Ok(Some(UsageReport { reader }))
+}
+
+///
+/// The read end of the pipe via which a reaper reports the resource usage of its child.
+///
+/// The reaper writes its report (atomically, as it is smaller than PIPE_BUF) before it exits, so
+/// once its exit has been observed the report is either entirely available, or will never be. The
+/// pipe is therefore non-blocking, so that reading it can never block the thread running a future.
+///
+pub struct UsageReport {
+  reader: File,
+}
+
+impl UsageReport {
+  ///
+  /// Reads the usage of the process, which must already have exited. Fails rather than waiting if
+  /// no usage was reported.
+  ///
+  /// NB: max_rss_bytes is the high-water mark of the process, which on Linux survives exec: see
+  /// `ResourceUsage`.
+  ///
+  pub fn read(mut self) -> Result<ResourceUsage, String> {
+    let mut bytes = [0_u8; REPORT_LEN * 8];
+    self
+      .reader
+      .read_exact(&mut bytes)
+      .map_err(|e| format!("Error reading resource usage of process: {:?}", e))?;
+    let report: [u64; REPORT_LEN] = unsafe { mem::transmute(bytes) };
+    Ok(ResourceUsage {
+      user_time: Duration::new(report[0], (report[1] * 1000) as u32),
+      system_time: Duration::new(report[2], (report[3] * 1000) as u32),
+      max_rss_bytes: report[4] * MAX_RSS_UNIT_BYTES,
+    })
+  }
+}
+
+fn pipe() -> Result<(File, File), String> {
+  let mut fds = [0; 2];
+  unsafe {
+    check(libc::pipe(fds.as_mut_ptr()))
+      .map_err(|e| format!("Error making pipe for resource usage: {:?}", e))?;
+    let files = (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]));
+    for fd in &fds {
+      check(libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC))
+        .map_err(|e| format!("Error making pipe for resource usage: {:?}", e))?;
+    }
+    check(libc::fcntl(fds[0], libc::F_SETFL, libc::O_NONBLOCK))
+      .map_err(|e| format!("Error making pipe for resource usage: {:?}", e))?;
+    Ok(files)
+  }
+}
+
+pub fn max_fd() -> libc::c_int {
+  match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+    n if n <= 0 => 1024,
+    n if n > 65536 => 65536,
+    n => n as libc::c_int,
+  }
+}
+
+pub fn check(result: libc::c_int) -> io::Result<()> {
+  if result < 0 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(())
+  }
+}
+
+///
+/// Runs in the forked child before exec, so must not allocate. Forks again: the new child returns
+/// to continue towards exec, and we (the reaper) wait for it and never return.
+///
+fn reap(report_fd: libc::c_int, max_fd: libc::c_int) -> io::Result<()> {
+  unsafe {
+    let pid = libc::fork();
+    if pid < 0 {
+      return Err(io::Error::last_os_error());
+    } else if pid == 0 {
+      return set_parent_death_signal();
+    }
+
+    // Close our copies of every other non-stdio file descriptor. Among them is the pipe which the
+    // spawning process reads from until exec happens, which must be held only by the child.
+    for fd in 3..max_fd {
+      if fd != report_fd {
+        libc::close(fd);
+      }
+    }
+
+    let mut status = 0;
+    let mut rusage: libc::rusage = mem::zeroed();
+    while libc::wait4(pid, &mut status, 0, &mut rusage) != pid {
+      if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+        libc::_exit(1);
+      }
+    }
+    let report: [u64; REPORT_LEN] = [
+      rusage.ru_utime.tv_sec as u64,
+      rusage.ru_utime.tv_usec as u64,
+      rusage.ru_stime.tv_sec as u64,
+      rusage.ru_stime.tv_usec as u64,
+      rusage.ru_maxrss as u64,
+    ];
+    libc::write(
+      report_fd,
+      report.as_ptr() as *const libc::c_void,
+      mem::size_of_val(&report),
+    );
+    exit_like(status)
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn set_parent_death_signal() -> io::Result<()> {
+  check(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_parent_death_signal() -> io::Result<()> {
+  Ok(())
+}
+
+///
+/// Exits the current process in the same way as a waited-for process with the given status: with
+/// the same exit code, or by the same signal. Must not allocate.
+///
+pub unsafe fn exit_like(status: libc::c_int) -> ! {
+  if libc::WIFSIGNALED(status) {
+    let signal = libc::WTERMSIG(status);
+    libc::signal(signal, libc::SIG_DFL);
+    let mut signals: libc::sigset_t = mem::zeroed();
+    libc::sigemptyset(&mut signals);
+    libc::sigaddset(&mut signals, signal);
+    libc::sigprocmask(libc::SIG_UNBLOCK, &signals, ptr::null_mut());
+    libc::raise(signal);
+    // Some signals (e.g. SIGCHLD) do not terminate by default.
+    libc::_exit(128 + signal)
+  }
+  libc::_exit(libc::WEXITSTATUS(status))
+}
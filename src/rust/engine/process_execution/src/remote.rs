@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Component, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -7,7 +7,7 @@ use bazel_protos;
 use boxfuture::{BoxFuture, Boxable};
 use bytes::Bytes;
 use digest::{Digest as DigestTrait, FixedOutput};
-use fs::{self, File, PathStat, Store};
+use fs::{self, File, Link, PathStat, Store};
 use futures::{future, Future, Stream};
 use futures_timer::Delay;
 use grpcio;
@@ -16,7 +16,9 @@ use protobuf::{self, Message, ProtobufEnum};
 use resettable::Resettable;
 use sha2::Sha256;
 
-use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
+use super::{
+  CommandRunner as CommandRunnerTrait, ExecuteProcessRequest, FallibleExecuteProcessResult,
+};
 use std::cmp::min;
 
 #[derive(Clone)]
@@ -25,7 +27,12 @@ pub struct CommandRunner {
   env: Resettable<Arc<grpcio::Environment>>,
   execution_client: Resettable<Arc<bazel_protos::remote_execution_grpc::ExecutionClient>>,
   operations_client: Resettable<Arc<bazel_protos::operations_grpc::OperationsClient>>,
+  capabilities_client: Resettable<Arc<bazel_protos::remote_execution_grpc::CapabilitiesClient>>,
   store: Store,
+  // Sent with every request, if set, so that the server can tell who it is serving.
+  request_metadata: Option<Arc<bazel_protos::RequestMetadata>>,
+  // The action which requests are being made for, if any, which is sent in request_metadata.
+  action_digest: Option<Digest>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -54,7 +61,7 @@ impl CommandRunner {
       self
         .execution_client
         .get()
-        .execute(&execute_request)
+        .execute_opt(&execute_request, self.call_option())
         .map_err(rpcerror_to_string)
     );
     stream
@@ -95,9 +102,13 @@ impl super::CommandRunner for CommandRunner {
   /// TODO: Request jdk_home be created if set.
   ///
   fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
-    let operations_client = self.operations_client.clone();
+    if req.stdin.is_some() {
+      let command_runner = self.clone();
+      return redirect_stdin(self.store.clone(), req)
+        .and_then(move |req| command_runner.run(req))
+        .to_boxed();
+    }
 
-    let store = self.store.clone();
     let execute_request_result = make_execute_request(&req);
 
     let ExecuteProcessRequest {
@@ -108,13 +119,17 @@ impl super::CommandRunner for CommandRunner {
 
     match execute_request_result {
       Ok((action, command, execute_request)) => {
-        let command_runner = self.clone();
-        let command_runner2 = self.clone();
+        let action_digest: Result<Digest, String> = execute_request.get_action_digest().into();
+        // Everything we ask either server from here on is on behalf of this action.
+        let command_runner = self.for_action(try_future!(action_digest));
+        let command_runner2 = command_runner.clone();
+        let operations_client = command_runner.operations_client.clone();
+        let store = command_runner.store.clone();
         let execute_request = Arc::new(execute_request);
         let execute_request2 = execute_request.clone();
-        self
+        command_runner
           .upload_proto(&command)
-          .join(self.upload_proto(&action))
+          .join(command_runner.upload_proto(&action))
           .and_then(move |_| {
             debug!(
               "Executing remotely request: {:?} (command: {:?})",
@@ -144,6 +159,8 @@ impl super::CommandRunner for CommandRunner {
                         missing_digests
                       );
                       let execute_request = execute_request2.clone();
+                      // Whatever we thought the remote had, it doesn't have these any more.
+                      store.forget_remote_has(&missing_digests);
                       store.ensure_remote_has_recursive(missing_digests)
                               .and_then(move |()| {
                                 command_runner2.oneshot_execute(&execute_request)
@@ -156,6 +173,7 @@ impl super::CommandRunner for CommandRunner {
                       let mut operation_request =
                         bazel_protos::operations::GetOperationRequest::new();
                       operation_request.set_name(operation_name.clone());
+                      let call_option = command_runner2.call_option();
 
                       let backoff_period = min(
                         CommandRunner::BACKOFF_MAX_WAIT_MILLIS,
@@ -183,7 +201,7 @@ impl super::CommandRunner for CommandRunner {
                             future::done(
                               operations_client
                                 .get()
-                                .get_operation(&operation_request)
+                                .get_operation_opt(&operation_request, call_option)
                                 .or_else(move |err| {
                                   rpcerror_recover_cancelled(operation_request.take_name(), err)
                                 })
@@ -211,12 +229,60 @@ impl super::CommandRunner for CommandRunner {
     self.env.reset();
     self.execution_client.reset();
     self.operations_client.reset();
+    self.capabilities_client.reset();
+  }
+
+  ///
+  /// Asks the server what it supports, and fails if it can't execute our requests, so that a
+  /// misconfigured server is reported up front rather than by the first process we try to run.
+  /// Servers which don't implement the Capabilities service are assumed to be able to.
+  ///
+  fn check_capabilities(&self) -> Result<(), String> {
+    let capabilities = match self
+      .capabilities_client
+      .get()
+      .get_capabilities_opt(
+        &bazel_protos::remote_execution::GetCapabilitiesRequest::new(),
+        self
+          .call_option()
+          .timeout(Duration::from_secs(CommandRunner::CAPABILITIES_TIMEOUT_SECS)),
+      ) {
+      Ok(capabilities) => capabilities,
+      Err(grpcio::Error::RpcFailure(grpcio::RpcStatus {
+        status: grpcio::RpcStatusCode::Unimplemented,
+        ..
+      })) => {
+        debug!("Remote execution server doesn't implement GetCapabilities; assuming it's usable");
+        return Ok(());
+      }
+      Err(err) => {
+        return Err(format!(
+          "Error from server in response to GetCapabilities request: {}",
+          rpcerror_to_string(err)
+        ))
+      }
+    };
+    let execution_capabilities = capabilities.get_execution_capabilities();
+    if !execution_capabilities.get_exec_enabled() {
+      return Err("The remote execution server has execution disabled.".to_owned());
+    }
+    if execution_capabilities.get_digest_function()
+      != bazel_protos::remote_execution::DigestFunction::SHA256
+    {
+      return Err(format!(
+        "The remote execution server uses {:?} digests, but we only use SHA256.",
+        execution_capabilities.get_digest_function()
+      ));
+    }
+    Ok(())
   }
 }
 
 impl CommandRunner {
   const BACKOFF_INCR_WAIT_MILLIS: u64 = 500;
   const BACKOFF_MAX_WAIT_MILLIS: u64 = 5000;
+  // How long the server has to say what it supports before we give up on it.
+  const CAPABILITIES_TIMEOUT_SECS: u64 = 30;
 
   pub fn new(address: String, thread_count: usize, store: Store) -> CommandRunner {
     let env = Resettable::new(move || Arc::new(grpcio::Environment::new(thread_count)));
@@ -225,6 +291,7 @@ impl CommandRunner {
       Resettable::new(move || grpcio::ChannelBuilder::new(env2.get()).connect(&address));
     let channel2 = channel.clone();
     let channel3 = channel.clone();
+    let channel4 = channel.clone();
     let execution_client = Resettable::new(move || {
       Arc::new(bazel_protos::remote_execution_grpc::ExecutionClient::new(
         channel2.get(),
@@ -235,13 +302,55 @@ impl CommandRunner {
         channel3.get(),
       ))
     });
+    let capabilities_client = Resettable::new(move || {
+      Arc::new(bazel_protos::remote_execution_grpc::CapabilitiesClient::new(
+        channel4.get(),
+      ))
+    });
 
     CommandRunner {
       channel,
       env,
       execution_client,
       operations_client,
+      capabilities_client,
       store,
+      request_metadata: None,
+      action_digest: None,
+    }
+  }
+
+  ///
+  /// Sends the given RequestMetadata with every request to the execution server, and to the
+  /// remote store.
+  ///
+  pub fn with_request_metadata(
+    self,
+    request_metadata: bazel_protos::RequestMetadata,
+  ) -> CommandRunner {
+    CommandRunner {
+      store: self.store.with_request_metadata(request_metadata.clone()),
+      request_metadata: Some(Arc::new(request_metadata)),
+      ..self
+    }
+  }
+
+  ///
+  /// A clone of this CommandRunner whose requests are attributed to the action with the given
+  /// digest.
+  ///
+  fn for_action(&self, action_digest: Digest) -> CommandRunner {
+    CommandRunner {
+      store: self.store.for_action(action_digest),
+      action_digest: Some(action_digest),
+      ..self.clone()
+    }
+  }
+
+  fn call_option(&self) -> grpcio::CallOption {
+    match self.request_metadata {
+      Some(ref request_metadata) => request_metadata.call_option(self.action_digest.as_ref()),
+      None => grpcio::CallOption::default(),
     }
   }
 
@@ -301,6 +410,7 @@ impl CommandRunner {
             stderr: stderr,
             exit_code: execute_response.get_result().get_exit_code(),
             output_directory: output_directory,
+            resource_usage: None,
           }).to_boxed(),
           grpcio::RpcStatusCode::FailedPrecondition => {
             if execute_response.get_status().get_details().len() != 1 {
@@ -503,10 +613,10 @@ impl CommandRunner {
       }));
     }
 
-    // Make a directory for the files
+    // Make a directory for the files and symlinks
     let mut path_map = HashMap::new();
-    let path_stats_result: Result<Vec<PathStat>, String> = execute_response
-      .get_result()
+    let action_result = execute_response.get_result();
+    let path_stats_result: Result<Vec<PathStat>, String> = action_result
       .get_output_files()
       .into_iter()
       .map(|output_file| {
@@ -521,6 +631,20 @@ impl CommandRunner {
           },
         ))
       })
+      .chain(
+        action_result
+          .get_output_file_symlinks()
+          .iter()
+          .chain(action_result.get_output_directory_symlinks().iter())
+          .map(|output_symlink| {
+            let output_symlink_path_buf = PathBuf::from(output_symlink.get_path());
+            Ok(PathStat::link(
+              output_symlink_path_buf.clone(),
+              Link(output_symlink_path_buf),
+              PathBuf::from(output_symlink.get_target()),
+            ))
+          }),
+      )
       .collect();
 
     let path_stats = try_future!(path_stats_result.map_err(ExecutionError::Fatal));
@@ -575,6 +699,82 @@ impl CommandRunner {
   }
 }
 
+///
+/// The name of the file in the input root which stdin is provided in for remote execution.
+///
+const STDIN_FILE_NAME: &str = ".pants-stdin";
+
+///
+/// The Remote Execution API has no way to provide stdin to a process, so converts a request which
+/// has stdin into an equivalent one which doesn't: the stdin file is added to the input root, and
+/// the argv is wrapped in a shell which redirects stdin from it.
+///
+/// This requires that the remote execution environment has a /bin/sh, and that the input root
+/// does not already contain STDIN_FILE_NAME. Because the argv is changed, the Action differs from
+/// that of the same process run without stdin.
+///
+fn redirect_stdin(
+  store: Store,
+  mut req: ExecuteProcessRequest,
+) -> BoxFuture<ExecuteProcessRequest, String> {
+  let stdin_digest = match req.stdin.take() {
+    Some(digest) => digest,
+    None => return future::ok(req).to_boxed(),
+  };
+
+  // The shell runs in the working_directory, so the stdin file must be referenced relative to it.
+  let mut stdin_path = String::new();
+  if let Some(ref working_directory) = req.working_directory {
+    try_future!(super::validate_working_directory(working_directory));
+    for component in working_directory.components() {
+      if let Component::Normal(_) = component {
+        stdin_path.push_str("../");
+      }
+    }
+  }
+  stdin_path.push_str(STDIN_FILE_NAME);
+
+  let mut argv = vec![
+    "/bin/sh".to_owned(),
+    "-c".to_owned(),
+    format!("exec \"$0\" \"$@\" < {}", stdin_path),
+  ];
+  argv.extend(req.argv.drain(..));
+  req.argv = argv;
+
+  let mut stdin_directory = bazel_protos::remote_execution::Directory::new();
+  stdin_directory.mut_files().push({
+    let mut file_node = bazel_protos::remote_execution::FileNode::new();
+    file_node.set_name(STDIN_FILE_NAME.to_owned());
+    file_node.set_digest((&stdin_digest).into());
+    file_node.set_is_executable(false);
+    file_node
+  });
+
+  let input_files = req.input_files;
+  store
+    .record_directory(&stdin_directory, true)
+    .and_then(move |stdin_directory_digest| {
+      // The stdin file must not replace (or be merged with) anything in the input root.
+      fs::Snapshot::merge_directories_with_policy(
+        store,
+        vec![input_files, stdin_directory_digest],
+        fs::MergePolicy::Error,
+      )
+    })
+    .map_err(|err| {
+      format!(
+        "Error adding stdin to input files, which must not contain {}: {}",
+        STDIN_FILE_NAME, err
+      )
+    })
+    .map(move |merged_digest| {
+      req.input_files = merged_digest;
+      req
+    })
+    .to_boxed()
+}
+
 fn make_execute_request(
   req: &ExecuteProcessRequest,
 ) -> Result<
@@ -617,6 +817,16 @@ fn make_execute_request(
   output_directories.sort();
   command.set_output_directories(protobuf::RepeatedField::from_vec(output_directories));
 
+  if let Some(ref working_directory) = req.working_directory {
+    super::validate_working_directory(working_directory)?;
+    command.set_working_directory(
+      working_directory
+        .to_str()
+        .map(|s| s.to_owned())
+        .ok_or_else(|| format!("Non-UTF8 working directory path: {:?}", working_directory))?,
+    );
+  }
+
   let mut action = bazel_protos::remote_execution::Action::new();
   action.set_command_digest(digest(&command)?);
   action.set_input_root_digest((&req.input_files).into());
@@ -695,14 +905,14 @@ mod tests {
   use testutil::data::{TestData, TestDirectory};
   use testutil::{as_bytes, owned_string_vec};
 
-  use super::super::CommandRunner as CommandRunnerTrait;
+  use super::super::{CommandRunner as CommandRunnerTrait, ResourceLimits};
   use super::{CommandRunner, ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult};
   use mock::execution_server::MockOperation;
   use std::collections::{BTreeMap, BTreeSet};
   use std::iter::{self, FromIterator};
   use std::ops::Sub;
   use std::path::PathBuf;
-  use std::sync::Arc;
+  use std::sync::{Arc, Mutex};
   use std::time::Duration;
 
   #[derive(Debug, PartialEq)]
@@ -738,6 +948,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "some description".to_owned(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     };
     let result = super::make_execute_request(&req);
 
@@ -787,6 +1002,179 @@ mod tests {
     );
   }
 
+  #[test]
+  fn make_execute_request_with_working_directory() {
+    let input_directory = TestDirectory::containing_roland();
+    let req = ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "yo"]),
+      env: vec![("SOME".to_owned(), "value".to_owned())]
+        .into_iter()
+        .collect(),
+      input_files: input_directory.digest(),
+      output_files: vec!["path/to/file", "other/file"]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect(),
+      output_directories: vec!["directory/name"]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect(),
+      timeout: Duration::from_millis(1000),
+      description: "some description".to_owned(),
+      jdk_home: None,
+      working_directory: Some(PathBuf::from("cats")),
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
+    };
+    let result = super::make_execute_request(&req);
+
+    let mut want_command = bazel_protos::remote_execution::Command::new();
+    want_command.mut_arguments().push("/bin/echo".to_owned());
+    want_command.mut_arguments().push("yo".to_owned());
+    want_command.mut_environment_variables().push({
+      let mut env = bazel_protos::remote_execution::Command_EnvironmentVariable::new();
+      env.set_name("SOME".to_owned());
+      env.set_value("value".to_owned());
+      env
+    });
+    want_command
+      .mut_output_files()
+      .push("other/file".to_owned());
+    want_command
+      .mut_output_files()
+      .push("path/to/file".to_owned());
+    want_command
+      .mut_output_directories()
+      .push("directory/name".to_owned());
+    want_command.set_working_directory("cats".to_owned());
+
+    let mut want_action = bazel_protos::remote_execution::Action::new();
+    want_action.set_command_digest(
+      (&Digest(
+        Fingerprint::from_hex_string(
+          "ccab5925d3f68293e780eb1cc56851e9dcaed79797c4b132920cdc5ced4f1735",
+        ).unwrap(),
+        78,
+      )).into(),
+    );
+    want_action.set_input_root_digest((&input_directory.digest()).into());
+
+    let mut want_execute_request = bazel_protos::remote_execution::ExecuteRequest::new();
+    want_execute_request.set_action_digest(
+      (&Digest(
+        Fingerprint::from_hex_string(
+          "3ceceeadccbf2cd53ebcf93039fd24ac0a313593090e69a249c587825655efd4",
+        ).unwrap(),
+        140,
+      )).into(),
+    );
+
+    assert_eq!(
+      result,
+      Ok((want_action, want_command, want_execute_request))
+    );
+  }
+
+  #[test]
+  fn make_execute_request_with_escaping_working_directory() {
+    let mut req = echo_foo_request();
+    req.working_directory = Some(PathBuf::from("../cats"));
+    super::make_execute_request(&req).expect_err("Want Err");
+  }
+
+  #[test]
+  fn make_execute_request_ignores_named_caches() {
+    let mut req = echo_foo_request();
+    req.named_caches = vec![("pip".to_owned(), PathBuf::from(".cache/pip"))]
+      .into_iter()
+      .collect();
+    assert_eq!(
+      super::make_execute_request(&req),
+      super::make_execute_request(&echo_foo_request())
+    );
+  }
+
+  #[test]
+  fn redirect_stdin() {
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(
+      store_dir.path(),
+      Arc::new(fs::ResettablePool::new("test-pool-".to_owned())),
+    ).unwrap();
+    let roland = TestData::roland();
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Saving file bytes");
+
+    let mut req = cat_roland_request();
+    req.argv = owned_string_vec(&["/bin/cat"]);
+    req.input_files = fs::EMPTY_DIGEST;
+    req.working_directory = Some(PathBuf::from("cats/food"));
+    req.stdin = Some(roland.digest());
+
+    let redirected = super::redirect_stdin(store.clone(), req)
+      .wait()
+      .expect("Redirecting stdin");
+    assert_eq!(redirected.stdin, None);
+    assert_eq!(
+      redirected.argv,
+      owned_string_vec(&[
+        "/bin/sh",
+        "-c",
+        "exec \"$0\" \"$@\" < ../../.pants-stdin",
+        "/bin/cat",
+      ])
+    );
+
+    let input_directory = store
+      .load_directory(redirected.input_files)
+      .wait()
+      .expect("Loading input directory")
+      .expect("Input directory was missing");
+    let want_digest: bazel_protos::remote_execution::Digest = (&roland.digest()).into();
+    assert_eq!(input_directory.get_files().len(), 1);
+    assert_eq!(input_directory.get_files()[0].get_name(), ".pants-stdin");
+    assert_eq!(input_directory.get_files()[0].get_digest(), &want_digest);
+  }
+
+  #[test]
+  fn redirect_stdin_rejects_existing_stdin_file() {
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(
+      store_dir.path(),
+      Arc::new(fs::ResettablePool::new("test-pool-".to_owned())),
+    ).unwrap();
+    let roland = TestData::roland();
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Saving file bytes");
+    let mut input_directory = bazel_protos::remote_execution::Directory::new();
+    input_directory.mut_files().push({
+      let mut file_node = bazel_protos::remote_execution::FileNode::new();
+      file_node.set_name(".pants-stdin".to_owned());
+      file_node.set_digest((&roland.digest()).into());
+      file_node
+    });
+    let input_digest = store
+      .record_directory(&input_directory, false)
+      .wait()
+      .expect("Saving directory");
+
+    let mut req = cat_roland_request();
+    req.argv = owned_string_vec(&["/bin/cat"]);
+    req.input_files = input_digest;
+    req.stdin = Some(roland.digest());
+
+    let err = super::redirect_stdin(store, req)
+      .wait()
+      .expect_err("Want error");
+    assert_contains(&err, "must not contain .pants-stdin");
+  }
+
   #[test]
   fn server_rejecting_execute_request_gives_error() {
     let execute_request = echo_foo_request();
@@ -803,6 +1191,11 @@ mod tests {
           timeout: Duration::from_millis(1000),
           description: "wrong command".to_string(),
           jdk_home: None,
+          working_directory: None,
+          stdin: None,
+          resource_limits: ResourceLimits::default(),
+          report_resource_usage: false,
+          named_caches: BTreeMap::new(),
         }).unwrap()
           .2,
         vec![],
@@ -847,10 +1240,68 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       }
     );
   }
 
+  #[test]
+  fn sends_request_metadata() {
+    let roland = TestData::roland();
+    let execute_request = echo_foo_request();
+    let action_id = super::make_execute_request(&execute_request)
+      .unwrap()
+      .2
+      .get_action_digest()
+      .get_hash()
+      .to_owned();
+
+    let mock_server = {
+      let op_name = "gimme-roland".to_string();
+
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&execute_request).unwrap().2,
+        vec![
+          make_incomplete_operation(&op_name),
+          make_successful_operation(
+            &op_name,
+            StdoutType::Digest(roland.digest()),
+            StderrType::Raw("".to_owned()),
+            0,
+          ),
+        ],
+      ))
+    };
+    let cas = mock::StubCAS::with_roland_and_directory(1024);
+    let command_runner = create_command_runner(mock_server.address(), &cas).with_request_metadata(
+      bazel_protos::RequestMetadata {
+        tool_name: "pants".to_owned(),
+        tool_version: "1.2.3".to_owned(),
+        tool_invocation_id: "invocation".to_owned(),
+        correlated_invocations_id: "ci-run".to_owned(),
+      },
+    );
+
+    let result = command_runner.run(execute_request).wait().unwrap();
+    assert_eq!(result.stdout, roland.bytes());
+
+    assert_eq!(
+      requests_with_request_metadata(&mock_server.mock_responder.request_metadata, &action_id),
+      vec!["ExecuteRequest", "GetOperationRequest"]
+        .into_iter()
+        .map(str::to_owned)
+        .collect::<BTreeSet<_>>()
+    );
+    assert_eq!(
+      requests_with_request_metadata(&cas.request_metadata, &action_id),
+      vec!["FindMissingBlobsRequest", "ReadRequest", "WriteRequest"]
+        .into_iter()
+        .map(str::to_owned)
+        .collect::<BTreeSet<_>>()
+    );
+  }
+
   #[test]
   fn extract_response_with_digest_stdout() {
     let op_name = "gimme-foo".to_string();
@@ -871,6 +1322,7 @@ mod tests {
         stderr: testdata_empty.bytes(),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       })
     );
   }
@@ -895,6 +1347,7 @@ mod tests {
         stderr: testdata.bytes(),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       })
     );
   }
@@ -928,7 +1381,8 @@ mod tests {
     let store = fs::Store::with_remote(
       &store_dir_path,
       Arc::new(fs::ResettablePool::new("test-pool-".to_owned())),
-      cas.address(),
+      vec![cas.address()],
+      fs::EndpointSelection::PrimarySecondary,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
@@ -943,6 +1397,7 @@ mod tests {
         stderr: test_stderr.bytes(),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       })
     );
 
@@ -1000,6 +1455,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       }
     );
   }
@@ -1018,6 +1474,11 @@ mod tests {
       timeout: request_timeout,
       description: "echo-a-foo".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     };
 
     let mock_server = {
@@ -1039,6 +1500,64 @@ mod tests {
     assert_contains(&error_msg, "echo-a-foo");
   }
 
+  #[test]
+  fn check_capabilities() {
+    let execute_request = echo_foo_request();
+    let cas = mock::StubCAS::empty();
+
+    let mock_server = mock::execution_server::TestServer::with_capabilities(
+      mock::execution_server::MockExecution::new(
+        "gimme-foo".to_string(),
+        super::make_execute_request(&execute_request).unwrap().2,
+        vec![],
+      ),
+      execution_capabilities(true, bazel_protos::remote_execution::DigestFunction::SHA256),
+    );
+    assert_eq!(
+      create_command_runner(mock_server.address(), &cas).check_capabilities(),
+      Ok(())
+    );
+
+    let mock_server = mock::execution_server::TestServer::with_capabilities(
+      mock::execution_server::MockExecution::new(
+        "gimme-foo".to_string(),
+        super::make_execute_request(&execute_request).unwrap().2,
+        vec![],
+      ),
+      execution_capabilities(false, bazel_protos::remote_execution::DigestFunction::SHA256),
+    );
+    let error = create_command_runner(mock_server.address(), &cas)
+      .check_capabilities()
+      .expect_err("Want error");
+    assert_contains(&error, "execution disabled");
+
+    let mock_server = mock::execution_server::TestServer::with_capabilities(
+      mock::execution_server::MockExecution::new(
+        "gimme-foo".to_string(),
+        super::make_execute_request(&execute_request).unwrap().2,
+        vec![],
+      ),
+      execution_capabilities(true, bazel_protos::remote_execution::DigestFunction::MD5),
+    );
+    let error = create_command_runner(mock_server.address(), &cas)
+      .check_capabilities()
+      .expect_err("Want error");
+    assert_contains(&error, "MD5");
+
+    // Servers which predate GetCapabilities are assumed to work.
+    let mock_server = mock::execution_server::TestServer::new(
+      mock::execution_server::MockExecution::new(
+        "gimme-foo".to_string(),
+        super::make_execute_request(&execute_request).unwrap().2,
+        vec![],
+      ),
+    );
+    assert_eq!(
+      create_command_runner(mock_server.address(), &cas).check_capabilities(),
+      Ok(())
+    );
+  }
+
   #[test]
   fn retry_for_canceled_channel() {
     let execute_request = echo_foo_request();
@@ -1071,6 +1590,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       }
     );
   }
@@ -1257,7 +1777,8 @@ mod tests {
     let store = fs::Store::with_remote(
       store_dir,
       Arc::new(fs::ResettablePool::new("test-pool-".to_owned())),
-      cas.address(),
+      vec![cas.address()],
+      fs::EndpointSelection::PrimarySecondary,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
@@ -1277,6 +1798,7 @@ mod tests {
         stderr: Bytes::from(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        resource_usage: None,
       })
     );
     {
@@ -1311,7 +1833,8 @@ mod tests {
     let store = fs::Store::with_remote(
       store_dir,
       Arc::new(fs::ResettablePool::new("test-pool-".to_owned())),
-      cas.address(),
+      vec![cas.address()],
+      fs::EndpointSelection::PrimarySecondary,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
@@ -1353,6 +1876,7 @@ mod tests {
       stderr: Bytes::from("simba"),
       exit_code: 17,
       output_directory: TestDirectory::nested().digest(),
+      resource_usage: None,
     };
 
     let mut output_file = bazel_protos::remote_execution::OutputFile::new();
@@ -1659,6 +2183,51 @@ mod tests {
     )
   }
 
+  #[test]
+  fn extract_output_files_from_response_symlinks() {
+    let mut execute_response = bazel_protos::remote_execution::ExecuteResponse::new();
+    execute_response.set_result({
+      let mut result = bazel_protos::remote_execution::ActionResult::new();
+      result.set_exit_code(0);
+      result.set_output_files({
+        let mut output_files = protobuf::RepeatedField::new();
+        output_files.push({
+          let mut output_file = bazel_protos::remote_execution::OutputFile::new();
+          output_file.set_path("roland".into());
+          output_file.set_digest((&TestData::roland().digest()).into());
+          output_file
+        });
+        output_files
+      });
+      result.set_output_file_symlinks({
+        let mut output_symlinks = protobuf::RepeatedField::new();
+        output_symlinks.push({
+          let mut output_symlink = bazel_protos::remote_execution::OutputSymlink::new();
+          output_symlink.set_path("european_burmese".into());
+          output_symlink.set_target("roland".into());
+          output_symlink
+        });
+        output_symlinks
+      });
+      result.set_output_directory_symlinks({
+        let mut output_symlinks = protobuf::RepeatedField::new();
+        output_symlinks.push({
+          let mut output_symlink = bazel_protos::remote_execution::OutputSymlink::new();
+          output_symlink.set_path("felines".into());
+          output_symlink.set_target("cats".into());
+          output_symlink
+        });
+        output_symlinks
+      });
+      result
+    });
+
+    assert_eq!(
+      extract_output_files_from_response(&execute_response),
+      Ok(TestDirectory::containing_roland_and_symlinks().digest())
+    )
+  }
+
   #[test]
   fn extract_output_files_from_response_just_directory() {
     let mut output_directory = bazel_protos::remote_execution::OutputDirectory::new();
@@ -1740,6 +2309,11 @@ mod tests {
       timeout: Duration::from_millis(5000),
       description: "echo a foo".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     }
   }
 
@@ -1843,12 +2417,47 @@ mod tests {
     command_runner.run(request).wait()
   }
 
+  ///
+  /// The names of the requests which a mock server received, asserting that all of them were sent
+  /// with the RequestMetadata for the action with the given id.
+  ///
+  fn requests_with_request_metadata(
+    received: &Mutex<Vec<(String, Option<bazel_protos::remote_execution::RequestMetadata>)>>,
+    action_id: &str,
+  ) -> BTreeSet<String> {
+    let received = received.lock().unwrap();
+    for &(ref request_name, ref request_metadata) in received.iter() {
+      let request_metadata = request_metadata
+        .as_ref()
+        .unwrap_or_else(|| panic!("No RequestMetadata was sent with {}", request_name));
+      assert_eq!(request_metadata.get_action_id(), action_id);
+      assert_eq!(request_metadata.get_tool_invocation_id(), "invocation");
+    }
+    received
+      .iter()
+      .map(|&(ref request_name, _)| request_name.clone())
+      .collect()
+  }
+
+  fn execution_capabilities(
+    exec_enabled: bool,
+    digest_function: bazel_protos::remote_execution::DigestFunction,
+  ) -> bazel_protos::remote_execution::ServerCapabilities {
+    let mut execution_capabilities = bazel_protos::remote_execution::ExecutionCapabilities::new();
+    execution_capabilities.set_exec_enabled(exec_enabled);
+    execution_capabilities.set_digest_function(digest_function);
+    let mut capabilities = bazel_protos::remote_execution::ServerCapabilities::new();
+    capabilities.set_execution_capabilities(execution_capabilities);
+    capabilities
+  }
+
   fn create_command_runner(address: String, cas: &mock::StubCAS) -> CommandRunner {
     let store_dir = TempDir::new().unwrap();
     let store = fs::Store::with_remote(
       store_dir,
       Arc::new(fs::ResettablePool::new("test-pool-".to_owned())),
-      cas.address(),
+      vec![cas.address()],
+      fs::EndpointSelection::PrimarySecondary,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
@@ -1915,6 +2524,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "cat a roland".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     }
   }
 
@@ -1928,6 +2542,11 @@ mod tests {
       timeout: Duration::from_millis(1000),
       description: "unleash a roaring meow".to_string(),
       jdk_home: None,
+      working_directory: None,
+      stdin: None,
+      resource_limits: ResourceLimits::default(),
+      report_resource_usage: false,
+      named_caches: BTreeMap::new(),
     }
   }
 }
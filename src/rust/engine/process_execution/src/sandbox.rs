@@ -0,0 +1,384 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+///
+/// Configuration for running local processes inside of Linux user, mount, PID and network
+/// namespaces.
+///
+/// A sandboxed process sees only its materialized input root, a fresh /tmp, and a configurable set
+/// of system paths (which are mounted read-only). It has no network access other than an
+/// unconfigured loopback device, and it runs as PID 1 of its own PID namespace, so that any
+/// processes which it leaves behind are killed when it exits.
+///
+/// The input root is mounted at the same absolute path inside of the sandbox as it has outside of
+/// it, so absolute paths into the input root (and to outputs) are unaffected.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sandbox {
+  read_only_paths: Vec<PathBuf>,
+}
+
+impl Sandbox {
+  pub fn new(read_only_paths: Vec<PathBuf>) -> Result<Sandbox, String> {
+    for path in &read_only_paths {
+      if !path.is_absolute() {
+        return Err(format!(
+          "Sandbox read-only paths must be absolute, but got: {:?}",
+          path
+        ));
+      }
+    }
+    Ok(Sandbox { read_only_paths })
+  }
+
+  ///
+  /// System paths which most tools need in order to run at all.
+  ///
+  pub fn default_read_only_paths() -> Vec<PathBuf> {
+    vec!["/bin", "/dev", "/etc", "/lib", "/lib64", "/sbin", "/usr"]
+      .into_iter()
+      .map(PathBuf::from)
+      .collect()
+  }
+
+  ///
+  /// Populates the (empty) directory sandbox_root with mount points, and configures command to
+  /// enter a sandbox rooted there after it is spawned, but before it execs.
+  ///
+  /// input_root will be mounted read-write, and the process will run in cwd, which must be inside
+  /// of input_root.
+  ///
+  /// Each of writable_mounts is a (source, target) pair of directories to additionally mount
+  /// read-write, where target is an existing directory inside of input_root.
+  ///
+  pub fn apply(
+    &self,
+    command: &mut Command,
+    sandbox_root: &Path,
+    input_root: &Path,
+    cwd: &Path,
+    writable_mounts: &[(PathBuf, PathBuf)],
+  ) -> Result<(), String> {
+    linux::apply(
+      &self.read_only_paths,
+      command,
+      sandbox_root,
+      input_root,
+      cwd,
+      writable_mounts,
+    )
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+  use libc;
+
+  use std::ffi::{CStr, CString};
+  use std::fs;
+  use std::io;
+  use std::mem;
+  use std::os::unix::ffi::OsStrExt;
+  use std::os::unix::process::CommandExt;
+  use std::path::{Path, PathBuf};
+  use std::process::Command;
+  use std::ptr;
+
+  use reaper::{check, exit_like, max_fd};
+
+  ///
+  /// A bind mount to make in the sandbox.
+  ///
+  struct BindMount {
+    source: CString,
+    target: CString,
+    // If Some, the mount is remounted read-only, preserving these (locked) flags of the source.
+    read_only_flags: Option<libc::c_ulong>,
+  }
+
+  ///
+  /// Everything needed to enter the sandbox, computed ahead of time: between fork and exec in a
+  /// multi-threaded process we may only make async-signal-safe calls, so may not allocate.
+  ///
+  struct Entry {
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    bind_mounts: Vec<BindMount>,
+    tmp_target: CString,
+    proc_target: CString,
+    root: CString,
+    cwd: CString,
+    max_fd: libc::c_int,
+  }
+
+  pub fn apply(
+    read_only_paths: &[PathBuf],
+    command: &mut Command,
+    sandbox_root: &Path,
+    input_root: &Path,
+    cwd: &Path,
+    writable_mounts: &[(PathBuf, PathBuf)],
+  ) -> Result<(), String> {
+    if !cwd.starts_with(input_root) {
+      return Err(format!(
+        "Sandboxed process must run inside of its input root {:?}, but cwd was {:?}",
+        input_root, cwd
+      ));
+    }
+
+    let mut bind_mounts = vec![];
+    for source in read_only_paths {
+      let metadata = match fs::metadata(source) {
+        Ok(metadata) => metadata,
+        // Not all systems have all of the default paths (e.g. /lib64).
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(format!("Error statting {:?} for sandbox: {:?}", source, e)),
+      };
+      let target = make_mount_point(sandbox_root, source, metadata.is_dir())?;
+      let source = cstring(source)?;
+      let read_only_flags = locked_mount_flags(&source)?;
+      bind_mounts.push(BindMount {
+        source: source,
+        target: cstring(&target)?,
+        read_only_flags: Some(read_only_flags),
+      });
+    }
+    let input_root_target = make_mount_point(sandbox_root, input_root, true)?;
+    bind_mounts.push(BindMount {
+      source: cstring(input_root)?,
+      target: cstring(&input_root_target)?,
+      read_only_flags: None,
+    });
+    for &(ref source, ref target) in writable_mounts {
+      if !target.starts_with(input_root) {
+        return Err(format!(
+          "Sandbox mounts must be inside of the input root {:?}, but got {:?}",
+          input_root, target
+        ));
+      }
+      // The mount point already exists inside of the input root, which is mounted first.
+      let relative_target = target
+        .strip_prefix("/")
+        .map_err(|_| format!("Expected an absolute path, but got: {:?}", target))?;
+      bind_mounts.push(BindMount {
+        source: cstring(source)?,
+        target: cstring(&sandbox_root.join(relative_target))?,
+        read_only_flags: None,
+      });
+    }
+
+    let tmp_target = make_mount_point(sandbox_root, Path::new("/tmp"), true)?;
+    let proc_target = make_mount_point(sandbox_root, Path::new("/proc"), true)?;
+
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let entry = Entry {
+      uid_map: format!("{} {} 1\n", uid, uid).into_bytes(),
+      gid_map: format!("{} {} 1\n", gid, gid).into_bytes(),
+      bind_mounts: bind_mounts,
+      tmp_target: cstring(&tmp_target)?,
+      proc_target: cstring(&proc_target)?,
+      root: cstring(sandbox_root)?,
+      cwd: cstring(cwd)?,
+      max_fd: max_fd(),
+    };
+
+    unsafe {
+      command.before_exec(move || enter(&entry));
+    }
+    Ok(())
+  }
+
+  ///
+  /// Creates the path inside of sandbox_root which corresponds to the absolute path, so that it
+  /// can be mounted over.
+  ///
+  fn make_mount_point(sandbox_root: &Path, path: &Path, is_dir: bool) -> Result<PathBuf, String> {
+    let relative = path
+      .strip_prefix("/")
+      .map_err(|_| format!("Expected an absolute path, but got: {:?}", path))?;
+    let target = sandbox_root.join(relative);
+    if is_dir {
+      fs::create_dir_all(&target)
+        .map_err(|e| format!("Error making sandbox mount point {:?}: {:?}", target, e))?;
+    } else {
+      if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+          .map_err(|e| format!("Error making sandbox mount point {:?}: {:?}", parent, e))?;
+      }
+      fs::File::create(&target)
+        .map_err(|e| format!("Error making sandbox mount point {:?}: {:?}", target, e))?;
+    }
+    Ok(target)
+  }
+
+  fn cstring(path: &Path) -> Result<CString, String> {
+    CString::new(path.as_os_str().as_bytes())
+      .map_err(|e| format!("Path {:?} could not be used in sandbox: {:?}", path, e))
+  }
+
+  ///
+  /// The flags of the mount containing path which may not be cleared by an unprivileged user, and
+  /// so must be preserved when remounting it read-only.
+  ///
+  fn locked_mount_flags(path: &CStr) -> Result<libc::c_ulong, String> {
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+      return Err(format!(
+        "Error statting mount of {:?} for sandbox: {:?}",
+        path,
+        io::Error::last_os_error()
+      ));
+    }
+    let mut flags = 0;
+    for &(st_flag, ms_flag) in &[
+      (libc::ST_NOSUID, libc::MS_NOSUID),
+      (libc::ST_NODEV, libc::MS_NODEV),
+      (libc::ST_NOEXEC, libc::MS_NOEXEC),
+      (libc::ST_NOATIME, libc::MS_NOATIME),
+      (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+      (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+      if stat.f_flag & st_flag != 0 {
+        flags |= ms_flag;
+      }
+    }
+    Ok(flags)
+  }
+
+  unsafe fn write_file(path: &[u8], contents: &[u8]) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr() as *const libc::c_char, libc::O_WRONLY);
+    check(fd)?;
+    let written = libc::write(fd, contents.as_ptr() as *const libc::c_void, contents.len());
+    let result = if written < 0 {
+      Err(io::Error::last_os_error())
+    } else {
+      Ok(())
+    };
+    libc::close(fd);
+    result
+  }
+
+  ///
+  /// Runs in the forked child before exec. Must not allocate.
+  ///
+  fn enter(entry: &Entry) -> io::Result<()> {
+    unsafe {
+      check(libc::unshare(
+        libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWNET,
+      ))?;
+      write_file(b"/proc/self/setgroups\0", b"deny")?;
+      write_file(b"/proc/self/uid_map\0", &entry.uid_map)?;
+      write_file(b"/proc/self/gid_map\0", &entry.gid_map)?;
+
+      // Ensure that none of our mounts propagate back out to the host.
+      check(libc::mount(
+        ptr::null(),
+        b"/\0".as_ptr() as *const libc::c_char,
+        ptr::null(),
+        libc::MS_REC | libc::MS_PRIVATE,
+        ptr::null(),
+      ))?;
+      // The new root must be a mount point in order to pivot to it, and the mounts inside of it are
+      // made on top of this one.
+      check(libc::mount(
+        entry.root.as_ptr(),
+        entry.root.as_ptr(),
+        ptr::null(),
+        libc::MS_BIND,
+        ptr::null(),
+      ))?;
+      for bind_mount in &entry.bind_mounts {
+        check(libc::mount(
+          bind_mount.source.as_ptr(),
+          bind_mount.target.as_ptr(),
+          ptr::null(),
+          libc::MS_BIND | libc::MS_REC,
+          ptr::null(),
+        ))?;
+        if let Some(flags) = bind_mount.read_only_flags {
+          check(libc::mount(
+            ptr::null(),
+            bind_mount.target.as_ptr(),
+            ptr::null(),
+            libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | flags,
+            ptr::null(),
+          ))?;
+        }
+      }
+      check(libc::mount(
+        b"tmpfs\0".as_ptr() as *const libc::c_char,
+        entry.tmp_target.as_ptr(),
+        b"tmpfs\0".as_ptr() as *const libc::c_char,
+        libc::MS_NOSUID | libc::MS_NODEV,
+        ptr::null(),
+      ))?;
+
+      // Only children enter a new PID namespace, so fork: the child becomes PID 1 of the namespace
+      // (and so the namespace is torn down when it exits), and we wait for it.
+      let pid = libc::fork();
+      if pid < 0 {
+        return Err(io::Error::last_os_error());
+      } else if pid > 0 {
+        wait_and_exit(pid, entry.max_fd);
+      }
+
+      check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+      check(libc::mount(
+        b"proc\0".as_ptr() as *const libc::c_char,
+        entry.proc_target.as_ptr(),
+        b"proc\0".as_ptr() as *const libc::c_char,
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        ptr::null(),
+      ))?;
+      // Unlike a chroot, pivoting leaves nothing which refers to the host's filesystem, which is
+      // then unmounted entirely. Pivoting to "." stacks the old root on top of the new one, so that
+      // it can be unmounted without needing a directory for it inside of the sandbox.
+      check(libc::chdir(entry.root.as_ptr()))?;
+      check(libc::syscall(
+        libc::SYS_pivot_root,
+        b".\0".as_ptr() as *const libc::c_char,
+        b".\0".as_ptr() as *const libc::c_char,
+      ) as libc::c_int)?;
+      check(libc::umount2(b".\0".as_ptr() as *const libc::c_char, libc::MNT_DETACH))?;
+      check(libc::chdir(entry.cwd.as_ptr()))?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Waits for the PID 1 of the sandbox to exit, and exits in the same way.
+  ///
+  fn wait_and_exit(pid: libc::pid_t, max_fd: libc::c_int) -> ! {
+    unsafe {
+      // Close our copies of every non-stdio file descriptor. Among them is the pipe which the
+      // spawning process reads from until exec happens, which must be held only by the child.
+      for fd in 3..max_fd {
+        libc::close(fd);
+      }
+      let mut status = 0;
+      while libc::waitpid(pid, &mut status, 0) != pid {
+        if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+          libc::_exit(1);
+        }
+      }
+      exit_like(status)
+    }
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod linux {
+  use std::path::{Path, PathBuf};
+  use std::process::Command;
+
+  pub fn apply(
+    _read_only_paths: &[PathBuf],
+    _command: &mut Command,
+    _sandbox_root: &Path,
+    _input_root: &Path,
+    _cwd: &Path,
+    _writable_mounts: &[(PathBuf, PathBuf)],
+  ) -> Result<(), String> {
+    Err("Sandboxed local process execution is only supported on Linux".to_owned())
+  }
+}
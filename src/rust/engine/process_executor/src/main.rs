@@ -69,6 +69,20 @@ fn main() {
         .required(true)
         .help("Length of the proto-bytes whose digest to use as the input file tree."),
     )
+    .arg(
+      Arg::with_name("stdin-digest")
+        .long("stdin-digest")
+        .takes_value(true)
+        .requires("stdin-digest-length")
+        .help("Fingerprint (hex string) of the digest of a file to provide as stdin."),
+    )
+    .arg(
+      Arg::with_name("stdin-digest-length")
+        .long("stdin-digest-length")
+        .takes_value(true)
+        .requires("stdin-digest")
+        .help("Length of the file whose digest to provide as stdin."),
+    )
     .arg(
       Arg::with_name("server")
         .long("server")
@@ -82,7 +96,12 @@ fn main() {
       Arg::with_name("cas-server")
         .long("cas-server")
         .takes_value(true)
-        .help("The host:port of the gRPC CAS server to connect to."),
+        .multiple(true)
+        .number_of_values(1)
+        .help(
+          "The host:port of the gRPC CAS server to connect to. May be repeated for replicas of \
+           the same CAS, which are failed over to in the order given.",
+        ),
     )
       .arg(
         Arg::with_name("upload-chunk-bytes")
@@ -92,6 +111,28 @@ fn main() {
             .required(false)
             .default_value("3145728") // 3MB
       )
+    .arg(
+      Arg::with_name("working-directory")
+        .long("working-directory")
+        .takes_value(true)
+        .help("Path to run the process in, relative to the root of the input digest."),
+    )
+    .arg(
+      Arg::with_name("sandbox")
+        .long("sandbox")
+        .help(
+          "Run the process in Linux namespaces, seeing only its inputs and read-only system paths. \
+           Only applies to local execution.",
+        ),
+    )
+    .arg(
+      Arg::with_name("link-inputs")
+        .long("link-inputs")
+        .help(
+          "Link input files into place from a cache of files in the work dir, rather than writing \
+           them out. Only applies to local execution.",
+        ),
+    )
     .arg(
       Arg::with_name("env")
         .long("env")
@@ -132,15 +173,16 @@ fn main() {
   let local_store_path = args.value_of("local-store-path").unwrap();
   let pool = Arc::new(fs::ResettablePool::new("process-executor-".to_owned()));
   let server_arg = args.value_of("server");
-  let store = match (server_arg, args.value_of("cas-server")) {
-    (Some(_server), Some(cas_server)) => {
+  let store = match (server_arg, args.values_of("cas-server")) {
+    (Some(_server), Some(cas_servers)) => {
       let chunk_size =
         value_t!(args.value_of("upload-chunk-bytes"), usize).expect("Bad upload-chunk-bytes flag");
 
       fs::Store::with_remote(
         local_store_path,
         pool.clone(),
-        cas_server.to_owned(),
+        cas_servers.map(str::to_owned).collect(),
+        fs::EndpointSelection::PrimarySecondary,
         1,
         chunk_size,
         Duration::from_secs(30),
@@ -148,7 +190,16 @@ fn main() {
     }
     (None, None) => fs::Store::local_only(local_store_path, pool.clone()),
     _ => panic!("Must specify either both --server and --cas-server or neither."),
-  }.expect("Error making store");
+  }.and_then(|store| store.negotiate_remote_capabilities())
+    .and_then(|store| {
+      if args.is_present("link-inputs") {
+        fs::FileCache::new(work_dir.join("file_cache"), pool.clone())
+          .map(|file_cache| store.with_file_cache(file_cache))
+      } else {
+        Ok(store)
+      }
+    })
+    .expect("Error making store");
 
   let input_files = {
     let fingerprint = Fingerprint::from_hex_string(args.value_of("input-digest").unwrap())
@@ -161,6 +212,16 @@ fn main() {
     Digest(fingerprint, length)
   };
 
+  let stdin = args.value_of("stdin-digest").map(|fingerprint| {
+    let fingerprint = Fingerprint::from_hex_string(fingerprint).expect("Bad stdin-digest");
+    let length = args
+      .value_of("stdin-digest-length")
+      .unwrap()
+      .parse::<usize>()
+      .expect("stdin-digest-length must be a non-negative number");
+    Digest(fingerprint, length)
+  });
+
   let request = process_execution::ExecuteProcessRequest {
     argv,
     env,
@@ -170,18 +231,40 @@ fn main() {
     timeout: Duration::new(15 * 60, 0),
     description: "process_executor".to_string(),
     jdk_home: None,
+    working_directory: args.value_of("working-directory").map(PathBuf::from),
+    stdin,
+    resource_limits: process_execution::ResourceLimits::default(),
+    report_resource_usage: false,
+    named_caches: BTreeMap::new(),
   };
 
   let runner: Box<process_execution::CommandRunner> = match server_arg {
-    Some(address) => Box::new(process_execution::remote::CommandRunner::new(
-      address.to_owned(),
-      1,
-      store,
-    )),
+    Some(address) => {
+      Box::new(process_execution::remote::CommandRunner::new(
+        address.to_owned(),
+        1,
+        store,
+      ))
+    }
     None => Box::new(process_execution::local::CommandRunner::new(
-      store, pool, work_dir, true,
+      store,
+      pool,
+      work_dir,
+      true,
+      if args.is_present("sandbox") {
+        Some(
+          process_execution::sandbox::Sandbox::new(
+            process_execution::sandbox::Sandbox::default_read_only_paths(),
+          ).expect("Error configuring sandbox"),
+        )
+      } else {
+        None
+      },
     )),
   };
+  runner
+    .check_capabilities()
+    .expect("Could not use remote execution server");
 
   let result = runner.run(request).wait().expect("Error executing");
 
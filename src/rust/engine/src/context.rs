@@ -12,10 +12,14 @@ use futures::Future;
 use boxfuture::{BoxFuture, Boxable};
 use core::{Failure, TypeId};
 use dirs;
-use fs::{safe_create_dir_all_ioerror, PosixFS, ResettablePool, Store};
+use fs::{
+  safe_create_dir_all_ioerror, EndpointSelection, FileCache, PosixFS, RequestMetadata,
+  ResettablePool, Store,
+};
 use graph::{EntryId, Graph, NodeContext};
 use handles::maybe_drop_handles;
 use nodes::{NodeKey, TryInto, WrappedNode};
+use process_execution::sandbox::Sandbox;
 use process_execution::{self, BoundedCommandRunner, CommandRunner};
 use resettable::Resettable;
 use rule_graph::RuleGraph;
@@ -38,6 +42,8 @@ pub struct Core {
   pub fs_pool: Arc<ResettablePool>,
   pub runtime: Resettable<Arc<Runtime>>,
   pub store: Store,
+  // Local process execution links input files into place from this cache, if set.
+  pub file_cache: Option<FileCache>,
   pub vfs: PosixFS,
   pub command_runner: BoundedCommandRunner,
 }
@@ -51,51 +57,104 @@ impl Core {
     build_root: &Path,
     ignore_patterns: &[String],
     work_dir: PathBuf,
-    remote_store_server: Option<String>,
+    remote_store_servers: Vec<String>,
+    remote_store_server_selection: EndpointSelection,
     remote_execution_server: Option<String>,
+    request_metadata: RequestMetadata,
     remote_store_thread_count: usize,
     remote_store_chunk_bytes: usize,
     remote_store_chunk_upload_timeout: Duration,
     process_execution_parallelism: usize,
     process_execution_cleanup_local_dirs: bool,
-  ) -> Core {
+    process_execution_local_sandbox: Option<Sandbox>,
+    process_execution_local_link_inputs: bool,
+    local_store_compression_threshold_bytes: Option<usize>,
+    local_store_read_only_dir: Option<PathBuf>,
+    local_store_shard_count: usize,
+    local_store_map_size_bytes: usize,
+    local_store_max_map_size_bytes: Option<usize>,
+    local_store_large_file_threshold_bytes: usize,
+  ) -> Result<Core, String> {
     let fs_pool = Arc::new(ResettablePool::new("io-".to_string()));
     let runtime = Resettable::new(|| {
       Arc::new(Runtime::new().unwrap_or_else(|e| panic!("Could not initialize Runtime: {:?}", e)))
     });
 
-    let store_path = match dirs::home_dir() {
-      Some(home_dir) => home_dir.join(".cache").join("pants").join("lmdb_store"),
-      None => panic!("Could not find home dir"),
-    };
+    let store_path = dirs::home_dir()
+      .ok_or_else(|| "Could not find home dir".to_owned())?
+      .join(".cache")
+      .join("pants")
+      .join("lmdb_store");
 
     let store = safe_create_dir_all_ioerror(&store_path)
       .map_err(|e| format!("Error making directory {:?}: {:?}", store_path, e))
-      .and_then(|()| match remote_store_server {
-        Some(address) => Store::with_remote(
-          store_path,
-          fs_pool.clone(),
-          address,
-          remote_store_thread_count,
-          remote_store_chunk_bytes,
-          remote_store_chunk_upload_timeout,
-        ),
-        None => Store::local_only(store_path, fs_pool.clone()),
+      .and_then(|()| {
+        if remote_store_servers.is_empty() {
+          Store::local_only(store_path, fs_pool.clone())
+        } else {
+          Store::with_remote(
+            store_path,
+            fs_pool.clone(),
+            remote_store_servers,
+            remote_store_server_selection,
+            remote_store_thread_count,
+            remote_store_chunk_bytes,
+            remote_store_chunk_upload_timeout,
+          )
+        }
+      })
+      .and_then(|store| {
+        store.with_lmdb_config(
+          local_store_shard_count,
+          local_store_map_size_bytes,
+          local_store_max_map_size_bytes,
+        )
+      })
+      .map(|store| store.with_large_file_threshold(local_store_large_file_threshold_bytes))
+      .map(|store| store.with_request_metadata(request_metadata.clone()))
+      .and_then(|store| store.negotiate_remote_capabilities())
+      .map(|store| match local_store_compression_threshold_bytes {
+        Some(threshold_bytes) => store.with_compression(threshold_bytes),
+        None => store,
+      })
+      .and_then(|store| match local_store_read_only_dir {
+        Some(dir) => store.with_read_only_local_store(dir, fs_pool.clone()),
+        None => Ok(store),
       })
-      .unwrap_or_else(|e| panic!("Could not initialize Store: {:?}", e));
+      .map_err(|e| format!("Could not initialize Store: {}", e))?;
+
+    // Files can only be linked within a filesystem, so the cache lives next to the directories
+    // that processes are run in.
+    let file_cache = if process_execution_local_link_inputs {
+      Some(
+        FileCache::new(work_dir.join("file_cache"), fs_pool.clone())
+          .map_err(|e| format!("Could not initialize file cache: {}", e))?,
+      )
+    } else {
+      None
+    };
 
     let underlying_command_runner: Box<CommandRunner> = match remote_execution_server {
-      Some(address) => Box::new(process_execution::remote::CommandRunner::new(
-        address,
-        // Allow for some overhead for bookkeeping threads (if any).
-        process_execution_parallelism + 2,
-        store.clone(),
-      )),
+      Some(address) => {
+        let command_runner = process_execution::remote::CommandRunner::new(
+          address,
+          // Allow for some overhead for bookkeeping threads (if any).
+          process_execution_parallelism + 2,
+          store.clone(),
+        ).with_request_metadata(request_metadata);
+        Box::new(command_runner)
+      }
+      // Only sandboxes link from the file cache: directories materialized for users (e.g. by
+      // materialize_directories) must be writable, which hardlinked files are not.
       None => Box::new(process_execution::local::CommandRunner::new(
-        store.clone(),
+        match file_cache {
+          Some(ref file_cache) => store.clone().with_file_cache(file_cache.clone()),
+          None => store.clone(),
+        },
         fs_pool.clone(),
         work_dir,
         process_execution_cleanup_local_dirs,
+        process_execution_local_sandbox,
       )),
     };
 
@@ -104,21 +163,21 @@ impl Core {
 
     let rule_graph = RuleGraph::new(&tasks, root_subject_types);
 
-    Core {
+    let vfs = PosixFS::new(build_root, fs_pool.clone(), &ignore_patterns)
+      .map_err(|e| format!("Could not initialize VFS: {:?}", e))?;
+
+    Ok(Core {
       graph: Graph::new(),
       tasks: tasks,
       rule_graph: rule_graph,
       types: types,
-      fs_pool: fs_pool.clone(),
+      fs_pool: fs_pool,
       runtime: runtime,
       store: store,
-      // FIXME: Errors in initialization should definitely be exposed as python
-      // exceptions, rather than as panics.
-      vfs: PosixFS::new(build_root, fs_pool, &ignore_patterns).unwrap_or_else(|e| {
-        panic!("Could not initialize VFS: {:?}", e);
-      }),
+      file_cache: file_cache,
+      vfs: vfs,
       command_runner: command_runner,
-    }
+    })
   }
 
   pub fn pre_fork(&self) {
@@ -140,6 +140,13 @@ pub fn project_str(value: &Value, field: &str) -> String {
   val_to_str(&name_val)
 }
 
+///
+/// Projects a Python bool, which is converted to a string (as "True" or "False") on the way.
+///
+pub fn project_bool(value: &Value, field: &str) -> bool {
+  project_str(value, field) == "True"
+}
+
 pub fn key_to_str(key: &Key) -> String {
   val_to_str(&val_for(key))
 }
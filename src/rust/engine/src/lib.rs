@@ -51,13 +51,15 @@ extern crate lazy_static;
 extern crate log;
 extern crate process_execution;
 extern crate resettable;
+extern crate tempfile;
 extern crate tokio;
 
 use std::ffi::CStr;
-use std::fs::File;
+use std::fs::{File, Permissions};
 use std::io;
 use std::mem;
 use std::os::raw;
+use std::os::unix::fs::PermissionsExt;
 use std::panic;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
@@ -74,6 +76,7 @@ use externs::{
 use futures::Future;
 use handles::Handle;
 use hashing::Digest;
+use process_execution::CommandRunner;
 use rule_graph::{GraphMaker, RuleGraph};
 use scheduler::{ExecutionRequest, RootResult, Scheduler, Session};
 use tasks::Tasks;
@@ -243,14 +246,28 @@ pub extern "C" fn scheduler_create(
   work_dir_buf: Buffer,
   ignore_patterns_buf: BufferBuffer,
   root_type_ids: TypeIdBuffer,
-  remote_store_server: Buffer,
+  remote_store_servers_buf: BufferBuffer,
+  remote_store_server_selection: Buffer,
   remote_execution_server: Buffer,
+  remote_tool_version: Buffer,
+  remote_tool_invocation_id: Buffer,
+  remote_correlated_invocations_id: Buffer,
   remote_store_thread_count: u64,
   remote_store_chunk_bytes: u64,
   remote_store_chunk_upload_timeout_seconds: u64,
   process_execution_parallelism: u64,
   process_execution_cleanup_local_dirs: bool,
-) -> *const Scheduler {
+  process_execution_local_sandbox: bool,
+  process_execution_local_sandbox_read_only_paths_buf: BufferBuffer,
+  process_execution_local_link_inputs: bool,
+  local_store_compression_threshold_bytes: u64,
+  local_store_read_only_dir_buf: Buffer,
+  local_store_shard_count: u64,
+  local_store_map_size_bytes: u64,
+  local_store_max_map_size_bytes: u64,
+  local_store_large_file_threshold_bytes: u64,
+  scheduler_ptr: *mut *const Scheduler,
+) -> PyResult {
   let root_type_ids = root_type_ids.to_vec();
   let ignore_patterns = ignore_patterns_buf
     .to_strings()
@@ -283,36 +300,100 @@ pub extern "C" fn scheduler_create(
   };
   let mut tasks = with_tasks(tasks_ptr, |tasks| tasks.clone());
   tasks.intrinsics_set(&types);
-  // Allocate on the heap via `Box` and return a raw pointer to the boxed value.
-  let remote_store_server_string = remote_store_server
+  let remote_store_servers = remote_store_servers_buf
+    .to_strings()
+    .unwrap_or_else(|e| panic!("Failed to decode remote store servers as UTF8: {:?}", e));
+  let remote_store_server_selection = match remote_store_server_selection
     .to_string()
-    .expect("remote_store_server was not valid UTF8");
+    .expect("remote_store_server_selection was not valid UTF8")
+    .as_ref()
+  {
+    "round_robin" => fs::EndpointSelection::RoundRobin,
+    "primary_secondary" => fs::EndpointSelection::PrimarySecondary,
+    other => panic!("Unknown remote store server selection: {}", other),
+  };
   let remote_execution_server_string = remote_execution_server
     .to_string()
     .expect("remote_execution_server was not valid UTF8");
-  Box::into_raw(Box::new(Scheduler::new(Core::new(
-    root_type_ids.clone(),
-    tasks,
-    types,
-    build_root_buf.to_os_string().as_ref(),
-    &ignore_patterns,
-    PathBuf::from(work_dir_buf.to_os_string()),
-    if remote_store_server_string.is_empty() {
-      None
-    } else {
-      Some(remote_store_server_string)
-    },
-    if remote_execution_server_string.is_empty() {
-      None
+  let request_metadata = fs::RequestMetadata {
+    tool_name: "pants".to_owned(),
+    tool_version: remote_tool_version
+      .to_string()
+      .expect("remote_tool_version was not valid UTF8"),
+    tool_invocation_id: remote_tool_invocation_id
+      .to_string()
+      .expect("remote_tool_invocation_id was not valid UTF8"),
+    correlated_invocations_id: remote_correlated_invocations_id
+      .to_string()
+      .expect("remote_correlated_invocations_id was not valid UTF8"),
+  };
+  let local_store_read_only_dir = local_store_read_only_dir_buf.to_os_string();
+  let local_sandbox = if process_execution_local_sandbox {
+    let read_only_paths: Vec<PathBuf> = process_execution_local_sandbox_read_only_paths_buf
+      .to_os_strings()
+      .into_iter()
+      .map(PathBuf::from)
+      .collect();
+    // Python passes no paths in order to use the defaults, which only the Sandbox defines.
+    let read_only_paths = if read_only_paths.is_empty() {
+      process_execution::sandbox::Sandbox::default_read_only_paths()
     } else {
-      Some(remote_execution_server_string)
-    },
-    remote_store_thread_count as usize,
-    remote_store_chunk_bytes as usize,
-    Duration::from_secs(remote_store_chunk_upload_timeout_seconds),
-    process_execution_parallelism as usize,
-    process_execution_cleanup_local_dirs as bool,
-  ))))
+      read_only_paths
+    };
+    process_execution::sandbox::Sandbox::new(read_only_paths)
+      .map(Some)
+      .map_err(|e| format!("Invalid local sandbox configuration: {}", e))
+  } else {
+    Ok(None)
+  };
+  let core = local_sandbox.and_then(|local_sandbox| {
+    Core::new(
+      root_type_ids.clone(),
+      tasks,
+      types,
+      build_root_buf.to_os_string().as_ref(),
+      &ignore_patterns,
+      PathBuf::from(work_dir_buf.to_os_string()),
+      remote_store_servers,
+      remote_store_server_selection,
+      if remote_execution_server_string.is_empty() {
+        None
+      } else {
+        Some(remote_execution_server_string)
+      },
+      request_metadata,
+      remote_store_thread_count as usize,
+      remote_store_chunk_bytes as usize,
+      Duration::from_secs(remote_store_chunk_upload_timeout_seconds),
+      process_execution_parallelism as usize,
+      process_execution_cleanup_local_dirs as bool,
+      local_sandbox,
+      process_execution_local_link_inputs,
+      if local_store_compression_threshold_bytes == 0 {
+        None
+      } else {
+        Some(local_store_compression_threshold_bytes as usize)
+      },
+      if local_store_read_only_dir.is_empty() {
+        None
+      } else {
+        Some(PathBuf::from(local_store_read_only_dir))
+      },
+      local_store_shard_count as usize,
+      local_store_map_size_bytes as usize,
+      if local_store_max_map_size_bytes == 0 {
+        None
+      } else {
+        Some(local_store_max_map_size_bytes as usize)
+      },
+      local_store_large_file_threshold_bytes as usize,
+    )
+  });
+  // Allocate on the heap via `Box` and hand Python a raw pointer to the boxed value.
+  core
+    .map(|core| unsafe {
+      *scheduler_ptr = Box::into_raw(Box::new(Scheduler::new(core)));
+    }).into()
 }
 
 ///
@@ -550,6 +631,13 @@ pub extern "C" fn validator_run(scheduler_ptr: *mut Scheduler) -> PyResult {
   })
 }
 
+#[no_mangle]
+pub extern "C" fn scheduler_check_capabilities(scheduler_ptr: *mut Scheduler) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler.core.command_runner.check_capabilities().into()
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn rule_graph_visualize(
   scheduler_ptr: *mut Scheduler,
@@ -612,6 +700,11 @@ pub extern "C" fn garbage_collect_store(scheduler_ptr: *mut Scheduler) {
       Ok(_) => {}
       Err(err) => error!("{}", err),
     }
+    if let Some(ref file_cache) = scheduler.core.file_cache {
+      if let Err(err) = file_cache.garbage_collect() {
+        error!("{}", err);
+      }
+    }
   });
 }
 
@@ -671,23 +764,198 @@ pub extern "C" fn capture_snapshots(
 #[no_mangle]
 pub extern "C" fn merge_directories(
   scheduler_ptr: *mut Scheduler,
-  directories_value: Handle,
+  directories_to_merge: Handle,
 ) -> PyResult {
-  let digests_result: Result<Vec<hashing::Digest>, String> =
-    externs::project_multi(&directories_value.into(), "dependencies")
-      .iter()
-      .map(|v| nodes::lift_digest(v))
-      .collect();
-  let digests = match digests_result {
+  let value: Value = directories_to_merge.into();
+  let digests_result: Result<Vec<hashing::Digest>, String> = externs::project_multi(
+    &externs::project_ignoring_type(&value, "directories"),
+    "dependencies",
+  ).iter()
+    .map(|v| nodes::lift_digest(v))
+    .collect();
+  let digests_and_policy = digests_result.and_then(|digests| {
+    fs::MergePolicy::create(&externs::project_str(&value, "merge_policy"))
+      .map(|policy| (digests, policy))
+  });
+  let (digests, policy) = match digests_and_policy {
+    Ok(d) => d,
+    Err(err) => {
+      let e: Result<Value, String> = Err(err);
+      return e.into();
+    }
+  };
+
+  with_scheduler(scheduler_ptr, |scheduler| {
+    fs::Snapshot::merge_directories_with_policy(scheduler.core.store.clone(), digests, policy)
+      .wait()
+      .map(|dir| nodes::Snapshot::store_directory(&scheduler.core, &dir))
+      .into()
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn add_prefix(
+  scheduler_ptr: *mut Scheduler,
+  directory_with_prefix: Handle,
+) -> PyResult {
+  let value: Value = directory_with_prefix.into();
+  let digest_result =
+    nodes::lift_digest(&externs::project_ignoring_type(&value, "directory_digest"));
+  let digest = match digest_result {
     Ok(d) => d,
     Err(err) => {
       let e: Result<Value, String> = Err(err);
       return e.into();
     }
   };
+  let prefix = PathBuf::from(externs::project_str(&value, "prefix"));
 
   with_scheduler(scheduler_ptr, |scheduler| {
-    fs::Snapshot::merge_directories(scheduler.core.store.clone(), digests)
+    fs::Snapshot::add_prefix(scheduler.core.store.clone(), digest, prefix)
+      .wait()
+      .map(|dir| nodes::Snapshot::store_directory(&scheduler.core, &dir))
+      .into()
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn strip_prefix(
+  scheduler_ptr: *mut Scheduler,
+  directory_with_prefix: Handle,
+) -> PyResult {
+  let value: Value = directory_with_prefix.into();
+  let digest_result =
+    nodes::lift_digest(&externs::project_ignoring_type(&value, "directory_digest"));
+  let digest = match digest_result {
+    Ok(d) => d,
+    Err(err) => {
+      let e: Result<Value, String> = Err(err);
+      return e.into();
+    }
+  };
+  let prefix = PathBuf::from(externs::project_str(&value, "prefix"));
+
+  with_scheduler(scheduler_ptr, |scheduler| {
+    fs::Snapshot::strip_prefix(scheduler.core.store.clone(), digest, prefix)
+      .wait()
+      .map(|dir| nodes::Snapshot::store_directory(&scheduler.core, &dir))
+      .into()
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn subset_directory(
+  scheduler_ptr: *mut Scheduler,
+  directory_subset: Handle,
+) -> PyResult {
+  let value: Value = directory_subset.into();
+  let digest_and_path_globs =
+    nodes::lift_digest(&externs::project_ignoring_type(&value, "directory_digest")).and_then(
+      |digest| {
+        nodes::Snapshot::lift_path_globs(&externs::project_ignoring_type(&value, "path_globs"))
+          .map(|path_globs| (digest, path_globs))
+      },
+    );
+  let (digest, path_globs) = match digest_and_path_globs {
+    Ok(d) => d,
+    Err(err) => {
+      let e: Result<Value, String> = Err(err);
+      return e.into();
+    }
+  };
+
+  with_scheduler(scheduler_ptr, |scheduler| {
+    fs::Snapshot::subset(scheduler.core.store.clone(), digest, path_globs)
+      .wait()
+      .map(|dir| nodes::Snapshot::store_directory(&scheduler.core, &dir))
+      .into()
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn archive_directory(
+  scheduler_ptr: *mut Scheduler,
+  directory_to_archive: Handle,
+) -> PyResult {
+  let value: Value = directory_to_archive.into();
+  let digest_and_format =
+    nodes::lift_digest(&externs::project_ignoring_type(&value, "directory_digest")).and_then(
+      |digest| {
+        fs::ArchiveFormat::create(&externs::project_str(&value, "format"))
+          .map(|format| (digest, format))
+      },
+    );
+  let (digest, format) = match digest_and_format {
+    Ok(d) => d,
+    Err(err) => {
+      let e: Result<Value, String> = Err(err);
+      return e.into();
+    }
+  };
+  let path = PathBuf::from(externs::project_str(&value, "path"));
+  // The archive is written to a temporary file in the same directory, which is only moved into
+  // place once the archive is complete, so that a failed export leaves nothing behind.
+  let parent = match path.parent() {
+    Some(parent) if parent != Path::new("") => parent.to_owned(),
+    _ => PathBuf::from("."),
+  };
+  let temp_file = match tempfile::Builder::new()
+    .prefix(".pants-archive-")
+    .tempfile_in(&parent)
+  {
+    Ok(f) => f,
+    Err(err) => {
+      let e: Result<Value, String> = Err(format!("Error creating archive {:?}: {}", path, err));
+      return e.into();
+    }
+  };
+
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler
+      .core
+      .store
+      .export_archive(digest, format, temp_file)
+      .wait()
+      .and_then(|temp_file| {
+        // Temporary files are only readable by their owner, unlike the files which we create.
+        std::fs::set_permissions(temp_file.path(), Permissions::from_mode(0o644))
+          .map_err(|err| format!("Error creating archive {:?}: {}", path, err))?;
+        temp_file
+          .persist(&path)
+          .map(|_| ())
+          .map_err(|err| format!("Error creating archive {:?}: {}", path, err.error))
+      })
+      .into()
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn import_archive(
+  scheduler_ptr: *mut Scheduler,
+  archive_to_import: Handle,
+) -> PyResult {
+  let value: Value = archive_to_import.into();
+  let format = match fs::ArchiveFormat::create(&externs::project_str(&value, "format")) {
+    Ok(f) => f,
+    Err(err) => {
+      let e: Result<Value, String> = Err(err);
+      return e.into();
+    }
+  };
+  let path = PathBuf::from(externs::project_str(&value, "path"));
+  let file = match File::open(&path) {
+    Ok(f) => f,
+    Err(err) => {
+      let e: Result<Value, String> = Err(format!("Error opening archive {:?}: {}", path, err));
+      return e.into();
+    }
+  };
+
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler
+      .core
+      .store
+      .import_archive(format, io::BufReader::new(file))
       .wait()
       .map(|dir| nodes::Snapshot::store_directory(&scheduler.core, &dir))
       .into()
@@ -700,13 +968,27 @@ pub extern "C" fn materialize_directories(
   directories_paths_and_digests_value: Handle,
 ) -> PyResult {
   let values = externs::project_multi(&directories_paths_and_digests_value.into(), "dependencies");
-  let directories_paths_and_digests_results: Result<Vec<(PathBuf, Digest)>, String> = values
+  let directories_paths_and_digests_results: Result<Vec<DirectoryToMaterialize>, String> = values
     .iter()
     .map(|value| {
       let dir = PathBuf::from(externs::project_str(&value, "path"));
       let dir_digest =
         nodes::lift_digest(&externs::project_ignoring_type(&value, "directory_digest"));
-      dir_digest.map(|dir_digest| (dir, dir_digest))
+      let sync = if externs::project_bool(&value, "sync") {
+        let managed_prefix = externs::project_str(&value, "managed_prefix");
+        Some(if managed_prefix.is_empty() {
+          None
+        } else {
+          Some(PathBuf::from(managed_prefix))
+        })
+      } else {
+        None
+      };
+      dir_digest.map(|dir_digest| DirectoryToMaterialize {
+        dir,
+        digest: dir_digest,
+        sync,
+      })
     })
     .collect();
 
@@ -722,7 +1004,17 @@ pub extern "C" fn materialize_directories(
     futures::future::join_all(
       dir_and_digests
         .into_iter()
-        .map(|(dir, digest)| scheduler.core.store.materialize_directory(dir, digest))
+        .map(|to_materialize| match to_materialize.sync {
+          Some(managed_prefix) => scheduler.core.store.sync_directory(
+            to_materialize.dir,
+            to_materialize.digest,
+            managed_prefix,
+          ),
+          None => scheduler
+            .core
+            .store
+            .materialize_directory(to_materialize.dir, to_materialize.digest),
+        })
         .collect::<Vec<_>>(),
     )
   }).map(|_| ())
@@ -730,6 +1022,13 @@ pub extern "C" fn materialize_directories(
     .into()
 }
 
+struct DirectoryToMaterialize {
+  dir: PathBuf,
+  digest: Digest,
+  // If set, the directory is synced (with the given managed prefix) rather than written into.
+  sync: Option<Option<PathBuf>>,
+}
+
 fn graph_full(scheduler: &Scheduler, subject_types: Vec<TypeId>) -> RuleGraph {
   let graph_maker = GraphMaker::new(&scheduler.core.tasks, subject_types);
   graph_maker.full_graph()
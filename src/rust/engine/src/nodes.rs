@@ -14,7 +14,7 @@ use core::{throw, Failure, Key, Noop, TypeConstraint, Value, Variants};
 use externs;
 use fs::{
   self, Dir, DirectoryListing, File, FileContent, GlobMatching, Link, PathGlobs, PathStat,
-  StoreFileByDigest, StrictGlobMatching, VFS,
+  StoreFileByDigest, StrictGlobMatching, SymlinkBehavior, VFS,
 };
 use hashing;
 use process_execution::{self, CommandRunner};
@@ -46,7 +46,7 @@ fn was_required(failure: Failure) -> Failure {
 }
 
 impl VFS<Failure> for Context {
-  fn read_link(&self, link: &Link) -> NodeFuture<PathBuf> {
+  fn read_link_target(&self, link: &Link) -> NodeFuture<PathBuf> {
     self.get(ReadLink(link.clone())).map(|res| res.0).to_boxed()
   }
 
@@ -390,6 +390,7 @@ impl Select {
                     externs::store_bytes(&result.0.stderr),
                     externs::store_i64(result.0.exit_code.into()),
                     Snapshot::store_directory(&context.core, &result.0.output_directory),
+                    store_resource_usage(result.0.resource_usage),
                   ],
                 )
               })
@@ -522,6 +523,37 @@ impl ExecuteProcess {
       }
     };
 
+    let mut named_caches: BTreeMap<String, PathBuf> = BTreeMap::new();
+    let named_cache_parts = externs::project_multi_strs(&value, "named_caches");
+    if named_cache_parts.len() % 2 != 0 {
+      return Err("Error parsing named_caches: odd number of parts".to_owned());
+    }
+    for i in 0..(named_cache_parts.len() / 2) {
+      named_caches.insert(
+        named_cache_parts[2 * i].clone(),
+        PathBuf::from(&named_cache_parts[2 * i + 1]),
+      );
+    }
+
+    let working_directory = {
+      let val = externs::project_str(&value, "working_directory");
+      if val.is_empty() {
+        None
+      } else {
+        Some(PathBuf::from(val))
+      }
+    };
+
+    // NB: None is represented by an empty string.
+    let stdin = if externs::project_str(&value, "stdin").is_empty() {
+      None
+    } else {
+      Some(
+        lift_digest(&externs::project_ignoring_type(&value, "stdin"))
+          .map_err(|err| format!("Error parsing stdin digest {}", err))?,
+      )
+    };
+
     Ok(ExecuteProcess(process_execution::ExecuteProcessRequest {
       argv: externs::project_multi_strs(&value, "argv"),
       env: env,
@@ -531,10 +563,59 @@ impl ExecuteProcess {
       timeout: Duration::from_millis((timeout_in_seconds * 1000.0) as u64),
       description: description,
       jdk_home: jdk_home,
+      working_directory: working_directory,
+      stdin: stdin,
+      resource_limits: lift_resource_limits(&externs::project_ignoring_type(
+        &value,
+        "resource_limits",
+      ))?,
+      report_resource_usage: externs::project_bool(&value, "report_resource_usage"),
+      named_caches: named_caches,
     }))
   }
 }
 
+///
+/// Lifts a python ResourceLimits value, whose limits are each either an integer or None.
+///
+fn lift_resource_limits(value: &Value) -> Result<process_execution::ResourceLimits, String> {
+  let lift_limit = |field: &str| {
+    let val = externs::project_str(value, field);
+    if val.is_empty() {
+      Ok(None)
+    } else {
+      val
+        .parse::<u64>()
+        .map(Some)
+        .map_err(|err| format!("Resource limit {} was not an unsigned integer: {:?}", field, err))
+    }
+  };
+  Ok(process_execution::ResourceLimits {
+    cpu_seconds: lift_limit("cpu_seconds")?,
+    address_space_bytes: lift_limit("address_space_bytes")?,
+    open_files: lift_limit("open_files")?,
+    max_output_bytes: lift_limit("max_output_bytes")?,
+  })
+}
+
+///
+/// Stores a ResourceUsage as the tuple of its fields which python's FallibleExecuteProcessResult
+/// expects, which is empty if the usage is unavailable.
+///
+fn store_resource_usage(resource_usage: Option<process_execution::ResourceUsage>) -> Value {
+  let to_micros = |duration: Duration| {
+    (duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())) as i64
+  };
+  match resource_usage {
+    Some(usage) => externs::store_tuple(&[
+      externs::store_i64(to_micros(usage.user_time)),
+      externs::store_i64(to_micros(usage.system_time)),
+      externs::store_i64(usage.max_rss_bytes as i64),
+    ]),
+    None => externs::store_tuple(&[]),
+  }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ProcessResult(process_execution::FallibleExecuteProcessResult);
 
@@ -561,7 +642,8 @@ impl From<ExecuteProcess> for NodeKey {
 }
 
 ///
-/// A Node that represents reading the destination of a symlink (non-recursively).
+/// A Node that represents reading the target of a symlink (non-recursively), exactly as it was
+/// written.
 ///
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct ReadLink(Link);
@@ -577,7 +659,7 @@ impl WrappedNode for ReadLink {
     context
       .core
       .vfs
-      .read_link(&self.0)
+      .read_link_target(&self.0)
       .map(LinkDest)
       .map_err(move |e| throw(&format!("Failed to read_link for {:?}: {:?}", link, e)))
       .to_boxed()
@@ -603,16 +685,9 @@ impl WrappedNode for DigestFile {
     let file = self.0.clone();
     context
       .core
-      .vfs
-      .read_file(&self.0)
-      .map_err(move |e| throw(&format!("Error reading file {:?}: {:?}", file, e,)))
-      .and_then(move |c| {
-        context
-          .core
-          .store
-          .store_file_bytes(c.content, true)
-          .map_err(|e| throw(&e))
-      })
+      .store
+      .store_file_from_path(context.core.vfs.file_path(&self.0), true)
+      .map_err(move |e| throw(&format!("Error storing file {:?}: {}", file, e)))
       .to_boxed()
   }
 }
@@ -682,12 +757,19 @@ impl Snapshot {
       externs::project_ignoring_type(item, "glob_match_error_behavior");
     let failure_behavior = externs::project_str(&glob_match_error_behavior, "failure_behavior");
     let strict_glob_matching = StrictGlobMatching::create(failure_behavior.as_str())?;
-    PathGlobs::create(&include, &exclude, strict_glob_matching).map_err(|e| {
-      format!(
-        "Failed to parse PathGlobs for include({:?}), exclude({:?}): {}",
-        include, exclude, e
-      )
-    })
+    let symlink_behavior = if externs::project_bool(item, "preserve_symlinks") {
+      SymlinkBehavior::Aware
+    } else {
+      SymlinkBehavior::Oblivious
+    };
+    PathGlobs::create(&include, &exclude, strict_glob_matching)
+      .map(|path_globs| path_globs.with_symlink_behavior(symlink_behavior))
+      .map_err(|e| {
+        format!(
+          "Failed to parse PathGlobs for include({:?}), exclude({:?}): {}",
+          include, exclude, e
+        )
+      })
   }
 
   pub fn store_directory(core: &Arc<Core>, item: &hashing::Digest) -> Value {
@@ -729,6 +811,11 @@ impl Snapshot {
     externs::unsafe_call(&core.types.construct_file, &args)
   }
 
+  fn store_link(core: &Arc<Core>, item: &Link) -> Value {
+    let args = [Self::store_path(item.0.as_path())];
+    externs::unsafe_call(&core.types.construct_link, &args)
+  }
+
   fn store_path_stat(core: &Arc<Core>, item: &PathStat) -> Value {
     let args = match item {
       &PathStat::Dir { ref path, ref stat } => {
@@ -737,6 +824,9 @@ impl Snapshot {
       &PathStat::File { ref path, ref stat } => {
         vec![Self::store_path(path), Self::store_file(core, stat)]
       }
+      &PathStat::Link { ref path, ref stat, .. } => {
+        vec![Self::store_path(path), Self::store_link(core, stat)]
+      }
     };
     externs::unsafe_call(&core.types.construct_path_stat, &args)
   }
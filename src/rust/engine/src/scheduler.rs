@@ -161,7 +161,7 @@ impl Scheduler {
   }
 
   ///
-  /// Return Scheduler and per-Session metrics.
+  /// Return Scheduler and per-Session metrics, including statistics about the local store's cache.
   ///
   pub fn metrics(&self, session: &Session) -> HashMap<&str, i64> {
     let mut m = HashMap::new();
@@ -174,6 +174,60 @@ impl Scheduler {
     );
     m.insert("preceding_graph_size", session.preceding_graph_size as i64);
     m.insert("resulting_graph_size", self.core.graph.len() as i64);
+    // Statistics about the leases of the local store require reading every entry, which is too slow
+    // to do on every run: see `fs_util store stats` for those. LMDB keeps counts of the entries and
+    // pages of each database though.
+    match self.core.store.databases_stats() {
+      Ok(databases) => {
+        for &(entries_metric, bytes_metric, stats) in &[
+          (
+            "local_store_file_entries",
+            "local_store_file_bytes",
+            &databases.files,
+          ),
+          (
+            "local_store_file_lease_entries",
+            "local_store_file_lease_bytes",
+            &databases.file_leases,
+          ),
+          (
+            "local_store_file_encoding_entries",
+            "local_store_file_encoding_bytes",
+            &databases.file_encodings,
+          ),
+          (
+            "local_store_directory_entries",
+            "local_store_directory_bytes",
+            &databases.directories,
+          ),
+          (
+            "local_store_directory_lease_entries",
+            "local_store_directory_lease_bytes",
+            &databases.directory_leases,
+          ),
+          (
+            "local_store_directory_encoding_entries",
+            "local_store_directory_encoding_bytes",
+            &databases.directory_encodings,
+          ),
+        ] {
+          m.insert(entries_metric, stats.entries as i64);
+          m.insert(bytes_metric, stats.size_bytes as i64);
+        }
+      }
+      Err(e) => warn!("Error reading local store stats: {}", e),
+    }
+    let hot_cache = self.core.store.hot_cache_stats();
+    m.insert("local_store_hot_cache_entries", hot_cache.entries as i64);
+    m.insert("local_store_hot_cache_bytes", hot_cache.size_bytes as i64);
+    m.insert(
+      "local_store_hot_cache_hits",
+      (hot_cache.directory_hits + hot_cache.file_hits) as i64,
+    );
+    m.insert(
+      "local_store_hot_cache_misses",
+      (hot_cache.directory_misses + hot_cache.file_misses) as i64,
+    );
     m
   }
 
@@ -1,3 +1,4 @@
+use std::cmp::min;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
@@ -5,9 +6,11 @@ use bazel_protos;
 use futures;
 use grpcio;
 
+use bazel_protos::remote_execution::RequestMetadata;
 use bytes::Bytes;
 use futures::{Future, IntoFuture, Stream};
 use hashing::{Digest, Fingerprint};
+use request_metadata;
 use testutil::data::{TestData, TestDirectory};
 
 ///
@@ -19,6 +22,8 @@ pub struct StubCAS {
   read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
   pub blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  // The name of each request received, and the RequestMetadata which was sent with it (if any).
+  pub request_metadata: Arc<Mutex<Vec<(String, Option<RequestMetadata>)>>>,
 }
 
 impl StubCAS {
@@ -42,22 +47,46 @@ impl StubCAS {
   /// * `chunk_size_bytes` - The maximum number of bytes of content to include per streamed message.
   ///                        Messages will saturate until the last one, which may be smaller than
   ///                        this value.
-  ///                        If a negative value is given, all requests will receive an error.
+  ///                        If a negative value is given, all requests will receive an error, as
+  ///                        if the server were unavailable.
   /// * `blobs`            - Known Fingerprints and their content responses. These are not checked
   ///                        for correctness.
   pub fn with_unverified_content(
     chunk_size_bytes: i64,
     blobs: HashMap<Fingerprint, Bytes>,
+  ) -> StubCAS {
+    StubCAS::new(chunk_size_bytes, blobs, None, false)
+  }
+
+  ///
+  /// An empty StubCAS which answers GetCapabilities requests with the given capabilities (by
+  /// default, the Capabilities service is unimplemented).
+  ///
+  pub fn with_capabilities(
+    capabilities: bazel_protos::remote_execution::ServerCapabilities,
+  ) -> StubCAS {
+    StubCAS::new(1024, HashMap::new(), Some(capabilities), false)
+  }
+
+  fn new(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+    capabilities: Option<bazel_protos::remote_execution::ServerCapabilities>,
+    always_rejects: bool,
   ) -> StubCAS {
     let env = Arc::new(grpcio::Environment::new(1));
     let read_request_count = Arc::new(Mutex::new(0));
     let write_message_sizes = Arc::new(Mutex::new(Vec::new()));
     let blobs = Arc::new(Mutex::new(blobs));
+    let request_metadata = Arc::new(Mutex::new(Vec::new()));
     let responder = StubCASResponder {
       chunk_size_bytes: chunk_size_bytes,
       blobs: blobs.clone(),
       read_request_count: read_request_count.clone(),
       write_message_sizes: write_message_sizes.clone(),
+      request_metadata: request_metadata.clone(),
+      capabilities: capabilities,
+      always_rejects: always_rejects,
     };
     let mut server_transport = grpcio::ServerBuilder::new(env)
       .register_service(bazel_protos::bytestream_grpc::create_byte_stream(
@@ -66,6 +95,9 @@ impl StubCAS {
       .register_service(
         bazel_protos::remote_execution_grpc::create_content_addressable_storage(responder.clone()),
       )
+      .register_service(bazel_protos::remote_execution_grpc::create_capabilities(
+        responder.clone(),
+      ))
       .bind("localhost", 0)
       .build()
       .unwrap();
@@ -76,6 +108,7 @@ impl StubCAS {
       read_request_count,
       write_message_sizes,
       blobs,
+      request_metadata,
     }
   }
 
@@ -95,6 +128,14 @@ impl StubCAS {
     StubCAS::with_unverified_content(-1, HashMap::new())
   }
 
+  ///
+  /// A StubCAS which fails every request with an error which is not the fault of the server (as if
+  /// every request were invalid), so that a client shouldn't try another server instead.
+  ///
+  pub fn always_rejects() -> StubCAS {
+    StubCAS::new(1024, HashMap::new(), None, true)
+  }
+
   ///
   /// The address on which this server is listening over insecure HTTP transport.
   ///
@@ -114,11 +155,36 @@ pub struct StubCASResponder {
   blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
   pub read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
+  request_metadata: Arc<Mutex<Vec<(String, Option<RequestMetadata>)>>>,
+  capabilities: Option<bazel_protos::remote_execution::ServerCapabilities>,
+  always_rejects: bool,
 }
 
 impl StubCASResponder {
   fn should_always_fail(&self) -> bool {
-    self.chunk_size_bytes < 0
+    self.chunk_size_bytes < 0 || self.always_rejects
+  }
+
+  fn failure(always_rejects: bool) -> grpcio::RpcStatus {
+    if always_rejects {
+      grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::InvalidArgument,
+        Some("StubCAS is configured to reject every request".to_owned()),
+      )
+    } else {
+      grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unavailable,
+        Some("StubCAS is configured to always fail".to_owned()),
+      )
+    }
+  }
+
+  fn record_request_metadata(&self, ctx: &grpcio::RpcContext, request_name: &str) {
+    self
+      .request_metadata
+      .lock()
+      .unwrap()
+      .push((request_name.to_owned(), request_metadata(ctx)));
   }
 
   fn read_internal(
@@ -143,24 +209,46 @@ impl StubCASResponder {
       )
     })?;
     if self.should_always_fail() {
+      return Err(StubCASResponder::failure(self.always_rejects));
+    }
+    if req.get_read_offset() < 0 || req.get_read_limit() < 0 {
       return Err(grpcio::RpcStatus::new(
-        grpcio::RpcStatusCode::Internal,
-        Some("StubCAS is configured to always fail".to_owned()),
+        grpcio::RpcStatusCode::InvalidArgument,
+        Some(format!(
+          "Negative read offset {} or limit {}",
+          req.get_read_offset(),
+          req.get_read_limit()
+        )),
       ));
     }
     let blobs = self.blobs.lock().unwrap();
     let maybe_bytes = blobs.get(&fingerprint);
     match maybe_bytes {
-      Some(bytes) => Ok(
+      Some(bytes) if req.get_read_offset() as usize > bytes.len() => Err(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::OutOfRange,
+        Some(format!(
+          "Read offset {} is past the end of {}",
+          req.get_read_offset(),
+          fingerprint
+        )),
+      )),
+      Some(bytes) => Ok({
+        let begin = req.get_read_offset() as usize;
+        // 0 means no limit.
+        let end = match req.get_read_limit() as usize {
+          0 => bytes.len(),
+          limit => min(begin + limit, bytes.len()),
+        };
         bytes
+          .slice(begin, end)
           .chunks(self.chunk_size_bytes as usize)
           .map(|b| {
             let mut resp = bazel_protos::bytestream::ReadResponse::new();
             resp.set_data(Bytes::from(b));
             resp
           })
-          .collect(),
-      ),
+          .collect()
+      }),
       None => Err(grpcio::RpcStatus::new(
         grpcio::RpcStatusCode::NotFound,
         Some(format!("Did not find digest {}", fingerprint)),
@@ -191,6 +279,7 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
     req: bazel_protos::bytestream::ReadRequest,
     sink: grpcio::ServerStreamingSink<bazel_protos::bytestream::ReadResponse>,
   ) {
+    self.record_request_metadata(&ctx, "ReadRequest");
     {
       let mut request_count = self.read_request_count.lock().unwrap();
       *request_count += 1;
@@ -217,7 +306,9 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
     stream: grpcio::RequestStream<bazel_protos::bytestream::WriteRequest>,
     sink: grpcio::ClientStreamingSink<bazel_protos::bytestream::WriteResponse>,
   ) {
+    self.record_request_metadata(&ctx, "WriteRequest");
     let should_always_fail = self.should_always_fail();
+    let always_rejects = self.always_rejects;
     let write_message_sizes = self.write_message_sizes.clone();
     let blobs = self.blobs.clone();
     ctx.spawn(
@@ -317,10 +408,7 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
               }
 
               if should_always_fail {
-                return Err(grpcio::RpcStatus::new(
-                  grpcio::RpcStatusCode::Internal,
-                  Some("StubCAS is configured to always fail".to_owned()),
-                ));
+                return Err(StubCASResponder::failure(always_rejects));
               }
 
               {
@@ -358,15 +446,13 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
 impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASResponder {
   fn find_missing_blobs(
     &self,
-    _ctx: grpcio::RpcContext,
+    ctx: grpcio::RpcContext,
     req: bazel_protos::remote_execution::FindMissingBlobsRequest,
     sink: grpcio::UnarySink<bazel_protos::remote_execution::FindMissingBlobsResponse>,
   ) {
+    self.record_request_metadata(&ctx, "FindMissingBlobsRequest");
     if self.should_always_fail() {
-      sink.fail(grpcio::RpcStatus::new(
-        grpcio::RpcStatusCode::Internal,
-        Some("StubCAS is configured to always fail".to_owned()),
-      ));
+      sink.fail(StubCASResponder::failure(self.always_rejects));
       return;
     }
     let blobs = self.blobs.lock().unwrap();
@@ -383,14 +469,28 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
 
   fn batch_update_blobs(
     &self,
-    _ctx: grpcio::RpcContext,
-    _req: bazel_protos::remote_execution::BatchUpdateBlobsRequest,
+    ctx: grpcio::RpcContext,
+    req: bazel_protos::remote_execution::BatchUpdateBlobsRequest,
     sink: grpcio::UnarySink<bazel_protos::remote_execution::BatchUpdateBlobsResponse>,
   ) {
-    sink.fail(grpcio::RpcStatus::new(
-      grpcio::RpcStatusCode::Unimplemented,
-      None,
-    ));
+    self.record_request_metadata(&ctx, "BatchUpdateBlobsRequest");
+    if self.should_always_fail() {
+      sink.fail(StubCASResponder::failure(self.always_rejects));
+      return;
+    }
+    let mut blobs = self.blobs.lock().unwrap();
+    let mut response = bazel_protos::remote_execution::BatchUpdateBlobsResponse::new();
+    for blob_request in req.get_requests() {
+      let digest_result: Result<Digest, String> = blob_request.get_content_digest().into();
+      let digest = digest_result.expect("Bad digest");
+      blobs.insert(digest.0, Bytes::from(blob_request.get_data()));
+      let mut blob_response =
+        bazel_protos::remote_execution::BatchUpdateBlobsResponse_Response::new();
+      blob_response.set_blob_digest(blob_request.get_content_digest().clone());
+      blob_response.set_status(bazel_protos::status::Status::new());
+      response.mut_responses().push(blob_response);
+    }
+    sink.success(response);
   }
   fn get_tree(
     &self,
@@ -403,3 +503,20 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
     unimplemented!()
   }
 }
+
+impl bazel_protos::remote_execution_grpc::Capabilities for StubCASResponder {
+  fn get_capabilities(
+    &self,
+    _ctx: grpcio::RpcContext,
+    _req: bazel_protos::remote_execution::GetCapabilitiesRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::ServerCapabilities>,
+  ) {
+    match self.capabilities {
+      Some(ref capabilities) => sink.success(capabilities.clone()),
+      None => sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unimplemented,
+        None,
+      )),
+    };
+  }
+}
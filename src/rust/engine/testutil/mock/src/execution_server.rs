@@ -8,9 +8,11 @@ use std::time::Duration;
 use std::time::Instant;
 
 use bazel_protos;
+use bazel_protos::remote_execution::RequestMetadata;
 use futures::{Future, Sink};
 use grpcio;
 use protobuf;
+use request_metadata;
 
 ///
 /// A MockOperation to be used with MockExecution.
@@ -84,7 +86,25 @@ impl TestServer {
   ///                      MockExecution's name, or more requests are received than stub responses
   ///                      are available for, an error will be returned.
   pub fn new(mock_execution: MockExecution) -> TestServer {
-    let mock_responder = MockResponder::new(mock_execution);
+    TestServer::create(mock_execution, None)
+  }
+
+  ///
+  /// As new, but also answers GetCapabilities requests with the given capabilities (by default,
+  /// the Capabilities service is unimplemented).
+  ///
+  pub fn with_capabilities(
+    mock_execution: MockExecution,
+    capabilities: bazel_protos::remote_execution::ServerCapabilities,
+  ) -> TestServer {
+    TestServer::create(mock_execution, Some(capabilities))
+  }
+
+  fn create(
+    mock_execution: MockExecution,
+    capabilities: Option<bazel_protos::remote_execution::ServerCapabilities>,
+  ) -> TestServer {
+    let mock_responder = MockResponder::new(mock_execution, capabilities);
 
     let env = Arc::new(grpcio::Environment::new(1));
     let mut server_transport = grpcio::ServerBuilder::new(env)
@@ -94,6 +114,9 @@ impl TestServer {
       .register_service(bazel_protos::operations_grpc::create_operations(
         mock_responder.clone(),
       ))
+      .register_service(bazel_protos::remote_execution_grpc::create_capabilities(
+        mock_responder.clone(),
+      ))
       .bind("localhost", 0)
       .build()
       .unwrap();
@@ -152,18 +175,30 @@ impl Drop for TestServer {
 #[derive(Clone, Debug)]
 pub struct MockResponder {
   mock_execution: MockExecution,
+  capabilities: Option<bazel_protos::remote_execution::ServerCapabilities>,
   pub received_messages: Arc<Mutex<Vec<(String, Box<protobuf::Message>, Instant)>>>,
+  // The name of each request received, and the RequestMetadata which was sent with it (if any).
+  pub request_metadata: Arc<Mutex<Vec<(String, Option<RequestMetadata>)>>>,
 }
 
 impl MockResponder {
-  fn new(mock_execution: MockExecution) -> MockResponder {
+  fn new(
+    mock_execution: MockExecution,
+    capabilities: Option<bazel_protos::remote_execution::ServerCapabilities>,
+  ) -> MockResponder {
     MockResponder {
       mock_execution: mock_execution,
+      capabilities: capabilities,
       received_messages: Arc::new(Mutex::new(vec![])),
+      request_metadata: Arc::new(Mutex::new(vec![])),
     }
   }
 
-  fn log<T: protobuf::Message + Sized>(&self, message: T) {
+  fn log<T: protobuf::Message + Sized>(&self, ctx: &grpcio::RpcContext, message: T) {
+    self.request_metadata.lock().unwrap().push((
+      message.descriptor().name().to_string(),
+      request_metadata(ctx),
+    ));
     self.received_messages.lock().unwrap().push((
       message.descriptor().name().to_string(),
       Box::new(message),
@@ -259,7 +294,7 @@ impl bazel_protos::remote_execution_grpc::Execution for MockResponder {
     req: bazel_protos::remote_execution::ExecuteRequest,
     sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
   ) {
-    self.log(req.clone());
+    self.log(&ctx, req.clone());
 
     if self.mock_execution.execute_request != req {
       ctx.spawn(
@@ -289,11 +324,11 @@ impl bazel_protos::remote_execution_grpc::Execution for MockResponder {
 impl bazel_protos::operations_grpc::Operations for MockResponder {
   fn get_operation(
     &self,
-    _: grpcio::RpcContext,
+    ctx: grpcio::RpcContext,
     req: bazel_protos::operations::GetOperationRequest,
     sink: grpcio::UnarySink<bazel_protos::operations::Operation>,
   ) {
-    self.log(req.clone());
+    self.log(&ctx, req.clone());
 
     self.send_next_operation_unary(sink)
   }
@@ -334,3 +369,21 @@ impl bazel_protos::operations_grpc::Operations for MockResponder {
     ));
   }
 }
+
+impl bazel_protos::remote_execution_grpc::Capabilities for MockResponder {
+  fn get_capabilities(
+    &self,
+    ctx: grpcio::RpcContext,
+    req: bazel_protos::remote_execution::GetCapabilitiesRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::ServerCapabilities>,
+  ) {
+    self.log(&ctx, req);
+    match self.capabilities {
+      Some(ref capabilities) => sink.success(capabilities.clone()),
+      None => sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unimplemented,
+        None,
+      )),
+    };
+  }
+}
@@ -28,3 +28,16 @@ extern crate testutil;
 mod cas;
 pub use cas::StubCAS;
 pub mod execution_server;
+
+///
+/// The RequestMetadata which was sent with a request, if any.
+///
+fn request_metadata(
+  ctx: &grpcio::RpcContext,
+) -> Option<bazel_protos::remote_execution::RequestMetadata> {
+  ctx
+    .request_headers()
+    .iter()
+    .find(|&(key, _)| key == bazel_protos::REQUEST_METADATA_HEADER)
+    .map(|(_, value)| protobuf::parse_from_bytes(value).expect("Invalid RequestMetadata header"))
+}
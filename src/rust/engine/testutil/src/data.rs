@@ -229,6 +229,35 @@ impl TestDirectory {
     TestDirectory { directory }
   }
 
+  // Directory structure:
+  //
+  // /european_burmese -> roland
+  // /felines -> cats
+  // /roland
+  pub fn containing_roland_and_symlinks() -> TestDirectory {
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_files().push({
+      let mut file = bazel_protos::remote_execution::FileNode::new();
+      file.set_name("roland".to_string());
+      file.set_digest((&TestData::roland().digest()).into());
+      file.set_is_executable(false);
+      file
+    });
+    directory.mut_symlinks().push({
+      let mut symlink = bazel_protos::remote_execution::SymlinkNode::new();
+      symlink.set_name("european_burmese".to_string());
+      symlink.set_target("roland".to_string());
+      symlink
+    });
+    directory.mut_symlinks().push({
+      let mut symlink = bazel_protos::remote_execution::SymlinkNode::new();
+      symlink.set_name("felines".to_string());
+      symlink.set_target("cats".to_string());
+      symlink
+    });
+    TestDirectory { directory }
+  }
+
   pub fn directory(&self) -> bazel_protos::remote_execution::Directory {
     self.directory.clone()
   }
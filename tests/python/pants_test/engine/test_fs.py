@@ -14,9 +14,13 @@ from contextlib import contextmanager
 from future.utils import text_type
 
 from pants.base.project_tree import Dir, Link
-from pants.engine.fs import (EMPTY_DIRECTORY_DIGEST, DirectoryDigest, DirectoryToMaterialize,
-                             FilesContent, PathGlobs, PathGlobsAndRoot, Snapshot, create_fs_rules)
+from pants.engine.fs import (EMPTY_DIRECTORY_DIGEST, ArchiveFormat, ArchiveToImport,
+                             DirectoryDigest, DirectorySubset, DirectoryToArchive,
+                             DirectoryToMaterialize, DirectoryWithPrefixToAdd,
+                             DirectoryWithPrefixToStrip, FilesContent, MergePolicy, PathGlobs,
+                             PathGlobsAndRoot, Snapshot, create_fs_rules)
 from pants.util.contextutil import temporary_dir
+from pants.util.dirutil import safe_mkdir, touch
 from pants.util.meta import AbstractClass
 from pants_test.engine.scheduler_test_base import SchedulerTestBase
 from pants_test.test_base import TestBase
@@ -312,6 +316,23 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
         scheduler.capture_snapshots((PathGlobsAndRoot(globs, text_type(os.path.join(temp_dir, "doesnotexist"))),))
       self.assertIn("doesnotexist", str(cm.exception))
 
+  def test_snapshot_preserving_symlinks(self):
+    with temporary_dir() as temp_dir:
+      with open(os.path.join(temp_dir, "roland"), "w") as f:
+        f.write("European Burmese")
+      os.symlink("roland", os.path.join(temp_dir, "european_burmese"))
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+
+      resolved, preserved = scheduler.capture_snapshots((
+        PathGlobsAndRoot(PathGlobs(("*",), ()), text_type(temp_dir)),
+        PathGlobsAndRoot(PathGlobs(("*",), (), preserve_symlinks=True), text_type(temp_dir)),
+      ))
+      self.assertEqual(["european_burmese", "roland"], sorted(f.path for f in resolved.files))
+      self.assertEqual([], resolved.links)
+      self.assertEqual(["roland"], [f.path for f in preserved.files])
+      self.assertEqual(["european_burmese"], [l.path for l in preserved.links])
+      self.assertNotEqual(resolved.directory_digest, preserved.directory_digest)
+
   def assert_snapshot_equals(self, snapshot, files, directory_digest):
     self.assertEqual([file.path for file in snapshot.files], files)
     self.assertEqual(snapshot.directory_digest, directory_digest)
@@ -359,6 +380,110 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
 
       self.assertEqual(both_snapshot.directory_digest, both_merged)
 
+  def test_merge_directories_with_policy(self):
+    with temporary_dir() as temp_dir:
+      safe_mkdir(os.path.join(temp_dir, "first"))
+      safe_mkdir(os.path.join(temp_dir, "second"))
+      with open(os.path.join(temp_dir, "first", "roland"), "w") as f:
+        f.write("European Burmese")
+      with open(os.path.join(temp_dir, "second", "roland"), "w") as f:
+        f.write("Not sure actually")
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      (first_snapshot, second_snapshot) = scheduler.capture_snapshots((
+        PathGlobsAndRoot(PathGlobs(("roland",), ()), text_type(os.path.join(temp_dir, "first"))),
+        PathGlobsAndRoot(PathGlobs(("roland",), ()), text_type(os.path.join(temp_dir, "second"))),
+      ))
+      digests = (first_snapshot.directory_digest, second_snapshot.directory_digest)
+
+      with self.assertRaises(Exception) as cm:
+        scheduler.merge_directories(digests)
+      self.assertIn("from input 1", str(cm.exception))
+
+      self.assertEqual(first_snapshot.directory_digest,
+                       scheduler.merge_directories(digests, MergePolicy.FIRST_WINS))
+      self.assertEqual(second_snapshot.directory_digest,
+                       scheduler.merge_directories(digests, MergePolicy.LAST_WINS))
+
+  def test_add_and_strip_prefix(self):
+    with temporary_dir() as temp_dir:
+      safe_mkdir(os.path.join(temp_dir, "cats"))
+      with open(os.path.join(temp_dir, "cats", "roland"), "w") as f:
+        f.write("European Burmese")
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      (roland_snapshot, cats_snapshot) = scheduler.capture_snapshots((
+        PathGlobsAndRoot(PathGlobs(("roland",), ()), text_type(os.path.join(temp_dir, "cats"))),
+        PathGlobsAndRoot(PathGlobs(("cats/roland",), ()), text_type(temp_dir)),
+      ))
+
+      prefixed = scheduler.add_prefix(
+        DirectoryWithPrefixToAdd(roland_snapshot.directory_digest, text_type("cats")))
+      self.assertEqual(cats_snapshot.directory_digest, prefixed)
+
+      stripped = scheduler.strip_prefix(
+        DirectoryWithPrefixToStrip(cats_snapshot.directory_digest, text_type("cats")))
+      self.assertEqual(roland_snapshot.directory_digest, stripped)
+
+      with self.assertRaises(Exception) as cm:
+        scheduler.strip_prefix(
+          DirectoryWithPrefixToStrip(cats_snapshot.directory_digest, text_type("dogs")))
+      self.assertIn("dogs", str(cm.exception))
+
+  def test_subset_directory(self):
+    with temporary_dir() as temp_dir:
+      with open(os.path.join(temp_dir, "roland"), "w") as f:
+        f.write("European Burmese")
+      with open(os.path.join(temp_dir, "susannah"), "w") as f:
+        f.write("Not sure actually")
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      (roland_snapshot, both_snapshot) = scheduler.capture_snapshots((
+        PathGlobsAndRoot(PathGlobs(("roland",), ()), text_type(temp_dir)),
+        PathGlobsAndRoot(PathGlobs(("*",), ()), text_type(temp_dir)),
+      ))
+
+      subset = scheduler.subset_directory(
+        DirectorySubset(both_snapshot.directory_digest, PathGlobs(("rol*",), ())))
+      self.assertEqual(roland_snapshot.directory_digest, subset)
+
+      empty = scheduler.subset_directory(
+        DirectorySubset(both_snapshot.directory_digest, PathGlobs(("doesnotmatch",), ())))
+      self.assertEqual(EMPTY_DIRECTORY_DIGEST, empty)
+
+  def test_archive_and_import_directory(self):
+    with temporary_dir() as temp_dir:
+      safe_mkdir(os.path.join(temp_dir, "cats"))
+      with open(os.path.join(temp_dir, "cats", "roland"), "w") as f:
+        f.write("European Burmese")
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      snapshot = scheduler.capture_snapshots((
+        PathGlobsAndRoot(PathGlobs(("**",), ()), text_type(temp_dir)),
+      ))[0]
+
+      for archive_format in ArchiveFormat.allowed_values:
+        with temporary_dir() as archive_dir:
+          archive_path = os.path.join(archive_dir, "cats.{}".format(archive_format))
+          scheduler.archive_directory(DirectoryToArchive(
+            snapshot.directory_digest, text_type(archive_path), text_type(archive_format)))
+          digest = scheduler.import_archive(
+            ArchiveToImport(text_type(archive_path), text_type(archive_format)))
+          self.assertEqual(snapshot.directory_digest, digest)
+
+      with temporary_dir() as archive_dir:
+        archive_path = os.path.join(archive_dir, "cats.tar")
+        scheduler.archive_directory(DirectoryToArchive(
+          snapshot.directory_digest, text_type(archive_path), text_type(ArchiveFormat.TAR)))
+        with tarfile.open(archive_path) as tar:
+          self.assertEqual(["cats", "cats/roland"], tar.getnames())
+          self.assertEqual(0, tar.getmember("cats/roland").mtime)
+
+      # A failed export leaves nothing behind.
+      with temporary_dir() as archive_dir:
+        archive_path = os.path.join(archive_dir, "missing.zip")
+        missing_digest = DirectoryDigest(text_type("1" * 64), 80)
+        with self.assertRaises(Exception):
+          scheduler.archive_directory(DirectoryToArchive(
+            missing_digest, text_type(archive_path), text_type(ArchiveFormat.ZIP)))
+        self.assertEqual([], os.listdir(archive_dir))
+
   def test_materialize_directories(self):
     # I tried passing in the digest of a file, but it didn't make it to the
     # rust code due to all of the checks we have in place (which is probably a good thing).
@@ -378,6 +503,51 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
         content = f.read()
         self.assertEqual(content, "European Burmese")
 
+  def test_materialize_directories_sync(self):
+    self.prime_store_with_roland_digest()
+
+    with temporary_dir() as temp_dir:
+      dir_path = os.path.join(temp_dir, "containing_roland")
+      safe_mkdir(os.path.join(dir_path, "unmanaged"))
+      touch(os.path.join(dir_path, "unmanaged", "stale"))
+      touch(os.path.join(dir_path, "stale"))
+      digest = DirectoryDigest(
+        text_type("63949aa823baf765eff07b946050d76ec0033144c785a94d3ebd82baa931cd16"),
+        80
+      )
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      scheduler.materialize_directories((
+        DirectoryToMaterialize(text_type(dir_path), digest, sync=True),
+      ))
+
+      self.assertEqual(["roland"], os.listdir(dir_path))
+      with open(os.path.join(dir_path, "roland"), 'r') as f:
+        self.assertEqual(f.read(), "European Burmese")
+
+  def test_materialize_directories_sync_managed_prefix(self):
+    self.prime_store_with_roland_digest()
+
+    with temporary_dir() as temp_dir:
+      dir_path = os.path.join(temp_dir, "containing_roland")
+      safe_mkdir(os.path.join(dir_path, "managed"))
+      touch(os.path.join(dir_path, "managed", "stale"))
+      touch(os.path.join(dir_path, "unmanaged"))
+      digest = DirectoryDigest(
+        text_type("63949aa823baf765eff07b946050d76ec0033144c785a94d3ebd82baa931cd16"),
+        80
+      )
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      scheduler.materialize_directories((
+        DirectoryToMaterialize(
+          text_type(dir_path),
+          digest,
+          sync=True,
+          managed_prefix=text_type("managed"),
+        ),
+      ))
+
+      self.assertEqual(["roland", "unmanaged"], sorted(os.listdir(dir_path)))
+
   def test_glob_match_error(self):
     with self.assertRaises(ValueError) as cm:
       self.assert_walk_files(PathGlobs(
@@ -10,10 +10,11 @@ from builtins import str
 
 from future.utils import text_type
 
-from pants.engine.fs import (EMPTY_DIRECTORY_DIGEST, DirectoryDigest, FileContent, FilesContent,
-                             PathGlobs, Snapshot)
+from pants.engine.fs import (EMPTY_DIRECTORY_DIGEST, DirectoryDigest, FileContent, FileDigest,
+                             FilesContent, PathGlobs, Snapshot)
 from pants.engine.isolated_process import (ExecuteProcessRequest, ExecuteProcessResult,
-                                           FallibleExecuteProcessResult, ProcessExecutionFailure)
+                                           FallibleExecuteProcessResult, ProcessExecutionFailure,
+                                           ResourceLimits)
 from pants.engine.rules import RootRule, rule
 from pants.engine.scheduler import ExecutionError
 from pants.engine.selectors import Get, Select
@@ -259,6 +260,12 @@ class ExecuteProcessRequestTest(unittest.TestCase):
         description=''
       )
 
+  def test_resource_limits(self):
+    req = self._default_args_execute_process_request()
+    self.assertEqual(req.resource_limits, ResourceLimits())
+    with self.assertRaisesRegexp(TypeCheckError, "cpu_seconds"):
+      ResourceLimits(cpu_seconds=1.5)
+
   def test_create_from_snapshot_with_env(self):
     req = ExecuteProcessRequest(
       argv=('foo',),
@@ -401,6 +408,20 @@ class Broken {
       result = self.scheduler.product_request(ExecuteProcessResult, [request])[0]
       self.assertEqual(result.stdout, 'European Burmese')
 
+  def test_stdin(self):
+    # Capturing the file makes its content known to the engine.
+    self.create_file('roland', 'European Burmese')
+    self.scheduler.product_request(Snapshot, [PathGlobs(include=['roland'])])
+
+    request = ExecuteProcessRequest(
+      argv=('/bin/cat',),
+      input_files=EMPTY_DIRECTORY_DIGEST,
+      description='cat stdin roland',
+      stdin=FileDigest.for_content(b'European Burmese'),
+    )
+    result = self.scheduler.product_request(ExecuteProcessResult, [request])[0]
+    self.assertEqual(result.stdout, b'European Burmese')
+
   def test_fallible_failing_command_returns_exited_result(self):
     request = ExecuteProcessRequest(
       argv=("/bin/bash", "-c", "exit 1"),
@@ -422,3 +443,30 @@ class Broken {
     with self.assertRaises(ExecutionError) as cm:
       self.scheduler.product_request(ExecuteProcessResult, [request])
     self.assertIn("process 'one-cat' failed with exit code 1.", str(cm.exception))
+
+  def test_resource_usage(self):
+    request = ExecuteProcessRequest(
+      argv=("/bin/bash", "-c", "echo -n 'European Burmese'"),
+      description='limited-cat',
+      input_files=EMPTY_DIRECTORY_DIGEST,
+      resource_limits=ResourceLimits(cpu_seconds=60, open_files=256),
+      report_resource_usage=True,
+    )
+
+    result = self.scheduler.product_request(FallibleExecuteProcessResult, [request])[0]
+
+    self.assertEqual(result.exit_code, 0)
+    self.assertEqual(result.stdout, b'European Burmese')
+    self.assertGreater(result.resource_usage.max_rss_bytes, 0)
+
+  def test_max_output_bytes(self):
+    request = ExecuteProcessRequest(
+      argv=("/bin/bash", "-c", "echo -n 'European Burmese'"),
+      description='chatty-cat',
+      input_files=EMPTY_DIRECTORY_DIGEST,
+      resource_limits=ResourceLimits(max_output_bytes=8),
+    )
+
+    with self.assertRaises(ExecutionError) as cm:
+      self.scheduler.product_request(FallibleExecuteProcessResult, [request])
+    self.assertIn("limit of 8 bytes of output", str(cm.exception))